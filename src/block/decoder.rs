@@ -5,7 +5,7 @@ use crate::event::Event;
 use crate::image::Image;
 use crate::status::Status;
 
-use crate::enc_dec_builder::{EncoderDecoderBuilder, PtEncoderDecoder};
+use crate::enc_dec_builder::{EncoderDecoderBuilder, PtDecoder, PtEncoderDecoder};
 #[cfg(feature = "libipt_master")]
 use libipt_sys::pt_blk_resync;
 use libipt_sys::{
@@ -22,6 +22,8 @@ use std::ptr::NonNull;
 /// raw trace data and remain valid for the lifetime of the decoder.
 ///
 /// The decoder needs to be synchronized before it can be used.
+///
+/// The lifetime `'a` bounds both the trace buffer and the custom image, if any.
 #[derive(Debug)]
 pub struct BlockDecoder<'a> {
     inner: NonNull<pt_block_decoder>,
@@ -29,6 +31,8 @@ pub struct BlockDecoder<'a> {
     custom_image: Option<&'a mut Image>,
 }
 
+impl PtDecoder for BlockDecoder<'_> {}
+
 impl PtEncoderDecoder for BlockDecoder<'_> {
     /// Create an Intel PT block decoder.
     ///
@@ -184,7 +188,12 @@ impl BlockDecoder<'_> {
     /// Returns Eos if the decoder reaches the end of its trace buffer.
     /// Returns Nosync if there is no syncpoint at @offset.
     pub fn set_sync(&mut self, offset: u64) -> Result<(), PtError> {
-        ensure_ptok(unsafe { pt_blk_sync_set(self.inner.as_ptr(), offset) })
+        self.set_sync_status(offset).map(|_| ())
+    }
+
+    /// Same as `set_sync()`, also returning the decoder status, e.g. to process pending events.
+    pub(crate) fn set_sync_status(&mut self, offset: u64) -> Result<Status, PtError> {
+        extract_status_or_pterr(unsafe { pt_blk_sync_set(self.inner.as_ptr(), offset) })
     }
 
     /// Return the current time.
@@ -275,18 +284,15 @@ mod test {
 
     #[test]
     fn test_blkdec_alloc() {
-        let mut kek = [1u8; 2];
+        let kek = [1u8; 2];
         let builder = BlockDecoder::builder();
-        unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        builder.buffer(&kek).build().unwrap();
     }
 
     #[test]
     fn test_blkdec_props() {
-        let kek = &mut [1u8; 2];
-        let mut builder = BlockDecoder::builder();
-        builder = unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) };
+        let kek = [1u8; 2];
+        let builder = BlockDecoder::builder().buffer(&kek);
         let mut b = builder.build().unwrap();
 
         let a = b.asid().unwrap();
@@ -0,0 +1,493 @@
+use crate::error::PtError;
+use crate::event::ExecModeType;
+
+/// The maximum size of an x86 instruction.
+pub(crate) const MAX_INSN_SIZE: usize = 15;
+/// The size of the memory read at once from the image.
+const WINDOW_SIZE: usize = 256;
+
+/// The memory around the decoded instructions, read from the image by chunks.
+#[derive(Debug, Clone)]
+pub(crate) struct Window {
+    bytes: [u8; WINDOW_SIZE],
+    ip: u64,
+    len: usize,
+}
+
+impl Window {
+    pub(crate) fn new() -> Self {
+        Self {
+            bytes: [0; WINDOW_SIZE],
+            ip: 0,
+            len: 0,
+        }
+    }
+
+    /// The memory at @ip, at least `MAX_INSN_SIZE` bytes unless the section ends before.
+    ///
+    /// The window is refilled with @read, given the buffer and @ip, unless it holds a full
+    /// instruction at @ip or the end of a section. After an error the window is empty.
+    pub(crate) fn fetch<F>(&mut self, ip: u64, read: F) -> Result<&[u8], PtError>
+    where
+        F: FnOnce(&mut [u8], u64) -> Result<usize, PtError>,
+    {
+        let offset = ip.wrapping_sub(self.ip);
+        let cached = offset < self.len as u64
+            && (self.len < WINDOW_SIZE || self.len - offset as usize >= MAX_INSN_SIZE);
+        if !cached {
+            self.len = 0;
+            self.len = read(&mut self.bytes, ip)?;
+            self.ip = ip;
+        }
+        let offset = (ip - self.ip) as usize;
+        Ok(&self.bytes[offset..self.len])
+    }
+}
+
+/// Where the execution continues after an instruction, as far as it can be told without trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Next {
+    /// The next instruction in memory, also for conditional branches not taken, returns and
+    /// other control flow changes that end a block
+    Sequential,
+    /// The target of a direct jump or call
+    Direct(u64),
+    /// The target of an indirect or far jump or call, only known from the trace
+    Indirect,
+}
+
+/// The kind of branch of an instruction and how it is traced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Branch {
+    /// Not a branch
+    None,
+    /// A conditional branch (jcc, loop, jcxz) to the given target, traced with a TNT bit
+    Conditional(u64),
+    /// A direct near jump to the given target
+    Jump(u64),
+    /// A direct near call to the given target
+    Call(u64),
+    /// A near return, traced with a TIP or with a TNT bit when compressed
+    Return,
+    /// An indirect near jump, traced with a TIP
+    Indirect,
+    /// An indirect near call, traced with a TIP
+    IndirectCall,
+    /// A far transfer, traced with a TIP: far jump, call and return, software interrupt, system
+    /// call and return
+    Far,
+}
+
+/// The length and the control flow of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Ild {
+    pub(crate) size: u8,
+    pub(crate) next: Next,
+    pub(crate) branch: Branch,
+}
+
+/// The target of the relative branch at @ip, whose @imm bytes displacement ends the instruction
+/// at @end.
+fn rel_target(bytes: &[u8], end: usize, imm: usize, ip: u64, bits: u32, osz: usize) -> Option<u64> {
+    let rel = bytes.get(end - imm..end)?;
+    let rel = match rel.len() {
+        1 => i64::from(rel[0] as i8),
+        2 => i64::from(i16::from_le_bytes([rel[0], rel[1]])),
+        _ => i64::from(i32::from_le_bytes([rel[0], rel[1], rel[2], rel[3]])),
+    };
+    let target = ip.wrapping_add(end as u64).wrapping_add(rel as u64);
+    Some(match (bits, osz) {
+        (64, _) => target,
+        (_, 16) => target & 0xffff,
+        _ => target & 0xffff_ffff,
+    })
+}
+
+/// The size of the ModRM byte, SIB byte and displacement starting at @pos.
+fn modrm_size(bytes: &[u8], pos: usize, asz: u32) -> Option<usize> {
+    let modrm = *bytes.get(pos)?;
+    let md = modrm >> 6;
+    let rm = modrm & 7;
+    if md == 3 {
+        return Some(1);
+    }
+    if asz == 16 {
+        return Some(match (md, rm) {
+            (0, 6) | (2, _) => 3,
+            (0, _) => 1,
+            _ => 2,
+        });
+    }
+
+    let mut size = 1;
+    let mut base = rm;
+    if rm == 4 {
+        base = *bytes.get(pos + 1)? & 7;
+        size += 1;
+    }
+    size += match md {
+        0 if base == 5 => 4,
+        0 => 0,
+        1 => 1,
+        _ => 4,
+    };
+    Some(size)
+}
+
+/// Whether the one-byte opcode @op has a ModRM byte.
+fn one_byte_has_modrm(op: u8) -> bool {
+    match op {
+        0x00..=0x3f => op & 7 < 4,
+        0x62 | 0x63 | 0x69 | 0x6b | 0x80..=0x8f | 0xc0 | 0xc1 | 0xc4..=0xc7 | 0xd0..=0xd3 => true,
+        0xd8..=0xdf | 0xf6 | 0xf7 | 0xfe | 0xff => true,
+        _ => false,
+    }
+}
+
+/// Whether the two-byte opcode `0f @op` has a ModRM byte.
+fn two_byte_has_modrm(op: u8) -> bool {
+    !matches!(
+        op,
+        0x04..=0x0c
+            | 0x0e
+            | 0x30..=0x37
+            | 0x77
+            | 0x80..=0x8f
+            | 0xa0..=0xa2
+            | 0xa8..=0xaa
+            | 0xc8..=0xcf
+    )
+}
+
+/// Decode the length of the instruction at the start of @bytes, at @ip in execution mode @mode.
+///
+/// Returns `None` if @mode is unknown, or if @bytes are too short or not a valid instruction.
+pub(crate) fn decode(bytes: &[u8], ip: u64, mode: ExecModeType) -> Option<Ild> {
+    let bits = match mode {
+        ExecModeType::Bit16 => 16,
+        ExecModeType::Bit32 => 32,
+        ExecModeType::Bit64 => 64,
+        ExecModeType::Unknown => return None,
+    };
+    let bytes = &bytes[..bytes.len().min(MAX_INSN_SIZE)];
+
+    // Legacy and REX prefixes, a REX prefix is ignored unless it comes last.
+    let mut pos = 0;
+    let mut osz_prefix = false;
+    let mut asz_prefix = false;
+    let mut rex = 0;
+    loop {
+        match *bytes.get(pos)? {
+            0x66 => osz_prefix = true,
+            0x67 => asz_prefix = true,
+            0xf0 | 0xf2 | 0xf3 | 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 => {}
+            b @ 0x40..=0x4f if bits == 64 => {
+                rex = b;
+                pos += 1;
+                continue;
+            }
+            _ => break,
+        }
+        rex = 0;
+        pos += 1;
+    }
+    let rex_w = rex & 8 != 0;
+    let osz: usize = match (bits, rex_w, osz_prefix) {
+        (64, true, _) => 64,
+        (16, _, false) | (32 | 64, _, true) => 16,
+        _ => 32,
+    };
+    let asz: u32 = match (bits, asz_prefix) {
+        (64, false) => 64,
+        (16, false) | (32, true) => 16,
+        _ => 32,
+    };
+    let immz = if osz == 16 { 2 } else { 4 };
+    // Near branches ignore the operand size prefix in 64-bit mode.
+    let relz = if bits == 64 { 4 } else { immz };
+
+    let op = *bytes.get(pos)?;
+    pos += 1;
+    let mut branch = Branch::None;
+    let imm: usize;
+    match op {
+        // VEX and EVEX, LES, LDS and BOUND with a memory operand outside of 64-bit mode.
+        0xc4 | 0xc5 | 0x62 if bits == 64 || *bytes.get(pos)? >> 6 == 3 => {
+            let evex = op == 0x62;
+            let (map, payload) = match op {
+                0xc5 => (1, 1),
+                0xc4 => (*bytes.get(pos)? & 0x1f, 2),
+                _ => (*bytes.get(pos)? & 7, 3),
+            };
+            pos += payload;
+            let op = *bytes.get(pos)?;
+            pos += 1;
+            // vzeroupper and vzeroall have no ModRM.
+            if evex || map != 1 || op != 0x77 {
+                pos += modrm_size(bytes, pos, asz)?;
+            }
+            imm = match (map, op) {
+                (3, _) | (1, 0x70..=0x73 | 0xc2 | 0xc4..=0xc6) => 1,
+                _ => 0,
+            };
+        }
+        // XOP, POP with a memory operand otherwise.
+        0x8f if *bytes.get(pos)? & 0x1f >= 8 => {
+            let map = bytes[pos] & 0x1f;
+            pos += 3;
+            pos += modrm_size(bytes, pos, asz)?;
+            imm = match map {
+                8 => 1,
+                0xa => 4,
+                _ => 0,
+            };
+        }
+        0x0f => {
+            let op = *bytes.get(pos)?;
+            pos += 1;
+            match op {
+                0x38 => {
+                    pos += 1;
+                    pos += modrm_size(bytes, pos, asz)?;
+                    imm = 0;
+                }
+                0x3a => {
+                    pos += 1;
+                    pos += modrm_size(bytes, pos, asz)?;
+                    imm = 1;
+                }
+                // 3DNow!, the opcode is a suffix byte.
+                0x0f => {
+                    pos += modrm_size(bytes, pos, asz)?;
+                    imm = 1;
+                }
+                _ => {
+                    if two_byte_has_modrm(op) {
+                        pos += modrm_size(bytes, pos, asz)?;
+                    }
+                    imm = match op {
+                        0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => 1,
+                        0x80..=0x8f => relz,
+                        _ => 0,
+                    };
+                    branch = match op {
+                        0x80..=0x8f => {
+                            Branch::Conditional(rel_target(bytes, pos + imm, imm, ip, bits, osz)?)
+                        }
+                        // syscall, sysret, sysenter and sysexit.
+                        0x05 | 0x07 | 0x34 | 0x35 => Branch::Far,
+                        _ => Branch::None,
+                    };
+                }
+            }
+        }
+        _ => {
+            let reg = bytes.get(pos).map_or(0, |modrm| (modrm >> 3) & 7);
+            if one_byte_has_modrm(op) {
+                pos += modrm_size(bytes, pos, asz)?;
+            }
+            imm = match op {
+                0x00..=0x3f if op & 7 == 4 => 1,
+                0x00..=0x3f if op & 7 == 5 => immz,
+                0x68 | 0x69 | 0x81 | 0xa9 | 0xc7 => immz,
+                0xe8 | 0xe9 => relz,
+                0x6a | 0x6b | 0x70..=0x7f | 0x80 | 0x82 | 0x83 | 0xa8 | 0xb0..=0xb7 => 1,
+                0xc0 | 0xc1 | 0xc6 | 0xcd | 0xd4 | 0xd5 | 0xe0..=0xe7 | 0xeb => 1,
+                0xb8..=0xbf => osz / 8,
+                0xa0..=0xa3 => asz as usize / 8,
+                0xc2 | 0xca => 2,
+                0xc8 => 3,
+                0x9a | 0xea if bits == 64 => return None,
+                0x9a | 0xea => 2 + immz,
+                0xf6 if reg < 2 => 1,
+                0xf7 if reg < 2 => immz,
+                _ => 0,
+            };
+            let end = pos + imm;
+            branch = match op {
+                0x70..=0x7f | 0xe0..=0xe3 => {
+                    Branch::Conditional(rel_target(bytes, end, imm, ip, bits, osz)?)
+                }
+                0xe8 => Branch::Call(rel_target(bytes, end, imm, ip, bits, osz)?),
+                0xe9 | 0xeb => Branch::Jump(rel_target(bytes, end, imm, ip, bits, osz)?),
+                0xc2 | 0xc3 => Branch::Return,
+                0xff if reg == 2 => Branch::IndirectCall,
+                0xff if reg == 4 => Branch::Indirect,
+                0xff if reg == 3 || reg == 5 => Branch::Far,
+                0x9a | 0xea | 0xca..=0xcf | 0xf1 => Branch::Far,
+                _ => Branch::None,
+            };
+        }
+    }
+
+    let next = match branch {
+        Branch::Jump(target) | Branch::Call(target) => Next::Direct(target),
+        Branch::Indirect | Branch::IndirectCall => Next::Indirect,
+        Branch::Far if matches!(op, 0x9a | 0xea | 0xff) => Next::Indirect,
+        _ => Next::Sequential,
+    };
+    let size = pos + imm;
+    (size <= bytes.len()).then_some(Ild {
+        size: size as u8,
+        next,
+        branch,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn size(bytes: &[u8], mode: ExecModeType) -> Option<u8> {
+        decode(bytes, 0x1000, mode).map(|ild| ild.size)
+    }
+
+    #[test]
+    fn test_ild_sizes() {
+        let bit64: &[&[u8]] = &[
+            &[0x90],
+            &[0x31, 0xc0],
+            &[0x48, 0xb8, 1, 2, 3, 4, 5, 6, 7, 8],
+            &[0x66, 0xb8, 1, 2],
+            &[0x8b, 0x05, 1, 2, 3, 4],
+            &[0x89, 0x44, 0x8b, 0x08],
+            &[0x48, 0x81, 0x84, 0x24, 1, 2, 3, 4, 5, 6, 7, 8],
+            &[0xf3, 0x0f, 0x1e, 0xfa],
+            &[0x0f, 0x84, 1, 2, 3, 4],
+            &[0x66, 0x0f, 0x3a, 0x0f, 0xc1, 0x08],
+            &[0xc5, 0xf8, 0x77],
+            &[0xc4, 0xe3, 0x79, 0x0f, 0xc1, 0x08],
+            &[0x62, 0xf1, 0x7c, 0x48, 0x10, 0x44, 0x24, 0x01],
+            &[0x8f, 0xe8, 0x78, 0xc2, 0xc1, 0x08],
+            &[0xf6, 0xc0, 0x01],
+            &[0xf6, 0xd0],
+            &[0xc8, 0x10, 0x00, 0x01],
+            &[0xa1, 1, 2, 3, 4, 5, 6, 7, 8],
+            &[0x0f, 0x0f, 0xc1, 0x9e],
+        ];
+        for bytes in bit64 {
+            assert_eq!(
+                size(bytes, ExecModeType::Bit64),
+                Some(bytes.len() as u8),
+                "{bytes:02x?}"
+            );
+        }
+
+        // LDS, mov ax, [0x1234] and the segment:offset far jump.
+        assert_eq!(size(&[0xc5, 0x06], ExecModeType::Bit32), Some(2));
+        assert_eq!(
+            size(&[0x8b, 0x06, 0x34, 0x12], ExecModeType::Bit16),
+            Some(4)
+        );
+        assert_eq!(size(&[0xea, 1, 2, 3, 4], ExecModeType::Bit16), Some(5));
+        assert_eq!(
+            size(&[0xea, 1, 2, 3, 4, 5, 6], ExecModeType::Bit32),
+            Some(7)
+        );
+        assert_eq!(size(&[0xea, 1, 2, 3, 4, 5, 6], ExecModeType::Bit64), None);
+
+        assert_eq!(size(&[0x0f, 0x84, 1, 2], ExecModeType::Bit64), None);
+        assert_eq!(size(&[0x66; 16], ExecModeType::Bit64), None);
+        assert_eq!(size(&[0x90], ExecModeType::Unknown), None);
+    }
+
+    #[test]
+    fn test_ild_next() {
+        let next = |bytes: &[u8], mode| decode(bytes, 0x1000, mode).unwrap().next;
+        let bit64 = ExecModeType::Bit64;
+        assert_eq!(next(&[0xe8, 3, 0, 0, 0], bit64), Next::Direct(0x1008));
+        assert_eq!(
+            next(&[0xe9, 0xfb, 0xff, 0xff, 0xff], bit64),
+            Next::Direct(0x1000)
+        );
+        assert_eq!(next(&[0xeb, 0xfe], bit64), Next::Direct(0x1000));
+        assert_eq!(next(&[0x66, 0xe8, 3, 0, 0, 0], bit64), Next::Direct(0x1009));
+        assert_eq!(next(&[0x74, 0x02], bit64), Next::Sequential);
+        assert_eq!(next(&[0xc3], bit64), Next::Sequential);
+        assert_eq!(next(&[0xff, 0xe0], bit64), Next::Indirect);
+        assert_eq!(next(&[0xff, 0x15, 0, 0, 0, 0], bit64), Next::Indirect);
+        assert_eq!(next(&[0xff, 0xc0], bit64), Next::Sequential);
+
+        // The IP wraps around at 64KiB with a 16-bit operand size.
+        assert_eq!(
+            decode(&[0xe9, 0xfd, 0xef], 0x1000, ExecModeType::Bit16)
+                .unwrap()
+                .next,
+            Next::Direct(0x0000)
+        );
+    }
+
+    #[test]
+    fn test_ild_branch() {
+        let branch = |bytes: &[u8], mode| decode(bytes, 0x1000, mode).unwrap().branch;
+        let bit64 = ExecModeType::Bit64;
+        assert_eq!(branch(&[0x74, 0x02], bit64), Branch::Conditional(0x1004));
+        assert_eq!(
+            branch(&[0x0f, 0x85, 0xfa, 0xff, 0xff, 0xff], bit64),
+            Branch::Conditional(0x1000)
+        );
+        assert_eq!(branch(&[0xe2, 0xfe], bit64), Branch::Conditional(0x1000));
+        assert_eq!(branch(&[0xe8, 3, 0, 0, 0], bit64), Branch::Call(0x1008));
+        assert_eq!(branch(&[0xeb, 0xfe], bit64), Branch::Jump(0x1000));
+        assert_eq!(branch(&[0xc3], bit64), Branch::Return);
+        assert_eq!(branch(&[0xc2, 8, 0], bit64), Branch::Return);
+        assert_eq!(branch(&[0xff, 0xe0], bit64), Branch::Indirect);
+        assert_eq!(branch(&[0xff, 0xd0], bit64), Branch::IndirectCall);
+        assert_eq!(branch(&[0x0f, 0x05], bit64), Branch::Far);
+        assert_eq!(branch(&[0xcd, 0x80], bit64), Branch::Far);
+        assert_eq!(branch(&[0x48, 0xcf], bit64), Branch::Far);
+        assert_eq!(branch(&[0xff, 0xc0], bit64), Branch::None);
+        assert_eq!(branch(&[0x0f, 0x1f, 0x00], bit64), Branch::None);
+
+        // System calls end a block without being an indirect branch.
+        assert_eq!(
+            decode(&[0x0f, 0x05], 0x1000, bit64).unwrap().next,
+            Next::Sequential
+        );
+        assert_eq!(
+            branch(&[0x74, 0xfe], ExecModeType::Bit16),
+            Branch::Conditional(0x1000)
+        );
+    }
+
+    #[test]
+    fn test_ild_window() {
+        fn fetch(window: &mut Window, reads: &mut Vec<u64>, ip: u64, len: usize) -> usize {
+            let read = |buffer: &mut [u8], ip| {
+                reads.push(ip);
+                Ok(len.min(buffer.len()))
+            };
+            window.fetch(ip, read).unwrap().len()
+        }
+
+        let mut reads = Vec::new();
+        let mut window = Window::new();
+        // A full window is refilled when less than an instruction is left.
+        assert_eq!(
+            fetch(&mut window, &mut reads, 0x1000, usize::MAX),
+            WINDOW_SIZE
+        );
+        let ip = 0x1000 + (WINDOW_SIZE - MAX_INSN_SIZE) as u64;
+        assert_eq!(
+            fetch(&mut window, &mut reads, ip, usize::MAX),
+            MAX_INSN_SIZE
+        );
+        assert_eq!(
+            fetch(&mut window, &mut reads, ip + 1, usize::MAX),
+            WINDOW_SIZE
+        );
+        // A short read is the end of a section, it is kept up to its last byte.
+        assert_eq!(fetch(&mut window, &mut reads, 0x2000, 4), 4);
+        assert_eq!(fetch(&mut window, &mut reads, 0x2003, 4), 1);
+        assert_eq!(reads, [0x1000, ip + 1, 0x2000]);
+
+        // Errors empty the window.
+        assert!(
+            window
+                .fetch(0x2000, |_, _| Err(PtError::from_code(-1)))
+                .is_err()
+        );
+        assert_eq!(fetch(&mut window, &mut reads, 0x2000, 4), 4);
+        assert_eq!(reads.len(), 4);
+    }
+}
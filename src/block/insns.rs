@@ -0,0 +1,162 @@
+use super::Block;
+use super::ild::{self, MAX_INSN_SIZE, Next, Window};
+use crate::asid::Asid;
+use crate::error::{PtError, PtErrorCode};
+use crate::image::Image;
+
+/// An instruction of a `Block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInsn {
+    ip: u64,
+    size: u8,
+    raw: [u8; MAX_INSN_SIZE],
+}
+
+impl BlockInsn {
+    /// The virtual address of the instruction.
+    #[must_use]
+    pub fn ip(&self) -> u64 {
+        self.ip
+    }
+
+    /// The size of the instruction in bytes.
+    #[must_use]
+    pub fn size(&self) -> u8 {
+        self.size
+    }
+
+    /// The raw bytes of the instruction.
+    #[must_use]
+    pub fn raw(&self) -> &[u8] {
+        &self.raw[..usize::from(self.size)]
+    }
+}
+
+/// Iterator over the instructions of a `Block`, created by `Block::instructions()`.
+///
+/// The instructions are read from the section of the block in the image and their length is
+/// decoded in the execution mode of the block. Direct jumps and calls are followed.
+/// After an error the iterator ends.
+#[derive(Debug)]
+pub struct BlockInsns<'a> {
+    image: &'a Image,
+    asid: Option<Asid>,
+    block: Block,
+    ip: u64,
+    left: u16,
+    window: Window,
+}
+
+impl<'a> BlockInsns<'a> {
+    pub(super) fn new(block: Block, image: &'a Image) -> Self {
+        Self {
+            image,
+            asid: None,
+            block,
+            ip: block.ip(),
+            left: block.ninsn(),
+            window: Window::new(),
+        }
+    }
+
+    /// Read the in-memory sections of the image in @asid, e.g. the `asid()` of the decoder.
+    ///
+    /// By default in-memory sections of any address space are read.
+    #[must_use]
+    pub fn with_asid(mut self, asid: Asid) -> Self {
+        self.asid = Some(asid);
+        self
+    }
+
+    /// The memory at @ip, at least `MAX_INSN_SIZE` bytes unless the section ends before.
+    fn fetch(&mut self, ip: u64) -> Result<&[u8], PtError> {
+        let (image, asid) = (self.image, self.asid.as_ref());
+        let isid = u32::try_from(self.block.isid()).unwrap_or(0);
+        self.window
+            .fetch(ip, |buffer, ip| image.read(buffer, isid, ip, asid))
+    }
+
+    fn step(&mut self) -> Result<BlockInsn, PtError> {
+        let ip = self.ip;
+        let block = self.block;
+        let mode = block.mode();
+        let last = self.left == 1;
+        let mut insn = BlockInsn {
+            ip,
+            size: 0,
+            raw: [0; MAX_INSN_SIZE],
+        };
+
+        let bytes = match block.raw() {
+            Some(raw) if last => {
+                insn.raw[..raw.len()].copy_from_slice(raw);
+                &insn.raw[..raw.len()]
+            }
+            _ => self.fetch(ip)?,
+        };
+        let ild = ild::decode(bytes, ip, mode).ok_or(PtError::new(
+            PtErrorCode::BadInsn,
+            "Failed to decode the instruction length",
+        ))?;
+        let size = usize::from(ild.size);
+        let mut raw = [0; MAX_INSN_SIZE];
+        raw[..size].copy_from_slice(&bytes[..size]);
+        insn.raw = raw;
+        insn.size = ild.size;
+
+        self.left -= 1;
+        if last {
+            if ip != block.end_ip() {
+                return Err(PtError::new(
+                    PtErrorCode::BadInsn,
+                    "The last instruction is not at the end of the block",
+                ));
+            }
+        } else {
+            self.ip = match ild.next {
+                Next::Sequential => ip.wrapping_add(u64::from(ild.size)),
+                Next::Direct(target) => target,
+                Next::Indirect => {
+                    return Err(PtError::new(
+                        PtErrorCode::BadInsn,
+                        "Indirect branch inside a block",
+                    ));
+                }
+            };
+        }
+        Ok(insn)
+    }
+}
+
+impl Iterator for BlockInsns<'_> {
+    type Item = Result<BlockInsn, PtError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        let result = self.step();
+        if result.is_err() {
+            self.left = 0;
+        }
+        Some(result)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(usize::from(self.left)))
+    }
+}
+
+impl Block {
+    /// Iterate over the instructions of this block, reading them from @image.
+    ///
+    /// @image must be the image used by the decoder, e.g. `BlockDecoder::image()`.
+    /// The block's section must have been added through a `SectionCache` or with
+    /// `Image::add_bytes()`, see `Image::read()`.
+    /// The iterator yields `BadInsn` if an instruction can't be decoded or the block doesn't
+    /// end at `end_ip()`, and `Nomap` if the memory of the block can't be read.
+    #[must_use]
+    pub fn instructions<'a>(&self, image: &'a Image) -> BlockInsns<'a> {
+        BlockInsns::new(*self, image)
+    }
+}
@@ -7,16 +7,30 @@ use libipt_sys::pt_block;
 use std::convert::TryFrom;
 
 mod decoder;
+pub(crate) mod ild;
+mod insns;
 pub use decoder::*;
+pub use insns::*;
 
 /// A block of instructions.
 ///
 /// Instructions in this block are executed sequentially but are not necessarily
-/// contiguous in memory. Users are expected to follow direct branches.
+/// contiguous in memory. Users are expected to follow direct branches, or to use
+/// `instructions()`.
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct Block(pub(super) pt_block);
 impl Block {
+    /// A block of @ninsn instructions from @ip to @end_ip, the other fields are zero.
+    #[cfg(test)]
+    pub(crate) fn from_range(ip: u64, end_ip: u64, ninsn: u16) -> Self {
+        let mut block: pt_block = unsafe { std::mem::zeroed() };
+        block.ip = ip;
+        block.end_ip = end_ip;
+        block.ninsn = ninsn;
+        Self(block)
+    }
+
     /// The IP of the first instruction in this block.
     #[must_use]
     pub const fn ip(&self) -> u64 {
@@ -101,7 +115,9 @@ impl Block {
 #[cfg(test)]
 mod test {
     use super::*;
-    use libipt_sys::{pt_exec_mode_ptem_32bit, pt_insn_class_ptic_error};
+    use crate::image::Image;
+    use crate::synth::fixture;
+    use libipt_sys::{pt_exec_mode_ptem_32bit, pt_exec_mode_ptem_64bit, pt_insn_class_ptic_error};
 
     #[test]
     fn test_block_props() {
@@ -158,4 +174,46 @@ mod test {
         assert!(!blk.truncated());
         assert!(!blk.speculative());
     }
+
+    #[test]
+    fn test_block_instructions() {
+        let image = fixture::image();
+
+        let block = |ninsn, end_ip| {
+            Block(pt_block {
+                ip: fixture::BASE,
+                end_ip,
+                isid: 0,
+                mode: pt_exec_mode_ptem_64bit,
+                iclass: pt_insn_class_ptic_error,
+                ninsn,
+                raw: [0; 15],
+                size: 0,
+                _bitfield_align_1: [],
+                _bitfield_1: pt_block::new_bitfield_1(0, 0),
+                __bindgen_padding_0: Default::default(),
+            })
+        };
+
+        // The conditional branch is not taken, the call is followed.
+        let insns: Vec<_> = block(6, 0x1010)
+            .instructions(&image)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let ips: Vec<u64> = insns.iter().map(|insn| insn.ip()).collect();
+        assert_eq!(ips, [0x1000, 0x1002, 0x1004, 0x1006, 0x1008, 0x1010]);
+        assert_eq!(insns[4].raw(), &fixture::CODE[8..13]);
+        assert_eq!(insns[5].size(), 1);
+
+        let mut insns = block(6, 0x100d).instructions(&image);
+        assert_eq!(insns.by_ref().filter(Result::is_ok).count(), 5);
+        assert!(insns.next().is_none());
+
+        let err = block(1, 0x1000)
+            .instructions(&Image::new(None).unwrap())
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.code(), crate::error::PtErrorCode::Nomap);
+    }
 }
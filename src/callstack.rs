@@ -0,0 +1,504 @@
+use crate::asid::Asid;
+use crate::block::{Block, BlockDecoder};
+use crate::enc_dec_builder::EncoderDecoderBuilder;
+use crate::error::PtError;
+use crate::event::{Event, EventType};
+use crate::insn::Class;
+use crate::merge::{Decoded, Lane, TraceDecoder};
+use std::collections::{HashMap, VecDeque};
+
+/// The maximum length of an x86 instruction, bounding the distance between a call and its return
+/// address.
+const MAX_INSN_SIZE: u64 = 15;
+
+/// How a function was entered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnterKind {
+    /// A near call
+    Call,
+    /// A call-like far transfer, e.g. SYSCALL
+    FarCall,
+    /// An asynchronous branch, e.g. an interrupt or an exception
+    Async,
+}
+
+/// How a function was exited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitKind {
+    /// A near return
+    Return,
+    /// A return-like far transfer, e.g. SYSRET or IRET
+    FarReturn,
+    /// The frame was skipped by a return to an outer frame, e.g. after a longjmp
+    Unwound,
+    /// The frame was dropped at a trace gap, after an overflow or a decoding error
+    Gap,
+}
+
+/// A frame of the shadow call stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// The IP of the call instruction, or the interrupted instruction for `EnterKind::Async`
+    pub call_site: u64,
+    /// The IP of the first instruction of the function
+    pub entry: u64,
+    /// How the function was entered
+    pub kind: EnterKind,
+}
+
+/// An item of the call stack timeline.
+#[derive(Debug, Clone)]
+pub enum StackEvent {
+    /// A function is entered, @depth is the stack depth including @frame.
+    Enter { frame: Frame, depth: usize },
+    /// A function is exited, @depth is the stack depth without @frame.
+    ///
+    /// @frame is `None` for returns from functions entered before the trace begins.
+    /// @to is the IP execution continues at, `None` at trace gaps.
+    Exit {
+        frame: Option<Frame>,
+        to: Option<u64>,
+        kind: ExitKind,
+        depth: usize,
+    },
+    /// A block executed at the stack depth @depth.
+    Block { block: Block, depth: usize },
+    /// An event of the decoder.
+    Event(Event),
+}
+
+/// A shadow call stack rebuilt from the blocks of a `BlockDecoder`.
+///
+/// Calls and returns are matched by address: a return pops the innermost frame whose call
+/// instruction precedes the return target, exiting the frames above it as `ExitKind::Unwound`.
+/// Far calls and asynchronous branches (interrupts, exceptions) are only exited by far
+/// returns. The stack is emptied at trace gaps (overflows and decoding errors).
+///
+/// A separate stack is kept for every address space, following the paging and VMCS events.
+/// Transfers to code that is not traced (tracing disabled by filters) are ignored.
+///
+/// Direct calls don't end blocks by default: the decoder must be built with
+/// `set_end_on_call(true)` for every call to be seen, see `CallStack::from_builder`.
+#[derive(Debug)]
+pub struct CallStack<D: TraceDecoder<Item = Block>> {
+    lane: Lane<D>,
+    stacks: HashMap<Asid, Vec<Frame>>,
+    asid: Asid,
+    // The last call or return whose target is not known yet: its class and IP.
+    pending: Option<(Class, u64)>,
+    queue: VecDeque<Result<(u64, StackEvent), PtError>>,
+}
+
+impl<'a> CallStack<BlockDecoder<'a>> {
+    /// Follow the call stack of a block decoder built from @builder.
+    ///
+    /// The decoder ends its blocks on calls, whatever the configuration of @builder.
+    /// Its image is empty, fill it with `decoder_mut().image()`.
+    /// Returns an error if the decoder can't be built.
+    pub fn from_builder(
+        builder: &EncoderDecoderBuilder<BlockDecoder<'a>>,
+    ) -> Result<Self, PtError> {
+        let decoder = builder.clone().set_end_on_call(true).build()?;
+        Ok(Self::new(decoder))
+    }
+}
+
+impl<D: TraceDecoder<Item = Block>> CallStack<D> {
+    /// Follow the call stack of the blocks decoded by @decoder.
+    ///
+    /// The decoder must not be synchronized yet.
+    pub fn new(decoder: D) -> Self {
+        Self {
+            lane: Lane::new(decoder),
+            stacks: HashMap::new(),
+            asid: Asid::default(),
+            pending: None,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// The current address space.
+    #[must_use]
+    pub fn asid(&self) -> Asid {
+        self.asid
+    }
+
+    /// The call stack of the current address space, outermost frame first.
+    #[must_use]
+    pub fn stack(&self) -> &[Frame] {
+        self.stacks.get(&self.asid).map_or(&[], Vec::as_slice)
+    }
+
+    /// The depth of the call stack of the current address space.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        self.stack().len()
+    }
+
+    /// The wrapped decoder, e.g. to update its image.
+    #[must_use]
+    pub fn decoder_mut(&mut self) -> &mut D {
+        self.lane.decoder_mut()
+    }
+
+    /// Get back the wrapped decoder.
+    #[must_use]
+    pub fn into_decoder(self) -> D {
+        self.lane.into_decoder()
+    }
+
+    fn emit(&mut self, tsc: u64, event: StackEvent) {
+        self.queue.push_back(Ok((tsc, event)));
+    }
+
+    fn push(&mut self, tsc: u64, frame: Frame) {
+        let stack = self.stacks.entry(self.asid).or_default();
+        stack.push(frame);
+        let depth = stack.len();
+        self.emit(tsc, StackEvent::Enter { frame, depth });
+    }
+
+    /// Pop the frames above @index as `Unwound`, then the frame at @index as @kind.
+    fn pop(&mut self, tsc: u64, index: usize, to: Option<u64>, kind: ExitKind) {
+        let stack = self.stacks.entry(self.asid).or_default();
+        let popped: Vec<Frame> = stack.drain(index..).rev().collect();
+        let last = popped.len().saturating_sub(1);
+        for (i, frame) in popped.into_iter().enumerate() {
+            let kind = if i == last { kind } else { ExitKind::Unwound };
+            let depth = index + last - i;
+            let frame = Some(frame);
+            self.emit(
+                tsc,
+                StackEvent::Exit {
+                    frame,
+                    to,
+                    kind,
+                    depth,
+                },
+            );
+        }
+    }
+
+    /// Exit all the frames of the current stack at a trace gap.
+    fn gap(&mut self, tsc: u64) {
+        self.pending = None;
+        let frames = self.stacks.remove(&self.asid).unwrap_or_default();
+        for (depth, frame) in frames.into_iter().enumerate().rev() {
+            let exit = StackEvent::Exit {
+                frame: Some(frame),
+                to: None,
+                kind: ExitKind::Gap,
+                depth,
+            };
+            self.emit(tsc, exit);
+        }
+    }
+
+    /// Resolve the pending call or return now that execution continued at @target.
+    fn resolve(&mut self, tsc: u64, target: u64) {
+        let Some((class, ip)) = self.pending.take() else {
+            return;
+        };
+        let stack = self.stack();
+        match class {
+            Class::Call | Class::FarCall => {
+                let kind = if class == Class::Call {
+                    EnterKind::Call
+                } else {
+                    EnterKind::FarCall
+                };
+                let frame = Frame {
+                    call_site: ip,
+                    entry: target,
+                    kind,
+                };
+                self.push(tsc, frame);
+            }
+            Class::Return => {
+                // A near return can't cross a far frame.
+                let matched = stack
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .take_while(|(_, frame)| frame.kind == EnterKind::Call)
+                    .find(|(_, frame)| {
+                        (1..=MAX_INSN_SIZE).contains(&target.wrapping_sub(frame.call_site))
+                    })
+                    .map(|(index, _)| index);
+                self.exit(tsc, matched, target, ExitKind::Return);
+            }
+            Class::FarReturn => {
+                let matched = stack
+                    .iter()
+                    .rposition(|frame| frame.kind != EnterKind::Call);
+                self.exit(tsc, matched, target, ExitKind::FarReturn);
+            }
+            _ => {}
+        }
+    }
+
+    fn exit(&mut self, tsc: u64, matched: Option<usize>, to: u64, kind: ExitKind) {
+        match matched {
+            Some(index) => self.pop(tsc, index, Some(to), kind),
+            None => {
+                let depth = self.depth();
+                self.emit(
+                    tsc,
+                    StackEvent::Exit {
+                        frame: None,
+                        to: Some(to),
+                        kind,
+                        depth,
+                    },
+                );
+            }
+        }
+    }
+
+    fn on_block(&mut self, tsc: u64, block: Block) {
+        self.resolve(tsc, block.ip());
+        let depth = self.depth();
+        self.emit(tsc, StackEvent::Block { block, depth });
+        self.pending = matches!(
+            block.class(),
+            Class::Call | Class::FarCall | Class::Return | Class::FarReturn
+        )
+        .then_some((block.class(), block.end_ip()));
+    }
+
+    fn on_event(&mut self, tsc: u64, event: Event) {
+        match event.event_type() {
+            EventType::Paging(paging) => self.asid.set_cr3(paging.cr3()),
+            EventType::AsyncPaging(paging) => self.asid.set_cr3(paging.cr3()),
+            EventType::Vmcs(vmcs) => self.asid.set_vmcs(vmcs.base()),
+            EventType::AsyncVmcs(vmcs) => self.asid.set_vmcs(vmcs.base()),
+            EventType::AsyncBranch(branch) => {
+                self.resolve(tsc, branch.from());
+                let frame = Frame {
+                    call_site: branch.from(),
+                    entry: branch.to(),
+                    kind: EnterKind::Async,
+                };
+                self.push(tsc, frame);
+            }
+            // The target of the pending transfer is not traced.
+            EventType::Disabled(_) | EventType::AsnycDisabled(_) => self.pending = None,
+            EventType::Overflow(_) => self.gap(tsc),
+            _ => {}
+        }
+        self.emit(tsc, StackEvent::Event(event));
+    }
+}
+
+impl<D: TraceDecoder<Item = Block>> Iterator for CallStack<D> {
+    type Item = Result<(u64, StackEvent), PtError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.queue.pop_front() {
+                return Some(item);
+            }
+            match self.lane.fetch()? {
+                Ok((tsc, Decoded::Item(block))) => self.on_block(tsc, block),
+                Ok((tsc, Decoded::Event(event))) => self.on_event(tsc, event),
+                Err(e) => {
+                    // The decoder resynchronizes at the next PSB.
+                    self.gap(self.lane.tsc());
+                    self.queue.push_back(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::enc_dec_builder::PtEncoderDecoder;
+    use crate::error::PtErrorCode;
+    use crate::image::Image;
+    use crate::status::Status;
+    use libipt_sys::{
+        pt_event, pt_event_type_ptev_async_branch, pt_event_type_ptev_overflow, pt_insn_class,
+        pt_insn_class_ptic_call, pt_insn_class_ptic_far_return, pt_insn_class_ptic_jump,
+        pt_insn_class_ptic_other, pt_insn_class_ptic_return,
+    };
+    use std::mem;
+
+    /// A decoder returning a fixed list of blocks and events.
+    struct Scripted {
+        items: VecDeque<Decoded<Block>>,
+        synced: bool,
+        image: Image,
+    }
+
+    impl Scripted {
+        fn status(&self) -> Status {
+            match self.items.front() {
+                Some(Decoded::Event(_)) => Status::EVENT_PENDING,
+                _ => Status::empty(),
+            }
+        }
+    }
+
+    impl TraceDecoder for Scripted {
+        type Item = Block;
+
+        fn decode_next(&mut self) -> Result<(Block, Status), PtError> {
+            match self.items.pop_front() {
+                Some(Decoded::Item(block)) => Ok((block, self.status())),
+                Some(Decoded::Event(_)) => Err(PtErrorCode::BadQuery.into()),
+                None => Err(PtErrorCode::Eos.into()),
+            }
+        }
+
+        fn event(&mut self) -> Result<(Event, Status), PtError> {
+            match self.items.pop_front() {
+                Some(Decoded::Event(event)) => Ok((event, self.status())),
+                _ => Err(PtErrorCode::BadQuery.into()),
+            }
+        }
+
+        fn sync_forward(&mut self) -> Result<Status, PtError> {
+            if self.synced {
+                return Err(PtErrorCode::Eos.into());
+            }
+            self.synced = true;
+            Ok(self.status())
+        }
+
+        fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+            Err(PtErrorCode::NoTime.into())
+        }
+
+        fn image(&mut self) -> &mut Image {
+            &mut self.image
+        }
+    }
+
+    fn block(ip: u64, end_ip: u64, iclass: pt_insn_class) -> Decoded<Block> {
+        let mut block = Block::from_range(ip, end_ip, 1);
+        block.0.iclass = iclass;
+        Decoded::Item(block)
+    }
+
+    fn async_branch(from: u64, to: u64) -> Decoded<Block> {
+        let mut event: pt_event = unsafe { mem::zeroed() };
+        event.type_ = pt_event_type_ptev_async_branch;
+        event.variant.async_branch.from = from;
+        event.variant.async_branch.to = to;
+        Decoded::Event(Event(event))
+    }
+
+    fn overflow() -> Decoded<Block> {
+        let mut event: pt_event = unsafe { mem::zeroed() };
+        event.type_ = pt_event_type_ptev_overflow;
+        Decoded::Event(Event(event))
+    }
+
+    /// Run a call stack over @items, summarizing its output.
+    fn run(items: Vec<Decoded<Block>>) -> Vec<String> {
+        let decoder = Scripted {
+            items: items.into(),
+            synced: false,
+            image: Image::new(None).unwrap(),
+        };
+        CallStack::new(decoder)
+            .map(|result| match result.unwrap().1 {
+                StackEvent::Enter { frame, depth } => format!("enter {:x} {depth}", frame.entry),
+                StackEvent::Exit { kind, depth, .. } => format!("{kind:?} {depth}"),
+                StackEvent::Block { block, depth } => format!("block {:x} {depth}", block.ip()),
+                StackEvent::Event(_) => "event".to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_callstack_calls() {
+        let output = run(vec![
+            block(0x1000, 0x1008, pt_insn_class_ptic_call),
+            block(0x2000, 0x2004, pt_insn_class_ptic_call),
+            block(0x3000, 0x3002, pt_insn_class_ptic_return),
+            block(0x2009, 0x200a, pt_insn_class_ptic_return),
+            block(0x100d, 0x100e, pt_insn_class_ptic_other),
+        ]);
+        assert_eq!(
+            output,
+            [
+                "block 1000 0",
+                "enter 2000 1",
+                "block 2000 1",
+                "enter 3000 2",
+                "block 3000 2",
+                "Return 1",
+                "block 2009 1",
+                "Return 0",
+                "block 100d 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_callstack_unmatched() {
+        // A longjmp back to the first function, then a return to a caller outside the trace.
+        let output = run(vec![
+            block(0x1000, 0x1008, pt_insn_class_ptic_call),
+            block(0x2000, 0x2004, pt_insn_class_ptic_call),
+            block(0x3000, 0x3010, pt_insn_class_ptic_jump),
+            block(0x4000, 0x4001, pt_insn_class_ptic_return),
+            block(0x100d, 0x100e, pt_insn_class_ptic_return),
+            block(0x5000, 0x5001, pt_insn_class_ptic_other),
+        ]);
+        assert_eq!(
+            output[4..],
+            [
+                "block 3000 2",
+                "block 4000 2",
+                "Unwound 1",
+                "Return 0",
+                "block 100d 0",
+                "Return 0",
+                "block 5000 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_callstack_async() {
+        let output = run(vec![
+            block(0x1000, 0x1008, pt_insn_class_ptic_call),
+            block(0x2000, 0x2002, pt_insn_class_ptic_other),
+            async_branch(0x2004, 0xffff_0000),
+            block(0xffff_0000, 0xffff_0010, pt_insn_class_ptic_far_return),
+            block(0x2004, 0x2008, pt_insn_class_ptic_other),
+            overflow(),
+            block(0x6000, 0x6001, pt_insn_class_ptic_other),
+        ]);
+        assert_eq!(
+            output[3..],
+            [
+                "enter ffff0000 2",
+                "event",
+                "block ffff0000 2",
+                "FarReturn 1",
+                "block 2004 1",
+                "Gap 0",
+                "event",
+                "block 6000 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_callstack_from_builder() {
+        let trace = [0u8; 16];
+        let builder = BlockDecoder::builder().buffer(&trace);
+        let mut callstack = CallStack::from_builder(&builder).unwrap();
+        let config = &callstack.decoder_mut().used_builder().config;
+        assert_eq!(unsafe { config.flags.variant.block.end_on_call() }, 1);
+        assert_eq!(
+            unsafe { builder.config.flags.variant.block.end_on_call() },
+            0
+        );
+    }
+}
@@ -0,0 +1,559 @@
+use crate::asid::Asid;
+use crate::block::{Block, BlockInsns};
+use crate::error::{PtError, PtErrorCode, write_sink};
+use crate::event::EventType;
+use crate::image::Image;
+use crate::merge::{Decoded, Lane, TraceDecoder};
+#[cfg(feature = "symbolize")]
+use crate::symbolize::Symbolizer;
+#[cfg(feature = "symbolize")]
+use std::collections::BTreeMap;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::io::Write;
+
+/// A basic block, identified by its address space, its image section and its address range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CoveredBlock {
+    /// The address space of the block
+    pub asid: Asid,
+    /// The image section of the block, see `Block::isid()`
+    pub isid: i32,
+    /// The IP of the first instruction
+    pub ip: u64,
+    /// The IP of the last instruction
+    pub end_ip: u64,
+}
+
+impl CoveredBlock {
+    /// A total order, for stable outputs.
+    fn sort_key(&self) -> (Option<u64>, Option<u64>, i32, u64, u64) {
+        (
+            self.asid.cr3(),
+            self.asid.vmcs(),
+            self.isid,
+            self.ip,
+            self.end_ip,
+        )
+    }
+}
+
+/// A control flow edge, from the last instruction of a block to the first instruction of the
+/// next block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Edge {
+    /// The source block, ending at the branch
+    pub from: CoveredBlock,
+    /// The destination block
+    pub to: CoveredBlock,
+}
+
+/// A module of a DRCOV file, the basic blocks are stored relative to its base.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DrcovModule {
+    /// Path of the binary
+    pub path: String,
+    /// Load address of the binary
+    pub base: u64,
+    /// Size of the mapping of the binary
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+struct BlockStats {
+    block: Block,
+    hits: u64,
+}
+
+/// Basic block and edge coverage of one or more traces, with hit counts.
+///
+/// Blocks are keyed by address space and image section, the address space being tracked
+/// from the paging and VMCS events. Edges are only recorded between consecutive blocks of the
+/// same address space, overflows, trace disables, asynchronous branches and decoding errors
+/// break the chain.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    blocks: HashMap<CoveredBlock, BlockStats>,
+    edges: HashMap<Edge, u64>,
+    last: Option<CoveredBlock>,
+}
+
+impl Coverage {
+    /// Create an empty coverage.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add all the blocks of @decoder, until the end of its trace.
+    ///
+    /// Pass a `&mut` decoder to keep using it afterwards.
+    /// Decoding errors are skipped, the decoder resynchronizes at the next PSB.
+    pub fn record<D>(&mut self, decoder: D)
+    where
+        D: TraceDecoder<Item = Block>,
+    {
+        let mut lane = Lane::new(decoder);
+        let mut asid = Asid::default();
+        self.gap();
+        while let Some(result) = lane.fetch() {
+            match result {
+                Ok((_, Decoded::Item(block))) => self.add_block(asid, &block),
+                Ok((_, Decoded::Event(event))) => match event.event_type() {
+                    EventType::Paging(paging) => asid.set_cr3(paging.cr3()),
+                    EventType::AsyncPaging(paging) => asid.set_cr3(paging.cr3()),
+                    EventType::Vmcs(vmcs) => asid.set_vmcs(vmcs.base()),
+                    EventType::AsyncVmcs(vmcs) => asid.set_vmcs(vmcs.base()),
+                    EventType::Overflow(_)
+                    | EventType::Disabled(_)
+                    | EventType::AsnycDisabled(_)
+                    | EventType::AsyncBranch(_) => self.gap(),
+                    _ => {}
+                },
+                Err(_) => self.gap(),
+            }
+        }
+        self.gap();
+    }
+
+    /// Add @block, executed in @asid, and the edge from the previous block.
+    ///
+    /// Empty blocks are ignored.
+    pub fn add_block(&mut self, asid: Asid, block: &Block) {
+        if block.ninsn() == 0 {
+            return;
+        }
+        let key = CoveredBlock {
+            asid,
+            isid: block.isid(),
+            ip: block.ip(),
+            end_ip: block.end_ip(),
+        };
+        self.blocks
+            .entry(key)
+            .or_insert(BlockStats {
+                block: *block,
+                hits: 0,
+            })
+            .hits += 1;
+        if let Some(from) = self.last {
+            if from.asid == asid {
+                *self.edges.entry(Edge { from, to: key }).or_default() += 1;
+            }
+        }
+        self.last = Some(key);
+    }
+
+    /// Break the chain of blocks, the next block has no incoming edge.
+    pub fn gap(&mut self) {
+        self.last = None;
+    }
+
+    /// Add the blocks, edges and hit counts of @other.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (key, stats) in &other.blocks {
+            self.blocks
+                .entry(*key)
+                .or_insert(BlockStats {
+                    block: stats.block,
+                    hits: 0,
+                })
+                .hits += stats.hits;
+        }
+        for (edge, hits) in &other.edges {
+            *self.edges.entry(*edge).or_default() += hits;
+        }
+    }
+
+    /// Keep only the blocks for which @keep returns true, and the edges between them.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&CoveredBlock) -> bool,
+    {
+        self.blocks.retain(|key, _| keep(key));
+        self.edges.retain(|edge, _| {
+            self.blocks.contains_key(&edge.from) && self.blocks.contains_key(&edge.to)
+        });
+        self.last = None;
+    }
+
+    /// The covered blocks and their hit counts, in no particular order.
+    pub fn blocks(&self) -> impl Iterator<Item = (&CoveredBlock, u64)> {
+        self.blocks.iter().map(|(key, stats)| (key, stats.hits))
+    }
+
+    /// The covered edges and their hit counts, in no particular order.
+    pub fn edges(&self) -> impl Iterator<Item = (&Edge, u64)> {
+        self.edges.iter().map(|(edge, hits)| (edge, *hits))
+    }
+
+    /// The number of executions of @block.
+    #[must_use]
+    pub fn hits(&self, block: &CoveredBlock) -> u64 {
+        self.blocks.get(block).map_or(0, |stats| stats.hits)
+    }
+
+    /// The instructions of the covered block @block, read from @image.
+    ///
+    /// Returns `None` if @block is not covered.
+    /// See `Block::instructions()`.
+    #[must_use]
+    pub fn instructions<'a>(
+        &self,
+        block: &CoveredBlock,
+        image: &'a Image,
+    ) -> Option<BlockInsns<'a>> {
+        let stats = self.blocks.get(block)?;
+        Some(stats.block.instructions(image).with_asid(block.asid))
+    }
+
+    /// Add the edges to the AFL style bitmap @map.
+    ///
+    /// As in AFL's QEMU mode, each block start address is hashed to a location and an edge
+    /// increments `map[from >> 1 ^ to]`. Counts saturate at 255.
+    /// Returns `Invalid` if the size of @map is not a power of two.
+    pub fn write_afl_bitmap(&self, map: &mut [u8]) -> Result<(), PtError> {
+        let mask = afl_mask(map)?;
+        for (edge, hits) in &self.edges {
+            let count = &mut map[afl_index(edge.from.ip, edge.to.ip, mask)];
+            *count = count.saturating_add(u8::try_from(*hits).unwrap_or(u8::MAX));
+        }
+        Ok(())
+    }
+
+    /// Write the covered blocks in the DRCOV format (version 2), as read by Lighthouse and
+    /// other coverage explorers.
+    ///
+    /// Blocks may follow direct jumps and calls: their instructions are read from @image and
+    /// each contiguous run of instructions is written as a basic block, up to the end of its
+    /// last instruction. Runs are attributed to the first of @modules containing their first
+    /// instruction, runs outside of @modules are skipped.
+    /// Blocks whose instructions can't be read, see `Block::instructions()`, are written as a
+    /// single basic block from their first to their last instruction address.
+    /// Returns the number of blocks written from their boundaries on success.
+    /// Returns `BadFile` if @out can't be written.
+    pub fn write_drcov<W: Write>(
+        &self,
+        mut out: W,
+        modules: &[DrcovModule],
+        image: &Image,
+    ) -> Result<usize, PtError> {
+        let mut entries = BTreeSet::new();
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        let mut unread = 0;
+        for (key, stats) in &self.blocks {
+            runs.clear();
+            for insn in stats.block.instructions(image).with_asid(key.asid) {
+                let Ok(insn) = insn else {
+                    // Fall back to the block boundaries if the memory can't be read.
+                    runs.clear();
+                    runs.push((key.ip, key.end_ip.wrapping_add(1)));
+                    unread += 1;
+                    break;
+                };
+                let end = insn.ip().wrapping_add(u64::from(insn.size()));
+                match runs.last_mut() {
+                    Some(run) if run.1 == insn.ip() => run.1 = end,
+                    _ => runs.push((insn.ip(), end)),
+                }
+            }
+
+            for &(start, end) in &runs {
+                let Some((id, module)) = modules
+                    .iter()
+                    .enumerate()
+                    .find(|(_, m)| start >= m.base && start - m.base < m.size)
+                else {
+                    continue;
+                };
+                let (Ok(id), Ok(offset)) = (u16::try_from(id), u32::try_from(start - module.base))
+                else {
+                    continue;
+                };
+                let size = u16::try_from(end.wrapping_sub(start)).unwrap_or(u16::MAX);
+                entries.insert((id, offset, size));
+            }
+        }
+
+        let mut header = String::from("DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\n");
+        writeln!(header, "Module Table: version 2, count {}", modules.len()).unwrap();
+        header.push_str("Columns: id, base, end, entry, checksum, timestamp, path\n");
+        for (id, module) in modules.iter().enumerate() {
+            writeln!(
+                header,
+                "{id:3}, {:#018x}, {:#018x}, 0x0000000000000000, 0x00000000, 0x00000000, {}",
+                module.base,
+                module.base.wrapping_add(module.size),
+                module.path
+            )
+            .unwrap();
+        }
+        writeln!(header, "BB Table: {} bbs", entries.len()).unwrap();
+
+        let mut data = header.into_bytes();
+        for (id, start, size) in entries {
+            data.extend_from_slice(&start.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        write_sink(&mut out, &data).map(|()| unread)
+    }
+
+    /// Write the control flow graph of the covered blocks and edges in the Graphviz DOT format.
+    ///
+    /// Nodes and edges are labeled with their hit counts, @name maps the start address of a
+    /// block to its name, unnamed blocks are shown as hexadecimal addresses.
+    /// Returns `BadFile` if @out can't be written.
+    pub fn write_dot<W, F>(&self, mut out: W, mut name: F) -> Result<(), PtError>
+    where
+        W: Write,
+        F: FnMut(u64) -> Option<String>,
+    {
+        let mut blocks: Vec<(&CoveredBlock, u64)> = self.blocks().collect();
+        blocks.sort_by_key(|(key, _)| key.sort_key());
+        let ids: HashMap<&CoveredBlock, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(id, (key, _))| (*key, id))
+            .collect();
+
+        let mut dot = String::from("digraph coverage {\n    node [shape=box];\n");
+        for (id, (key, hits)) in blocks.iter().enumerate() {
+            let label = name(key.ip).unwrap_or_else(|| format!("{:#x}", key.ip));
+            writeln!(
+                dot,
+                "    b{id} [label=\"{}\\n{:#x}-{:#x}\\n{hits} hits\"];",
+                escape_dot(&label),
+                key.ip,
+                key.end_ip
+            )
+            .unwrap();
+        }
+
+        let mut edges: Vec<(usize, usize, u64)> = self
+            .edges()
+            .map(|(edge, hits)| (ids[&edge.from], ids[&edge.to], hits))
+            .collect();
+        edges.sort_unstable();
+        for (from, to, hits) in edges {
+            writeln!(dot, "    b{from} -> b{to} [label=\"{hits}\"];").unwrap();
+        }
+        dot.push_str("}\n");
+        write_sink(&mut out, dot.as_bytes())
+    }
+
+    /// Write the source lines of the covered blocks in the lcov tracefile format.
+    ///
+    /// The instructions of the blocks are read from @image and resolved with @symbolizer, the
+    /// hit count of a line is the number of executions of the blocks covering it.
+    /// Only covered lines are listed.
+    /// Returns `BadFile` if @out can't be written.
+    #[cfg(feature = "symbolize")]
+    pub fn write_lcov<W: Write>(
+        &self,
+        mut out: W,
+        image: &Image,
+        symbolizer: &mut Symbolizer,
+    ) -> Result<(), PtError> {
+        let mut files: BTreeMap<String, BTreeMap<u32, u64>> = BTreeMap::new();
+        let mut lines = Vec::new();
+        for (key, stats) in &self.blocks {
+            let isid = u32::try_from(key.isid).unwrap_or(0);
+            let mut ips: Vec<u64> = stats
+                .block
+                .instructions(image)
+                .with_asid(key.asid)
+                .map_while(Result::ok)
+                .map(|insn| insn.ip())
+                .collect();
+            // Fall back to the block boundaries if the memory can't be read.
+            ips.extend([key.ip, key.end_ip]);
+
+            lines.clear();
+            for ip in ips {
+                let Some(symbol) = symbolizer.symbolize_isid(isid, ip) else {
+                    continue;
+                };
+                if let (Some(file), Some(line)) = (symbol.file(), symbol.line()) {
+                    lines.push((file.to_owned(), line));
+                }
+            }
+            lines.sort_unstable();
+            lines.dedup();
+            for (file, line) in lines.drain(..) {
+                *files.entry(file).or_default().entry(line).or_default() += stats.hits;
+            }
+        }
+
+        let mut text = String::new();
+        for (file, lines) in files {
+            writeln!(text, "TN:\nSF:{file}").unwrap();
+            for (line, hits) in &lines {
+                writeln!(text, "DA:{line},{hits}").unwrap();
+            }
+            writeln!(text, "LF:{0}\nLH:{0}\nend_of_record", lines.len()).unwrap();
+        }
+        write_sink(&mut out, text.as_bytes())
+    }
+}
+
+/// The index mask of the AFL bitmap @map.
+///
+/// Returns `Invalid` if the size of @map is not a power of two.
+pub(crate) fn afl_mask(map: &[u8]) -> Result<u64, PtError> {
+    if map.len().is_power_of_two() {
+        Ok(map.len() as u64 - 1)
+    } else {
+        Err(PtError::new(
+            PtErrorCode::Invalid,
+            "The AFL bitmap size must be a power of two",
+        ))
+    }
+}
+
+/// The index in an AFL bitmap of the edge from the block at @from to the block at @to.
+#[inline]
+pub(crate) fn afl_index(from: u64, to: u64, mask: u64) -> usize {
+    let location = |ip: u64| ((ip >> 4) ^ (ip << 8)) & mask;
+    ((location(from) >> 1) ^ location(to)) as usize
+}
+
+/// Escape @s for a DOT string.
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::synth::fixture;
+    use libipt_sys::pt_exec_mode_ptem_64bit;
+
+    fn block(ip: u64, end_ip: u64) -> Block {
+        Block::from_range(ip, end_ip, 1)
+    }
+
+    fn key(asid: Asid, ip: u64, end_ip: u64) -> CoveredBlock {
+        CoveredBlock {
+            asid,
+            isid: 0,
+            ip,
+            end_ip,
+        }
+    }
+
+    fn coverage() -> Coverage {
+        let asid = Asid::new(Some(0x1000), None);
+        let mut coverage = Coverage::new();
+        for _ in 0..2 {
+            coverage.add_block(asid, &block(0x1000, 0x1008));
+            coverage.add_block(asid, &block(0x2000, 0x2004));
+        }
+        coverage.gap();
+        coverage.add_block(asid, &block(0x1000, 0x1008));
+        // Switching address space breaks the chain.
+        coverage.add_block(Asid::new(Some(0x2000), None), &block(0x2000, 0x2004));
+        coverage.add_block(asid, &block(0x3000, 0x3000));
+        coverage
+    }
+
+    #[test]
+    fn test_coverage_edges() {
+        let asid = Asid::new(Some(0x1000), None);
+        let coverage = coverage();
+        assert_eq!(coverage.blocks().count(), 4);
+        assert_eq!(coverage.hits(&key(asid, 0x1000, 0x1008)), 3);
+        assert_eq!(coverage.hits(&key(asid, 0x2000, 0x2004)), 2);
+
+        let mut edges: Vec<(u64, u64, u64)> = coverage
+            .edges()
+            .map(|(edge, hits)| (edge.from.end_ip, edge.to.ip, hits))
+            .collect();
+        edges.sort_unstable();
+        assert_eq!(edges, [(0x1008, 0x2000, 2), (0x2004, 0x1000, 1)]);
+
+        let mut merged = coverage.clone();
+        merged.merge(&coverage);
+        assert_eq!(merged.hits(&key(asid, 0x1000, 0x1008)), 6);
+        assert_eq!(merged.edges().map(|(_, hits)| hits).sum::<u64>(), 6);
+
+        merged.retain(|block| block.ip != 0x2000);
+        assert_eq!(merged.blocks().count(), 2);
+        assert_eq!(merged.edges().count(), 0);
+    }
+
+    #[test]
+    fn test_coverage_afl() {
+        let mut map = [0u8; 1 << 16];
+        coverage().write_afl_bitmap(&mut map).unwrap();
+        let location = |ip: u64| ((ip >> 4) ^ (ip << 8)) & 0xffff;
+        assert_eq!(
+            map[((location(0x1000) >> 1) ^ location(0x2000)) as usize],
+            2
+        );
+        assert_eq!(map.iter().map(|&count| u64::from(count)).sum::<u64>(), 3);
+        assert!(coverage().write_afl_bitmap(&mut [0u8; 3]).is_err());
+    }
+
+    #[test]
+    fn test_coverage_drcov() {
+        let image = fixture::image();
+        let mut block = Block::from_range(fixture::BASE, fixture::BASE + 0x10, 6);
+        block.0.mode = pt_exec_mode_ptem_64bit;
+        let mut coverage = Coverage::new();
+        coverage.add_block(Asid::default(), &block);
+
+        let modules = [DrcovModule {
+            path: "/bin/a.out".to_owned(),
+            base: fixture::BASE,
+            size: 0x1800,
+        }];
+        let mut out = Vec::new();
+        assert_eq!(coverage.write_drcov(&mut out, &modules, &image).unwrap(), 0);
+
+        let header = "DRCOV VERSION: 2\nDRCOV FLAVOR: drcov\n\
+            Module Table: version 2, count 1\n\
+            Columns: id, base, end, entry, checksum, timestamp, path\n  \
+            0, 0x0000000000001000, 0x0000000000002800, 0x0000000000000000, 0x00000000, \
+            0x00000000, /bin/a.out\n\
+            BB Table: 2 bbs\n";
+        assert_eq!(&out[..header.len()], header.as_bytes());
+        // The block is split at the call, both runs end after their last instruction.
+        assert_eq!(
+            &out[header.len()..],
+            [0, 0, 0, 0, 0xd, 0, 0, 0, 0x10, 0, 0, 0, 1, 0, 0, 0]
+        );
+
+        // The instructions of the blocks can't be read, they are written from their boundaries.
+        let mut out = Vec::new();
+        assert_eq!(
+            coverage().write_drcov(&mut out, &modules, &image).unwrap(),
+            4
+        );
+        // The block at 0x3000 is outside of the module.
+        assert_eq!(&out[..header.len()], header.as_bytes());
+        assert_eq!(
+            &out[header.len()..],
+            [0, 0, 0, 0, 9, 0, 0, 0, 0, 0x10, 0, 0, 5, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_coverage_dot() {
+        let mut coverage = Coverage::new();
+        coverage.add_block(Asid::default(), &block(0x1000, 0x1008));
+        coverage.add_block(Asid::default(), &block(0x2000, 0x2004));
+
+        let mut out = Vec::new();
+        coverage
+            .write_dot(&mut out, |ip| (ip == 0x2000).then(|| "f\"oo".to_owned()))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "digraph coverage {\n    node [shape=box];\n    \
+            b0 [label=\"0x1000\\n0x1000-0x1008\\n1 hits\"];\n    \
+            b1 [label=\"f\\\"oo\\n0x2000-0x2004\\n1 hits\"];\n    \
+            b0 -> b1 [label=\"1\"];\n}\n"
+        );
+    }
+}
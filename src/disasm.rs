@@ -0,0 +1,369 @@
+use crate::error::{PtError, PtErrorCode, write_sink};
+use crate::event::ExecModeType;
+use crate::insn::Insn;
+use crate::merge::{Decoded, Lane, TraceDecoder};
+use iced_x86::{
+    Decoder, DecoderOptions, FlowControl, Formatter, Instruction, InstructionInfoFactory,
+    IntelFormatter, OpAccess, OpKind, Register,
+};
+use std::fmt::Write as _;
+use std::io::Write;
+
+/// A memory operand accessed by an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryAccess {
+    /// Segment register
+    pub segment: String,
+    /// Base register, `None` for absolute and IP relative accesses
+    pub base: Option<String>,
+    /// Index register
+    pub index: Option<String>,
+    /// Scale of the index register (1, 2, 4 or 8)
+    pub scale: u32,
+    /// Displacement, the absolute address for IP relative accesses
+    pub displacement: u64,
+    /// Size of the access in bytes, 0 if unknown
+    pub size: usize,
+    /// Whether the memory is read (maybe conditionally)
+    pub read: bool,
+    /// Whether the memory is written (maybe conditionally)
+    pub write: bool,
+}
+
+impl MemoryAccess {
+    /// The address of the access, if it doesn't depend on registers (absolute or IP relative).
+    #[must_use]
+    pub fn address(&self) -> Option<u64> {
+        (self.base.is_none() && self.index.is_none()).then_some(self.displacement)
+    }
+}
+
+/// A disassembled instruction.
+#[derive(Debug, Clone)]
+pub struct DisasmInsn {
+    instruction: Instruction,
+    bytes: Vec<u8>,
+    mnemonic: String,
+    operands: Vec<String>,
+    text: String,
+    memory: Vec<MemoryAccess>,
+}
+
+impl DisasmInsn {
+    /// The address of the instruction.
+    #[must_use]
+    pub fn ip(&self) -> u64 {
+        self.instruction.ip()
+    }
+
+    /// The bytes of the instruction.
+    #[must_use]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The mnemonic, with its prefixes (`rep movsb`, `lock add`, ...).
+    #[must_use]
+    pub fn mnemonic(&self) -> &str {
+        &self.mnemonic
+    }
+
+    /// The operands, in Intel syntax and order.
+    #[must_use]
+    pub fn operands(&self) -> &[String] {
+        &self.operands
+    }
+
+    /// The full instruction, in Intel syntax.
+    #[must_use]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The memory accessed by the instruction, `lea` and `nop` operands excluded.
+    #[must_use]
+    pub fn memory(&self) -> &[MemoryAccess] {
+        &self.memory
+    }
+
+    /// The target of a direct near branch, call or loop.
+    #[must_use]
+    pub fn branch_target(&self) -> Option<u64> {
+        matches!(
+            self.instruction.op0_kind(),
+            OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+        )
+        .then(|| self.instruction.near_branch_target())
+    }
+
+    /// How the instruction changes the control flow.
+    #[must_use]
+    pub fn flow_control(&self) -> FlowControl {
+        self.instruction.flow_control()
+    }
+
+    /// The underlying `iced_x86` instruction.
+    #[must_use]
+    pub fn instruction(&self) -> &Instruction {
+        &self.instruction
+    }
+}
+
+/// An x86 disassembler for the instructions of an `InsnDecoder`, built on `iced_x86`.
+///
+/// Instructions are decoded in the bitness of their execution mode and formatted in Intel
+/// syntax.
+pub struct Disassembler {
+    formatter: IntelFormatter,
+    info: InstructionInfoFactory,
+}
+
+impl std::fmt::Debug for Disassembler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Disassembler").finish_non_exhaustive()
+    }
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Disassembler {
+    /// Create a disassembler with the default Intel syntax options.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_formatter(IntelFormatter::new())
+    }
+
+    /// Create a disassembler formatting instructions with @formatter.
+    #[must_use]
+    pub fn with_formatter(formatter: IntelFormatter) -> Self {
+        Self {
+            formatter,
+            info: InstructionInfoFactory::new(),
+        }
+    }
+
+    /// Disassemble @insn.
+    ///
+    /// Returns `BadInsn` if the execution mode of @insn is unknown or its bytes are not a valid
+    /// instruction.
+    pub fn disassemble(&mut self, insn: &Insn) -> Result<DisasmInsn, PtError> {
+        self.disassemble_bytes(insn.raw(), insn.ip(), insn.mode())
+    }
+
+    /// Disassemble the instruction @bytes at @ip, in execution mode @mode.
+    ///
+    /// Returns `BadInsn` if @mode is unknown or @bytes don't start with a valid instruction.
+    pub fn disassemble_bytes(
+        &mut self,
+        bytes: &[u8],
+        ip: u64,
+        mode: ExecModeType,
+    ) -> Result<DisasmInsn, PtError> {
+        let bitness = match mode {
+            ExecModeType::Bit16 => 16,
+            ExecModeType::Bit32 => 32,
+            ExecModeType::Bit64 => 64,
+            ExecModeType::Unknown => {
+                return Err(PtError::new(PtErrorCode::BadInsn, "Unknown execution mode"));
+            }
+        };
+        let instruction = Decoder::with_ip(bitness, bytes, ip, DecoderOptions::NONE).decode();
+        if instruction.is_invalid() {
+            return Err(PtError::new(
+                PtErrorCode::BadInsn,
+                "Failed to disassemble the instruction",
+            ));
+        }
+
+        let mut text = String::new();
+        self.formatter.format(&instruction, &mut text);
+        let mut mnemonic = String::new();
+        self.formatter.format_mnemonic(&instruction, &mut mnemonic);
+        let operands = (0..self.formatter.operand_count(&instruction))
+            .map(|operand| {
+                let mut text = String::new();
+                // The operand index is in range.
+                let _ = self
+                    .formatter
+                    .format_operand(&instruction, &mut text, operand);
+                text
+            })
+            .collect();
+
+        let used = self.info.info(&instruction).used_memory().to_vec();
+        let memory = used
+            .iter()
+            .map(|used| {
+                let access = used.access();
+                let mut base = used.base();
+                let mut displacement = used.displacement();
+                if matches!(base, Register::RIP | Register::EIP) {
+                    base = Register::None;
+                    displacement = instruction.ip_rel_memory_address();
+                }
+                MemoryAccess {
+                    segment: self.register(used.segment()).unwrap_or_default(),
+                    base: self.register(base),
+                    index: self.register(used.index()),
+                    scale: used.scale(),
+                    displacement,
+                    size: used.memory_size().size(),
+                    read: matches!(
+                        access,
+                        OpAccess::Read
+                            | OpAccess::CondRead
+                            | OpAccess::ReadWrite
+                            | OpAccess::ReadCondWrite
+                    ),
+                    write: matches!(
+                        access,
+                        OpAccess::Write
+                            | OpAccess::CondWrite
+                            | OpAccess::ReadWrite
+                            | OpAccess::ReadCondWrite
+                    ),
+                }
+            })
+            .filter(|access| access.read || access.write)
+            .collect();
+
+        Ok(DisasmInsn {
+            instruction,
+            bytes: bytes[..instruction.len()].to_vec(),
+            mnemonic,
+            operands,
+            text,
+            memory,
+        })
+    }
+
+    /// The name of @register, `None` for `Register::None`.
+    fn register(&mut self, register: Register) -> Option<String> {
+        (register != Register::None).then(|| self.formatter.format_register(register).to_owned())
+    }
+
+    /// Format @insn as an `objdump` style line: address, bytes and instruction.
+    ///
+    /// Instructions that can't be disassembled are shown as `(bad)`.
+    pub fn format_line(&mut self, insn: &Insn) -> String {
+        let text = match self.disassemble(insn) {
+            Ok(disasm) => disasm.text,
+            Err(_) => "(bad)".to_owned(),
+        };
+        let mut hex = String::new();
+        for (i, byte) in insn.raw().iter().enumerate() {
+            if i > 0 {
+                hex.push(' ');
+            }
+            write!(hex, "{byte:02x}").unwrap();
+        }
+        format!("{:>16x}:\t{hex:<20}\t{text}", insn.ip())
+    }
+
+    /// Write an `objdump` style listing of the instructions of @decoder, until the end of its
+    /// trace.
+    ///
+    /// Events and decoding errors are written as `;` comments, the decoder resynchronizes at
+    /// the next PSB after an error.
+    /// Returns the number of listed instructions on success.
+    /// Returns `BadFile` if @out can't be written.
+    pub fn write_listing<D, W>(&mut self, decoder: D, mut out: W) -> Result<u64, PtError>
+    where
+        D: TraceDecoder<Item = Insn>,
+        W: Write,
+    {
+        let mut lane = Lane::new(decoder);
+        let mut count = 0;
+        while let Some(result) = lane.fetch() {
+            let line = match result {
+                Ok((_, Decoded::Item(insn))) => {
+                    count += 1;
+                    self.format_line(&insn)
+                }
+                Ok((_, Decoded::Event(event))) => format!("; {event:?}"),
+                Err(e) => format!("; error: {e:?}"),
+            };
+            write_sink(&mut out, format!("{line}\n").as_bytes())?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disasm_modes() {
+        let mut disasm = Disassembler::new();
+        let insn = disasm
+            .disassemble_bytes(&[0x31, 0xc0, 0x90], 0x1000, ExecModeType::Bit64)
+            .unwrap();
+        assert_eq!(insn.text(), "xor eax,eax");
+        assert_eq!(insn.mnemonic(), "xor");
+        assert_eq!(insn.operands(), ["eax", "eax"]);
+        assert_eq!(insn.bytes(), [0x31, 0xc0]);
+        assert_eq!(insn.flow_control(), FlowControl::Next);
+        assert!(insn.memory().is_empty());
+
+        let insn = disasm
+            .disassemble_bytes(&[0x31, 0xc0], 0x1000, ExecModeType::Bit16)
+            .unwrap();
+        assert_eq!(insn.text(), "xor ax,ax");
+
+        let err = disasm
+            .disassemble_bytes(&[0x31, 0xc0], 0x1000, ExecModeType::Unknown)
+            .unwrap_err();
+        assert_eq!(err.code(), PtErrorCode::BadInsn);
+        assert!(
+            disasm
+                .disassemble_bytes(&[0x0f], 0x1000, ExecModeType::Bit64)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_disasm_branch() {
+        let mut disasm = Disassembler::new();
+        let insn = disasm
+            .disassemble_bytes(&[0xe8, 0x03, 0, 0, 0], 0x1008, ExecModeType::Bit64)
+            .unwrap();
+        assert_eq!(insn.mnemonic(), "call");
+        assert_eq!(insn.branch_target(), Some(0x1010));
+        assert_eq!(insn.flow_control(), FlowControl::Call);
+
+        let insn = disasm
+            .disassemble_bytes(&[0xff, 0xe0], 0x100d, ExecModeType::Bit64)
+            .unwrap();
+        assert_eq!(insn.branch_target(), None);
+        assert_eq!(insn.flow_control(), FlowControl::IndirectBranch);
+    }
+
+    #[test]
+    fn test_disasm_memory() {
+        let mut disasm = Disassembler::new();
+        // mov eax, [rip + 0x10]
+        let insn = disasm
+            .disassemble_bytes(&[0x8b, 0x05, 0x10, 0, 0, 0], 0x1000, ExecModeType::Bit64)
+            .unwrap();
+        let access = &insn.memory()[0];
+        assert_eq!(access.address(), Some(0x1016));
+        assert_eq!(access.size, 4);
+        assert!(access.read && !access.write);
+
+        // mov [rbx + rcx * 4 + 8], eax
+        let insn = disasm
+            .disassemble_bytes(&[0x89, 0x44, 0x8b, 0x08], 0x1000, ExecModeType::Bit64)
+            .unwrap();
+        let access = &insn.memory()[0];
+        assert_eq!(access.base.as_deref(), Some("rbx"));
+        assert_eq!(access.index.as_deref(), Some("rcx"));
+        assert_eq!((access.scale, access.displacement), (4, 8));
+        assert_eq!(access.address(), None);
+        assert!(!access.read && access.write);
+    }
+}
@@ -0,0 +1,597 @@
+use crate::asid::Asid;
+use crate::block::ild::{self, Branch, Window};
+use crate::coverage::{afl_index, afl_mask};
+use crate::error::{PtError, PtErrorCode};
+use crate::event::ExecModeType;
+use crate::image::Image;
+use crate::packet::{Exec, LastIp, Packet, PacketDecoder, Payload, Tsx};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// The maximum number of instructions of a block, to give up on endless direct jump loops.
+const MAX_BLOCK_INSNS: u32 = 0x10000;
+/// The depth of the return stack used for return compression, as the processor's.
+pub(crate) const RET_STACK_SIZE: usize = 64;
+
+/// Hasher for IP keys, much faster than the default SipHash on the decoding hot path.
+#[derive(Default)]
+struct IpHasher(u64);
+
+impl Hasher for IpHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(u64::from(byte));
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        let hash = (self.0.rotate_left(5) ^ n).wrapping_mul(0x517c_c1b7_2722_0a95);
+        // Fold the well mixed high bits into the low ones, used for the bucket index.
+        self.0 = hash ^ (hash >> 32);
+    }
+}
+
+/// The control flow of a block up to the branch that needs trace to be resolved.
+#[derive(Debug, Clone)]
+struct CachedBlock {
+    mode: ExecModeType,
+    /// The IP following the last instruction, the fall-through or return address
+    next_ip: u64,
+    /// The branch ending the block
+    end: Branch,
+    /// The return addresses pushed by the direct calls followed in the block
+    calls: Box<[u64]>,
+}
+
+/// How an FUP packet is to be interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fup {
+    /// The FUP binds to the next TIP or TIP.PGD, for an asynchronous branch or disable
+    Async,
+    /// The FUP only tells the IP of a TSX, PTWRITE or EXSTOP event
+    Standalone,
+    /// The FUP gives the IP at which execution resumes, in PSB+ or after an overflow
+    Resume,
+}
+
+/// The processor's call stack, for compressed returns.
+#[derive(Debug, Clone)]
+struct RetStack {
+    ips: [u64; RET_STACK_SIZE],
+    top: usize,
+    len: usize,
+}
+
+impl RetStack {
+    fn new() -> Self {
+        Self {
+            ips: [0; RET_STACK_SIZE],
+            top: 0,
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Push @ip, the oldest entry is dropped when the stack is full.
+    fn push(&mut self, ip: u64) {
+        self.top = (self.top + 1) % RET_STACK_SIZE;
+        self.ips[self.top] = ip;
+        self.len = (self.len + 1).min(RET_STACK_SIZE);
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        self.len = self.len.checked_sub(1)?;
+        let ip = self.ips[self.top];
+        self.top = (self.top + RET_STACK_SIZE - 1) % RET_STACK_SIZE;
+        Some(ip)
+    }
+}
+
+/// The decoding state of a single trace.
+#[derive(Debug, Clone)]
+struct Walk {
+    mode: ExecModeType,
+    last_ip: LastIp,
+    /// The first IP of the block being executed, `None` while tracing is disabled or after an
+    /// error until the next IP packet
+    ip: Option<u64>,
+    /// How the next FUP packet is to be interpreted
+    fup: Fup,
+    /// Whether an FUP binds to the next TIP or TIP.PGD
+    pending_fup: bool,
+    ret_stack: RetStack,
+}
+
+impl Walk {
+    fn new() -> Self {
+        Self {
+            mode: ExecModeType::Bit64,
+            last_ip: LastIp::new(),
+            ip: None,
+            fup: Fup::Async,
+            pending_fup: false,
+            ret_stack: RetStack::new(),
+        }
+    }
+
+    /// Forget the control flow, until the next IP packet.
+    fn desync(&mut self) {
+        self.ip = None;
+        self.fup = Fup::Async;
+        self.pending_fup = false;
+        self.ret_stack.clear();
+    }
+
+    /// The next FUP only tells the IP of an event, unless it gives the IP of a PSB+.
+    fn standalone_fup(&mut self) {
+        if self.fup != Fup::Resume {
+            self.fup = Fup::Standalone;
+        }
+    }
+}
+
+/// The statistics of a decoded trace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EdgeStats {
+    /// The number of edges taken
+    pub edges: u64,
+    /// The number of errors, the flow is resumed at the next IP packet or PSB
+    pub errors: u64,
+}
+
+/// A fast decoder of the control flow edges of a trace, for fuzzers.
+///
+/// Unlike `BlockDecoder`, the blocks and events are not reconstructed: the TNT and TIP packets
+/// of a `PacketDecoder` directly drive a walk through a cache of the blocks of the image, in the
+/// spirit of libxdc and kAFL.
+/// Each block is decoded once from the image, up to the branch that needs trace to be resolved,
+/// and cached along with its branch. The cache is kept from one trace to the next, the traces
+/// of a fuzzing campaign are decoded at the packet decoder speed once the cache is warm.
+///
+/// An edge goes from the first IP of a block to the first IP of the next block. The edges are
+/// the ones recorded by `Coverage` from the `BlockDecoder` blocks, as long as the blocks are not
+/// split by events (TSX, PTWRITE, power events, ...), section boundaries or address space
+/// switches, which are ignored. Edges are not recorded across overflows, trace disables and
+/// asynchronous branches.
+///
+/// The image must not change while the cache is in use, call `clear_cache()` otherwise.
+#[derive(Debug)]
+pub struct EdgeDecoder<'a> {
+    image: &'a Image,
+    asid: Option<Asid>,
+    blocks: HashMap<u64, CachedBlock, BuildHasherDefault<IpHasher>>,
+}
+
+impl<'a> EdgeDecoder<'a> {
+    /// Create an edge decoder reading the traced code from @image.
+    ///
+    /// See `Image::read_at()`.
+    #[must_use]
+    pub fn new(image: &'a Image) -> Self {
+        Self {
+            image,
+            asid: None,
+            blocks: HashMap::default(),
+        }
+    }
+
+    /// Read the sections of the image in @asid.
+    ///
+    /// By default sections of any address space are read.
+    #[must_use]
+    pub fn with_asid(mut self, asid: Asid) -> Self {
+        self.asid = Some(asid);
+        self.blocks.clear();
+        self
+    }
+
+    /// The number of cached blocks.
+    #[must_use]
+    pub fn cached_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Empty the block cache, e.g. after the traced code changed.
+    pub fn clear_cache(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// Decode the edges of the trace of @packets, calling @edge with the first IP of the source
+    /// and destination blocks of each edge taken.
+    ///
+    /// @packets must not be synchronized yet.
+    /// Errors (a trace not matching the image, unmapped code, corrupted packets) are counted in
+    /// the returned `EdgeStats`, the decoding resumes at the next IP packet or PSB.
+    pub fn decode<T, F>(&mut self, packets: &mut PacketDecoder<'_, T>, mut edge: F) -> EdgeStats
+    where
+        F: FnMut(u64, u64),
+    {
+        let mut walk = Walk::new();
+        let mut stats = EdgeStats::default();
+        if packets.sync_forward().is_err() {
+            return stats;
+        }
+
+        loop {
+            let packet = match packets.decode_next() {
+                Ok(packet) => packet,
+                Err(e) if e.code() == PtErrorCode::Eos => break,
+                Err(_) => {
+                    // Resynchronize at the next PSB.
+                    stats.errors += 1;
+                    walk.desync();
+                    if packets.sync_forward().is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if self
+                .step(&mut walk, &packet, &mut edge, &mut stats)
+                .is_err()
+            {
+                stats.errors += 1;
+                walk.desync();
+            }
+        }
+        stats
+    }
+
+    /// Decode the edges of the trace of @packets into the AFL style bitmap @map.
+    ///
+    /// The bitmap is the one written by `Coverage::write_afl_bitmap()`: edges increment
+    /// `map[from >> 1 ^ to]` of the hashed block addresses, counts saturate at 255.
+    /// Returns `Invalid` if the size of @map is not a power of two.
+    pub fn decode_afl<T>(
+        &mut self,
+        packets: &mut PacketDecoder<'_, T>,
+        map: &mut [u8],
+    ) -> Result<EdgeStats, PtError> {
+        let mask = afl_mask(map)?;
+        Ok(self.decode(packets, |from, to| {
+            let count = &mut map[afl_index(from, to, mask)];
+            *count = count.saturating_add(1);
+        }))
+    }
+
+    /// Apply @packet to @walk.
+    fn step<T, F>(
+        &mut self,
+        walk: &mut Walk,
+        packet: &Packet<T>,
+        edge: &mut F,
+        stats: &mut EdgeStats,
+    ) -> Result<(), PtError>
+    where
+        F: FnMut(u64, u64),
+    {
+        match packet {
+            Packet::Tnt8(tnt) => {
+                walk.pending_fup = false;
+                self.tnt(walk, u64::from(tnt.payload()), tnt.bitsize(), edge, stats)?;
+            }
+            Packet::Tnt64(tnt) => {
+                walk.pending_fup = false;
+                self.tnt(walk, tnt.payload(), tnt.bitsize(), edge, stats)?;
+            }
+            Packet::Tip(tip) => {
+                let target = walk.last_ip.update(tip.tip(), tip.compression()).ok();
+                if walk.pending_fup {
+                    // An interrupt, exception or TSX abort, not an edge.
+                    walk.pending_fup = false;
+                    walk.ip = target;
+                } else {
+                    self.indirect(walk, target, edge, stats)?;
+                }
+            }
+            Packet::TipPge(tip) => {
+                walk.ip = walk.last_ip.update(tip.tippge(), tip.compression()).ok();
+                walk.fup = Fup::Async;
+                walk.pending_fup = false;
+                walk.ret_stack.clear();
+            }
+            Packet::TipPgd(tip) => {
+                // The IP is the target of the branch leaving the traced region, if not suppressed.
+                let _ = walk.last_ip.update(tip.tippgd(), tip.compression());
+                walk.ip = None;
+                walk.pending_fup = false;
+            }
+            Packet::Fup(fup) => {
+                let ip = walk.last_ip.update(fup.fup(), fup.compression()).ok();
+                match walk.fup {
+                    Fup::Resume => walk.ip = walk.ip.or(ip),
+                    Fup::Standalone => {}
+                    Fup::Async => walk.pending_fup = true,
+                }
+                walk.fup = Fup::Async;
+            }
+            Packet::Psb(_) => {
+                walk.last_ip.reset();
+                walk.ret_stack.clear();
+                walk.fup = Fup::Resume;
+            }
+            Packet::Psbend(_) => {
+                if walk.fup == Fup::Resume {
+                    walk.fup = Fup::Async;
+                }
+            }
+            Packet::Ovf(_) => {
+                walk.desync();
+                walk.fup = Fup::Resume;
+            }
+            Packet::Mode(mode) => match mode.payload() {
+                Payload::Exec(exec) => {
+                    walk.mode = if exec.contains(Exec::CSL) {
+                        ExecModeType::Bit64
+                    } else if exec.contains(Exec::CSD) {
+                        ExecModeType::Bit32
+                    } else {
+                        ExecModeType::Bit16
+                    };
+                }
+                // An abort is followed by an FUP and a TIP, as other asynchronous branches.
+                Payload::Tsx(tsx) if !tsx.contains(Tsx::ABRT) => walk.standalone_fup(),
+                Payload::Tsx(_) => {}
+            },
+            Packet::Exstop(exstop) if exstop.ip() => walk.standalone_fup(),
+            Packet::Ptw(ptw) if ptw.ip() => walk.standalone_fup(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Resolve the conditional branches and compressed returns of a TNT packet, the oldest bit
+    /// being the most significant one.
+    fn tnt<F>(
+        &mut self,
+        walk: &mut Walk,
+        payload: u64,
+        bits: u8,
+        edge: &mut F,
+        stats: &mut EdgeStats,
+    ) -> Result<(), PtError>
+    where
+        F: FnMut(u64, u64),
+    {
+        let Some(mut ip) = walk.ip else {
+            return Ok(());
+        };
+        for bit in (0..bits).rev() {
+            let taken = (payload >> bit) & 1 != 0;
+            let block = self.block(ip, walk.mode)?;
+            for &call in &block.calls {
+                walk.ret_stack.push(call);
+            }
+            let next = match block.end {
+                Branch::Conditional(target) if taken => target,
+                Branch::Conditional(_) => block.next_ip,
+                Branch::Return if taken => walk.ret_stack.pop().ok_or(PtError::new(
+                    PtErrorCode::BadRetcomp,
+                    "Compressed return without a matching call",
+                ))?,
+                _ => {
+                    return Err(PtError::new(
+                        PtErrorCode::BadQuery,
+                        "TNT bit without a conditional branch",
+                    ));
+                }
+            };
+            edge(ip, next);
+            stats.edges += 1;
+            ip = next;
+            walk.ip = Some(ip);
+        }
+        Ok(())
+    }
+
+    /// Resolve the indirect branch, far transfer or uncompressed return at the end of the
+    /// current block, with the target of a TIP packet.
+    fn indirect<F>(
+        &mut self,
+        walk: &mut Walk,
+        target: Option<u64>,
+        edge: &mut F,
+        stats: &mut EdgeStats,
+    ) -> Result<(), PtError>
+    where
+        F: FnMut(u64, u64),
+    {
+        let Some(ip) = walk.ip else {
+            // Resynchronize on the target.
+            walk.ip = target;
+            return Ok(());
+        };
+        let block = self.block(ip, walk.mode)?;
+        for &call in &block.calls {
+            walk.ret_stack.push(call);
+        }
+        match block.end {
+            Branch::IndirectCall => walk.ret_stack.push(block.next_ip),
+            Branch::Indirect | Branch::Return | Branch::Far => {}
+            _ => {
+                return Err(PtError::new(
+                    PtErrorCode::BadQuery,
+                    "TIP without an indirect branch",
+                ));
+            }
+        }
+        let target = target.ok_or(PtError::new(
+            PtErrorCode::IpSuppressed,
+            "TIP with a suppressed IP",
+        ))?;
+        edge(ip, target);
+        stats.edges += 1;
+        walk.ip = Some(target);
+        Ok(())
+    }
+
+    /// The cached block starting at @ip in @mode, decoded from the image on the first use.
+    fn block(&mut self, ip: u64, mode: ExecModeType) -> Result<&CachedBlock, PtError> {
+        match self.blocks.entry(ip) {
+            Entry::Occupied(entry) if entry.get().mode == mode => Ok(entry.into_mut()),
+            entry => {
+                let block = decode_block(self.image, self.asid.as_ref(), ip, mode)?;
+                Ok(match entry {
+                    Entry::Occupied(mut entry) => {
+                        entry.insert(block);
+                        entry.into_mut()
+                    }
+                    Entry::Vacant(entry) => entry.insert(block),
+                })
+            }
+        }
+    }
+}
+
+/// Decode the block starting at @ip, following direct jumps and calls up to the first branch
+/// that needs trace.
+fn decode_block(
+    image: &Image,
+    asid: Option<&Asid>,
+    mut ip: u64,
+    mode: ExecModeType,
+) -> Result<CachedBlock, PtError> {
+    let mut window = Window::new();
+    let mut calls = Vec::new();
+
+    for _ in 0..MAX_BLOCK_INSNS {
+        let bytes = window.fetch(ip, |buffer, ip| image.read_at(buffer, ip, asid))?;
+
+        let insn = ild::decode(bytes, ip, mode).ok_or(PtError::new(
+            PtErrorCode::BadInsn,
+            "Failed to decode the instruction length",
+        ))?;
+        let next_ip = ip.wrapping_add(u64::from(insn.size));
+        ip = match insn.branch {
+            Branch::None => next_ip,
+            Branch::Jump(target) => target,
+            Branch::Call(target) => {
+                calls.push(next_ip);
+                target
+            }
+            end => {
+                return Ok(CachedBlock {
+                    mode,
+                    next_ip,
+                    end,
+                    calls: calls.into(),
+                });
+            }
+        };
+    }
+    Err(PtError::new(
+        PtErrorCode::BadInsn,
+        "No branch needing trace at the end of the block",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::enc_dec_builder::PtEncoderDecoder;
+    use crate::synth::fixture::{BASE, CODE, STEPS, image};
+    use crate::synth::{Step, Synthesizer};
+
+    fn edges(decoder: &mut EdgeDecoder<'_>, trace: &[u8]) -> (Vec<(u64, u64)>, EdgeStats) {
+        let mut packets = PacketDecoder::<()>::builder()
+            .buffer(trace)
+            .build()
+            .unwrap();
+        let mut edges = Vec::new();
+        let stats = decoder.decode(&mut packets, |from, to| edges.push((from, to)));
+        (edges, stats)
+    }
+
+    #[test]
+    fn test_edges_decode() {
+        for ret_compression in [false, true] {
+            let trace = Synthesizer::new()
+                .ret_compression(ret_compression)
+                .steps(STEPS)
+                .synthesize()
+                .unwrap();
+
+            let image = image();
+            let mut decoder = EdgeDecoder::new(&image);
+            let (edges, stats) = edges(&mut decoder, &trace);
+            assert_eq!(
+                edges,
+                [
+                    (0x1000, 0x1008),
+                    (0x1008, 0x100d),
+                    (0x100d, 0x1000),
+                    (0x1000, 0x1006),
+                    (0x1006, 0x100d),
+                ]
+            );
+            assert_eq!(
+                stats,
+                EdgeStats {
+                    edges: 5,
+                    errors: 0
+                }
+            );
+            assert_eq!(decoder.cached_blocks(), 4);
+        }
+    }
+
+    #[test]
+    fn test_edges_errors() {
+        // The conditional branch at 0x1004 is traced as an indirect jump.
+        let trace = Synthesizer::new()
+            .steps([
+                Step::Enable(0x1000),
+                Step::Indirect(0x2000),
+                Step::Indirect(0x1000),
+                Step::Branch(true),
+                Step::Disable(None),
+            ])
+            .synthesize()
+            .unwrap();
+
+        let image = image();
+        let mut decoder = EdgeDecoder::new(&image);
+        let (edges, stats) = edges(&mut decoder, &trace);
+        // The flow resumes at the target of the next TIP.
+        assert_eq!(edges, [(0x1000, 0x1008)]);
+        assert_eq!(stats.errors, 1);
+    }
+
+    #[test]
+    fn test_edges_file_sections() {
+        let trace = Synthesizer::new().steps(STEPS).synthesize().unwrap();
+        let file = std::env::temp_dir().join(format!("libipt-edges-{}.bin", std::process::id()));
+        std::fs::write(&file, CODE).unwrap();
+
+        let mut image = Image::new(None).unwrap();
+        image
+            .add_file(file.to_str().unwrap(), 0, CODE.len() as u64, None, BASE)
+            .unwrap();
+        let mut decoder = EdgeDecoder::new(&image);
+        let (edges, stats) = edges(&mut decoder, &trace);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(edges.len(), 5);
+        assert_eq!(stats.errors, 0);
+    }
+
+    #[test]
+    fn test_edges_ret_stack() {
+        let mut stack = RetStack::new();
+        assert_eq!(stack.pop(), None);
+        for ip in 0..RET_STACK_SIZE as u64 + 2 {
+            stack.push(ip);
+        }
+        for ip in (2..RET_STACK_SIZE as u64 + 2).rev() {
+            assert_eq!(stack.pop(), Some(ip));
+        }
+        assert_eq!(stack.pop(), None);
+    }
+}
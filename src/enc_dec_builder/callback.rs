@@ -0,0 +1,77 @@
+use crate::error::PtErrorCode;
+use libipt_sys::{pt_config, pt_packet_unknown};
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+
+type DynUnknownCallback<T> = dyn FnMut(&[u8]) -> Option<(T, usize)>;
+
+/// Trampoline called by libipt when it finds a packet with an unknown opcode.
+///
+/// The Rust closure behind `context` gets the trace from the unknown packet up to the end of the
+/// buffer. On success, the decoded value is boxed into the packet and will be owned by the
+/// resulting `Packet::Unknown`.
+pub(super) unsafe extern "C" fn decode_callback<T>(
+    unknown: *mut pt_packet_unknown,
+    config: *const pt_config,
+    pos: *const u8,
+    context: *mut c_void,
+) -> c_int {
+    unsafe {
+        let len = (*config).end as usize - pos as usize;
+        let trace = std::slice::from_raw_parts(pos, len);
+
+        match UnknownCallback::<T>::call(context, trace) {
+            Some((value, size)) if size > 0 && size <= len => match c_int::try_from(size) {
+                Ok(size) => {
+                    (*unknown).priv_ = Box::into_raw(Box::new(value)).cast();
+                    size
+                }
+                Err(_) => -(PtErrorCode::BadPacket as c_int),
+            },
+            Some(_) => -(PtErrorCode::BadPacket as c_int),
+            None => -(PtErrorCode::BadOpc as c_int),
+        }
+    }
+}
+
+/// A boxed unknown packet decoder closure that can be passed to and from C code.
+///
+/// Uses the same double boxing as the image read callback, see `image::BoxedCallback`.
+#[derive(Debug)]
+#[repr(transparent)]
+pub(crate) struct UnknownCallback<T>(pub(super) *mut c_void, PhantomData<T>);
+
+impl<T> UnknownCallback<T> {
+    /// Box the given Rust closure into an `UnknownCallback`.
+    pub(super) fn box_callback<F>(callback: F) -> Self
+    where
+        F: FnMut(&[u8]) -> Option<(T, usize)> + 'static,
+        T: 'static,
+    {
+        let boxed_dyn_callback: Box<DynUnknownCallback<T>> = Box::new(callback);
+        let boxed_ptr = Box::new(Box::into_raw(boxed_dyn_callback));
+        Self(Box::into_raw(boxed_ptr).cast(), PhantomData)
+    }
+
+    /// Invoke the callback behind the given opaque boxed callback pointer.
+    unsafe fn call(opaque_cb: *mut c_void, trace: &[u8]) -> Option<(T, usize)> {
+        unsafe {
+            let raw_boxed_ptr = opaque_cb.cast::<*mut DynUnknownCallback<T>>();
+            let func = (*raw_boxed_ptr).as_mut().unwrap();
+            func(trace)
+        }
+    }
+}
+
+impl<T> Drop for UnknownCallback<T> {
+    fn drop(&mut self) {
+        let raw_boxed_ptr = self.0.cast::<*mut DynUnknownCallback<T>>();
+
+        unsafe {
+            // Drop from inside to outside.
+            drop(Box::from_raw(*raw_boxed_ptr));
+            drop(Box::from_raw(raw_boxed_ptr));
+        }
+    }
+}
@@ -2,42 +2,24 @@ use crate::block::BlockDecoder;
 use crate::error::{PtError, PtErrorCode};
 use crate::event::QueryDecoder;
 use crate::insn::InsnDecoder;
+use crate::packet::{Encoder, PacketDecoder, StreamEncoder};
 
 use libipt_sys::pt_config;
 use std::fmt::Debug;
+use std::io::Write;
 use std::marker::PhantomData;
-use std::mem;
+use std::{mem, ptr};
 
+mod callback;
 mod cpu;
 mod filter;
 mod freqency;
+mod owned;
+pub(crate) use callback::UnknownCallback;
 pub use cpu::*;
 pub use filter::*;
 pub use freqency::*;
-
-// unsafe extern "C" fn decode_callback<'a, F, C>(
-//     ukn: *mut pt_packet_unknown,
-//     cfg: *const pt_config,
-//     pos: *const u8,
-//     ctx: *mut c_void,
-// ) -> c_int
-// where
-//     F: FnMut(&Config<C>, &[u8]) -> (Unknown<C>, u32),
-// {
-//     let sz = (*cfg).end as usize - pos as usize;
-//     let pos = std::slice::from_raw_parts(pos, sz);
-//
-//     let c = ctx as *mut F;
-//     let c = &mut *c;
-//
-//     let (res, bytes) = c(&(&*cfg).into(), pos);
-//     (*ukn).priv_ = match res.0 {
-//         Some(r) => Box::into_raw(r) as *mut _,
-//         None => std::ptr::null_mut(),
-//     };
-//
-//     bytes as i32
-// }
+pub use owned::*;
 
 pub trait PtEncoderDecoder {
     fn builder() -> EncoderDecoderBuilder<Self>
@@ -52,6 +34,13 @@ pub trait PtEncoderDecoder {
         Self: Sized;
 }
 
+/// The decoders, which only ever read their trace buffer.
+///
+/// Their builders can be cloned and reused to build any number of decoders on the same buffer.
+/// Encoders write to their buffer: their builder builds a single encoder, see
+/// `EncoderDecoderBuilder::<Encoder>::build()`.
+pub trait PtDecoder: PtEncoderDecoder {}
+
 #[derive(Debug)]
 #[repr(transparent)]
 pub struct EncoderDecoderBuilder<T> {
@@ -68,10 +57,30 @@ where
     }
 }
 
-impl<T> Clone for EncoderDecoderBuilder<T> {
+impl<T> Clone for EncoderDecoderBuilder<T>
+where
+    T: PtDecoder,
+{
     fn clone(&self) -> Self {
+        let mut config = self.config;
+        // The unknown packet decoder callback is owned by the decoder it was built for.
+        config.decode.callback = None;
+        config.decode.context = ptr::null_mut();
         Self {
-            config: self.config,
+            config,
+            target: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for EncoderDecoderBuilder<Encoder<'_, T>> {
+    fn clone(&self) -> Self {
+        let mut config = self.config;
+        // The buffer is mutably borrowed by the encoder built from this builder only.
+        config.begin = ptr::null_mut();
+        config.end = ptr::null_mut();
+        Self {
+            config,
             target: PhantomData,
         }
     }
@@ -105,21 +114,6 @@ where
         self
     }
 
-    // /// Set a decoder callback.
-    // pub fn callback<F>(&mut self, mut cb: F) -> Result<&mut Self, PtError>
-    // where
-    //     F: FnMut(&Config<T>, &[u8]) -> (Unknown<T>, u32),
-    //     F: 'a,
-    // {
-    //     let mut cfg: pt_config = unsafe { mem::zeroed() };
-    //     cfg.size = mem::size_of::<pt_config>();
-    //     cfg.begin = buf.as_mut_ptr();
-    //     cfg.end = unsafe { buf.as_mut_ptr().add(buf.len()) };
-    //     cfg.decode.callback = Some(decode_callback::<F, T>);
-    //     cfg.decode.context = &mut cb as *mut _ as *mut c_void;
-    //     Ok(EncoderDecoderBuilder::<T>(cfg, PhantomData))
-    // }
-
     /// The cpu used for capturing the data.
     /// It's highly recommended to provide this information.
     /// Processor specific workarounds will be identified this way.
@@ -148,19 +142,212 @@ where
         self
     }
 
-    /// Turn itself into a PT encoder/decoder
-    ///
-    /// Returns `Err` if the buffer is not set.
-    pub fn build(&self) -> Result<T, PtError> {
+    fn ensure_buffer(&self) -> Result<(), PtError> {
         if self.config.begin.is_null() && self.config.end.is_null() {
             Err(PtError::new(
                 PtErrorCode::BadConfig,
                 "To build an encoder/decoder, a buffer must be set",
             ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<T> EncoderDecoderBuilder<T>
+where
+    T: PtDecoder,
+{
+    /// Turn itself into a PT decoder
+    ///
+    /// Returns `Err` if the buffer is not set.
+    pub fn build(&self) -> Result<T, PtError> {
+        self.ensure_buffer()?;
+        if self.config.decode.callback.is_some() {
+            // This is the builder used by another decoder, don't share its callback.
+            T::new_from_builder(&self.clone())
         } else {
             T::new_from_builder(self)
         }
     }
+
+    /// Set up a clone of this builder for decoding unknown packets with `callback`.
+    fn with_unknown_callback<U>(&self, callback: &UnknownCallback<U>) -> Result<Self, PtError> {
+        self.ensure_buffer()?;
+        let mut builder = self.clone();
+        builder.config.decode.callback = Some(callback::decode_callback::<U>);
+        builder.config.decode.context = callback.0;
+        Ok(builder)
+    }
+
+    /// Build a decoder on an owned buffer, see `OwnedDecoder`.
+    ///
+    /// # Safety
+    /// `T` must never write to its buffer.
+    unsafe fn build_owned_unchecked<B>(&self, buf: B) -> Result<OwnedDecoder<T>, PtError>
+    where
+        B: AsRef<[u8]> + 'static,
+    {
+        let (buffer, ptr, len) = OwnedDecoder::<T>::leak_buffer(buf);
+        let builder = unsafe { self.clone().buffer_from_raw(ptr.cast_mut(), len) };
+        match builder.build() {
+            Ok(decoder) => Ok(unsafe { OwnedDecoder::new(decoder, buffer) }),
+            Err(e) => {
+                unsafe { OwnedDecoder::<T>::free_buffer(buffer) };
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'a, T> EncoderDecoderBuilder<PacketDecoder<'a, T>> {
+    /// Set the decoder buffer.
+    ///
+    /// The buffer is not copied, the built decoders borrow it for their entire lifetime.
+    pub fn buffer(self, buf: &'a [u8]) -> Self {
+        // SAFETY: the decoder never writes to the buffer and the lifetime `'a` ensures that `buf`
+        // outlives every decoder built from this builder (or from its clones).
+        unsafe { self.buffer_from_raw(buf.as_ptr().cast_mut(), buf.len()) }
+    }
+
+    /// Turn itself into a decoder owning its trace buffer.
+    ///
+    /// `buf` can be anything exposing the trace as a byte slice: a `Vec<u8>`, a `Box<[u8]>`,
+    /// an `Arc<[u8]>` or a read-only memory mapped file.
+    /// Any buffer previously set on this builder is ignored.
+    pub fn build_owned<B>(&self, buf: B) -> Result<OwnedDecoder<PacketDecoder<'a, T>>, PtError>
+    where
+        B: AsRef<[u8]> + 'static,
+    {
+        // SAFETY: decoders never write to their buffer.
+        unsafe { self.build_owned_unchecked(buf) }
+    }
+}
+
+impl<'a, T> EncoderDecoderBuilder<PacketDecoder<'a, T>>
+where
+    T: 'static,
+{
+    /// Turn itself into a packet decoder using `callback` for unknown packets.
+    ///
+    /// libipt calls `callback` with the trace from the unknown packet's opcode up to the end of
+    /// the buffer. The callback returns the decoded value along with the size of the packet in
+    /// bytes, or `None` if it doesn't recognize the packet either.
+    /// The decoded value is returned by `PacketDecoder::decode_next()` in a `Packet::Unknown`.
+    ///
+    /// The callback must not panic, a panic aborts the process.
+    pub fn build_with_unknown_callback<F>(
+        &self,
+        callback: F,
+    ) -> Result<PacketDecoder<'a, T>, PtError>
+    where
+        F: FnMut(&[u8]) -> Option<(T, usize)> + 'static,
+    {
+        let callback = UnknownCallback::box_callback(callback);
+        let mut decoder = PacketDecoder::new_from_builder(&self.with_unknown_callback(&callback)?)?;
+        decoder.callback = Some(callback);
+        Ok(decoder)
+    }
+}
+
+impl<'a, T> EncoderDecoderBuilder<QueryDecoder<'a, T>> {
+    /// Set the decoder buffer.
+    ///
+    /// The buffer is not copied, the built decoders borrow it for their entire lifetime.
+    pub fn buffer(self, buf: &'a [u8]) -> Self {
+        // SAFETY: see `EncoderDecoderBuilder::<PacketDecoder>::buffer`
+        unsafe { self.buffer_from_raw(buf.as_ptr().cast_mut(), buf.len()) }
+    }
+
+    /// Turn itself into a decoder owning its trace buffer.
+    ///
+    /// `buf` can be anything exposing the trace as a byte slice: a `Vec<u8>`, a `Box<[u8]>`,
+    /// an `Arc<[u8]>` or a read-only memory mapped file.
+    /// Any buffer previously set on this builder is ignored.
+    pub fn build_owned<B>(&self, buf: B) -> Result<OwnedDecoder<QueryDecoder<'a, T>>, PtError>
+    where
+        B: AsRef<[u8]> + 'static,
+    {
+        // SAFETY: decoders never write to their buffer.
+        unsafe { self.build_owned_unchecked(buf) }
+    }
+}
+
+impl<'a> EncoderDecoderBuilder<InsnDecoder<'a>> {
+    /// Set the decoder buffer.
+    ///
+    /// The buffer is not copied, the built decoders borrow it for their entire lifetime.
+    pub fn buffer(self, buf: &'a [u8]) -> Self {
+        // SAFETY: see `EncoderDecoderBuilder::<PacketDecoder>::buffer`
+        unsafe { self.buffer_from_raw(buf.as_ptr().cast_mut(), buf.len()) }
+    }
+
+    /// Turn itself into a decoder owning its trace buffer.
+    ///
+    /// `buf` can be anything exposing the trace as a byte slice: a `Vec<u8>`, a `Box<[u8]>`,
+    /// an `Arc<[u8]>` or a read-only memory mapped file.
+    /// Any buffer previously set on this builder is ignored.
+    pub fn build_owned<B>(&self, buf: B) -> Result<OwnedDecoder<InsnDecoder<'a>>, PtError>
+    where
+        B: AsRef<[u8]> + 'static,
+    {
+        // SAFETY: decoders never write to their buffer.
+        unsafe { self.build_owned_unchecked(buf) }
+    }
+}
+
+impl<'a> EncoderDecoderBuilder<BlockDecoder<'a>> {
+    /// Set the decoder buffer.
+    ///
+    /// The buffer is not copied, the built decoders borrow it for their entire lifetime.
+    pub fn buffer(self, buf: &'a [u8]) -> Self {
+        // SAFETY: see `EncoderDecoderBuilder::<PacketDecoder>::buffer`
+        unsafe { self.buffer_from_raw(buf.as_ptr().cast_mut(), buf.len()) }
+    }
+
+    /// Turn itself into a decoder owning its trace buffer.
+    ///
+    /// `buf` can be anything exposing the trace as a byte slice: a `Vec<u8>`, a `Box<[u8]>`,
+    /// an `Arc<[u8]>` or a read-only memory mapped file.
+    /// Any buffer previously set on this builder is ignored.
+    pub fn build_owned<B>(&self, buf: B) -> Result<OwnedDecoder<BlockDecoder<'a>>, PtError>
+    where
+        B: AsRef<[u8]> + 'static,
+    {
+        // SAFETY: decoders never write to their buffer.
+        unsafe { self.build_owned_unchecked(buf) }
+    }
+}
+
+impl<'a, T> EncoderDecoderBuilder<Encoder<'a, T>> {
+    /// Set the encoder buffer.
+    ///
+    /// The buffer is not copied, the built encoder mutably borrows it for its entire lifetime.
+    pub fn buffer_mut(self, buf: &'a mut [u8]) -> Self {
+        // SAFETY: the lifetime `'a` ensures that `buf` outlives the encoder. The builder is the
+        // only holder of the pointer: clones of an encoder builder don't carry the buffer and
+        // `build()` consumes the builder, so at most one encoder ever writes to `buf`.
+        unsafe { self.buffer_from_raw(buf.as_mut_ptr(), buf.len()) }
+    }
+
+    /// Turn itself into a PT encoder
+    ///
+    /// The builder is consumed: the encoder is the only writer of its buffer.
+    /// Returns `Err` if the buffer is not set.
+    pub fn build(self) -> Result<Encoder<'a, T>, PtError> {
+        self.ensure_buffer()?;
+        Encoder::new_from_builder(&self)
+    }
+
+    /// Build a `StreamEncoder` writing to `sink` instead of a fixed buffer.
+    ///
+    /// The buffer, if set, is ignored.
+    pub fn build_stream<W>(&self, sink: W) -> Result<StreamEncoder<W>, PtError>
+    where
+        W: Write,
+    {
+        StreamEncoder::new(&self.config, sink)
+    }
 }
 
 impl EncoderDecoderBuilder<BlockDecoder<'_>> {
@@ -255,7 +442,27 @@ impl EncoderDecoderBuilder<InsnDecoder<'_>> {
     }
 }
 
-impl<T> EncoderDecoderBuilder<QueryDecoder<T>> {
+impl<'a, T> EncoderDecoderBuilder<QueryDecoder<'a, T>> {
+    /// Turn itself into a query decoder using `callback` to skip unknown packets.
+    ///
+    /// The callback works as in `EncoderDecoderBuilder::<PacketDecoder>::build_with_unknown_callback`,
+    /// but the query decoder only uses the returned size: the decoded value is dropped.
+    ///
+    /// The callback must not panic, a panic aborts the process.
+    pub fn build_with_unknown_callback<F>(
+        &self,
+        mut callback: F,
+    ) -> Result<QueryDecoder<'a, T>, PtError>
+    where
+        F: FnMut(&[u8]) -> Option<(T, usize)> + 'static,
+    {
+        let callback =
+            UnknownCallback::box_callback(move |trace| callback(trace).map(|(_, size)| ((), size)));
+        let mut decoder = QueryDecoder::new_from_builder(&self.with_unknown_callback(&callback)?)?;
+        decoder.callback = Some(callback);
+        Ok(decoder)
+    }
+
     pub fn set_keep_tcal_on_ovf(mut self, value: bool) -> Self {
         unsafe {
             self.config
@@ -281,6 +488,8 @@ impl<T> EncoderDecoderBuilder<QueryDecoder<T>> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::packet::Unknown;
+    use libipt_sys::pt_packet_unknown;
 
     struct FooDecoder {}
 
@@ -290,6 +499,8 @@ mod test {
         }
     }
 
+    impl PtDecoder for FooDecoder {}
+
     #[test]
     #[should_panic]
     fn test_config_empty() {
@@ -308,10 +519,70 @@ mod test {
         assert_eq!(c.config.end as usize - c.config.begin as usize, len);
     }
 
+    #[test]
+    fn test_config_safe_buf() {
+        let data = [0u8; 16];
+        let c = EncoderDecoderBuilder::<BlockDecoder>::new().buffer(&data);
+        assert_eq!(c.config.begin.cast_const(), data.as_ptr());
+        assert_eq!(c.config.end as usize - c.config.begin as usize, data.len());
+
+        let mut data = [0u8; 8];
+        let len = data.len();
+        let c = EncoderDecoderBuilder::<Encoder<()>>::new().buffer_mut(&mut data);
+        assert_eq!(c.config.end as usize - c.config.begin as usize, len);
+    }
+
+    #[test]
+    fn test_config_encoder_buffer_not_shared() {
+        let mut data = [0u8; 8];
+        let c = EncoderDecoderBuilder::<Encoder<()>>::new().buffer_mut(&mut data);
+        let copy = c.clone();
+        assert!(copy.config.begin.is_null() && copy.config.end.is_null());
+        assert_eq!(copy.build().unwrap_err().code(), PtErrorCode::BadConfig);
+        assert!(c.build().is_ok());
+    }
+
+    #[test]
+    fn test_config_build_owned() {
+        fn owned_decoder() -> OwnedDecoder<BlockDecoder<'static>> {
+            BlockDecoder::builder().build_owned(vec![1u8; 2]).unwrap()
+        }
+
+        let mut b = owned_decoder();
+        assert_eq!(b.buffer(), &[1u8; 2]);
+        assert!(b.sync_forward().is_err());
+
+        let data: std::sync::Arc<[u8]> = std::sync::Arc::from(vec![2u8; 4]);
+        let mut p = EncoderDecoderBuilder::<PacketDecoder<()>>::new()
+            .build_owned(data.clone())
+            .unwrap();
+        assert_eq!(p.buffer().as_ptr(), data.as_ptr());
+        assert!(p.sync_forward().is_err());
+        assert!(p.decode_next().is_err());
+    }
+
+    #[test]
+    fn test_config_build_owned_builder() {
+        let owned = BlockDecoder::builder()
+            .cpu(Cpu::intel(6, 0x55, 4))
+            .build_owned(vec![0u8; 4])
+            .unwrap();
+        let builder = owned.used_builder();
+        assert_eq!(builder.config.cpu.model, 0x55);
+        assert!(builder.config.begin.is_null() && builder.config.end.is_null());
+        assert_eq!(
+            builder.clone().build().unwrap_err().code(),
+            PtErrorCode::BadConfig
+        );
+
+        let data = [0u8; 2];
+        assert!(builder.buffer(&data).build().is_ok());
+    }
+
     #[test]
     fn test_config_all() {
-        let mut data = [18u8; 3];
-        let mut c = EncoderDecoderBuilder::<BlockDecoder>::new()
+        let data = [18u8; 3];
+        let c = EncoderDecoderBuilder::<BlockDecoder>::new()
             .filter(
                 AddrFilters::new(&[
                     AddrFilter::new(1, 2, AddrFilterType::STOP),
@@ -324,8 +595,8 @@ mod test {
             .cpu(Cpu::intel(1, 2, 3))
             .freq(Frequency::new(1, 2, 3, 4))
             .set_end_on_call(true)
-            .set_end_on_jump(true);
-        c = unsafe { c.buffer_from_raw(data.as_mut_ptr(), data.len()) };
+            .set_end_on_jump(true)
+            .buffer(&data);
 
         assert_eq!(c.config.cpu.family, 1);
         assert_eq!(c.config.cpu.model, 2);
@@ -370,81 +641,56 @@ mod test {
             unsafe { c.config.addr_filter.config.ctl.addr3_cfg() },
             AddrFilterType::STOP as u32
         );
+    }
+
+    #[test]
+    fn test_config_callback() {
+        let data = [10u8; 9];
+        let callback = UnknownCallback::box_callback(|p: &[u8]| Some((p[8] + 3, 7)));
+        let builder = EncoderDecoderBuilder::<PacketDecoder<u8>>::new()
+            .buffer(&data)
+            .with_unknown_callback(&callback)
+            .unwrap();
+
+        for _ in 0..10 {
+            unsafe {
+                let mut ukn: pt_packet_unknown = std::mem::zeroed();
+                assert_eq!(
+                    builder.config.decode.callback.unwrap()(
+                        &mut ukn,
+                        &raw const builder.config,
+                        builder.config.begin,
+                        builder.config.decode.context,
+                    ),
+                    7
+                );
+                assert_eq!(Unknown::<u8>::from(ukn).data(), Some(13));
+            }
+        }
 
-        // unsafe {
-        //     let mut ukn: pt_packet_unknown = std::mem::zeroed();
-        //     assert_eq!(
-        //         c.config.decode.callback.unwrap()(
-        //             &mut ukn,
-        //             &raw const c.config,
-        //             c.config.begin,
-        //             c.config.decode.context
-        //         ),
-        //         1
-        //     );
-        //     let pkt: Unknown<u8> = Unknown::from(ukn);
-        //     assert_eq!(pkt.data().unwrap(), 20);
-        // }
-    }
-
-    // fn check_callback<T>(cfg: &mut Config<T>, expect: T, expect_sz: i32) -> bool
-    // where
-    //     T: PartialEq,
-    // {
-    //     unsafe {
-    //         let mut ukn: pt_packet_unknown = std::mem::zeroed();
-    //         return cfg.config.decode.callback.unwrap()(
-    //             &mut ukn,
-    //             cfg.config.as_ref(),
-    //             cfg.config.begin,
-    //             cfg.config.decode.context,
-    //         ) == expect_sz
-    //             && Unknown::<T>::from(ukn).data().unwrap() == expect;
-    //     }
-    // }
-
-    // #[test]
-    // fn test_config_callback_safety() {
-    //     let mut kektop = [10; 9];
-    //     let mut cfg = EncoderDecoderBuilder::with_callback(&mut kektop, |c, p| {
-    //         (Unknown::new(c.config.cpu.stepping + p[8]), 17)
-    //     })
-    //     .unwrap()
-    //     .cpu(Cpu::intel(1, 2, 3))
-    //     .finish();
-    //
-    //     for _ in 0..10 {
-    //         assert!(check_callback(&mut cfg, 13, 17))
-    //     }
-    // }
-
-    // #[ignore]
-    // #[test]
-    // #[should_panic]
-    // fn test_config_callback_out_of_bounds() {
-    //     let mut kektop = [10; 9];
-    //     let cfg = EncoderDecoderBuilder::with_callback(&mut kektop, |c, p| {
-    //         // make sure no move or copy is done
-    //         if let Cow::Owned(_) = c.0 {
-    //             panic!("BUG!")
-    //         }
-    //         // assert_eq!(c.config.as_ref() as *const _, raw);
-    //         (Unknown::new(p[100]), 17)
-    //     })
-    //     .unwrap()
-    //     .cpu(Cpu::intel(1, 2, 3))
-    //     .finish();
-    //
-    //     unsafe {
-    //         let mut ukn: pt_packet_unknown = std::mem::zeroed();
-    //         cfg.config.decode.callback.unwrap()(
-    //             &mut ukn,
-    //             cfg.config.as_ref(),
-    //             cfg.config.begin,
-    //             cfg.config.decode.context,
-    //         );
-    //     }
-    // }
+        // out of bounds sizes are rejected
+        let callback = UnknownCallback::box_callback(|_: &[u8]| Some(((), 10)));
+        let builder = EncoderDecoderBuilder::<PacketDecoder<()>>::new()
+            .buffer(&data)
+            .with_unknown_callback(&callback)
+            .unwrap();
+        unsafe {
+            let mut ukn: pt_packet_unknown = std::mem::zeroed();
+            assert_eq!(
+                builder.config.decode.callback.unwrap()(
+                    &mut ukn,
+                    &raw const builder.config,
+                    builder.config.begin,
+                    builder.config.decode.context,
+                ),
+                -(PtErrorCode::BadPacket as i32)
+            );
+            assert!(ukn.priv_.is_null());
+        }
+
+        // clones don't share the callback
+        assert!(builder.clone().config.decode.callback.is_none());
+    }
 
     #[test]
     fn test_block_flags() {
@@ -0,0 +1,370 @@
+use crate::asid::Asid;
+use crate::block::{Block, BlockDecoder};
+use crate::enc_dec_builder::{EncoderDecoderBuilder, PtEncoderDecoder};
+use crate::error::PtError;
+use crate::event::{CondBranch, Event, QueryDecoder};
+use crate::image::Image;
+use crate::insn::{Insn, InsnDecoder};
+use crate::packet::{Packet, PacketDecoder};
+use crate::status::Status;
+use libipt_sys::pt_config;
+use std::fmt::{Debug, Formatter};
+use std::mem::ManuallyDrop;
+use std::ptr::{self, NonNull};
+
+/// A decoder owning the trace buffer it works on.
+///
+/// The buffer is moved on the heap and stays there, untouched, for the entire lifetime of the
+/// wrapped decoder. Built with `EncoderDecoderBuilder::build_owned()`.
+///
+/// `OwnedDecoder` forwards the methods and the `Iterator` implementation of the wrapped decoder,
+/// so it can be used exactly as a decoder borrowing its buffer.
+/// There is no access to the wrapped decoder nor to the builder it was built with: they could be
+/// used to build decoders outliving the buffer. `used_builder()` returns a copy of the
+/// configuration without the buffer instead.
+pub struct OwnedDecoder<D> {
+    decoder: ManuallyDrop<D>,
+    // Kept as a raw pointer instead of a `Box`: moving a `Box` asserts unique ownership of its
+    // content, while libipt holds a pointer into it.
+    buffer: NonNull<dyn AsRef<[u8]>>,
+}
+
+impl<D> OwnedDecoder<D> {
+    /// Move `buffer` on the heap, returning it along with the slice it exposes.
+    pub(super) fn leak_buffer<B>(buffer: B) -> (NonNull<dyn AsRef<[u8]>>, *const u8, usize)
+    where
+        B: AsRef<[u8]> + 'static,
+    {
+        let boxed: Box<dyn AsRef<[u8]>> = Box::new(buffer);
+        let raw = NonNull::from(Box::leak(boxed));
+        let data = unsafe { raw.as_ref() }.as_ref();
+        (raw, data.as_ptr(), data.len())
+    }
+
+    /// # Safety
+    /// `buffer` must come from `leak_buffer()` and `decoder` must be the only user of it.
+    pub(super) unsafe fn new(decoder: D, buffer: NonNull<dyn AsRef<[u8]>>) -> Self {
+        Self {
+            decoder: ManuallyDrop::new(decoder),
+            buffer,
+        }
+    }
+
+    /// Free a buffer returned by `leak_buffer()` that ended up unused.
+    ///
+    /// # Safety
+    /// `buffer` must come from `leak_buffer()` and must not be used afterward.
+    pub(super) unsafe fn free_buffer(buffer: NonNull<dyn AsRef<[u8]>>) {
+        drop(unsafe { Box::from_raw(buffer.as_ptr()) });
+    }
+
+    /// The trace buffer owned by this decoder.
+    #[must_use]
+    pub fn buffer(&self) -> &[u8] {
+        unsafe { self.buffer.as_ref() }.as_ref()
+    }
+
+    /// The wrapped decoder, for the crate's own decoding loops.
+    ///
+    /// The decoder must not be moved out of the returned reference.
+    pub(crate) fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+}
+
+impl<D> Drop for OwnedDecoder<D> {
+    fn drop(&mut self) {
+        // The decoder must go away before its buffer.
+        unsafe {
+            ManuallyDrop::drop(&mut self.decoder);
+            Self::free_buffer(self.buffer);
+        }
+    }
+}
+
+/// A builder with the configuration @config, without its buffer and unknown packet callback.
+fn detached<D>(config: &pt_config) -> EncoderDecoderBuilder<D>
+where
+    D: PtEncoderDecoder,
+{
+    let mut builder = EncoderDecoderBuilder::new();
+    builder.config = *config;
+    builder.config.begin = ptr::null_mut();
+    builder.config.end = ptr::null_mut();
+    builder.config.decode.callback = None;
+    builder.config.decode.context = ptr::null_mut();
+    builder
+}
+
+impl<T> OwnedDecoder<PacketDecoder<'_, T>> {
+    /// A copy of the builder used by the decoder, without the owned buffer.
+    ///
+    /// A new buffer must be set to build another decoder with it.
+    #[must_use]
+    pub fn used_builder<'b>(&self) -> EncoderDecoderBuilder<PacketDecoder<'b, T>> {
+        detached(&self.decoder.used_builder().config)
+    }
+
+    /// See `PacketDecoder::offset()`.
+    pub fn offset(&self) -> Result<u64, PtError> {
+        self.decoder.offset()
+    }
+
+    /// See `PacketDecoder::sync_offset()`.
+    pub fn sync_offset(&self) -> Result<u64, PtError> {
+        self.decoder.sync_offset()
+    }
+
+    /// See `PacketDecoder::decode_next()`.
+    pub fn decode_next(&mut self) -> Result<Packet<T>, PtError> {
+        self.decoder.decode_next()
+    }
+
+    /// See `PacketDecoder::sync_backward()`.
+    pub fn sync_backward(&mut self) -> Result<(), PtError> {
+        self.decoder.sync_backward()
+    }
+
+    /// See `PacketDecoder::sync_forward()`.
+    pub fn sync_forward(&mut self) -> Result<(), PtError> {
+        self.decoder.sync_forward()
+    }
+
+    /// See `PacketDecoder::sync_set()`.
+    pub fn sync_set(&mut self, offset: u64) -> Result<(), PtError> {
+        self.decoder.sync_set(offset)
+    }
+}
+
+impl<T> OwnedDecoder<QueryDecoder<'_, T>> {
+    /// A copy of the builder used by the decoder, without the owned buffer.
+    ///
+    /// A new buffer must be set to build another decoder with it.
+    #[must_use]
+    pub fn used_builder<'b>(&self) -> EncoderDecoderBuilder<QueryDecoder<'b, T>> {
+        detached(&self.decoder.used_builder().config)
+    }
+
+    /// See `QueryDecoder::core_bus_ratio()`.
+    pub fn core_bus_ratio(&self) -> Result<u32, PtError> {
+        self.decoder.core_bus_ratio()
+    }
+
+    /// See `QueryDecoder::offset()`.
+    pub fn offset(&self) -> Result<u64, PtError> {
+        self.decoder.offset()
+    }
+
+    /// See `QueryDecoder::sync_offset()`.
+    pub fn sync_offset(&self) -> Result<u64, PtError> {
+        self.decoder.sync_offset()
+    }
+
+    /// See `QueryDecoder::cond_branch()`.
+    pub fn cond_branch(&mut self) -> Result<(CondBranch, Status), PtError> {
+        self.decoder.cond_branch()
+    }
+
+    /// See `QueryDecoder::event()`.
+    pub fn event(&mut self) -> Result<(Event, Status), PtError> {
+        self.decoder.event()
+    }
+
+    /// See `QueryDecoder::indirect_branch()`.
+    pub fn indirect_branch(&mut self) -> Result<(u64, Status), PtError> {
+        self.decoder.indirect_branch()
+    }
+
+    /// See `QueryDecoder::sync_backward()`.
+    pub fn sync_backward(&mut self) -> Result<(u64, Status), PtError> {
+        self.decoder.sync_backward()
+    }
+
+    /// See `QueryDecoder::sync_forward()`.
+    pub fn sync_forward(&mut self) -> Result<(u64, Status), PtError> {
+        self.decoder.sync_forward()
+    }
+
+    /// See `QueryDecoder::sync_set()`.
+    pub fn sync_set(&mut self, offset: u64) -> Result<(u64, Status), PtError> {
+        self.decoder.sync_set(offset)
+    }
+
+    /// See `QueryDecoder::time()`.
+    pub fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        self.decoder.time()
+    }
+}
+
+impl<'a> OwnedDecoder<InsnDecoder<'a>> {
+    /// A copy of the builder used by the decoder, without the owned buffer.
+    ///
+    /// A new buffer must be set to build another decoder with it.
+    #[must_use]
+    pub fn used_builder<'b>(&self) -> EncoderDecoderBuilder<InsnDecoder<'b>> {
+        detached(&self.decoder.used_builder().config)
+    }
+
+    /// See `InsnDecoder::asid()`.
+    pub fn asid(&self) -> Result<Asid, PtError> {
+        self.decoder.asid()
+    }
+
+    /// See `InsnDecoder::core_bus_ratio()`.
+    pub fn core_bus_ratio(&self) -> Result<u32, PtError> {
+        self.decoder.core_bus_ratio()
+    }
+
+    /// See `InsnDecoder::offset()`.
+    pub fn offset(&self) -> Result<u64, PtError> {
+        self.decoder.offset()
+    }
+
+    /// See `InsnDecoder::sync_offset()`.
+    pub fn sync_offset(&self) -> Result<u64, PtError> {
+        self.decoder.sync_offset()
+    }
+
+    /// See `InsnDecoder::event()`.
+    pub fn event(&mut self) -> Result<(Event, Status), PtError> {
+        self.decoder.event()
+    }
+
+    /// See `InsnDecoder::image()`.
+    pub fn image(&mut self) -> &mut Image {
+        self.decoder.image()
+    }
+
+    /// See `InsnDecoder::decode_next()`.
+    pub fn decode_next(&mut self) -> Result<(Insn, Status), PtError> {
+        self.decoder.decode_next()
+    }
+
+    /// See `InsnDecoder::set_image()`.
+    pub fn set_image(&mut self, img: Option<&'a mut Image>) -> Result<(), PtError> {
+        self.decoder.set_image(img)
+    }
+
+    /// See `InsnDecoder::resync()`.
+    pub fn resync(&mut self) -> Result<Status, PtError> {
+        self.decoder.resync()
+    }
+
+    /// See `InsnDecoder::sync_backward()`.
+    pub fn sync_backward(&mut self) -> Result<Status, PtError> {
+        self.decoder.sync_backward()
+    }
+
+    /// See `InsnDecoder::sync_forward()`.
+    pub fn sync_forward(&mut self) -> Result<Status, PtError> {
+        self.decoder.sync_forward()
+    }
+
+    /// See `InsnDecoder::sync_set()`.
+    pub fn sync_set(&mut self, offset: u64) -> Result<(), PtError> {
+        self.decoder.sync_set(offset)
+    }
+
+    /// See `InsnDecoder::time()`.
+    pub fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        self.decoder.time()
+    }
+}
+
+impl<'a> OwnedDecoder<BlockDecoder<'a>> {
+    /// A copy of the builder used by the decoder, without the owned buffer.
+    ///
+    /// A new buffer must be set to build another decoder with it.
+    #[must_use]
+    pub fn used_builder<'b>(&self) -> EncoderDecoderBuilder<BlockDecoder<'b>> {
+        detached(&self.decoder.used_builder().config)
+    }
+
+    /// See `BlockDecoder::asid()`.
+    pub fn asid(&self) -> Result<Asid, PtError> {
+        self.decoder.asid()
+    }
+
+    /// See `BlockDecoder::core_bus_ratio()`.
+    pub fn core_bus_ratio(&self) -> Result<u32, PtError> {
+        self.decoder.core_bus_ratio()
+    }
+
+    /// See `BlockDecoder::offset()`.
+    pub fn offset(&self) -> Result<u64, PtError> {
+        self.decoder.offset()
+    }
+
+    /// See `BlockDecoder::sync_offset()`.
+    pub fn sync_offset(&self) -> Result<u64, PtError> {
+        self.decoder.sync_offset()
+    }
+
+    /// See `BlockDecoder::event()`.
+    pub fn event(&mut self) -> Result<(Event, Status), PtError> {
+        self.decoder.event()
+    }
+
+    /// See `BlockDecoder::image()`.
+    pub fn image(&mut self) -> &mut Image {
+        self.decoder.image()
+    }
+
+    /// See `BlockDecoder::decode_next()`.
+    pub fn decode_next(&mut self) -> Result<(Block, Status), PtError> {
+        self.decoder.decode_next()
+    }
+
+    /// See `BlockDecoder::set_image()`.
+    pub fn set_image(&mut self, img: Option<&'a mut Image>) -> Result<(), PtError> {
+        self.decoder.set_image(img)
+    }
+
+    /// See `BlockDecoder::resync()`.
+    pub fn resync(&mut self) -> Result<Status, PtError> {
+        self.decoder.resync()
+    }
+
+    /// See `BlockDecoder::sync_backward()`.
+    pub fn sync_backward(&mut self) -> Result<Status, PtError> {
+        self.decoder.sync_backward()
+    }
+
+    /// See `BlockDecoder::sync_forward()`.
+    pub fn sync_forward(&mut self) -> Result<Status, PtError> {
+        self.decoder.sync_forward()
+    }
+
+    /// See `BlockDecoder::set_sync()`.
+    pub fn set_sync(&mut self, offset: u64) -> Result<(), PtError> {
+        self.decoder.set_sync(offset)
+    }
+
+    /// See `BlockDecoder::time()`.
+    pub fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        self.decoder.time()
+    }
+}
+
+impl<D> Iterator for OwnedDecoder<D>
+where
+    D: Iterator,
+{
+    type Item = D::Item;
+
+    fn next(&mut self) -> Option<D::Item> {
+        self.decoder.next()
+    }
+}
+
+impl<D> Debug for OwnedDecoder<D>
+where
+    D: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedDecoder")
+            .field("decoder", &*self.decoder)
+            .field("buffer_len", &self.buffer().len())
+            .finish()
+    }
+}
@@ -20,6 +20,7 @@ use std::convert::TryFrom;
 use std::error::Error;
 use std::ffi::CStr;
 use std::fmt::{Display, Formatter};
+use std::io::Write;
 
 #[derive(Clone, Copy, Debug, TryFromPrimitive, PartialEq)]
 #[repr(i32)]
@@ -172,6 +173,14 @@ pub(crate) fn extract_pterr(code: i32) -> Result<u32, PtError> {
     }
 }
 
+/// Writes @data to the sink @out.
+/// Returns BadFile if writing fails.
+#[inline]
+pub(crate) fn write_sink<W: Write + ?Sized>(out: &mut W, data: &[u8]) -> Result<(), PtError> {
+    out.write_all(data)
+        .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to write to the sink"))
+}
+
 /// Turns a negative code into a PtErr and a positive code into a Status
 #[inline]
 pub(crate) fn extract_status_or_pterr(code: i32) -> Result<Status, PtError> {
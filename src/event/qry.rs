@@ -1,4 +1,4 @@
-use crate::enc_dec_builder::{EncoderDecoderBuilder, PtEncoderDecoder};
+use crate::enc_dec_builder::{EncoderDecoderBuilder, PtDecoder, PtEncoderDecoder, UnknownCallback};
 use crate::error::{PtError, PtErrorCode, ensure_ptok, extract_status_or_pterr};
 use crate::event::Event;
 use crate::status::Status;
@@ -26,13 +26,16 @@ pub enum CondBranch {
 /// it shall contain raw trace data and remain valid for the lifetime of the decoder.
 /// The decoder needs to be synchronized before it can be used.
 #[derive(Debug)]
-#[repr(transparent)]
-pub struct QueryDecoder<T> {
+pub struct QueryDecoder<'a, T> {
     inner: NonNull<pt_query_decoder>,
-    phantom: PhantomData<T>,
+    // The unknown packet decoder callback, if any. It must outlive `inner`.
+    pub(crate) callback: Option<UnknownCallback<()>>,
+    phantom: PhantomData<(T, &'a [u8])>,
 }
 
-impl<T> PtEncoderDecoder for QueryDecoder<T> {
+impl<T> PtDecoder for QueryDecoder<'_, T> {}
+
+impl<T> PtEncoderDecoder for QueryDecoder<'_, T> {
     /// Allocate an Intel PT query decoder.
     ///
     /// The decoder will work on the buffer defined in @config,
@@ -45,12 +48,13 @@ impl<T> PtEncoderDecoder for QueryDecoder<T> {
             )?;
         Ok(Self {
             inner,
+            callback: None,
             phantom: PhantomData,
         })
     }
 }
 
-impl<T> QueryDecoder<T> {
+impl<T> QueryDecoder<'_, T> {
     /// Query whether the next unconditional branch has been taken.
     ///
     /// On success, provides Taken or NotTaken along with StatusFlags
@@ -211,7 +215,7 @@ impl<T> QueryDecoder<T> {
     }
 }
 
-impl<T> Iterator for QueryDecoder<T> {
+impl<T> Iterator for QueryDecoder<'_, T> {
     type Item = Result<(Event, Status), PtError>;
 
     fn next(&mut self) -> Option<Result<(Event, Status), PtError>> {
@@ -223,7 +227,7 @@ impl<T> Iterator for QueryDecoder<T> {
     }
 }
 
-impl<T> Drop for QueryDecoder<T> {
+impl<T> Drop for QueryDecoder<'_, T> {
     fn drop(&mut self) {
         unsafe { pt_qry_free_decoder(self.inner.as_ptr()) }
     }
@@ -236,20 +240,16 @@ mod test {
 
     #[test]
     fn test_qrydec_alloc() {
-        let mut kek = [1u8; 2];
+        let kek = [1u8; 2];
         let builder: EncoderDecoderBuilder<QueryDecoder<()>> = QueryDecoder::builder();
-        unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        builder.buffer(&kek).build().unwrap();
     }
 
     #[test]
     fn test_qrydec_props() {
-        let mut kek = [1u8; 2];
+        let kek = [1u8; 2];
         let builder: EncoderDecoderBuilder<QueryDecoder<()>> = QueryDecoder::builder();
-        let mut b = unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        let mut b = builder.buffer(&kek).build().unwrap();
 
         assert!(b.cond_branch().is_err());
         assert!(b.indirect_branch().is_err());
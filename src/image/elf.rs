@@ -0,0 +1,576 @@
+use crate::asid::Asid;
+use crate::error::{PtError, PtErrorCode};
+use crate::image::{Image, SectionCache, SectionInfo};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+pub(crate) const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+pub(crate) const ELFCLASS32: u8 = 1;
+pub(crate) const ELFCLASS64: u8 = 2;
+pub(crate) const ELFDATA2LSB: u8 = 1;
+pub(crate) const ELFDATA2MSB: u8 = 2;
+const ET_DYN: u16 = 3;
+pub(crate) const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PN_XNUM: u16 = 0xffff;
+const SHN_XINDEX: u16 = 0xffff;
+const PAGE_MASK: u64 = !0xfff;
+
+pub(crate) fn bad_elf(msg: &'static str) -> PtError {
+    PtError::new(PtErrorCode::BadImage, msg)
+}
+
+fn io_error(err: std::io::Error) -> PtError {
+    if err.kind() == ErrorKind::UnexpectedEof {
+        bad_elf("Truncated ELF file")
+    } else {
+        PtError::new(PtErrorCode::BadFile, "Failed to read the ELF file")
+    }
+}
+
+/// Little or big endian field reader.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Endian(pub(crate) bool);
+
+impl Endian {
+    pub(crate) fn u16(self, buf: &[u8], off: usize) -> u16 {
+        let bytes = buf[off..off + 2].try_into().unwrap();
+        if self.0 {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    }
+
+    pub(crate) fn u32(self, buf: &[u8], off: usize) -> u32 {
+        let bytes = buf[off..off + 4].try_into().unwrap();
+        if self.0 {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    pub(crate) fn u64(self, buf: &[u8], off: usize) -> u64 {
+        let bytes = buf[off..off + 8].try_into().unwrap();
+        if self.0 {
+            u64::from_le_bytes(bytes)
+        } else {
+            u64::from_be_bytes(bytes)
+        }
+    }
+}
+
+/// The ELF file header, only the fields we need, with the extended numbering resolved.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Ehdr {
+    pub(crate) endian: Endian,
+    pub(crate) is64: bool,
+    kind: u16,
+    phoff: u64,
+    phentsize: u16,
+    phnum: u32,
+    shoff: u64,
+    shentsize: u16,
+    shnum: u32,
+    pub(crate) shstrndx: u32,
+}
+
+/// A program header, only the fields we need.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Phdr {
+    pub(crate) kind: u32,
+    pub(crate) flags: u32,
+    pub(crate) offset: u64,
+    pub(crate) vaddr: u64,
+    /// The size of the segment in the file
+    pub(crate) size: u64,
+}
+
+/// A section header, only the fields we need.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Shdr {
+    pub(crate) name: u32,
+    pub(crate) kind: u32,
+    pub(crate) flags: u64,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+    pub(crate) link: u32,
+    info: u32,
+}
+
+impl Ehdr {
+    /// Read the file header of the ELF file @reader.
+    ///
+    /// With extended numbering, the number of program and section headers and the index of the
+    /// section names are read from the first section header.
+    /// Returns BadFile if @reader fails.
+    /// Returns `BadImage` if @reader does not contain a valid ELF file.
+    pub(crate) fn read<R>(reader: &mut R) -> Result<Self, PtError>
+    where
+        R: Read + Seek,
+    {
+        let mut ehdr = [0u8; 64];
+        reader.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        reader.read_exact(&mut ehdr[..52]).map_err(io_error)?;
+        if ehdr[..4] != ELF_MAGIC {
+            return Err(bad_elf("Not an ELF file"));
+        }
+
+        let endian = match ehdr[5] {
+            ELFDATA2LSB => Endian(true),
+            ELFDATA2MSB => Endian(false),
+            _ => return Err(bad_elf("Invalid ELF data encoding")),
+        };
+        let mut header = match ehdr[4] {
+            ELFCLASS32 => Self {
+                endian,
+                is64: false,
+                kind: endian.u16(&ehdr, 16),
+                phoff: u64::from(endian.u32(&ehdr, 28)),
+                phentsize: endian.u16(&ehdr, 42),
+                phnum: u32::from(endian.u16(&ehdr, 44)),
+                shoff: u64::from(endian.u32(&ehdr, 32)),
+                shentsize: endian.u16(&ehdr, 46),
+                shnum: u32::from(endian.u16(&ehdr, 48)),
+                shstrndx: u32::from(endian.u16(&ehdr, 50)),
+            },
+            ELFCLASS64 => {
+                reader.read_exact(&mut ehdr[52..]).map_err(io_error)?;
+                Self {
+                    endian,
+                    is64: true,
+                    kind: endian.u16(&ehdr, 16),
+                    phoff: endian.u64(&ehdr, 32),
+                    phentsize: endian.u16(&ehdr, 54),
+                    phnum: u32::from(endian.u16(&ehdr, 56)),
+                    shoff: endian.u64(&ehdr, 40),
+                    shentsize: endian.u16(&ehdr, 58),
+                    shnum: u32::from(endian.u16(&ehdr, 60)),
+                    shstrndx: u32::from(endian.u16(&ehdr, 62)),
+                }
+            }
+            _ => return Err(bad_elf("Invalid ELF class")),
+        };
+
+        let extended = header.phnum == u32::from(PN_XNUM)
+            || header.shstrndx == u32::from(SHN_XINDEX)
+            || (header.shnum == 0 && header.shoff != 0);
+        if extended {
+            if header.shoff == 0 {
+                return Err(bad_elf("Extended ELF numbering without section headers"));
+            }
+            let buf = header.table(
+                reader,
+                header.shoff,
+                header.shentsize,
+                1,
+                header.shdr_size(),
+            )?;
+            let first = header.shdr(&buf);
+            if header.shnum == 0 {
+                header.shnum =
+                    u32::try_from(first.size).map_err(|_| bad_elf("Invalid ELF shnum"))?;
+            }
+            if header.shstrndx == u32::from(SHN_XINDEX) {
+                header.shstrndx = first.link;
+            }
+            if header.phnum == u32::from(PN_XNUM) {
+                header.phnum = first.info;
+            }
+        }
+        Ok(header)
+    }
+
+    fn shdr_size(&self) -> usize {
+        if self.is64 { 64 } else { 40 }
+    }
+
+    /// Read the @num entries of @entsize bytes at @offset in @reader, each at least @size bytes.
+    fn table<R>(
+        &self,
+        reader: &mut R,
+        offset: u64,
+        entsize: u16,
+        num: u32,
+        size: usize,
+    ) -> Result<Vec<u8>, PtError>
+    where
+        R: Read + Seek,
+    {
+        if num == 0 {
+            return Ok(Vec::new());
+        }
+        if usize::from(entsize) < size {
+            return Err(bad_elf("Invalid ELF header entry size"));
+        }
+
+        // Don't trust the header with the allocation size.
+        let table_size = u64::from(entsize) * u64::from(num);
+        let file_size = reader.seek(SeekFrom::End(0)).map_err(io_error)?;
+        if offset
+            .checked_add(table_size)
+            .is_none_or(|end| end > file_size)
+        {
+            return Err(bad_elf("ELF headers beyond the end of the file"));
+        }
+
+        let mut table = vec![0u8; table_size as usize];
+        reader.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+        reader.read_exact(&mut table).map_err(io_error)?;
+        Ok(table)
+    }
+
+    /// Read the program headers from @reader.
+    ///
+    /// Returns `BadImage` if they are not contained in @reader.
+    pub(crate) fn phdrs<R>(&self, reader: &mut R) -> Result<Vec<Phdr>, PtError>
+    where
+        R: Read + Seek,
+    {
+        let endian = self.endian;
+        let size = if self.is64 { 56 } else { 32 };
+        let table = self.table(reader, self.phoff, self.phentsize, self.phnum, size)?;
+        Ok(table
+            .chunks_exact(usize::from(self.phentsize))
+            .map(|phdr| {
+                if self.is64 {
+                    Phdr {
+                        kind: endian.u32(phdr, 0),
+                        flags: endian.u32(phdr, 4),
+                        offset: endian.u64(phdr, 8),
+                        vaddr: endian.u64(phdr, 16),
+                        size: endian.u64(phdr, 32),
+                    }
+                } else {
+                    Phdr {
+                        kind: endian.u32(phdr, 0),
+                        flags: endian.u32(phdr, 24),
+                        offset: u64::from(endian.u32(phdr, 4)),
+                        vaddr: u64::from(endian.u32(phdr, 8)),
+                        size: u64::from(endian.u32(phdr, 16)),
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// Read the section headers from @reader, none if the file has no section header table.
+    ///
+    /// Returns `BadImage` if they are not contained in @reader.
+    pub(crate) fn shdrs<R>(&self, reader: &mut R) -> Result<Vec<Shdr>, PtError>
+    where
+        R: Read + Seek,
+    {
+        if self.shoff == 0 {
+            return Ok(Vec::new());
+        }
+        let table = self.table(
+            reader,
+            self.shoff,
+            self.shentsize,
+            self.shnum,
+            self.shdr_size(),
+        )?;
+        Ok(table
+            .chunks_exact(usize::from(self.shentsize))
+            .map(|shdr| self.shdr(shdr))
+            .collect())
+    }
+
+    fn shdr(&self, shdr: &[u8]) -> Shdr {
+        let endian = self.endian;
+        if self.is64 {
+            Shdr {
+                name: endian.u32(shdr, 0),
+                kind: endian.u32(shdr, 4),
+                flags: endian.u64(shdr, 8),
+                offset: endian.u64(shdr, 24),
+                size: endian.u64(shdr, 32),
+                link: endian.u32(shdr, 40),
+                info: endian.u32(shdr, 44),
+            }
+        } else {
+            Shdr {
+                name: endian.u32(shdr, 0),
+                kind: endian.u32(shdr, 4),
+                flags: u64::from(endian.u32(shdr, 8)),
+                offset: u64::from(endian.u32(shdr, 16)),
+                size: u64::from(endian.u32(shdr, 20)),
+                link: endian.u32(shdr, 24),
+                info: endian.u32(shdr, 28),
+            }
+        }
+    }
+}
+
+/// An executable `PT_LOAD` segment of an ELF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfSegment {
+    /// Offset of the segment in the file
+    pub offset: u64,
+    /// Size of the segment in the file
+    pub size: u64,
+    /// Virtual address of the segment, as linked
+    pub virtual_address: u64,
+}
+
+/// The executable segments of an ELF file, as described by its program headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfFile {
+    filename: String,
+    pie: bool,
+    segments: Vec<ElfSegment>,
+}
+
+impl ElfFile {
+    /// Read the program headers of the ELF file @filename.
+    ///
+    /// Returns BadFile if @filename can't be read.
+    /// Returns `BadImage` if @filename is not a valid ELF file.
+    pub fn open(filename: &str) -> Result<Self, PtError> {
+        let file = File::open(filename).map_err(io_error)?;
+        Self::parse(filename, file)
+    }
+
+    /// Read the program headers of an ELF file from @reader, @filename being its path.
+    ///
+    /// Returns BadFile if @reader fails.
+    /// Returns `BadImage` if @reader does not contain a valid ELF file.
+    pub fn parse<R>(filename: &str, mut reader: R) -> Result<Self, PtError>
+    where
+        R: Read + Seek,
+    {
+        let ehdr = Ehdr::read(&mut reader)?;
+        let segments = ehdr
+            .phdrs(&mut reader)?
+            .into_iter()
+            .filter(|phdr| phdr.kind == PT_LOAD && phdr.flags & PF_X != 0 && phdr.size > 0)
+            .map(|phdr| ElfSegment {
+                offset: phdr.offset,
+                size: phdr.size,
+                virtual_address: phdr.vaddr,
+            })
+            .collect();
+
+        Ok(Self {
+            filename: filename.to_owned(),
+            pie: ehdr.kind == ET_DYN,
+            segments,
+        })
+    }
+
+    /// Path of the ELF file.
+    #[must_use]
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Whether the file is position independent (`ET_DYN`), i.e. a PIE or a shared library.
+    #[must_use]
+    pub fn is_pie(&self) -> bool {
+        self.pie
+    }
+
+    /// The executable `PT_LOAD` segments.
+    #[must_use]
+    pub fn segments(&self) -> &[ElfSegment] {
+        &self.segments
+    }
+
+    /// The load bias of a position independent file whose first executable segment is mapped in
+    /// the page at @base.
+    ///
+    /// Non position independent files are always loaded at their link address, the bias is 0.
+    #[must_use]
+    pub fn bias_for_base(&self, base: u64) -> u64 {
+        match self.segments.iter().map(|s| s.virtual_address).min() {
+            Some(first) if self.pie => base.wrapping_sub(first & PAGE_MASK),
+            _ => 0,
+        }
+    }
+
+    /// The executable segments as `SectionInfo`s, relocated by @bias.
+    #[must_use]
+    pub fn sections(&self, bias: u64) -> Vec<SectionInfo> {
+        self.segments
+            .iter()
+            .map(|s| SectionInfo {
+                filename: self.filename.clone(),
+                offset: s.offset,
+                size: s.size,
+                virtual_address: s.virtual_address.wrapping_add(bias),
+            })
+            .collect()
+    }
+}
+
+/// Loads the executable segments of ELF files into `Image`s.
+///
+/// Sections are added through a `SectionCache`, so loading the same file (at the same address)
+/// into multiple images shares the same mapping.
+#[derive(Debug, Clone)]
+pub struct ElfLoader {
+    cache: Rc<SectionCache>,
+}
+
+impl ElfLoader {
+    /// Create a loader with a new, unnamed, section cache.
+    pub fn new() -> Result<Self, PtError> {
+        Ok(Self::with_cache(Rc::new(SectionCache::new(None)?)))
+    }
+
+    /// Create a loader adding sections to @cache.
+    #[must_use]
+    pub fn with_cache(cache: Rc<SectionCache>) -> Self {
+        Self { cache }
+    }
+
+    /// The section cache used by this loader.
+    #[must_use]
+    pub fn cache(&self) -> &Rc<SectionCache> {
+        &self.cache
+    }
+
+    /// Add the executable segments of @elf, relocated by @bias, to @image in @asid.
+    ///
+    /// Use `ElfFile::bias_for_base()` to compute the bias of a position independent file.
+    /// Returns the number of added sections on success.
+    pub fn load(
+        &self,
+        image: &mut Image,
+        elf: &ElfFile,
+        bias: u64,
+        asid: Option<&Asid>,
+    ) -> Result<u32, PtError> {
+        let mut added = 0;
+        for section in elf.sections(bias) {
+            let isid = self.cache.add_file_shared(
+                &section.filename,
+                section.offset,
+                section.size,
+                section.virtual_address,
+            )?;
+            image.add_cached(self.cache.clone(), isid, asid)?;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Open the ELF file @filename and add its executable segments to @image.
+    ///
+    /// See `load()`.
+    pub fn load_file(
+        &self,
+        image: &mut Image,
+        filename: &str,
+        bias: u64,
+        asid: Option<&Asid>,
+    ) -> Result<ElfFile, PtError> {
+        let elf = ElfFile::open(filename)?;
+        self.load(image, &elf, bias, asid)?;
+        Ok(elf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A minimal ELF64 little endian header with a text and a data `PT_LOAD` segment.
+    fn elf64(e_type: u16) -> Vec<u8> {
+        let mut elf = vec![0u8; 64 + 2 * 56];
+        elf[..4].copy_from_slice(&ELF_MAGIC);
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        elf[16..18].copy_from_slice(&e_type.to_le_bytes());
+        elf[32..40].copy_from_slice(&64u64.to_le_bytes());
+        elf[54..56].copy_from_slice(&56u16.to_le_bytes());
+        elf[56..58].copy_from_slice(&2u16.to_le_bytes());
+
+        for (i, (flags, offset, vaddr, size)) in [
+            (5u32, 0x1000u64, 0x1000u64, 0x200u64),
+            (6, 0x2000, 0x3000, 0x100),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let phdr = &mut elf[64 + i * 56..64 + (i + 1) * 56];
+            phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            phdr[4..8].copy_from_slice(&flags.to_le_bytes());
+            phdr[8..16].copy_from_slice(&offset.to_le_bytes());
+            phdr[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            phdr[32..40].copy_from_slice(&size.to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn test_elf_parse() {
+        let elf = ElfFile::parse("a.out", Cursor::new(elf64(2))).unwrap();
+        assert!(!elf.is_pie());
+        assert_eq!(
+            elf.segments(),
+            [ElfSegment {
+                offset: 0x1000,
+                size: 0x200,
+                virtual_address: 0x1000
+            }]
+        );
+        assert_eq!(elf.bias_for_base(0x5555_0000_0000), 0);
+    }
+
+    #[test]
+    fn test_elf_pie() {
+        let elf = ElfFile::parse("a.out", Cursor::new(elf64(ET_DYN))).unwrap();
+        assert!(elf.is_pie());
+        let bias = elf.bias_for_base(0x5555_0000_1000);
+        assert_eq!(bias, 0x5555_0000_0000);
+        assert_eq!(elf.sections(bias)[0].virtual_address, 0x5555_0000_1000);
+    }
+
+    #[test]
+    fn test_elf_extended_numbering() {
+        // The number of program headers is in the sh_info of the first section header.
+        let mut elf = elf64(2);
+        let shoff = elf.len() as u64;
+        elf[40..48].copy_from_slice(&shoff.to_le_bytes());
+        elf[56..58].copy_from_slice(&PN_XNUM.to_le_bytes());
+        elf[58..60].copy_from_slice(&64u16.to_le_bytes());
+        elf[60..62].copy_from_slice(&1u16.to_le_bytes());
+        let mut shdr = [0u8; 64];
+        shdr[44..48].copy_from_slice(&2u32.to_le_bytes());
+        elf.extend_from_slice(&shdr);
+
+        let parsed = ElfFile::parse("a.out", Cursor::new(elf.clone())).unwrap();
+        assert_eq!(parsed.segments().len(), 1);
+
+        // Without section headers.
+        elf[40..48].fill(0);
+        assert_eq!(
+            ElfFile::parse("a.out", Cursor::new(elf))
+                .unwrap_err()
+                .code(),
+            PtErrorCode::BadImage
+        );
+    }
+
+    #[test]
+    fn test_elf_invalid() {
+        assert!(ElfFile::parse("a.out", Cursor::new(vec![0u8; 64])).is_err());
+        assert!(ElfFile::parse("a.out", Cursor::new(elf64(2)[..80].to_vec())).is_err());
+
+        let mut huge = elf64(2);
+        huge[54..56].copy_from_slice(&0xfff0u16.to_le_bytes());
+        huge[56..58].copy_from_slice(&0xfff0u16.to_le_bytes());
+        assert_eq!(
+            ElfFile::parse("a.out", Cursor::new(huge))
+                .unwrap_err()
+                .code(),
+            PtErrorCode::BadImage
+        );
+        assert!(ElfFile::open("/this/file/does/not/exist").is_err());
+    }
+}
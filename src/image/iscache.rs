@@ -1,16 +1,20 @@
 use crate::error::{PtError, PtErrorCode, ensure_ptok, extract_pterr};
-use crate::image::{name_ptr_to_option_string, str_to_cstring_pterror};
+use crate::image::{SectionInfo, name_ptr_to_option_string, str_to_cstring_pterror};
 use libipt_sys::{
     pt_image_section_cache, pt_iscache_add_file, pt_iscache_alloc, pt_iscache_free,
     pt_iscache_name, pt_iscache_read, pt_iscache_set_limit,
 };
+use std::collections::HashMap;
 use std::ptr;
 use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, PoisonError};
 
 /// A cache of traced image sections.
 #[derive(Debug)]
 pub struct SectionCache {
     pub(crate) inner: NonNull<pt_image_section_cache>,
+    // The file section of each isid, libipt doesn't expose them.
+    sections: Mutex<HashMap<u32, SectionInfo>>,
 }
 impl SectionCache {
     /// Allocate a traced memory image section cache.
@@ -32,7 +36,10 @@ impl SectionCache {
             PtErrorCode::Internal,
             "SectionCache allocation failed",
         ))?;
-        Ok(Self { inner })
+        Ok(Self {
+            inner,
+            sections: Mutex::default(),
+        })
     }
 
     /// Get the image section cache name.
@@ -55,12 +62,49 @@ impl SectionCache {
         offset: u64,
         size: u64,
         vaddr: u64,
+    ) -> Result<u32, PtError> {
+        self.add_file_shared(filename, offset, size, vaddr)
+    }
+
+    /// Same as `add_file()`, for caches already shared with images.
+    ///
+    /// libipt protects the cache with its own lock.
+    pub(crate) fn add_file_shared(
+        &self,
+        filename: &str,
+        offset: u64,
+        size: u64,
+        vaddr: u64,
     ) -> Result<u32, PtError> {
         let cfilename = str_to_cstring_pterror(filename)?;
 
-        extract_pterr(unsafe {
+        let isid = extract_pterr(unsafe {
             pt_iscache_add_file(self.inner.as_ptr(), cfilename.as_ptr(), offset, size, vaddr)
-        })
+        })?;
+        self.sections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(isid)
+            .or_insert_with(|| SectionInfo {
+                filename: filename.to_owned(),
+                offset,
+                size,
+                virtual_address: vaddr,
+            });
+        Ok(isid)
+    }
+
+    /// Get the file section identified by @isid, as it was added to the cache.
+    ///
+    /// The size is the requested one, libipt truncates sections to the size of their file.
+    /// Returns None if @iscache does not contain @isid.
+    #[must_use]
+    pub fn section(&self, isid: u32) -> Option<SectionInfo> {
+        self.sections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&isid)
+            .cloned()
     }
 
     /// Read memory from a cached file section
@@ -90,6 +134,13 @@ impl SectionCache {
     /// A non-zero limit will keep the least recently used sections mapped until the limit is reached.
     /// A limit of zero disables caching.
     pub fn set_limit(&mut self, limit: u64) -> Result<(), PtError> {
+        self.set_limit_shared(limit)
+    }
+
+    /// Same as `set_limit()`, for caches already shared with images.
+    ///
+    /// libipt protects the cache with its own lock.
+    fn set_limit_shared(&self, limit: u64) -> Result<(), PtError> {
         ensure_ptok(unsafe { pt_iscache_set_limit(self.inner.as_ptr(), limit) }).inspect_err(|e| {
             // pt_iscache_set_limit returns -pte_invalid if @iscache is NULL, since self.inner is
             // NonNull this should never happen.
@@ -102,12 +153,74 @@ impl SectionCache {
     }
 }
 
+// SAFETY: libipt protects the section cache with its own lock, all the `pt_iscache_*` functions
+// can be called concurrently. The name is never modified after allocation, the section infos
+// are behind their own lock.
+unsafe impl Send for SectionCache {}
+unsafe impl Sync for SectionCache {}
+
 impl Drop for SectionCache {
     fn drop(&mut self) {
         unsafe { pt_iscache_free(self.inner.as_ptr()) }
     }
 }
 
+/// A cache of traced image sections that can be shared between threads.
+///
+/// Each thread builds its own `Image` with `Image::add_cached_shared()`, the sections of the
+/// cache are mapped only once for all of them.
+#[derive(Debug, Clone)]
+pub struct SharedSectionCache(pub(crate) Arc<SectionCache>);
+
+impl SharedSectionCache {
+    /// Allocate a traced memory image section cache, see `SectionCache::new()`.
+    pub fn new(name: Option<&str>) -> Result<Self, PtError> {
+        SectionCache::new(name).map(Self::from)
+    }
+
+    /// Get the image section cache name.
+    #[must_use]
+    pub fn name(&self) -> Option<String> {
+        self.0.name()
+    }
+
+    /// Add a new file section to the cache, see `SectionCache::add_file()`.
+    ///
+    /// Returns an image section identifier (isid) uniquely identifying that section in the cache.
+    /// Returns Invalid if @offset is too big.
+    pub fn add_file(
+        &self,
+        filename: &str,
+        offset: u64,
+        size: u64,
+        vaddr: u64,
+    ) -> Result<u32, PtError> {
+        self.0.add_file_shared(filename, offset, size, vaddr)
+    }
+
+    /// Read memory from a cached file section, see `SectionCache::read()`.
+    pub fn read(&self, buffer: &mut [u8], isid: u32, vaddr: u64) -> Result<u32, PtError> {
+        self.0.read(buffer, isid, vaddr)
+    }
+
+    /// Get the file section identified by @isid, see `SectionCache::section()`.
+    #[must_use]
+    pub fn section(&self, isid: u32) -> Option<SectionInfo> {
+        self.0.section(isid)
+    }
+
+    /// Set the image section cache limit, see `SectionCache::set_limit()`.
+    pub fn set_limit(&self, limit: u64) -> Result<(), PtError> {
+        self.0.set_limit_shared(limit)
+    }
+}
+
+impl From<SectionCache> for SharedSectionCache {
+    fn from(cache: SectionCache) -> Self {
+        Self(Arc::new(cache))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -156,6 +269,14 @@ mod test {
 
         let expect = &fs::read(&file).unwrap()[9..29];
         assert_eq!(expect, buf);
+
+        let section = isc.section(isid).unwrap();
+        assert_eq!(section.filename, file.to_str().unwrap());
+        assert_eq!(
+            (section.offset, section.size, section.virtual_address),
+            (5, 24, 0x666)
+        );
+        assert_eq!(isc.section(isid + 1), None);
     }
 
     #[test]
@@ -165,4 +286,33 @@ mod test {
         isc.set_limit(0).unwrap();
         isc.set_limit(std::u64::MAX).unwrap();
     }
+
+    #[test]
+    fn test_isc_shared() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let file = file.to_str().unwrap();
+        let expect = &fs::read(file).unwrap()[5..13];
+
+        let isc = SharedSectionCache::new(Some("shared")).unwrap();
+        isc.set_limit(1 << 20).unwrap();
+        let isids: Vec<u32> = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let isid = isc.add_file(file, 5, 8, 0x1000).unwrap();
+                        let mut buf = [0; 8];
+                        isc.read(&mut buf, isid, 0x1000).unwrap();
+                        assert_eq!(buf, expect);
+                        isid
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        // The same section is added only once.
+        assert!(isids.iter().all(|isid| *isid == isids[0]));
+        assert_eq!(isc.name().unwrap(), "shared");
+    }
 }
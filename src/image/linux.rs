@@ -0,0 +1,249 @@
+use crate::asid::Asid;
+use crate::error::{PtError, PtErrorCode};
+use crate::image::{Image, SectionCache};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::rc::Rc;
+
+/// A file mapping of a process, from a `/proc/<pid>/maps` line or a `PERF_RECORD_MMAP2` record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmapRecord {
+    /// Path of the mapped file
+    pub filename: String,
+    /// Offset of the mapping in the file
+    pub pgoff: u64,
+    /// Size of the mapping
+    pub len: u64,
+    /// Start virtual address of the mapping
+    pub addr: u64,
+    /// Process the mapping belongs to
+    pub pid: u32,
+}
+
+impl MmapRecord {
+    /// Whether the record maps a regular file that can be added to an `Image`.
+    ///
+    /// Anonymous and special mappings (`[vdso]`, `//anon`, `[kernel.kallsyms]`, ...) are not.
+    #[must_use]
+    pub fn is_file(&self) -> bool {
+        self.filename.starts_with('/')
+            && !self.filename.starts_with("//")
+            && !self.filename.ends_with(" (deleted)")
+    }
+}
+
+fn invalid_maps() -> PtError {
+    PtError::new(PtErrorCode::Invalid, "invalid /proc/<pid>/maps line")
+}
+
+/// Parse the content of `/proc/@pid/maps`.
+///
+/// Only executable file mappings are returned, the ones that can be traced.
+/// Returns Invalid if @maps contains a malformed line.
+pub fn parse_proc_maps(maps: &str, pid: u32) -> Result<Vec<MmapRecord>, PtError> {
+    let mut records = Vec::new();
+    for line in maps.lines().filter(|l| !l.trim().is_empty()) {
+        // address perms offset dev inode [pathname], the pathname may contain spaces.
+        let mut rest = line;
+        let mut fields = [""; 5];
+        for field in &mut fields {
+            let (f, r) = rest
+                .trim_start()
+                .split_once(' ')
+                .unwrap_or((rest.trim_start(), ""));
+            *field = f;
+            rest = r;
+        }
+        let [range, perms, offset, _, _] = fields;
+        let filename = rest.trim();
+
+        let (start, end) = range.split_once('-').ok_or_else(invalid_maps)?;
+        let start = u64::from_str_radix(start, 16).map_err(|_| invalid_maps())?;
+        let end = u64::from_str_radix(end, 16).map_err(|_| invalid_maps())?;
+        let pgoff = u64::from_str_radix(offset, 16).map_err(|_| invalid_maps())?;
+        if end < start || perms.len() != 4 {
+            return Err(invalid_maps());
+        }
+
+        let record = MmapRecord {
+            filename: filename.to_owned(),
+            pgoff,
+            len: end - start,
+            addr: start,
+            pid,
+        };
+        if perms.as_bytes()[2] == b'x' && record.is_file() {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// Read and parse `/proc/@pid/maps`, see `parse_proc_maps()`.
+///
+/// Returns BadFile if the file can't be read.
+#[cfg(target_os = "linux")]
+pub fn read_proc_maps(pid: u32) -> Result<Vec<MmapRecord>, PtError> {
+    let maps = std::fs::read_to_string(format!("/proc/{pid}/maps"))
+        .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to read /proc/<pid>/maps"))?;
+    parse_proc_maps(&maps, pid)
+}
+
+/// Builds `Image`s from process file mappings, sharing their sections through a `SectionCache`.
+#[derive(Debug, Clone)]
+pub struct MmapLoader {
+    cache: Rc<SectionCache>,
+}
+
+impl MmapLoader {
+    /// Create a loader with a new, unnamed, section cache.
+    pub fn new() -> Result<Self, PtError> {
+        Ok(Self::with_cache(Rc::new(SectionCache::new(None)?)))
+    }
+
+    /// Create a loader adding sections to @cache.
+    #[must_use]
+    pub fn with_cache(cache: Rc<SectionCache>) -> Self {
+        Self { cache }
+    }
+
+    /// The section cache used by this loader.
+    #[must_use]
+    pub fn cache(&self) -> &Rc<SectionCache> {
+        &self.cache
+    }
+
+    /// Add the mapping @record to @image in the address space @asid.
+    ///
+    /// Records that don't map a regular file are ignored, see `MmapRecord::is_file()`.
+    /// Existing sections that would overlap with the new section will be shrunk or split.
+    /// Returns whether the record has been added.
+    pub fn add(
+        &self,
+        image: &mut Image,
+        record: &MmapRecord,
+        asid: Option<&Asid>,
+    ) -> Result<bool, PtError> {
+        if !record.is_file() || record.len == 0 {
+            return Ok(false);
+        }
+
+        let isid =
+            self.cache
+                .add_file_shared(&record.filename, record.pgoff, record.len, record.addr)?;
+        image.add_cached(self.cache.clone(), isid, asid)?;
+        Ok(true)
+    }
+
+    /// Build one image per process.
+    ///
+    /// Records are applied in order, later mappings replace earlier overlapping ones.
+    pub fn per_process<'r>(
+        &self,
+        records: impl IntoIterator<Item = &'r MmapRecord>,
+    ) -> Result<HashMap<u32, Image>, PtError> {
+        let mut images = HashMap::new();
+        for record in records {
+            let image = match images.entry(record.pid) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(Image::new(None)?),
+            };
+            self.add(image, record, None)?;
+        }
+        Ok(images)
+    }
+
+    /// Build a single image, with the mappings of each process in its own address space.
+    ///
+    /// @asid_of gives the address space of a pid, e.g. from its CR3 found in the sideband.
+    /// Records of processes without an address space are added to all address spaces.
+    pub fn by_asid<'r, F>(
+        &self,
+        records: impl IntoIterator<Item = &'r MmapRecord>,
+        mut asid_of: F,
+    ) -> Result<Image, PtError>
+    where
+        F: FnMut(u32) -> Option<Asid>,
+    {
+        let mut image = Image::new(None)?;
+        for record in records {
+            let asid = asid_of(record.pid);
+            self.add(&mut image, record, asid.as_ref())?;
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    const MAPS: &str = "\
+55d0c0a00000-55d0c0a02000 r--p 00000000 08:01 1234                       /usr/bin/cat
+55d0c0a02000-55d0c0a06000 r-xp 00002000 08:01 1234                       /usr/bin/cat
+55d0c1000000-55d0c1021000 rw-p 00000000 00:00 0                          [heap]
+7f1e2c000000-7f1e2c010000 r-xp 00001000 08:01 42                         /opt/my app/lib.so
+7f1e2d000000-7f1e2d010000 r-xp 00000000 08:01 43                         /tmp/gone.so (deleted)
+7ffd5b7f0000-7ffd5b7f2000 r-xp 00000000 00:00 0                          [vdso]
+";
+
+    #[test]
+    fn test_parse_proc_maps() {
+        let records = parse_proc_maps(MAPS, 42).unwrap();
+        assert_eq!(
+            records,
+            [
+                MmapRecord {
+                    filename: "/usr/bin/cat".to_owned(),
+                    pgoff: 0x2000,
+                    len: 0x4000,
+                    addr: 0x55d0_c0a0_2000,
+                    pid: 42,
+                },
+                MmapRecord {
+                    filename: "/opt/my app/lib.so".to_owned(),
+                    pgoff: 0x1000,
+                    len: 0x10000,
+                    addr: 0x7f1e_2c00_0000,
+                    pid: 42,
+                },
+            ]
+        );
+
+        assert!(parse_proc_maps("nope r-xp 0 0 0 /bin/ls", 42).is_err());
+    }
+
+    #[test]
+    fn test_mmap_loader() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let record = |pid, addr| MmapRecord {
+            filename: file.to_str().unwrap().to_owned(),
+            pgoff: 0,
+            len: 8,
+            addr,
+            pid,
+        };
+        let vdso = MmapRecord {
+            filename: "[vdso]".to_owned(),
+            ..record(1, 0x3000)
+        };
+        let records = [
+            record(1, 0x1000),
+            record(2, 0x1000),
+            record(2, 0x2000),
+            vdso,
+        ];
+
+        let loader = MmapLoader::new().unwrap();
+        let images = loader.per_process(&records).unwrap();
+        assert_eq!(images.len(), 2);
+
+        let mut image = loader
+            .by_asid(&records, |pid| Some(Asid::new(Some(u64::from(pid)), None)))
+            .unwrap();
+        assert_eq!(image.remove_by_asid(&Asid::new(Some(2), None)).unwrap(), 2);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::asid::Asid;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Check whether two address spaces match, missing fields match any value.
+///
+/// This is the Rust equivalent of libipt's `pt_asid_match`.
+pub(super) fn asid_match(lhs: &Asid, rhs: &Asid) -> bool {
+    let cr3 = match (lhs.cr3(), rhs.cr3()) {
+        (Some(l), Some(r)) => l == r,
+        _ => true,
+    };
+    let vmcs = match (lhs.vmcs(), rhs.vmcs()) {
+        (Some(l), Some(r)) => l == r,
+        _ => true,
+    };
+    cr3 && vmcs
+}
+
+/// A slice of an in-memory section.
+#[derive(Debug, Clone)]
+struct Chunk {
+    data: Arc<[u8]>,
+    offset: usize,
+    size: u64,
+}
+
+impl Chunk {
+    fn bytes(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.size as usize]
+    }
+}
+
+/// The in-memory sections of an `Image`, added with `Image::add_bytes()`.
+///
+/// Sections are kept per address space in an interval map keyed by their start address.
+#[derive(Debug, Default)]
+pub(super) struct MemorySections {
+    spaces: Vec<(Asid, BTreeMap<u64, Chunk>)>,
+}
+
+impl MemorySections {
+    pub(super) fn is_empty(&self) -> bool {
+        self.spaces.iter().all(|(_, chunks)| chunks.is_empty())
+    }
+
+    /// Add @size bytes of @data from @offset at @vaddr in @asid.
+    ///
+    /// Existing sections that would overlap with the new section are shrunk or split, as
+    /// `pt_image_add_file()` does.
+    pub(super) fn add(
+        &mut self,
+        data: Arc<[u8]>,
+        offset: usize,
+        size: u64,
+        vaddr: u64,
+        asid: Asid,
+    ) {
+        let end = vaddr + size;
+        for (_, chunks) in self.spaces.iter_mut().filter(|(a, _)| asid_match(a, &asid)) {
+            Self::punch_hole(chunks, vaddr, end);
+        }
+
+        let chunk = Chunk { data, offset, size };
+        match self.spaces.iter_mut().find(|(a, _)| *a == asid) {
+            Some((_, chunks)) => {
+                chunks.insert(vaddr, chunk);
+            }
+            None => self.spaces.push((asid, BTreeMap::from([(vaddr, chunk)]))),
+        }
+    }
+
+    /// Remove the range [@begin, @end[ from @chunks, shrinking or splitting overlapping chunks.
+    fn punch_hole(chunks: &mut BTreeMap<u64, Chunk>, begin: u64, end: u64) {
+        let overlapping: Vec<u64> = chunks
+            .range(..begin)
+            .next_back()
+            .filter(|(start, chunk)| **start + chunk.size > begin)
+            .map(|(start, _)| *start)
+            .into_iter()
+            .chain(chunks.range(begin..end).map(|(start, _)| *start))
+            .collect();
+
+        for start in overlapping {
+            let chunk = chunks.remove(&start).unwrap();
+            let chunk_end = start + chunk.size;
+            if start < begin {
+                let mut left = chunk.clone();
+                left.size = begin - start;
+                chunks.insert(start, left);
+            }
+            if chunk_end > end {
+                let mut right = chunk;
+                right.offset += (end - start) as usize;
+                right.size = chunk_end - end;
+                chunks.insert(end, right);
+            }
+        }
+    }
+
+    /// Remove all sections in address spaces matching @asid.
+    ///
+    /// Returns the number of removed sections.
+    pub(super) fn remove_by_asid(&mut self, asid: &Asid) -> u32 {
+        let mut removed = 0;
+        self.spaces.retain(|(a, chunks)| {
+            let matching = asid_match(a, asid);
+            if matching {
+                removed += chunks.len() as u32;
+            }
+            !matching
+        });
+        removed
+    }
+
+    /// Add all sections of @src.
+    pub(super) fn extend(&mut self, src: &MemorySections) {
+        for (asid, chunks) in &src.spaces {
+            for (vaddr, chunk) in chunks {
+                self.add(chunk.data.clone(), chunk.offset, chunk.size, *vaddr, *asid);
+            }
+        }
+    }
+
+    /// Read memory at @ip in @asid into @buf.
+    ///
+    /// Returns the number of bytes read, `None` if there is no section at @ip.
+    pub(super) fn read(&self, buf: &mut [u8], ip: u64, asid: &Asid) -> Option<usize> {
+        self.spaces
+            .iter()
+            .filter(|(a, _)| asid_match(a, asid))
+            .find_map(|(_, chunks)| {
+                let (start, chunk) = chunks.range(..=ip).next_back()?;
+                let bytes = chunk.bytes().get((ip - start) as usize..)?;
+                (!bytes.is_empty()).then_some(bytes)
+            })
+            .map(|bytes| {
+                let size = buf.len().min(bytes.len());
+                buf[..size].copy_from_slice(&bytes[..size]);
+                size
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_overlap() {
+        let mut mem = MemorySections::default();
+        let asid = Asid::default();
+        mem.add(Arc::from([1u8; 16]), 0, 16, 0x1000, asid);
+        mem.add(Arc::from([2u8; 4]), 0, 4, 0x1004, asid);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(mem.read(&mut buf, 0x1000, &asid), Some(4));
+        assert_eq!(buf[..4], [1; 4]);
+        assert_eq!(mem.read(&mut buf, 0x1004, &asid), Some(4));
+        assert_eq!(buf[..4], [2; 4]);
+        assert_eq!(mem.read(&mut buf, 0x1008, &asid), Some(8));
+        assert_eq!(buf[..8], [1; 8]);
+        assert_eq!(mem.read(&mut buf, 0x1010, &asid), None);
+        assert_eq!(mem.read(&mut buf, 0xfff, &asid), None);
+    }
+
+    #[test]
+    fn test_memory_asid() {
+        let mut mem = MemorySections::default();
+        let asid1 = Asid::new(Some(1), None);
+        let asid2 = Asid::new(Some(2), None);
+        mem.add(Arc::from([1u8; 4]), 0, 4, 0x1000, asid1);
+        mem.add(Arc::from([2u8; 4]), 0, 4, 0x1000, asid2);
+
+        let mut buf = [0u8; 4];
+        assert_eq!(mem.read(&mut buf, 0x1000, &asid2), Some(4));
+        assert_eq!(buf, [2; 4]);
+        assert_eq!(mem.remove_by_asid(&asid2), 1);
+        assert_eq!(mem.read(&mut buf, 0x1000, &asid2), None);
+        assert_eq!(mem.read(&mut buf, 0x1000, &asid1), Some(4));
+        assert!(!mem.is_empty());
+    }
+}
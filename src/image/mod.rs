@@ -5,16 +5,28 @@ use libipt_sys::{
     pt_image_free, pt_image_name, pt_image_remove_by_asid, pt_image_remove_by_filename,
     pt_image_set_callback,
 };
+use std::cell::RefCell;
 use std::collections::HashSet;
 use std::ffi::c_void;
 use std::ffi::{CStr, CString};
+use std::ops::Deref;
 use std::os::raw::c_char;
 use std::ptr;
 use std::ptr::NonNull;
 use std::rc::Rc;
+use std::sync::Arc;
 
 mod iscache;
 pub use iscache::*;
+mod memory;
+use memory::{MemorySections, asid_match};
+
+/// Building images from Linux process mappings (`/proc/<pid>/maps`, perf `MMAP2` records).
+pub mod linux;
+
+/// Loading of ELF executables and shared libraries into an `Image`.
+#[cfg(feature = "elf")]
+pub mod elf;
 
 unsafe extern "C" fn read_callback(
     buffer: *mut u8,
@@ -122,9 +134,15 @@ pub struct Image {
     pub(crate) inner: NonNull<pt_image>,
     // do we need to free this instance on drop? in other words, is inner owned?
     inner_is_owned: bool,
-    // Any read data callback set by this `Image` instance.
+    // Any read data callback set by this `Image` instance, serving `memory` first.
     callback: Option<BoxedCallback>,
-    caches: Vec<Rc<SectionCache>>,
+    // Sections added with `add_bytes`, shared with `callback`.
+    memory: Rc<RefCell<MemorySections>>,
+    // The file sections, with the cache to read them back, in the order they were added.
+    // Entries are unique and dropped with the sections of their asid or file.
+    sections: Vec<FileSection>,
+    // The cache holding the sections added with `add_file()`.
+    file_cache: Option<Rc<SectionCache>>,
     // `HashSet` might grow and move the content around, we cannot use `Asid` directly since we
     // share a pointer with libipt, and it must be valid for the entire Image (section) lifetime.
     asids: HashSet<Rc<Asid>>,
@@ -153,7 +171,9 @@ impl Image {
             inner,
             inner_is_owned: true,
             callback: None,
-            caches: Vec::new(),
+            memory: Rc::default(),
+            sections: Vec::new(),
+            file_cache: None,
             asids: HashSet::new(),
         })
     }
@@ -175,7 +195,9 @@ impl Image {
             inner,
             inner_is_owned: false,
             callback: None,
-            caches: Vec::new(),
+            memory: Rc::default(),
+            sections: Vec::new(),
+            file_cache: None,
             asids: HashSet::new(),
         })
     }
@@ -197,7 +219,9 @@ impl Image {
             pt_image_remove_by_asid(self.inner.as_ptr(), &raw const asid.0)
         })?;
         self.asids.remove(asid);
-        Ok(res)
+        self.sections
+            .retain(|section| !asid_match(&section.asid, asid));
+        Ok(res + self.memory.borrow_mut().remove_by_asid(asid))
     }
 
     /// Remove all sections loaded from a file.
@@ -208,9 +232,14 @@ impl Image {
     pub fn remove_by_filename(&mut self, filename: &str, asid: Asid) -> Result<u32, PtError> {
         let cfilename = str_to_cstring_pterror(filename)?;
 
-        extract_pterr(unsafe {
+        let res = extract_pterr(unsafe {
             pt_image_remove_by_filename(self.inner.as_ptr(), cfilename.as_ptr(), &asid.0)
-        })
+        })?;
+        self.sections.retain(|section| {
+            !asid_match(&section.asid, &asid)
+                || section.info().is_none_or(|info| info.filename != filename)
+        });
+        Ok(res)
     }
 
     /// Set the memory callback for the traced memory image.
@@ -219,11 +248,32 @@ impl Image {
     /// There can only be one callback at any time.
     /// A subsequent call will replace the previous callback.
     /// If @callback is None, the callback is removed.
+    ///
+    /// Sections added with `add_bytes()` take precedence over @callback.
     pub fn set_callback<F>(&mut self, callback: Option<F>)
     where
         F: FnMut(&mut [u8], u64, Asid) -> i32,
     {
-        self.callback = callback.map(BoxedCallback::box_callback);
+        self.install_callback(callback.map(BoxedCallback::box_callback));
+    }
+
+    /// Set the libipt read memory callback, serving the in-memory sections and then @user.
+    fn install_callback(&mut self, user: Option<BoxedCallback>) {
+        self.callback = if user.is_none() && self.memory.borrow().is_empty() {
+            None
+        } else {
+            let memory = self.memory.clone();
+            Some(BoxedCallback::box_callback(move |buf, ip, asid| {
+                if let Some(size) = memory.borrow().read(buf, ip, &asid) {
+                    return size as i32;
+                }
+                match &user {
+                    Some(cb) => unsafe { BoxedCallback::call(cb.0, buf, ip, asid) },
+                    None => -(PtErrorCode::Nomap as i32),
+                }
+            }))
+        };
+
         let ret = unsafe {
             match &self.callback {
                 None => pt_image_set_callback(self.inner.as_ptr(), None, ptr::null_mut()),
@@ -251,7 +301,15 @@ impl Image {
                 );
             })?;
 
-        self.caches.extend_from_slice(&src.caches);
+        for section in &src.sections {
+            track(&mut self.sections, section.clone());
+        }
+        if !src.memory.borrow().is_empty() {
+            self.memory.borrow_mut().extend(&src.memory.borrow());
+            if self.callback.is_none() {
+                self.install_callback(None);
+            }
+        }
         for asid in &src.asids {
             self.asids.insert(asid.clone());
         }
@@ -270,6 +328,40 @@ impl Image {
         iscache: Rc<SectionCache>,
         isid: u32,
         asid: Option<&Asid>,
+    ) -> Result<(), PtError> {
+        self.add_cached_raw(&iscache, isid, asid)?;
+        track(
+            &mut self.sections,
+            FileSection::new(CacheRef::Local(iscache), isid, isid, asid),
+        );
+        Ok(())
+    }
+
+    /// Add a section from an image section cache shared between threads.
+    ///
+    /// Same as `add_cached()`, for a `SharedSectionCache`: images on different threads can
+    /// reference the same cache.
+    /// Returns `BadImage` if @iscache does not contain @isid.
+    pub fn add_cached_shared(
+        &mut self,
+        iscache: &SharedSectionCache,
+        isid: u32,
+        asid: Option<&Asid>,
+    ) -> Result<(), PtError> {
+        self.add_cached_raw(&iscache.0, isid, asid)?;
+        track(
+            &mut self.sections,
+            FileSection::new(CacheRef::Shared(iscache.0.clone()), isid, isid, asid),
+        );
+        Ok(())
+    }
+
+    /// Add the section @isid of @iscache, the caller must keep @iscache alive.
+    fn add_cached_raw(
+        &mut self,
+        iscache: &SectionCache,
+        isid: u32,
+        asid: Option<&Asid>,
     ) -> Result<(), PtError> {
         let asid_ptr = if let Some(a) = asid {
             // fixme: use get_or_insert once stable (if ever)
@@ -294,9 +386,7 @@ impl Image {
                 PtErrorCode::Invalid,
                 "pt_image_add_cached returned -pte_invalid"
             );
-        })?;
-        self.caches.push(iscache);
-        Ok(())
+        })
     }
 
     /// Add a new file section to the traced memory image.
@@ -308,6 +398,8 @@ impl Image {
     /// Use this when tracing a single process or when adding sections to all processes.
     /// The section is silently truncated to match the size of @filename.
     /// Existing sections that would overlap with the new section will be shrunk or split.
+    /// The section is also added to a cache owned by the image, to read it back with `read()`
+    /// and `read_at()`.
     /// Returns Invalid if @offset is too big.
     /// Returns Invalid if @filename contains null bytes
     pub fn add_file(
@@ -319,6 +411,15 @@ impl Image {
         vaddr: u64,
     ) -> Result<(), PtError> {
         let cfilename = str_to_cstring_pterror(filename)?;
+        let cache = match &self.file_cache {
+            Some(cache) => cache.clone(),
+            None => self
+                .file_cache
+                .insert(Rc::new(SectionCache::new(None)?))
+                .clone(),
+        };
+        let cache_isid = cache.add_file_shared(filename, offset, size, vaddr)?;
+
         let asid_ptr = if let Some(a) = asid {
             // fixme: use get_or_insert once stable (if ever)
             self.asids.insert(Rc::new(*a));
@@ -336,6 +437,45 @@ impl Image {
                 vaddr,
             )
         })?;
+        // libipt identifies the sections of `pt_image_add_file()` with a zero isid.
+        track(
+            &mut self.sections,
+            FileSection::new(CacheRef::Local(cache), cache_isid, 0, asid),
+        );
+        Ok(())
+    }
+
+    /// Add an in-memory section to the traced memory image.
+    ///
+    /// The bytes of @data are loaded at the virtual address @vaddr in the address space @asid.
+    /// The @asid may be None or (partially) invalid, as in `add_file()`.
+    /// Existing in-memory sections that would overlap with the new section will be shrunk or split.
+    ///
+    /// In-memory sections are served through the read memory callback, which libipt queries only
+    /// for addresses that are not covered by file sections: unlike `add_file()`, the new section
+    /// doesn't shrink or split the file sections it overlaps, they take precedence whatever the
+    /// order the sections were added in. Remove them first to override them.
+    /// Returns Invalid if @data is empty or if the section wraps around the address space.
+    pub fn add_bytes(
+        &mut self,
+        data: Arc<[u8]>,
+        vaddr: u64,
+        asid: Option<&Asid>,
+    ) -> Result<(), PtError> {
+        let size = data.len() as u64;
+        if size == 0 || vaddr.checked_add(size).is_none() {
+            return Err(PtError::new(
+                PtErrorCode::Invalid,
+                "invalid in-memory section: empty or wrapping around the address space",
+            ));
+        }
+
+        self.memory
+            .borrow_mut()
+            .add(data, 0, size, vaddr, asid.copied().unwrap_or_default());
+        if self.callback.is_none() {
+            self.install_callback(None);
+        }
         Ok(())
     }
 
@@ -363,6 +503,93 @@ impl Image {
 
         Ok(())
     }
+
+    /// The sections added through a `SectionCache`, with their isid and address space.
+    ///
+    /// The sections are listed in the order they were last added. Sections shrunk or split by
+    /// overlapping sections are listed as they were added.
+    /// Sections added with `add_file()` or `add_bytes()` are not listed.
+    pub fn cached_sections(&self) -> impl Iterator<Item = (u32, SectionInfo, Asid)> + '_ {
+        self.sections
+            .iter()
+            .filter(|section| section.image_isid != 0)
+            .filter_map(|section| Some((section.isid, section.info()?, section.asid)))
+    }
+
+    /// Read memory of the traced image at @vaddr into @buffer.
+    ///
+    /// Sections added through a `SectionCache` are identified by their @isid, as found in
+    /// `Insn::isid()` and `Block::isid()`. As in libipt, the sections added with `add_file()`
+    /// and `add_bytes()` have an @isid of zero, the file sections are read first.
+    /// Only the sections whose address space matches @asid are read, a None @asid matches all
+    /// of them.
+    /// Isids are only unique within a `SectionCache`: if the sections of several caches share
+    /// @isid at @vaddr, the most recently added is read.
+    /// Returns the number of bytes read on success, it may be less than the size of @buffer at
+    /// the end of the section.
+    /// Returns `Nomap` if there is no readable section at @vaddr.
+    pub fn read(
+        &self,
+        buffer: &mut [u8],
+        isid: u32,
+        vaddr: u64,
+        asid: Option<&Asid>,
+    ) -> Result<usize, PtError> {
+        let asid = asid.copied().unwrap_or_default();
+        let mut read = self.read_sections(buffer, vaddr, |section| {
+            section.image_isid == isid && asid_match(&section.asid, &asid)
+        });
+        if isid == 0 {
+            read = read.or_else(|| self.read_memory(buffer, vaddr, Some(&asid)));
+        }
+        read.ok_or(PtError::new(
+            PtErrorCode::Nomap,
+            "No section at the requested address",
+        ))
+    }
+
+    /// Read memory of the traced image at @vaddr in the address space @asid into @buffer,
+    /// whatever its section.
+    ///
+    /// As in libipt, the file sections are read first, the most recently added first, then the
+    /// in-memory sections. Only the sections whose address space matches @asid are read, a None
+    /// @asid matches all of them.
+    /// Returns the number of bytes read on success.
+    /// Returns `Nomap` if there is no readable section at @vaddr.
+    pub fn read_at(
+        &self,
+        buffer: &mut [u8],
+        vaddr: u64,
+        asid: Option<&Asid>,
+    ) -> Result<usize, PtError> {
+        let asid = asid.copied().unwrap_or_default();
+        self.read_sections(buffer, vaddr, |section| asid_match(&section.asid, &asid))
+            .or_else(|| self.read_memory(buffer, vaddr, Some(&asid)))
+            .ok_or(PtError::new(
+                PtErrorCode::Nomap,
+                "No section at the requested address",
+            ))
+    }
+
+    /// Read the in-memory sections added with `add_bytes()`.
+    fn read_memory(&self, buffer: &mut [u8], vaddr: u64, asid: Option<&Asid>) -> Option<usize> {
+        self.memory
+            .borrow()
+            .read(buffer, vaddr, &asid.copied().unwrap_or_default())
+    }
+
+    /// Read the file sections for which @filter returns true, the most recently added first.
+    fn read_sections<F>(&self, buffer: &mut [u8], vaddr: u64, filter: F) -> Option<usize>
+    where
+        F: Fn(&FileSection) -> bool,
+    {
+        self.sections
+            .iter()
+            .rev()
+            .filter(|section| filter(section))
+            .find_map(|section| section.cache.read(buffer, section.isid, vaddr).ok())
+            .map(|read| read as usize)
+    }
 }
 
 impl Drop for Image {
@@ -373,6 +600,64 @@ impl Drop for Image {
     }
 }
 
+/// The cache holding a file section of an `Image`.
+#[derive(Debug, Clone)]
+enum CacheRef {
+    Local(Rc<SectionCache>),
+    Shared(Arc<SectionCache>),
+}
+
+impl Deref for CacheRef {
+    type Target = SectionCache;
+
+    fn deref(&self) -> &SectionCache {
+        match self {
+            Self::Local(cache) => cache,
+            Self::Shared(cache) => cache,
+        }
+    }
+}
+
+/// A file section of an `Image`, read back through the cache holding it.
+#[derive(Debug, Clone)]
+struct FileSection {
+    cache: CacheRef,
+    /// The identifier of the section in `cache`.
+    isid: u32,
+    /// The identifier of the section reported by the decoders, zero for `add_file()` sections.
+    image_isid: u32,
+    asid: Asid,
+}
+
+impl FileSection {
+    fn new(cache: CacheRef, isid: u32, image_isid: u32, asid: Option<&Asid>) -> Self {
+        Self {
+            cache,
+            isid,
+            image_isid,
+            asid: asid.copied().unwrap_or_default(),
+        }
+    }
+
+    /// The file range of the section, as it was added to its cache.
+    fn info(&self) -> Option<SectionInfo> {
+        self.cache.section(self.isid)
+    }
+
+    /// Whether @other is the same section of the same cache, in the same address space.
+    fn same(&self, other: &FileSection) -> bool {
+        ptr::eq(&*self.cache, &*other.cache) && self.isid == other.isid && self.asid == other.asid
+    }
+}
+
+/// Keep a reference to the cache of @section, as the most recently added section.
+///
+/// A section added again moves to the end of @sections, as libipt reads it first.
+fn track(sections: &mut Vec<FileSection>, section: FileSection) {
+    sections.retain(|known| !known.same(&section));
+    sections.push(section);
+}
+
 /// Helper function for `pt_image`/`pt_iscache` names
 fn name_ptr_to_option_string(name_ptr: *const c_char) -> Option<String> {
     if name_ptr.is_null() {
@@ -509,6 +794,170 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_img_add_bytes() {
+        let mut i = Image::new(None).unwrap();
+        let asid = Asid::new(Some(1), None);
+        assert!(i.add_bytes(Arc::from([]), 0x1000, None).is_err());
+        assert!(i.add_bytes(Arc::from([0x90; 2]), u64::MAX, None).is_err());
+
+        i.add_bytes(Arc::from([0x90; 16]), 0x1000, Some(&asid))
+            .unwrap();
+        // Splits the previous section in two.
+        i.add_bytes(Arc::from([0xc3; 4]), 0x1004, Some(&asid))
+            .unwrap();
+        assert_eq!(i.remove_by_asid(&asid).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_img_add_bytes_precedence() {
+        use crate::enc_dec_builder::PtEncoderDecoder;
+        use crate::insn::InsnDecoder;
+        use crate::synth::{Step, Synthesizer};
+
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let mut i = Image::new(None).unwrap();
+        i.add_file(file.to_str().unwrap(), 0, 16, None, 0x1000)
+            .unwrap();
+        // Added after the file section, but doesn't override it.
+        i.add_bytes(Arc::from([0x90; 32]), 0x1000, None).unwrap();
+
+        let trace = Synthesizer::new()
+            .steps([Step::Enable(0x1000), Step::Branch(true)])
+            .synthesize()
+            .unwrap();
+        let mut dec = InsnDecoder::builder().buffer(&trace).build().unwrap();
+        dec.set_image(Some(&mut i)).unwrap();
+        let mut status = dec.sync_forward().unwrap();
+        while status.event_pending() {
+            status = dec.event().unwrap().1;
+        }
+        // "Lo", `rex.WR outs`, read from the file.
+        let (insn, _) = dec.decode_next().unwrap();
+        assert_eq!(insn.ip(), 0x1000);
+        assert_eq!(insn.raw(), b"Lo");
+    }
+
+    #[test]
+    fn test_img_read() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let mut expected = [0u8; 4];
+        expected.copy_from_slice(&std::fs::read(&file).unwrap()[5..9]);
+
+        let mut c = SectionCache::new(None).unwrap();
+        let isid = c.add_file(file.to_str().unwrap(), 5, 15, 0x1337).unwrap();
+        let mut i = Image::new(None).unwrap();
+        i.add_cached(Rc::new(c), isid, None).unwrap();
+        i.add_bytes(Arc::from([0x90, 0xc3]), 0x1000, None).unwrap();
+
+        let mut buf = [0u8; 4];
+        assert_eq!(i.read(&mut buf, isid, 0x1337, None).unwrap(), 4);
+        assert_eq!(buf, expected);
+        assert_eq!(i.read(&mut buf, 0, 0x1001, None).unwrap(), 1);
+        assert_eq!(buf[0], 0xc3);
+        assert_eq!(
+            i.read(&mut buf, 0, 0x1337, None).unwrap_err().code(),
+            PtErrorCode::Nomap
+        );
+        assert!(i.read(&mut buf, isid + 1, 0x1337, None).is_err());
+
+        assert_eq!(i.read_at(&mut buf, 0x1337, None).unwrap(), 4);
+        assert_eq!(buf, expected);
+        assert_eq!(i.read_at(&mut buf, 0x1000, None).unwrap(), 2);
+        assert_eq!(buf[..2], [0x90, 0xc3]);
+        assert_eq!(
+            i.read_at(&mut buf, 0x2000, None).unwrap_err().code(),
+            PtErrorCode::Nomap
+        );
+    }
+
+    #[test]
+    fn test_img_read_file_sections() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let content = std::fs::read(&file).unwrap();
+        let filename = file.to_str().unwrap();
+
+        let mut i = Image::new(None).unwrap();
+        let asid = Asid::new(Some(1), None);
+        i.add_file(filename, 5, 15, Some(&asid), 0x2000).unwrap();
+        i.add_bytes(Arc::from([0x90; 4]), 0x1000, None).unwrap();
+        assert_eq!(i.cached_sections().count(), 0);
+
+        // File sections have an isid of zero, as in-memory sections.
+        let mut buf = [0u8; 4];
+        assert_eq!(i.read(&mut buf, 0, 0x2000, None).unwrap(), 4);
+        assert_eq!(buf, content[5..9]);
+        assert_eq!(i.read(&mut buf, 0, 0x1000, Some(&asid)).unwrap(), 4);
+        assert_eq!(buf, [0x90; 4]);
+        assert_eq!(i.read_at(&mut buf, 0x2004, Some(&asid)).unwrap(), 4);
+        assert_eq!(buf, content[9..13]);
+
+        // Only in their address space.
+        let other = Asid::new(Some(2), None);
+        assert!(i.read(&mut buf, 0, 0x2000, Some(&other)).is_err());
+        assert!(i.read_at(&mut buf, 0x2000, Some(&other)).is_err());
+
+        // And until they are removed.
+        let mut copy = Image::new(None).unwrap();
+        copy.extend(&i).unwrap();
+        assert_eq!(i.remove_by_filename(filename, asid).unwrap(), 1);
+        assert!(i.read(&mut buf, 0, 0x2000, None).is_err());
+        assert_eq!(copy.read(&mut buf, 0, 0x2000, None).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_img_read_cached_asid() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let mut c = SectionCache::new(None).unwrap();
+        let isid = c.add_file(file.to_str().unwrap(), 5, 15, 0x1337).unwrap();
+        let mut i = Image::new(None).unwrap();
+        let asid = Asid::new(Some(1), None);
+        i.add_cached(Rc::new(c), isid, Some(&asid)).unwrap();
+
+        let mut buf = [0u8; 4];
+        assert_eq!(i.read(&mut buf, isid, 0x1337, Some(&asid)).unwrap(), 4);
+        assert_eq!(
+            i.read(&mut buf, isid, 0x1337, Some(&Asid::new(Some(2), None)))
+                .unwrap_err()
+                .code(),
+            PtErrorCode::Nomap
+        );
+    }
+
+    #[test]
+    fn test_img_read_at_precedence() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let mut expected = [0u8; 4];
+        expected.copy_from_slice(&std::fs::read(&file).unwrap()[5..9]);
+
+        let mut c = SectionCache::new(None).unwrap();
+        let isid = c.add_file(file.to_str().unwrap(), 5, 15, 0x1337).unwrap();
+        let mut i = Image::new(None).unwrap();
+        let asid = Asid::new(Some(1), None);
+        i.add_cached(Rc::new(c), isid, Some(&asid)).unwrap();
+        i.add_bytes(Arc::from([0x90; 4]), 0x1337, None).unwrap();
+
+        // The cached section takes precedence, as in libipt, but only in its address space.
+        let mut buf = [0u8; 4];
+        assert_eq!(i.read_at(&mut buf, 0x1337, None).unwrap(), 4);
+        assert_eq!(buf, expected);
+        assert_eq!(i.read_at(&mut buf, 0x1337, Some(&asid)).unwrap(), 4);
+        assert_eq!(buf, expected);
+        let other = Asid::new(Some(2), None);
+        assert_eq!(i.read_at(&mut buf, 0x1337, Some(&other)).unwrap(), 4);
+        assert_eq!(buf, [0x90; 4]);
+    }
+
     #[test]
     fn test_img_copy() {
         assert_eq!(img_with_file().extend(&img_with_file()).unwrap(), 0)
@@ -529,6 +978,103 @@ mod test {
         assert_eq!(i.remove_by_asid(&asid).unwrap(), 1);
     }
 
+    #[test]
+    fn test_img_cached_references() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+
+        let mut c = SectionCache::new(None).unwrap();
+        let isid = c.add_file(file.to_str().unwrap(), 5, 15, 0x1337).unwrap();
+        let base_isid = c.add_file(file.to_str().unwrap(), 0, 5, 0x1000).unwrap();
+        let c = Rc::new(c);
+        let asid = Asid::new(Some(3), None);
+        let mut base = Image::new(None).unwrap();
+        base.add_cached(c.clone(), base_isid, None).unwrap();
+
+        // Rebuilding the image over and over doesn't accumulate cache references.
+        let mut i = Image::new(None).unwrap();
+        for _ in 0..8 {
+            i.remove_by_asid(&Asid::default()).unwrap();
+            i.extend(&base).unwrap();
+            i.add_cached(c.clone(), isid, Some(&asid)).unwrap();
+            i.add_cached(c.clone(), isid, Some(&asid)).unwrap();
+            assert_eq!(i.sections.len(), 2);
+        }
+        let sections: Vec<_> = i.cached_sections().collect();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(
+            (sections[0].0, sections[0].1.virtual_address),
+            (base_isid, 0x1000)
+        );
+        assert_eq!(
+            (sections[1].0, sections[1].1.offset, sections[1].2),
+            (isid, 5, asid)
+        );
+        assert_eq!(i.remove_by_asid(&Asid::new(Some(4), None)).unwrap(), 1);
+        assert_eq!(i.sections.len(), 1);
+        drop((i, base));
+        assert_eq!(Rc::strong_count(&c), 1);
+    }
+
+    #[test]
+    fn test_img_add_cached_shared() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+
+        let c = SharedSectionCache::new(None).unwrap();
+        let isid = c.add_file(file.to_str().unwrap(), 5, 15, 0x1337).unwrap();
+        std::thread::scope(|s| {
+            for pid in 0..4 {
+                let c = &c;
+                s.spawn(move || {
+                    let mut i = Image::new(None).unwrap();
+                    let asid = Asid::new(Some(pid), None);
+                    i.add_cached_shared(c, isid, Some(&asid)).unwrap();
+
+                    // Copies keep the cache alive as well.
+                    let mut copy = Image::new(None).unwrap();
+                    copy.extend(&i).unwrap();
+                    drop(i);
+                    assert_eq!(copy.remove_by_asid(&asid).unwrap(), 1);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_img_cached_readd() {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        let content = std::fs::read(&file).unwrap();
+        let filename = file.to_str().unwrap();
+
+        let mut local = SectionCache::new(None).unwrap();
+        let local_isid = local.add_file(filename, 5, 15, 0x1337).unwrap();
+        let local = Rc::new(local);
+        let shared = SharedSectionCache::new(None).unwrap();
+        let shared_isid = shared.add_file(filename, 10, 15, 0x1337).unwrap();
+
+        // The same address in both caches, the local section is added again last.
+        let mut i = Image::new(None).unwrap();
+        i.add_cached(local.clone(), local_isid, None).unwrap();
+        i.add_cached_shared(&shared, shared_isid, None).unwrap();
+        i.add_cached(local, local_isid, None).unwrap();
+
+        let offsets: Vec<u64> = i
+            .cached_sections()
+            .map(|(_, info, _)| info.offset)
+            .collect();
+        assert_eq!(offsets, [10, 5]);
+        let mut buf = [0u8; 4];
+        assert_eq!(i.read_at(&mut buf, 0x1337, None).unwrap(), 4);
+        assert_eq!(buf, content[5..9]);
+        assert_eq!(i.read(&mut buf, local_isid, 0x1337, None).unwrap(), 4);
+        assert_eq!(buf, content[5..9]);
+    }
+
     #[test]
     fn img_extend() {
         let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
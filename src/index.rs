@@ -0,0 +1,481 @@
+use crate::asid::Asid;
+use crate::block::BlockDecoder;
+use crate::enc_dec_builder::EncoderDecoderBuilder;
+use crate::error::{PtError, PtErrorCode};
+use crate::event::ExecModeType;
+use crate::image::Image;
+use crate::insn::InsnDecoder;
+use crate::packet::{Exec, LastIp, Packet, PacketDecoder, Payload};
+use crate::status::Status;
+
+/// The magic number of a serialized `TraceIndex`, followed by the format version.
+const MAGIC: &[u8; 6] = b"PTIDX\0";
+const VERSION: u8 = 1;
+
+const HAS_TSC: u8 = 1 << 0;
+const HAS_IP: u8 = 1 << 1;
+const HAS_CR3: u8 = 1 << 2;
+const HAS_VMCS: u8 = 1 << 3;
+
+/// The decoding state at a PSB, where a decoder can be synchronized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    /// The offset of the PSB in the trace
+    pub offset: u64,
+    /// The last TSC at the end of the PSB+, if any
+    pub tsc: Option<u64>,
+    /// The address space at the end of the PSB+
+    pub asid: Asid,
+    /// The execution mode at the end of the PSB+
+    pub mode: ExecModeType,
+    /// The IP of the FUP in the PSB+, `None` if tracing is disabled
+    pub ip: Option<u64>,
+    /// The number of instructions decoded before this PSB
+    ///
+    /// The decoder reads a few packets ahead: this count is accurate to the few instructions
+    /// around the PSB.
+    pub insns: u64,
+}
+
+/// An index of the synchronization points of a trace, for random access by time or position.
+///
+/// The index records the state at every PSB of the trace: its offset, the time, the address
+/// space, the execution mode and the IP, along with the number of instructions executed before it.
+/// A decoder can then be positioned at the PSB preceding a given time, instruction or offset with
+/// `insn_decoder()` or `block_decoder()`, without decoding the trace from its beginning.
+///
+/// The index can be saved with `save()` (or `to_bytes()`) and loaded back with `load()`.
+/// Use `IntelPtConfig::perf_time_to_tsc()` to look up a perf time stamp.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceIndex {
+    entries: Vec<IndexEntry>,
+    insns: u64,
+}
+
+impl TraceIndex {
+    /// Index @trace, counting its instructions with decoders built from @builder.
+    ///
+    /// The buffer of @builder is ignored. @image is the memory image the trace is decoded with.
+    /// Decoding errors are skipped, the decoder resynchronizes at the next PSB.
+    /// Returns `BadOpc` or `BadPacket` if @trace contains unknown packets.
+    pub fn build(
+        trace: &[u8],
+        builder: &EncoderDecoderBuilder<InsnDecoder<'_>>,
+        image: &Image,
+    ) -> Result<Self, PtError> {
+        let mut index = Self {
+            entries: psb_entries(trace, builder)?,
+            insns: 0,
+        };
+        if index.entries.is_empty() {
+            return Ok(index);
+        }
+
+        // SAFETY: @trace outlives the decoder, which is dropped before returning, and decoders
+        // never write to their buffer.
+        let builder = unsafe {
+            builder
+                .clone()
+                .buffer_from_raw(trace.as_ptr().cast_mut(), trace.len())
+        };
+        let mut decoder = builder.build()?;
+        decoder.image().extend(image)?;
+
+        // The entries already reached by the decoder.
+        let mut reached = 0;
+        let mut status = None;
+        loop {
+            let current = match status {
+                Some(status) => status,
+                None => match decoder.sync_forward() {
+                    Ok(status) => status,
+                    Err(e) if e.code() == PtErrorCode::Eos => break,
+                    Err(e) => return Err(e),
+                },
+            };
+            let result = if current.event_pending() {
+                decoder.event().map(|(_, status)| (status, false))
+            } else if current.eos() {
+                break;
+            } else {
+                decoder.decode_next().map(|(_, status)| (status, true))
+            };
+
+            match result {
+                Ok((next, insn)) => {
+                    status = Some(next);
+                    let offset = decoder.offset()?;
+                    while index
+                        .entries
+                        .get(reached)
+                        .is_some_and(|e| e.offset < offset)
+                    {
+                        index.entries[reached].insns = index.insns;
+                        reached += 1;
+                    }
+                    index.insns += u64::from(insn);
+                }
+                Err(e) if e.code() == PtErrorCode::Eos => break,
+                // Resynchronize at the next PSB.
+                Err(_) => status = None,
+            }
+        }
+
+        for entry in &mut index.entries[reached..] {
+            entry.insns = index.insns;
+        }
+        Ok(index)
+    }
+
+    /// The indexed PSBs, in trace order.
+    #[must_use]
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// The total number of instructions of the trace.
+    #[must_use]
+    pub fn insns(&self) -> u64 {
+        self.insns
+    }
+
+    /// The last PSB at or before the time @tsc.
+    ///
+    /// PSBs without time are considered to be at time 0.
+    /// Returns `None` if @tsc is before the first PSB.
+    #[must_use]
+    pub fn find_tsc(&self, tsc: u64) -> Option<&IndexEntry> {
+        self.find(|entry| entry.tsc.unwrap_or(0) <= tsc)
+    }
+
+    /// The last PSB before the instruction number @insn (counting from 0).
+    #[must_use]
+    pub fn find_insn(&self, insn: u64) -> Option<&IndexEntry> {
+        self.find(|entry| entry.insns <= insn)
+    }
+
+    /// The last PSB at or before the trace offset @offset.
+    #[must_use]
+    pub fn find_offset(&self, offset: u64) -> Option<&IndexEntry> {
+        self.find(|entry| entry.offset <= offset)
+    }
+
+    fn find(&self, pred: impl FnMut(&IndexEntry) -> bool) -> Option<&IndexEntry> {
+        let index = self.entries.partition_point(pred);
+        index.checked_sub(1).map(|i| &self.entries[i])
+    }
+
+    /// Build an instruction flow decoder from @builder synchronized at @entry.
+    ///
+    /// The buffer of @builder must be the indexed trace.
+    /// Returns the decoder along with its status, like `InsnDecoder::sync_forward()`.
+    /// Returns Nosync if there is no PSB at the offset of @entry.
+    pub fn insn_decoder<'a>(
+        &self,
+        entry: &IndexEntry,
+        builder: &EncoderDecoderBuilder<InsnDecoder<'a>>,
+    ) -> Result<(InsnDecoder<'a>, Status), PtError> {
+        let mut decoder = builder.build()?;
+        let status = decoder.sync_set_status(entry.offset)?;
+        Ok((decoder, status))
+    }
+
+    /// Build a block decoder from @builder synchronized at @entry.
+    ///
+    /// The buffer of @builder must be the indexed trace.
+    /// Returns the decoder along with its status, like `BlockDecoder::sync_forward()`.
+    /// Returns Nosync if there is no PSB at the offset of @entry.
+    pub fn block_decoder<'a>(
+        &self,
+        entry: &IndexEntry,
+        builder: &EncoderDecoderBuilder<BlockDecoder<'a>>,
+    ) -> Result<(BlockDecoder<'a>, Status), PtError> {
+        let mut decoder = builder.build()?;
+        let status = decoder.set_sync_status(entry.offset)?;
+        Ok((decoder, status))
+    }
+
+    /// Serialize the index.
+    ///
+    /// Offsets, times and instruction counts are delta-encoded as LEB128 varints.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.entries.len() * 16);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        write_varint(&mut out, self.entries.len() as u64);
+        write_varint(&mut out, self.insns);
+
+        let mut prev = (0, 0, 0);
+        for entry in &self.entries {
+            let flags = [
+                (entry.tsc.is_some(), HAS_TSC),
+                (entry.ip.is_some(), HAS_IP),
+                (entry.asid.cr3().is_some(), HAS_CR3),
+                (entry.asid.vmcs().is_some(), HAS_VMCS),
+            ]
+            .into_iter()
+            .filter(|&(set, _)| set)
+            .fold(0, |flags, (_, flag)| flags | flag);
+            out.push(flags);
+            out.push(entry.mode as u8);
+
+            let tsc = entry.tsc.unwrap_or(prev.1);
+            write_varint(&mut out, entry.offset.wrapping_sub(prev.0));
+            write_varint(&mut out, tsc.wrapping_sub(prev.1));
+            write_varint(&mut out, entry.insns.wrapping_sub(prev.2));
+            prev = (entry.offset, tsc, entry.insns);
+
+            for value in [entry.asid.cr3(), entry.asid.vmcs(), entry.ip]
+                .into_iter()
+                .flatten()
+            {
+                write_varint(&mut out, value);
+            }
+        }
+        out
+    }
+
+    /// Deserialize an index serialized with `to_bytes()`.
+    ///
+    /// Returns Invalid if @data is not a valid index.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, PtError> {
+        let invalid = || PtError::new(PtErrorCode::Invalid, "Invalid trace index");
+        let data = data.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        let (&version, mut data) = data.split_first().ok_or_else(invalid)?;
+        if version != VERSION {
+            return Err(PtError::new(
+                PtErrorCode::Invalid,
+                "Unsupported trace index version",
+            ));
+        }
+
+        let count = read_varint(&mut data).ok_or_else(invalid)?;
+        let insns = read_varint(&mut data).ok_or_else(invalid)?;
+        // Each entry takes at least 5 bytes, don't trust @count for the allocation.
+        let mut entries = Vec::with_capacity((count as usize).min(data.len() / 5));
+        let mut prev = (0u64, 0u64, 0u64);
+        for _ in 0..count {
+            let (&[flags, mode], rest) = data.split_first_chunk().ok_or_else(invalid)?;
+            data = rest;
+            let mode = ExecModeType::try_from(u32::from(mode)).map_err(|_| invalid())?;
+
+            let mut next = || read_varint(&mut data).ok_or_else(invalid);
+            let offset = prev.0.wrapping_add(next()?);
+            let tsc = prev.1.wrapping_add(next()?);
+            let entry_insns = prev.2.wrapping_add(next()?);
+            prev = (offset, tsc, entry_insns);
+
+            let mut optional = |flag| (flags & flag != 0).then(&mut next).transpose();
+            let cr3 = optional(HAS_CR3)?;
+            let vmcs = optional(HAS_VMCS)?;
+            let ip = optional(HAS_IP)?;
+            entries.push(IndexEntry {
+                offset,
+                tsc: (flags & HAS_TSC != 0).then_some(tsc),
+                asid: Asid::new(cr3, vmcs),
+                mode,
+                ip,
+                insns: entry_insns,
+            });
+        }
+
+        if !data.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self { entries, insns })
+    }
+
+    /// Save the index to @filename, see `to_bytes()`.
+    ///
+    /// Returns `BadFile` if @filename can't be written.
+    pub fn save(&self, filename: &str) -> Result<(), PtError> {
+        std::fs::write(filename, self.to_bytes())
+            .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to write the trace index"))
+    }
+
+    /// Load an index saved with `save()`.
+    ///
+    /// Returns `BadFile` if @filename can't be read.
+    /// Returns Invalid if @filename is not a valid index.
+    pub fn load(filename: &str) -> Result<Self, PtError> {
+        let data = std::fs::read(filename)
+            .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to read the trace index"))?;
+        Self::from_bytes(&data)
+    }
+}
+
+/// The entries of all the PSBs of @trace, without instruction counts.
+fn psb_entries(
+    trace: &[u8],
+    builder: &EncoderDecoderBuilder<InsnDecoder<'_>>,
+) -> Result<Vec<IndexEntry>, PtError> {
+    let mut entries = Vec::new();
+    if trace.is_empty() {
+        return Ok(entries);
+    }
+
+    let mut packets = PacketDecoder::<()>::builder().buffer(trace);
+    packets.config.cpu = builder.config.cpu;
+    let mut decoder = packets.build()?;
+
+    let mut last_ip = LastIp::new();
+    let mut asid = Asid::default();
+    let mut tsc = None;
+    let mut mode = ExecModeType::Unknown;
+    // The PSB+ being decoded: its offset and FUP IP.
+    let mut psb: Option<(u64, Option<u64>)> = None;
+    match decoder.sync_forward() {
+        Err(e) if e.code() == PtErrorCode::Eos => return Ok(entries),
+        result => result?,
+    }
+    loop {
+        let offset = decoder.offset()?;
+        let packet = match decoder.decode_next() {
+            Ok(packet) => packet,
+            Err(e) if e.code() == PtErrorCode::Eos => break,
+            Err(e) => return Err(e),
+        };
+        let ip = last_ip.decode(&packet).and_then(Result::ok);
+        match packet {
+            Packet::Psb(_) => psb = Some((offset, None)),
+            Packet::Psbend(_) => {
+                if let Some((offset, ip)) = psb.take() {
+                    entries.push(IndexEntry {
+                        offset,
+                        tsc,
+                        asid,
+                        mode,
+                        ip,
+                        insns: 0,
+                    });
+                }
+            }
+            Packet::Fup(_) => {
+                if let Some((_, psb_ip)) = &mut psb {
+                    *psb_ip = ip;
+                }
+            }
+            Packet::Tsc(p) => tsc = Some(p.tsc()),
+            Packet::Pip(p) => asid.set_cr3(p.cr3()),
+            Packet::Vmcs(p) => asid.set_vmcs(p.base()),
+            Packet::Mode(p) => {
+                if let Payload::Exec(exec) = p.payload() {
+                    mode = exec_mode(exec);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// The execution mode of a mode.exec packet.
+fn exec_mode(exec: Exec) -> ExecModeType {
+    match (exec.contains(Exec::CSL), exec.contains(Exec::CSD)) {
+        (true, false) => ExecModeType::Bit64,
+        (false, true) => ExecModeType::Bit32,
+        (false, false) => ExecModeType::Bit16,
+        (true, true) => ExecModeType::Unknown,
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(offset: u64, tsc: Option<u64>, insns: u64) -> IndexEntry {
+        IndexEntry {
+            offset,
+            tsc,
+            asid: Asid::new(Some(0x1000), None),
+            mode: ExecModeType::Bit64,
+            ip: Some(0xffff_ffff_8100_0000),
+            insns,
+        }
+    }
+
+    fn index() -> TraceIndex {
+        TraceIndex {
+            entries: vec![
+                entry(0, None, 0),
+                entry(0x100, Some(1000), 10),
+                IndexEntry {
+                    asid: Asid::new(None, Some(0x2000)),
+                    mode: ExecModeType::Bit32,
+                    ip: None,
+                    ..entry(0x4000, Some(u64::MAX), 1 << 40)
+                },
+            ],
+            insns: (1 << 40) + 5,
+        }
+    }
+
+    #[test]
+    fn test_index_serialize() {
+        let index = index();
+        let bytes = index.to_bytes();
+        assert_eq!(TraceIndex::from_bytes(&bytes).unwrap(), index);
+
+        // Truncated or trailing data.
+        for data in [
+            &bytes[..bytes.len() - 1],
+            &[bytes.as_slice(), &[0]].concat(),
+        ] {
+            assert_eq!(
+                TraceIndex::from_bytes(data).unwrap_err().code(),
+                PtErrorCode::Invalid
+            );
+        }
+        assert!(TraceIndex::from_bytes(b"PTIDX").is_err());
+        assert_eq!(
+            TraceIndex::from_bytes(&TraceIndex::default().to_bytes()).unwrap(),
+            TraceIndex::default()
+        );
+    }
+
+    #[test]
+    fn test_index_find() {
+        let index = index();
+        assert_eq!(index.find_tsc(0).unwrap().offset, 0);
+        assert_eq!(index.find_tsc(999).unwrap().offset, 0);
+        assert_eq!(index.find_tsc(1000).unwrap().offset, 0x100);
+        assert_eq!(index.find_insn(9).unwrap().offset, 0);
+        assert_eq!(index.find_insn(u64::MAX).unwrap().offset, 0x4000);
+        assert_eq!(index.find_offset(0x3fff).unwrap().offset, 0x100);
+        assert!(TraceIndex::default().find_offset(0).is_none());
+    }
+
+    #[test]
+    fn test_index_varint() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            let mut data = out.as_slice();
+            assert_eq!(read_varint(&mut data), Some(value));
+            assert!(data.is_empty());
+        }
+        assert_eq!(read_varint(&mut [0x80; 11].as_slice()), None);
+    }
+}
@@ -1,7 +1,7 @@
 use super::Insn;
 use crate::asid::Asid;
 use crate::enc_dec_builder::EncoderDecoderBuilder;
-use crate::enc_dec_builder::PtEncoderDecoder;
+use crate::enc_dec_builder::{PtDecoder, PtEncoderDecoder};
 use crate::error::{PtError, PtErrorCode, ensure_ptok, extract_status_or_pterr};
 use crate::event::Event;
 use crate::image::Image;
@@ -19,9 +19,11 @@ use std::mem;
 use std::ptr;
 use std::ptr::NonNull;
 
-/// The decoder will work on the buffer defined in the Config,
+/// The decoder will work on the buffer defined in the builder,
 /// it shall contain raw trace data and remain valid for the lifetime of the decoder.
 /// The decoder needs to be synchronized before it can be used.
+///
+/// The lifetime `'a` bounds both the trace buffer and the custom image, if any.
 #[derive(Debug)]
 pub struct InsnDecoder<'a> {
     inner: NonNull<pt_insn_decoder>,
@@ -30,6 +32,8 @@ pub struct InsnDecoder<'a> {
     //builder: EncoderDecoderBuilder<Self>,
 }
 
+impl PtDecoder for InsnDecoder<'_> {}
+
 impl PtEncoderDecoder for InsnDecoder<'_> {
     /// Allocate an Intel PT instruction flow decoder.
     ///
@@ -203,7 +207,12 @@ impl<'a> InsnDecoder<'a> {
     /// Returns Eos if decoder reaches the end of its trace buffer.
     /// Returns Nosync if there is no syncpoint at @offset.
     pub fn sync_set(&mut self, offset: u64) -> Result<(), PtError> {
-        ensure_ptok(unsafe { pt_insn_sync_set(self.inner.as_ptr(), offset) })
+        self.sync_set_status(offset).map(|_| ())
+    }
+
+    /// Same as `sync_set()`, also returning the decoder status, e.g. to process pending events.
+    pub(crate) fn sync_set_status(&mut self, offset: u64) -> Result<Status, PtError> {
+        extract_status_or_pterr(unsafe { pt_insn_sync_set(self.inner.as_ptr(), offset) })
     }
 
     /// Return the current time.
@@ -255,20 +264,16 @@ mod test {
 
     #[test]
     fn test_insndec_alloc() {
-        let mut kek = [1u8; 2];
+        let kek = [1u8; 2];
         let builder = InsnDecoder::builder();
-        unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        builder.buffer(&kek).build().unwrap();
     }
 
     #[test]
     fn test_insndec_props() {
-        let mut kek = [1u8; 2];
+        let kek = [1u8; 2];
         let builder = InsnDecoder::builder();
-        let mut b = unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        let mut b = builder.buffer(&kek).build().unwrap();
 
         let a = b.asid().unwrap();
         assert!(a.cr3().is_none());
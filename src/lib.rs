@@ -38,3 +38,44 @@ pub mod asid;
 
 /// Info about the decoder status
 pub mod status;
+
+/// Synthetic Intel PT trace generation, from a description of the control flow.
+pub mod synth;
+
+/// Reading Intel PT recordings made with Linux perf (`perf.data` files).
+pub mod perf;
+
+/// Sideband (context switches, mmaps) correlation with the decoder timeline.
+pub mod sideband;
+
+/// Merging of per-cpu decoders into a single timeline, ordered by time stamp.
+pub mod merge;
+
+/// Parallel decoding of a trace split at PSB boundaries.
+pub mod parallel;
+
+/// Index of the PSBs of a trace, for random access by time, instruction or offset.
+pub mod index;
+
+/// Call stack reconstruction from the blocks of a `BlockDecoder`.
+pub mod callstack;
+
+/// Function-level profiles of a trace, exported as folded stacks or speedscope profiles.
+pub mod profile;
+
+/// Symbolization of instruction pointers against ELF symbol tables and DWARF line info.
+#[cfg(feature = "symbolize")]
+pub mod symbolize;
+
+/// Disassembly of the traced instructions, with mnemonics, operands and memory accesses.
+#[cfg(feature = "disasm")]
+pub mod disasm;
+
+/// Basic-block and edge coverage of a trace, exported as AFL bitmaps, DRCOV, lcov or DOT.
+pub mod coverage;
+
+/// Fast decoding of the control flow edges of a trace into AFL bitmaps, for fuzzers.
+pub mod edges;
+
+/// Instruction-level timing, spreading the time of CYC and other timing packets over blocks.
+pub mod timing;
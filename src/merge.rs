@@ -0,0 +1,431 @@
+use crate::block::{Block, BlockDecoder};
+use crate::enc_dec_builder::OwnedDecoder;
+use crate::error::{PtError, PtErrorCode};
+use crate::event::Event;
+use crate::image::Image;
+use crate::insn::{Insn, InsnDecoder};
+use crate::status::Status;
+
+/// A high-level decoder that can be merged with others in a `MergedDecoder`.
+pub trait TraceDecoder {
+    /// The decoded item, an instruction or a block.
+    type Item;
+
+    /// Decode the next item, see `InsnDecoder::decode_next()`.
+    fn decode_next(&mut self) -> Result<(Self::Item, Status), PtError>;
+
+    /// Get the next pending event, see `InsnDecoder::event()`.
+    fn event(&mut self) -> Result<(Event, Status), PtError>;
+
+    /// Synchronize onto the next PSB, see `InsnDecoder::sync_forward()`.
+    fn sync_forward(&mut self) -> Result<Status, PtError>;
+
+    /// The current decoder time, see `InsnDecoder::time()`.
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError>;
+
+    /// The image the decoder uses for reading memory, see `InsnDecoder::image()`.
+    fn image(&mut self) -> &mut Image;
+}
+
+impl TraceDecoder for InsnDecoder<'_> {
+    type Item = Insn;
+
+    fn decode_next(&mut self) -> Result<(Insn, Status), PtError> {
+        InsnDecoder::decode_next(self)
+    }
+
+    fn event(&mut self) -> Result<(Event, Status), PtError> {
+        InsnDecoder::event(self)
+    }
+
+    fn sync_forward(&mut self) -> Result<Status, PtError> {
+        InsnDecoder::sync_forward(self)
+    }
+
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        InsnDecoder::time(self)
+    }
+
+    fn image(&mut self) -> &mut Image {
+        InsnDecoder::image(self)
+    }
+}
+
+impl TraceDecoder for BlockDecoder<'_> {
+    type Item = Block;
+
+    fn decode_next(&mut self) -> Result<(Block, Status), PtError> {
+        BlockDecoder::decode_next(self)
+    }
+
+    fn event(&mut self) -> Result<(Event, Status), PtError> {
+        BlockDecoder::event(self)
+    }
+
+    fn sync_forward(&mut self) -> Result<Status, PtError> {
+        BlockDecoder::sync_forward(self)
+    }
+
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        BlockDecoder::time(self)
+    }
+
+    fn image(&mut self) -> &mut Image {
+        BlockDecoder::image(self)
+    }
+}
+
+/// Borrowed decoders, to keep using the decoder afterwards.
+impl<D: TraceDecoder + ?Sized> TraceDecoder for &mut D {
+    type Item = D::Item;
+
+    fn decode_next(&mut self) -> Result<(D::Item, Status), PtError> {
+        (**self).decode_next()
+    }
+
+    fn event(&mut self) -> Result<(Event, Status), PtError> {
+        (**self).event()
+    }
+
+    fn sync_forward(&mut self) -> Result<Status, PtError> {
+        (**self).sync_forward()
+    }
+
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        (**self).time()
+    }
+
+    fn image(&mut self) -> &mut Image {
+        (**self).image()
+    }
+}
+
+/// Decoders owning their trace buffer.
+impl<D: TraceDecoder> TraceDecoder for OwnedDecoder<D> {
+    type Item = D::Item;
+
+    fn decode_next(&mut self) -> Result<(D::Item, Status), PtError> {
+        self.decoder_mut().decode_next()
+    }
+
+    fn event(&mut self) -> Result<(Event, Status), PtError> {
+        self.decoder_mut().event()
+    }
+
+    fn sync_forward(&mut self) -> Result<Status, PtError> {
+        self.decoder_mut().sync_forward()
+    }
+
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        self.decoder_mut().time()
+    }
+
+    fn image(&mut self) -> &mut Image {
+        self.decoder_mut().image()
+    }
+}
+
+/// An item decoded by one of the decoders of a `MergedDecoder`.
+#[derive(Debug, Clone)]
+pub enum Decoded<T> {
+    /// An instruction or a block
+    Item(T),
+    /// An event
+    Event(Event),
+}
+
+/// An item of the global timeline built by a `MergedDecoder`.
+#[derive(Debug, Clone)]
+pub struct Merged<T> {
+    /// The index of the decoder (the cpu) the item comes from
+    pub cpu: usize,
+    /// The time of the item
+    pub tsc: u64,
+    /// The decoded item, or the error hit by the decoder.
+    ///
+    /// After an error the decoder resynchronizes at the next PSB.
+    pub result: Result<Decoded<T>, PtError>,
+}
+
+/// The decoding state of a single cpu.
+#[derive(Debug)]
+pub(crate) struct Lane<D: TraceDecoder> {
+    decoder: D,
+    status: Option<Status>,
+    tsc: u64,
+    done: bool,
+    next: Option<Merged<D::Item>>,
+}
+
+impl<D: TraceDecoder> Lane<D> {
+    pub(crate) fn new(decoder: D) -> Self {
+        Self {
+            decoder,
+            status: None,
+            tsc: 0,
+            done: false,
+            next: None,
+        }
+    }
+
+    /// The decoder of the lane.
+    pub(crate) fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    /// Get back the decoder of the lane.
+    pub(crate) fn into_decoder(self) -> D {
+        self.decoder
+    }
+
+    /// The time of the last decoded item.
+    pub(crate) fn tsc(&self) -> u64 {
+        self.tsc
+    }
+
+    /// Update the lane time, it never goes backwards.
+    fn update_tsc(&mut self, tsc: Option<u64>) -> u64 {
+        let tsc = tsc.or_else(|| self.decoder.time().ok().map(|(tsc, _, _)| tsc));
+        self.tsc = self.tsc.max(tsc.unwrap_or(0));
+        self.tsc
+    }
+
+    /// Decode the next item, events first.
+    ///
+    /// Returns `None` once the end of the trace is reached.
+    pub(crate) fn fetch(&mut self) -> Option<Result<(u64, Decoded<D::Item>), PtError>> {
+        if self.done {
+            return None;
+        }
+
+        let status = match self.status {
+            Some(status) => status,
+            None => match self.decoder.sync_forward() {
+                Ok(status) => status,
+                Err(e) => {
+                    // There is no PSB left, or the trace can't be parsed any further.
+                    self.done = true;
+                    return (e.code() != PtErrorCode::Eos).then_some(Err(e));
+                }
+            },
+        };
+
+        let result = if status.event_pending() {
+            self.decoder.event().map(|(event, status)| {
+                let tsc = self.update_tsc(event.tsc());
+                (tsc, Decoded::Event(event), status)
+            })
+        } else if status.eos() {
+            self.done = true;
+            return None;
+        } else {
+            self.decoder.decode_next().map(|(item, status)| {
+                let tsc = self.update_tsc(None);
+                (tsc, Decoded::Item(item), status)
+            })
+        };
+
+        match result {
+            Ok((tsc, decoded, status)) => {
+                self.status = Some(status);
+                Some(Ok((tsc, decoded)))
+            }
+            Err(e) if e.code() == PtErrorCode::Eos => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                // Resynchronize at the next PSB.
+                self.status = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Interleave the output of per-cpu decoders in timestamp order.
+///
+/// Each decoder is synchronized on its first PSB, then its instructions (or blocks) and events are
+/// merged into a single timeline, ordered by `time()` and `Event::tsc()`. Items with the same time
+/// are ordered by decoder index.
+/// Decoders that hit an error resynchronize at their next PSB, decoders reaching the end of their
+/// trace simply drop out of the timeline.
+///
+/// The trace must have been recorded with TSC packets enabled, the time of decoders without
+/// timing information is 0.
+#[derive(Debug)]
+pub struct MergedDecoder<D: TraceDecoder> {
+    lanes: Vec<Lane<D>>,
+}
+
+impl<D: TraceDecoder> MergedDecoder<D> {
+    /// Merge @decoders, the index of each decoder is the cpu of its items.
+    ///
+    /// The decoders must not be synchronized yet.
+    pub fn new(decoders: impl IntoIterator<Item = D>) -> Self {
+        Self {
+            lanes: decoders.into_iter().map(Lane::new).collect(),
+        }
+    }
+
+    /// The decoder of @cpu, e.g. to update its image.
+    #[must_use]
+    pub fn decoder_mut(&mut self, cpu: usize) -> Option<&mut D> {
+        self.lanes.get_mut(cpu).map(|lane| &mut lane.decoder)
+    }
+
+    /// Whether the decoder of @cpu reached the end of its trace.
+    #[must_use]
+    pub fn is_done(&self, cpu: usize) -> bool {
+        self.lanes
+            .get(cpu)
+            .is_none_or(|lane| lane.done && lane.next.is_none())
+    }
+
+    /// Get back the decoders.
+    #[must_use]
+    pub fn into_decoders(self) -> Vec<D> {
+        self.lanes.into_iter().map(|lane| lane.decoder).collect()
+    }
+}
+
+impl<D: TraceDecoder> Iterator for MergedDecoder<D> {
+    type Item = Merged<D::Item>;
+
+    fn next(&mut self) -> Option<Merged<D::Item>> {
+        for (cpu, lane) in self.lanes.iter_mut().enumerate() {
+            if lane.next.is_none() {
+                lane.next = lane.fetch().map(|result| match result {
+                    Ok((tsc, decoded)) => Merged {
+                        cpu,
+                        tsc,
+                        result: Ok(decoded),
+                    },
+                    Err(e) => Merged {
+                        cpu,
+                        tsc: lane.tsc,
+                        result: Err(e),
+                    },
+                });
+            }
+        }
+
+        self.lanes
+            .iter_mut()
+            .filter(|lane| lane.next.is_some())
+            .min_by_key(|lane| lane.next.as_ref().map(|next| next.tsc))
+            .and_then(|lane| lane.next.take())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// A decoder returning (time, item) pairs, `None` being a decode error.
+    struct Scripted {
+        items: VecDeque<Option<(u64, u32)>>,
+        time: Option<u64>,
+        syncs: u32,
+        image: Image,
+    }
+
+    impl Scripted {
+        fn new(items: &[Option<(u64, u32)>]) -> Self {
+            Self {
+                items: items.iter().copied().collect(),
+                time: None,
+                syncs: 0,
+                image: Image::new(None).unwrap(),
+            }
+        }
+    }
+
+    impl TraceDecoder for Scripted {
+        type Item = u32;
+
+        fn decode_next(&mut self) -> Result<(u32, Status), PtError> {
+            match self.items.pop_front() {
+                Some(Some((time, item))) => {
+                    self.time = Some(time);
+                    Ok((item, Status::empty()))
+                }
+                Some(None) => Err(PtErrorCode::Nosync.into()),
+                None => Err(PtErrorCode::Eos.into()),
+            }
+        }
+
+        fn event(&mut self) -> Result<(Event, Status), PtError> {
+            Err(PtErrorCode::Internal.into())
+        }
+
+        fn sync_forward(&mut self) -> Result<Status, PtError> {
+            self.syncs += 1;
+            if self.items.is_empty() {
+                Err(PtErrorCode::Eos.into())
+            } else {
+                Ok(Status::empty())
+            }
+        }
+
+        fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+            self.time
+                .map(|time| (time, 0, 0))
+                .ok_or_else(|| PtErrorCode::NoTime.into())
+        }
+
+        fn image(&mut self) -> &mut Image {
+            &mut self.image
+        }
+    }
+
+    #[test]
+    fn test_merge_order() {
+        let merged = MergedDecoder::new([
+            Scripted::new(&[Some((10, 0)), Some((30, 1)), Some((30, 2))]),
+            Scripted::new(&[Some((5, 10)), Some((30, 11)), Some((40, 12))]),
+            Scripted::new(&[]),
+        ]);
+
+        let items: Vec<(usize, u64, u32)> = merged
+            .map(|m| match m.result.unwrap() {
+                Decoded::Item(item) => (m.cpu, m.tsc, item),
+                Decoded::Event(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            items,
+            [
+                (1, 5, 10),
+                (0, 10, 0),
+                (0, 30, 1),
+                (0, 30, 2),
+                (1, 30, 11),
+                (1, 40, 12)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_errors() {
+        let mut merged = MergedDecoder::new([
+            Scripted::new(&[Some((10, 0)), None, Some((20, 1))]),
+            Scripted::new(&[Some((15, 10))]),
+        ]);
+
+        let results: Vec<(usize, u64, bool)> = merged
+            .by_ref()
+            .map(|m| (m.cpu, m.tsc, m.result.is_ok()))
+            .collect();
+        assert_eq!(
+            results,
+            [(0, 10, true), (0, 10, false), (1, 15, true), (0, 20, true)]
+        );
+        assert!(merged.is_done(0) && merged.is_done(1));
+
+        // The first decoder resynchronized after the error.
+        let decoders = merged.into_decoders();
+        assert_eq!(decoders[0].syncs, 2);
+    }
+}
@@ -1,7 +1,7 @@
 use super::Packet;
 use crate::error::{PtError, PtErrorCode, ensure_ptok};
 
-use crate::enc_dec_builder::{EncoderDecoderBuilder, PtEncoderDecoder};
+use crate::enc_dec_builder::{EncoderDecoderBuilder, PtDecoder, PtEncoderDecoder, UnknownCallback};
 use libipt_sys::{
     pt_packet, pt_packet_decoder, pt_pkt_alloc_decoder, pt_pkt_free_decoder, pt_pkt_get_config,
     pt_pkt_get_offset, pt_pkt_get_sync_offset, pt_pkt_next, pt_pkt_sync_backward,
@@ -11,13 +11,21 @@ use std::marker::PhantomData;
 use std::mem;
 use std::ptr::NonNull;
 
+/// The decoder will work on the buffer defined in the builder, it shall contain
+/// raw trace data and remain valid for the lifetime of the decoder.
+///
+/// The decoder needs to be synchronized before it can be used.
 #[derive(Debug)]
-pub struct PacketDecoder<T> {
+pub struct PacketDecoder<'a, T> {
     inner: NonNull<pt_packet_decoder>,
-    phantom: PhantomData<T>,
+    // The unknown packet decoder callback, if any. It must outlive `inner`.
+    pub(crate) callback: Option<UnknownCallback<T>>,
+    phantom: PhantomData<(T, &'a [u8])>,
 }
 
-impl<T> PtEncoderDecoder for PacketDecoder<T> {
+impl<T> PtDecoder for PacketDecoder<'_, T> {}
+
+impl<T> PtEncoderDecoder for PacketDecoder<'_, T> {
     /// Allocate an Intel PT packet decoder.
     ///
     /// The decoder will work on the buffer defined in @config,
@@ -31,12 +39,13 @@ impl<T> PtEncoderDecoder for PacketDecoder<T> {
 
         Ok(Self {
             inner,
+            callback: None,
             phantom: PhantomData,
         })
     }
 }
 
-impl<T> PacketDecoder<T> {
+impl<T> PacketDecoder<'_, T> {
     #[must_use]
     pub fn used_builder(&self) -> &EncoderDecoderBuilder<Self> {
         let ptr = unsafe { pt_pkt_get_config(self.inner.as_ptr()) };
@@ -102,7 +111,7 @@ impl<T> PacketDecoder<T> {
     }
 }
 
-impl<T> Iterator for PacketDecoder<T> {
+impl<T> Iterator for PacketDecoder<'_, T> {
     type Item = Result<Packet<T>, PtError>;
 
     fn next(&mut self) -> Option<Result<Packet<T>, PtError>> {
@@ -114,7 +123,7 @@ impl<T> Iterator for PacketDecoder<T> {
     }
 }
 
-impl<T> Drop for PacketDecoder<T> {
+impl<T> Drop for PacketDecoder<'_, T> {
     fn drop(&mut self) {
         unsafe { pt_pkt_free_decoder(self.inner.as_ptr()) }
     }
@@ -127,20 +136,16 @@ mod test {
 
     #[test]
     fn test_pktdec_alloc() {
-        let mut kek = [1u8; 2];
+        let kek = [1u8; 2];
         let builder: EncoderDecoderBuilder<PacketDecoder<()>> = PacketDecoder::builder();
-        unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        builder.buffer(&kek).build().unwrap();
     }
 
     #[test]
     fn test_pktdec_props() {
-        let mut kek = [1u8; 2];
+        let kek = [1u8; 2];
         let builder: EncoderDecoderBuilder<PacketDecoder<()>> = PacketDecoder::builder();
-        let mut p = unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        let mut p = builder.buffer(&kek).build().unwrap();
 
         let used_builder = p.used_builder();
         unsafe {
@@ -159,4 +164,33 @@ mod test {
         assert!(p.sync_backward().is_err());
         assert!(p.sync_forward().is_err());
     }
+
+    #[test]
+    fn test_pktdec_unknown_callback() {
+        // psb, psbend, an unknown extended opcode with 1 byte payload and a pad
+        let mut kek = [0x02, 0x82].repeat(8);
+        kek.extend_from_slice(&[0x02, 0x23, 0x02, 0x33, 0x00, 0x00]);
+
+        let builder: EncoderDecoderBuilder<PacketDecoder<u16>> = PacketDecoder::builder();
+        let mut p = builder
+            .buffer(&kek)
+            .build_with_unknown_callback(|trace| {
+                (trace[..2] == [0x02, 0x33]).then(|| (u16::from(trace[2]) + 1, 3))
+            })
+            .unwrap();
+
+        p.sync_forward().unwrap();
+        assert!(matches!(p.decode_next().unwrap(), Packet::Psb(_)));
+        assert!(matches!(p.decode_next().unwrap(), Packet::Psbend(_)));
+        match p.decode_next().unwrap() {
+            Packet::Unknown(u) => assert_eq!(u.data(), Some(1)),
+            x => panic!("unexpected packet {x:?}"),
+        }
+        assert!(matches!(p.decode_next().unwrap(), Packet::Pad(_)));
+
+        // a builder reused from a decoder doesn't share its callback
+        let mut p2 = p.used_builder().build().unwrap();
+        p2.sync_forward().unwrap();
+        assert!(p2.nth(2).unwrap().is_err());
+    }
 }
@@ -9,13 +9,15 @@ use libipt_sys::{
 use std::marker::PhantomData;
 use std::ptr::NonNull;
 
+/// The encoder will work on the buffer defined in the builder, it shall remain valid
+/// for the lifetime of the encoder.
 #[derive(Debug)]
-pub struct Encoder<T> {
+pub struct Encoder<'a, T> {
     inner: NonNull<pt_encoder>,
-    phantom: PhantomData<T>,
+    phantom: PhantomData<(T, &'a mut [u8])>,
 }
 
-impl<T> PtEncoderDecoder for Encoder<T> {
+impl<T> PtEncoderDecoder for Encoder<'_, T> {
     /// Allocate an Intel PT packet encoder.
     ///
     /// The encoder will work on the buffer defined in @config, it shall contain raw trace data and remain valid for the lifetime of the encoder.
@@ -32,7 +34,7 @@ impl<T> PtEncoderDecoder for Encoder<T> {
     }
 }
 
-impl<T> Encoder<T> {
+impl<T> Encoder<'_, T> {
     // pub fn config(&self) -> Result<Config<T>, PtError> {
     //     deref_ptresult(unsafe { pt_enc_get_config(self.inner.as_ptr()) }).map(Config::from)
     // }
@@ -70,7 +72,7 @@ impl<T> Encoder<T> {
     }
 }
 
-impl<T> Drop for Encoder<T> {
+impl<T> Drop for Encoder<'_, T> {
     fn drop(&mut self) {
         unsafe { pt_free_encoder(self.inner.as_ptr()) }
     }
@@ -85,18 +87,14 @@ mod tests {
     fn test_pktdec_alloc() {
         let mut kek = [1u8; 2];
         let builder: EncoderDecoderBuilder<Encoder<()>> = Encoder::builder();
-        unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        builder.buffer_mut(&mut kek).build().unwrap();
     }
 
     #[test]
     fn test_pktdec_props() {
         let mut kek = [1u8; 2];
         let builder: EncoderDecoderBuilder<Encoder<()>> = Encoder::builder();
-        let mut p = unsafe { builder.buffer_from_raw(kek.as_mut_ptr(), kek.len()) }
-            .build()
-            .unwrap();
+        let mut p = builder.buffer_mut(&mut kek).build().unwrap();
 
         // assert!(p.config().is_ok());
         assert_eq!(p.offset().unwrap(), 0);
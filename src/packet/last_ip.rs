@@ -0,0 +1,215 @@
+use super::{Compression, Fup, Packet, Tip, TipPgd, TipPge};
+use crate::error::{PtError, PtErrorCode};
+
+/// Sign extend the lower @bits bits of @ip.
+const fn sext(ip: u64, bits: u32) -> u64 {
+    let shift = u64::BITS - bits;
+    (((ip << shift) as i64) >> shift) as u64
+}
+
+/// The last IP, used to compress and decompress the IP payload of `Tip`, `Fup`, `TipPge` and
+/// `TipPgd` packets.
+///
+/// On the encoder side, `compress()` (or `tip()`, `fup()`, ...) picks the smallest `Compression`
+/// for a full address. On the decoder side, `update()` (or `decode()`) reconstructs the full
+/// address from a packet payload.
+/// The last IP must be `reset()` at every PSB, as the processor does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LastIp {
+    ip: u64,
+    have_ip: bool,
+    suppressed: bool,
+}
+
+impl LastIp {
+    /// Create a last IP tracker without any IP.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            ip: 0,
+            have_ip: false,
+            suppressed: false,
+        }
+    }
+
+    /// Forget the last IP, e.g. on PSB.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// The last IP.
+    ///
+    /// Returns Noip if there is no last IP.
+    /// Returns IpSuppressed if the last IP has been suppressed.
+    pub fn ip(&self) -> Result<u64, PtError> {
+        if !self.have_ip {
+            Err(PtErrorCode::Noip.into())
+        } else if self.suppressed {
+            Err(PtErrorCode::IpSuppressed.into())
+        } else {
+            Ok(self.ip)
+        }
+    }
+
+    /// Update the last IP with the payload of an IP packet.
+    ///
+    /// Returns the full IP on success, a PtError otherwise.
+    /// Returns IpSuppressed if @compression is `Suppressed`.
+    pub fn update(&mut self, payload: u64, compression: Compression) -> Result<u64, PtError> {
+        self.ip = match compression {
+            Compression::Suppressed => {
+                self.suppressed = true;
+                return Err(PtErrorCode::IpSuppressed.into());
+            }
+            Compression::Update16 => (self.ip & !0xffff) | (payload & 0xffff),
+            Compression::Update32 => (self.ip & !0xffff_ffff) | (payload & 0xffff_ffff),
+            Compression::Update48 => (self.ip & !0xffff_ffff_ffff) | (payload & 0xffff_ffff_ffff),
+            Compression::Sext48 => sext(payload, 48),
+            Compression::Full => payload,
+        };
+        self.have_ip = true;
+        self.suppressed = false;
+        Ok(self.ip)
+    }
+
+    /// Update the last IP with a decoded packet.
+    ///
+    /// Returns `None` if @packet is not an IP packet, the result of `update()` otherwise.
+    /// The last IP is reset on PSB.
+    pub fn decode<T>(&mut self, packet: &Packet<T>) -> Option<Result<u64, PtError>> {
+        match packet {
+            Packet::Psb(_) => {
+                self.reset();
+                None
+            }
+            Packet::Tip(p) => Some(self.update(p.tip(), p.compression())),
+            Packet::Fup(p) => Some(self.update(p.fup(), p.compression())),
+            Packet::TipPge(p) => Some(self.update(p.tippge(), p.compression())),
+            Packet::TipPgd(p) => Some(self.update(p.tippgd(), p.compression())),
+            _ => None,
+        }
+    }
+
+    /// Pick the smallest compression encoding @ip and update the last IP.
+    ///
+    /// The packet payload is @ip itself, only its relevant bytes are encoded.
+    pub fn compress(&mut self, ip: u64) -> Compression {
+        let compression = match self.ip() {
+            Ok(last) if last >> 16 == ip >> 16 => Compression::Update16,
+            Ok(last) if last >> 32 == ip >> 32 => Compression::Update32,
+            _ if sext(ip, 48) == ip => Compression::Sext48,
+            Ok(last) if last >> 48 == ip >> 48 => Compression::Update48,
+            _ => Compression::Full,
+        };
+        self.ip = ip;
+        self.have_ip = true;
+        self.suppressed = false;
+        compression
+    }
+
+    /// Create a `Tip` packet for @ip, using the smallest compression.
+    pub fn tip(&mut self, ip: u64) -> Tip {
+        Tip::new(ip, self.compress(ip))
+    }
+
+    /// Create a `Fup` packet for @ip, using the smallest compression.
+    pub fn fup(&mut self, ip: u64) -> Fup {
+        Fup::new(ip, self.compress(ip))
+    }
+
+    /// Create a `TipPge` packet for @ip, using the smallest compression.
+    pub fn tippge(&mut self, ip: u64) -> TipPge {
+        TipPge::new(ip, self.compress(ip))
+    }
+
+    /// Create a `TipPgd` packet for @ip, using the smallest compression.
+    pub fn tippgd(&mut self, ip: u64) -> TipPgd {
+        TipPgd::new(ip, self.compress(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enc_dec_builder::PtEncoderDecoder;
+    use crate::packet::{Encoder, PacketDecoder, Psb};
+
+    #[test]
+    fn test_lastip_compress() {
+        let mut last = LastIp::new();
+        assert!(last.ip().is_err());
+
+        assert_eq!(last.compress(0xffff_8000_1234_5678), Compression::Sext48);
+        assert_eq!(last.compress(0xffff_8000_1234_0000), Compression::Update16);
+        assert_eq!(last.compress(0xffff_8000_4321_0000), Compression::Update32);
+        assert_eq!(last.compress(0x0000_7fff_0000_0000), Compression::Sext48);
+        assert_eq!(last.compress(0x1234_7fff_0000_0000), Compression::Full);
+        assert_eq!(last.compress(0x1234_8fff_0000_0000), Compression::Update48);
+        assert_eq!(last.ip().unwrap(), 0x1234_8fff_0000_0000);
+
+        last.reset();
+        assert_eq!(last.compress(0x1234_8fff_0000_0000), Compression::Full);
+    }
+
+    #[test]
+    fn test_lastip_update() {
+        let mut last = LastIp::new();
+        assert_eq!(
+            last.update(0x8000_1234_5678, Compression::Sext48).unwrap(),
+            0xffff_8000_1234_5678
+        );
+        assert_eq!(
+            last.update(0xabcd, Compression::Update16).unwrap(),
+            0xffff_8000_1234_abcd
+        );
+        assert!(last.update(0, Compression::Suppressed).is_err());
+        assert!(last.ip().is_err());
+        assert_eq!(
+            last.update(0x1111_2222, Compression::Update32).unwrap(),
+            0xffff_8000_1111_2222
+        );
+    }
+
+    #[test]
+    fn test_lastip_roundtrip() {
+        let ips = [
+            0x0000_5555_0000_1000,
+            0x0000_5555_0000_1010,
+            0x0000_5555_1000_0000,
+            0xffff_8000_0000_0000,
+            0x00ff_0000_0000_0000,
+            0x00ff_0000_1000_0000,
+            0x0000_5555_0000_1000,
+        ];
+
+        let mut buf = [0u8; 128];
+        let mut enc = Encoder::<()>::builder()
+            .buffer_mut(&mut buf)
+            .build()
+            .unwrap();
+        let mut last = LastIp::new();
+        enc.next(Psb::new()).unwrap();
+        for (i, ip) in ips.iter().enumerate() {
+            if i % 2 == 0 {
+                enc.next(last.tip(*ip)).unwrap();
+            } else {
+                enc.next(last.fup(*ip)).unwrap();
+            }
+        }
+        let size = enc.offset().unwrap() as usize;
+        drop(enc);
+
+        let mut dec = PacketDecoder::<()>::builder()
+            .buffer(&buf[..size])
+            .build()
+            .unwrap();
+        dec.sync_forward().unwrap();
+
+        let mut last = LastIp::new();
+        let decoded: Vec<u64> = dec
+            .filter_map(|p| last.decode(&p.unwrap()))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(decoded, ips);
+    }
+}
@@ -50,6 +50,8 @@ mod tnt;
 pub use tnt::*;
 mod ip;
 pub use ip::*;
+mod last_ip;
+pub use last_ip::LastIp;
 mod mode;
 pub use mode::*;
 mod pip;
@@ -87,6 +89,18 @@ pub use decoder::PacketDecoder;
 mod encoder;
 pub use encoder::Encoder;
 
+mod stream_encoder;
+pub use stream_encoder::StreamEncoder;
+
+#[cfg(feature = "pure-rust-packets")]
+mod native;
+#[cfg(feature = "pure-rust-packets")]
+pub use native::NativePacketDecoder;
+#[cfg(feature = "pure-rust-packets")]
+mod native_encoder;
+#[cfg(feature = "pure-rust-packets")]
+pub use native_encoder::{NativeEncoder, encode_packet};
+
 #[cfg(test)]
 mod test {
     use super::*;
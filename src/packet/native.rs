@@ -0,0 +1,500 @@
+use super::*;
+use crate::enc_dec_builder::{EncoderDecoderBuilder, PtDecoder, PtEncoderDecoder};
+use crate::error::{PtError, PtErrorCode};
+use std::fmt::{Debug, Formatter};
+
+/// Size of a PSB packet, the synchronization point.
+const PSB_SIZE: usize = 16;
+/// The 2 bytes pattern repeated 8 times in a PSB packet.
+const PSB_PATTERN: [u8; 2] = [0x02, 0x82];
+
+/// Opcode of all the extended packets.
+const OPC_EXT: u8 = 0x02;
+/// Extended opcode of all the 2nd level extended packets.
+const EXT_EXT2: u8 = 0xc3;
+
+type DynUnknownCallback<T> = dyn FnMut(&[u8]) -> Option<(T, usize)>;
+
+/// A pure Rust Intel PT packet decoder.
+///
+/// This is a drop-in replacement for `PacketDecoder` that doesn't call into libipt:
+/// it decodes the same `Packet`s at the same offsets and follows the same synchronization rules,
+/// without any FFI call or C allocation.
+/// The packet types are still libipt's: the `pure-rust-packets` feature doesn't remove the
+/// dependency on `libipt-sys`, the C library is still built and linked.
+/// The decoder works on the buffer defined in the builder and needs to be synchronized before it
+/// can be used.
+pub struct NativePacketDecoder<'a, T> {
+    buf: &'a [u8],
+    // Current position, `None` if the decoder is not synchronized.
+    pos: Option<usize>,
+    // Position of the last synchronization point.
+    sync: Option<usize>,
+    callback: Option<Box<DynUnknownCallback<T>>>,
+}
+
+impl<T> PtDecoder for NativePacketDecoder<'_, T> {}
+
+impl<T> PtEncoderDecoder for NativePacketDecoder<'_, T> {
+    /// Create a pure Rust Intel PT packet decoder.
+    ///
+    /// Only the buffer of @builder is used, packet decoding doesn't depend on the other settings.
+    fn new_from_builder(builder: &EncoderDecoderBuilder<Self>) -> Result<Self, PtError> {
+        let begin = builder.config.begin.cast_const();
+        if begin.is_null() {
+            return Err(PtError::new(PtErrorCode::BadConfig, "No buffer"));
+        }
+        let len = (builder.config.end as usize)
+            .checked_sub(begin as usize)
+            .ok_or(PtError::new(PtErrorCode::BadConfig, "Invalid buffer"))?;
+        // SAFETY: the buffer is set with `buffer()`, bound to the decoder lifetime, or with the
+        // unsafe `buffer_from_raw()` whose caller guarantees its validity.
+        let buf = unsafe { std::slice::from_raw_parts(begin, len) };
+
+        Ok(Self {
+            buf,
+            pos: None,
+            sync: None,
+            callback: None,
+        })
+    }
+}
+
+impl<T> NativePacketDecoder<'_, T> {
+    /// Get the current decoder position.
+    ///
+    /// Returns Nosync if decoder is out of sync.
+    pub fn offset(&self) -> Result<u64, PtError> {
+        self.pos.map(|p| p as u64).ok_or(PtErrorCode::Nosync.into())
+    }
+
+    /// Get the position of the last synchronization point.
+    ///
+    /// Returns Nosync if decoder is out of sync.
+    pub fn sync_offset(&self) -> Result<u64, PtError> {
+        self.sync
+            .map(|p| p as u64)
+            .ok_or(PtErrorCode::Nosync.into())
+    }
+
+    /// Decode the next packet and advance the decoder.
+    ///
+    /// Returns BadOpc if the packet is unknown.
+    /// Returns BadPacket if an unknown packet payload is encountered.
+    /// Returns Eos if decoder reached the end of the Intel PT buffer.
+    /// Returns Nosync if decoder is out of sync.
+    pub fn decode_next(&mut self) -> Result<Packet<T>, PtError> {
+        let pos = self.pos.ok_or(PtError::from(PtErrorCode::Nosync))?;
+        let (packet, size) = self.decode_at(pos)?;
+        self.pos = Some(pos + size);
+        Ok(packet)
+    }
+
+    /// Synchronize backward to the previous PSB.
+    ///
+    /// If decoder has not been synchronized, yet, the search is started at the end of the buffer.
+    /// Returns Eos if no further synchronization point is found.
+    pub fn sync_backward(&mut self) -> Result<(), PtError> {
+        let start = self.pos.unwrap_or(self.buf.len());
+        let found = (0..start)
+            .rev()
+            .find(|&off| self.is_psb_start(off))
+            .ok_or(PtError::from(PtErrorCode::Eos))?;
+        self.sync = Some(found);
+        self.pos = Some(found);
+        Ok(())
+    }
+
+    /// Synchronize forward to the next PSB.
+    ///
+    /// If decoder has not been synchronized, yet, the search is started at the beginning of the
+    /// buffer.
+    /// Returns Eos if no further synchronization point is found.
+    pub fn sync_forward(&mut self) -> Result<(), PtError> {
+        let mut start = self.pos.unwrap_or(0);
+        if self.sync == Some(start) {
+            start += PSB_SIZE;
+        }
+        let found = (start..self.buf.len())
+            .find(|&off| self.is_psb_start(off))
+            .ok_or(PtError::from(PtErrorCode::Eos))?;
+        self.sync = Some(found);
+        self.pos = Some(found);
+        Ok(())
+    }
+
+    /// Hard set synchronization point of the decoder.
+    ///
+    /// Synchronize decoder to @offset within the trace buffer.
+    /// Returns Eos if the given offset is behind the end of the trace buffer.
+    pub fn sync_set(&mut self, offset: u64) -> Result<(), PtError> {
+        let offset = usize::try_from(offset)
+            .ok()
+            .filter(|&off| off <= self.buf.len())
+            .ok_or(PtError::from(PtErrorCode::Eos))?;
+        self.sync = Some(offset);
+        self.pos = Some(offset);
+        Ok(())
+    }
+
+    fn is_psb_at(&self, off: usize) -> bool {
+        self.buf
+            .get(off..off + PSB_SIZE)
+            .is_some_and(|psb| psb.chunks_exact(2).all(|p| p == PSB_PATTERN))
+    }
+
+    /// Whether a PSB starts at @off.
+    ///
+    /// The PSB pattern repeats, a match may be in the middle of back to back PSBs or start in the
+    /// bytes before a PSB. As libipt's `pt_find_psb()`, only the last 16 bytes of a pattern run
+    /// are a synchronization point.
+    fn is_psb_start(&self, off: usize) -> bool {
+        let end = off + PSB_SIZE;
+        self.is_psb_at(off) && self.buf.get(end..end + 2) != Some(&PSB_PATTERN[..])
+    }
+
+    /// Decode the packet at @pos, returning it along with its size.
+    fn decode_at(&mut self, pos: usize) -> Result<(Packet<T>, usize), PtError> {
+        let buf = self.buf;
+        let trace = &buf[pos.min(buf.len())..];
+        let opc = *trace.first().ok_or(PtError::from(PtErrorCode::Eos))?;
+
+        let decoded = match opc {
+            0x00 => Some((Packet::Pad(Pad::new()), 1)),
+            OPC_EXT => decode_ext(trace)?,
+            0x19 => {
+                let tsc = read_le(trace, 1, 7)?;
+                Some((Packet::Tsc(Tsc::new(tsc)), 8))
+            }
+            0x59 => {
+                let ctc = read_le(trace, 1, 1)?;
+                Some((Packet::Mtc(Mtc::new(ctc as u8)), 2))
+            }
+            0x99 => Some((decode_mode(trace)?, 2)),
+            x if x & 0x01 == 0 => {
+                let (payload, bitsize) = split_tnt(u64::from(x >> 1))?;
+                Some((Packet::Tnt8(Tnt8::new(payload as u8, bitsize)), 1))
+            }
+            x if x & 0x03 == 0x03 => Some(decode_cyc(trace)?),
+            x => match x & 0x1f {
+                0x01 | 0x0d | 0x11 | 0x1d => Some(decode_ip(trace)?),
+                _ => None,
+            },
+        };
+
+        match decoded {
+            Some(d) => Ok(d),
+            None => self.decode_unknown(trace),
+        }
+    }
+
+    fn decode_unknown(&mut self, trace: &[u8]) -> Result<(Packet<T>, usize), PtError> {
+        let callback = self
+            .callback
+            .as_mut()
+            .ok_or(PtError::from(PtErrorCode::BadOpc))?;
+        match callback(trace) {
+            Some((value, size)) if size > 0 && size <= trace.len() => {
+                Ok((Packet::Unknown(Unknown::new(value)), size))
+            }
+            Some(_) => Err(PtErrorCode::BadPacket.into()),
+            None => Err(PtErrorCode::BadOpc.into()),
+        }
+    }
+}
+
+impl<'a, T> EncoderDecoderBuilder<NativePacketDecoder<'a, T>> {
+    /// Set the decoder buffer.
+    ///
+    /// The buffer is not copied, the built decoders borrow it for their entire lifetime.
+    pub fn buffer(self, buf: &'a [u8]) -> Self {
+        // SAFETY: see `EncoderDecoderBuilder::<PacketDecoder>::buffer`
+        unsafe { self.buffer_from_raw(buf.as_ptr().cast_mut(), buf.len()) }
+    }
+}
+
+impl<'a, T> EncoderDecoderBuilder<NativePacketDecoder<'a, T>>
+where
+    T: 'static,
+{
+    /// Turn itself into a packet decoder using `callback` for unknown packets.
+    ///
+    /// See `EncoderDecoderBuilder::<PacketDecoder>::build_with_unknown_callback()`.
+    pub fn build_with_unknown_callback<F>(
+        &self,
+        callback: F,
+    ) -> Result<NativePacketDecoder<'a, T>, PtError>
+    where
+        F: FnMut(&[u8]) -> Option<(T, usize)> + 'static,
+    {
+        let mut decoder = NativePacketDecoder::new_from_builder(self)?;
+        decoder.callback = Some(Box::new(callback));
+        Ok(decoder)
+    }
+}
+
+impl<T> Iterator for NativePacketDecoder<'_, T> {
+    type Item = Result<Packet<T>, PtError>;
+
+    fn next(&mut self) -> Option<Result<Packet<T>, PtError>> {
+        match self.decode_next() {
+            // eos to stop iterating
+            Err(x) if x.code() == PtErrorCode::Eos => None,
+            x => Some(x),
+        }
+    }
+}
+
+impl<T> Debug for NativePacketDecoder<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativePacketDecoder")
+            .field("len", &self.buf.len())
+            .field("pos", &self.pos)
+            .field("sync", &self.sync)
+            .finish()
+    }
+}
+
+/// Read @size little endian bytes at @off in @trace.
+fn read_le(trace: &[u8], off: usize, size: usize) -> Result<u64, PtError> {
+    let bytes = trace
+        .get(off..off + size)
+        .ok_or(PtError::from(PtErrorCode::Eos))?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, &b| (acc << 8) | u64::from(b)))
+}
+
+/// Split a raw TNT payload into the payload without the stop bit and its bit size.
+fn split_tnt(raw: u64) -> Result<(u64, u8), PtError> {
+    if raw == 0 {
+        return Err(PtErrorCode::BadPacket.into());
+    }
+    let bitsize = 63 - raw.leading_zeros();
+    Ok((raw ^ (1 << bitsize), bitsize as u8))
+}
+
+fn decode_mode<T>(trace: &[u8]) -> Result<Packet<T>, PtError> {
+    let bits = read_le(trace, 1, 1)? as u32;
+    let payload = match bits >> 5 {
+        0 => Payload::Exec(Exec::from_bits_truncate(bits)),
+        1 => Payload::Tsx(Tsx::from_bits_truncate(bits)),
+        _ => return Err(PtErrorCode::BadPacket.into()),
+    };
+    Ok(Packet::Mode(Mode::new(payload)))
+}
+
+fn decode_cyc<T>(trace: &[u8]) -> Result<(Packet<T>, usize), PtError> {
+    let mut value = u64::from(trace[0] >> 3);
+    let mut ext = trace[0] & 0x04 != 0;
+    let mut size = 1;
+    let mut shift = 5;
+    while ext {
+        let byte = *trace.get(size).ok_or(PtError::from(PtErrorCode::Eos))?;
+        if shift >= 64 {
+            return Err(PtErrorCode::BadPacket.into());
+        }
+        value |= u64::from(byte >> 1) << shift;
+        shift += 7;
+        ext = byte & 0x01 != 0;
+        size += 1;
+    }
+    Ok((Packet::Cyc(Cyc::new(value)), size))
+}
+
+fn decode_ip<T>(trace: &[u8]) -> Result<(Packet<T>, usize), PtError> {
+    let opc = trace[0];
+    let compression =
+        Compression::try_from(u32::from(opc >> 5)).map_err(|_| PtErrorCode::BadPacket)?;
+    let size = match compression {
+        Compression::Suppressed => 0,
+        Compression::Update16 => 2,
+        Compression::Update32 => 4,
+        Compression::Sext48 | Compression::Update48 => 6,
+        Compression::Full => 8,
+    };
+    let ip = read_le(trace, 1, size)?;
+    let packet = match opc & 0x1f {
+        0x01 => Packet::TipPgd(TipPgd::new(ip, compression)),
+        0x0d => Packet::Tip(Tip::new(ip, compression)),
+        0x11 => Packet::TipPge(TipPge::new(ip, compression)),
+        _ => Packet::Fup(Fup::new(ip, compression)),
+    };
+    Ok((packet, 1 + size))
+}
+
+/// Decode an extended packet, returns `None` if the extended opcode is unknown.
+fn decode_ext<T>(trace: &[u8]) -> Result<Option<(Packet<T>, usize)>, PtError> {
+    let ext = read_le(trace, 1, 1)? as u8;
+    let decoded = match ext {
+        0x82 => {
+            if trace.len() < PSB_SIZE {
+                return Err(PtErrorCode::Eos.into());
+            }
+            if !trace[..PSB_SIZE].chunks_exact(2).all(|p| p == PSB_PATTERN) {
+                return Err(PtErrorCode::BadPacket.into());
+            }
+            (Packet::Psb(Psb::new()), PSB_SIZE)
+        }
+        0x23 => (Packet::Psbend(Psbend::new()), 2),
+        0xf3 => (Packet::Ovf(Ovf::new()), 2),
+        0x83 => (Packet::Stop(Stop::new()), 2),
+        0x62 => (Packet::Exstop(Exstop::new(false)), 2),
+        0xe2 => (Packet::Exstop(Exstop::new(true)), 2),
+        0x03 => {
+            let ratio = read_le(trace, 2, 2)? as u8;
+            (Packet::Cbr(Cbr::new(ratio)), 4)
+        }
+        0x43 => {
+            let payload = read_le(trace, 2, 6)?;
+            let cr3 = (payload >> 1) << 5;
+            (Packet::Pip(Pip::new(cr3, payload & 0x01 != 0)), 8)
+        }
+        0xa3 => {
+            let (payload, bitsize) = split_tnt(read_le(trace, 2, 6)?)?;
+            (Packet::Tnt64(Tnt64::new(payload, bitsize)), 8)
+        }
+        0x73 => {
+            let ctc = read_le(trace, 2, 2)? as u16;
+            let fc = read_le(trace, 5, 2)? as u16 & 0x1ff;
+            (Packet::Tma(Tma::new(ctc, fc)), 7)
+        }
+        0xc8 => {
+            let base = read_le(trace, 2, 5)? << 12;
+            (Packet::Vmcs(Vmcs::new(base)), 7)
+        }
+        0xc2 => {
+            let hints = read_le(trace, 2, 4)? as u32;
+            let ext = read_le(trace, 6, 4)? as u32;
+            (Packet::Mwait(Mwait::new(ext, hints)), 10)
+        }
+        0x22 => {
+            let payload = read_le(trace, 2, 2)?;
+            let state = ((payload >> 12) & 0xf) as u8;
+            let substate = ((payload >> 8) & 0xf) as u8;
+            (
+                Packet::Pwre(Pwre::new(state, substate, payload & 0x08 != 0)),
+                4,
+            )
+        }
+        0xa2 => {
+            let payload = read_le(trace, 2, 5)?;
+            let packet = Pwrx::new(
+                ((payload >> 4) & 0xf) as u8,
+                (payload & 0xf) as u8,
+                payload & 0x100 != 0,
+                payload & 0x400 != 0,
+                payload & 0x800 != 0,
+            );
+            (Packet::Pwrx(packet), 7)
+        }
+        EXT_EXT2 => match read_le(trace, 2, 1)? {
+            0x88 => (Packet::Mnt(Mnt::new(read_le(trace, 3, 8)?)), 11),
+            _ => return Ok(None),
+        },
+        x if x & 0x1f == 0x12 => {
+            let plc = (x >> 5) & 0x03;
+            let size = match plc {
+                0 => 4,
+                1 => 8,
+                _ => return Err(PtErrorCode::BadPacket.into()),
+            };
+            let payload = read_le(trace, 2, size)?;
+            (Packet::Ptw(Ptw::new(payload, plc, x & 0x80 != 0)), 2 + size)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(decoded))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_native_pktdec_sync() {
+        // The decoding is checked against libipt in `integration_native_decoding.rs`.
+        let mut buf = PSB_PATTERN.repeat(PSB_SIZE / 2);
+        buf.extend_from_slice(&[0x02, 0x23, 0x00, 0x59, 0x07]);
+        let mut rust = NativePacketDecoder::<()>::builder()
+            .buffer(&buf)
+            .build()
+            .unwrap();
+
+        assert!(rust.offset().is_err());
+        assert!(rust.sync_offset().is_err());
+        assert_eq!(rust.decode_next().unwrap_err().code(), PtErrorCode::Nosync);
+
+        rust.sync_forward().unwrap();
+        assert_eq!(rust.offset().unwrap(), 0);
+        assert!(matches!(rust.decode_next().unwrap(), Packet::Psb(_)));
+        assert!(matches!(rust.decode_next().unwrap(), Packet::Psbend(_)));
+        assert_eq!(rust.offset().unwrap(), 18);
+        assert_eq!(rust.sync_forward().unwrap_err().code(), PtErrorCode::Eos);
+
+        rust.sync_set(19).unwrap();
+        assert_eq!(rust.sync_offset().unwrap(), 19);
+        match rust.decode_next().unwrap() {
+            Packet::Mtc(mtc) => assert_eq!(mtc.ctc(), 7),
+            x => panic!("unexpected packet {x:?}"),
+        }
+        assert!(rust.next().is_none());
+        assert!(rust.sync_set(buf.len() as u64 + 1).is_err());
+
+        assert!(NativePacketDecoder::<()>::builder().build().is_err());
+    }
+
+    #[test]
+    fn test_native_pktdec_psb_runs() {
+        // Back to back PSBs, after a TSC ending like the PSB pattern.
+        let mut buf = vec![0x19, 0, 0, 0, 0, 0, 0x02, 0x82];
+        buf.extend_from_slice(&PSB_PATTERN.repeat(PSB_SIZE));
+        buf.extend_from_slice(&[0x02, 0x23]);
+        let mut rust = NativePacketDecoder::<()>::builder()
+            .buffer(&buf)
+            .build()
+            .unwrap();
+
+        // Only the last PSB of the run is a synchronization point.
+        rust.sync_forward().unwrap();
+        assert_eq!(rust.offset().unwrap(), 24);
+        assert!(rust.sync_forward().is_err());
+        assert!(rust.sync_backward().is_err());
+
+        rust.sync_set(buf.len() as u64).unwrap();
+        rust.sync_backward().unwrap();
+        assert_eq!(rust.offset().unwrap(), 24);
+        assert!(rust.sync_backward().is_err());
+    }
+
+    #[test]
+    fn test_native_pktdec_truncated() {
+        let buf = [0x19, 1, 2];
+        let mut rust = NativePacketDecoder::<()>::builder()
+            .buffer(&buf)
+            .build()
+            .unwrap();
+        rust.sync_set(0).unwrap();
+        assert_eq!(rust.decode_next().unwrap_err().code(), PtErrorCode::Eos);
+    }
+
+    #[test]
+    fn test_native_pktdec_unknown() {
+        let buf = [0x02, 0x33, 0x07, 0x00];
+        let builder = NativePacketDecoder::<u8>::builder().buffer(&buf);
+        let mut rust = builder.build().unwrap();
+        rust.sync_set(0).unwrap();
+        assert_eq!(rust.decode_next().unwrap_err().code(), PtErrorCode::BadOpc);
+
+        let mut rust = builder
+            .build_with_unknown_callback(|trace| Some((trace[2], 3)))
+            .unwrap();
+        rust.sync_set(0).unwrap();
+        match rust.decode_next().unwrap() {
+            Packet::Unknown(u) => assert_eq!(u.data(), Some(7)),
+            x => panic!("unexpected packet {x:?}"),
+        }
+        assert!(matches!(rust.decode_next().unwrap(), Packet::Pad(_)));
+    }
+}
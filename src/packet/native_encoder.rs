@@ -0,0 +1,218 @@
+use super::*;
+use crate::error::{PtError, PtErrorCode, write_sink};
+use std::io::Write;
+
+/// Size of a PSB packet.
+const PSB_SIZE: usize = 16;
+/// Maximum size of a CYC packet.
+const CYC_MAX_SIZE: usize = 15;
+
+/// A pure Rust Intel PT packet encoder.
+///
+/// The output is byte-identical to the one of the libipt based `Encoder`, but packets are
+/// written to any `io::Write` sink, e.g. a `Vec<u8>` or a file.
+/// The encoder doesn't call into libipt, but it takes libipt's packet types: the
+/// `pure-rust-packets` feature doesn't remove the dependency on `libipt-sys`.
+#[derive(Debug)]
+pub struct NativeEncoder<W> {
+    sink: W,
+    offset: u64,
+}
+
+impl<W> NativeEncoder<W>
+where
+    W: Write,
+{
+    /// Create an encoder writing to @sink.
+    pub fn new(sink: W) -> Self {
+        Self { sink, offset: 0 }
+    }
+
+    /// Get the current packet encoder position.
+    ///
+    /// This is the number of bytes written to the sink since the encoder was created.
+    #[must_use]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Encode an Intel PT packet.
+    ///
+    /// Writes @packet to the sink and advances the encoder beyond the written packet.
+    /// In case of errors, nothing is written.
+    /// Returns the number of bytes written on success, a PtError otherwise
+    /// Returns BadOpc if @packet.type is not known.
+    /// Returns BadPacket if @packet's payload is invalid.
+    /// Returns BadFile if writing to the sink fails.
+    pub fn next(&mut self, pck: impl Into<pt_packet>) -> Result<u32, PtError> {
+        let mut buf = Vec::with_capacity(PSB_SIZE);
+        let size = encode_packet(pck, &mut buf)?;
+        write_sink(&mut self.sink, &buf)?;
+        self.offset += u64::from(size);
+        Ok(size)
+    }
+
+    /// Get a reference to the sink.
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    /// Consume the encoder, returning the sink.
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+}
+
+/// Encode @packet, appending it to @out.
+///
+/// Returns the number of bytes appended on success.
+/// In case of errors, @out is left untouched.
+/// Returns BadOpc if @packet.type is not known.
+/// Returns BadPacket if @packet's payload is invalid.
+pub fn encode_packet(pck: impl Into<pt_packet>, out: &mut Vec<u8>) -> Result<u32, PtError> {
+    let pck = pck.into();
+    // Converting an unknown packet would take ownership of its (untyped) decoder data.
+    if pck.type_ == PT_PACKET_TYPE_PPT_UNKNOWN || pck.type_ == PT_PACKET_TYPE_PPT_INVALID {
+        return Err(PtErrorCode::BadOpc.into());
+    }
+
+    let start = out.len();
+    encode(Packet::<()>::from(pck), out).inspect_err(|_| out.truncate(start))?;
+    Ok((out.len() - start) as u32)
+}
+
+fn encode(packet: Packet<()>, out: &mut Vec<u8>) -> Result<(), PtError> {
+    match packet {
+        Packet::Invalid(_) | Packet::Unknown(_) => unreachable!(),
+        Packet::Pad(_) => out.push(0x00),
+        Packet::Psb(_) => out.extend_from_slice(&[0x02, 0x82].repeat(PSB_SIZE / 2)),
+        Packet::Psbend(_) => out.extend_from_slice(&[0x02, 0x23]),
+        Packet::Ovf(_) => out.extend_from_slice(&[0x02, 0xf3]),
+        Packet::Stop(_) => out.extend_from_slice(&[0x02, 0x83]),
+        Packet::Fup(p) => encode_ip(0x1d, p.fup(), p.compression(), out),
+        Packet::Tip(p) => encode_ip(0x0d, p.tip(), p.compression(), out),
+        Packet::TipPge(p) => encode_ip(0x11, p.tippge(), p.compression(), out),
+        Packet::TipPgd(p) => encode_ip(0x01, p.tippgd(), p.compression(), out),
+        Packet::Tnt8(p) => {
+            if p.bitsize() >= 7 {
+                return Err(PtErrorCode::BadPacket.into());
+            }
+            out.push((p.payload() << 1) | (1 << (p.bitsize() + 1)));
+        }
+        Packet::Tnt64(p) => {
+            if p.bitsize() >= 47 {
+                return Err(PtErrorCode::BadPacket.into());
+            }
+            out.extend_from_slice(&[0x02, 0xa3]);
+            push_le(out, p.payload() | (1 << p.bitsize()), 6);
+        }
+        Packet::Mode(p) => {
+            let (leaf, bits) = match p.payload() {
+                Payload::Exec(e) => (0, e.bits()),
+                Payload::Tsx(t) => (1, t.bits()),
+            };
+            out.extend_from_slice(&[0x99, (leaf << 5) | bits as u8]);
+        }
+        Packet::Pip(p) => {
+            out.extend_from_slice(&[0x02, 0x43]);
+            push_le(out, ((p.cr3() >> 5) << 1) | u64::from(p.nr()), 6);
+        }
+        Packet::Vmcs(p) => {
+            out.extend_from_slice(&[0x02, 0xc8]);
+            push_le(out, p.base() >> 12, 5);
+        }
+        Packet::Cbr(p) => out.extend_from_slice(&[0x02, 0x03, p.ratio(), 0x00]),
+        Packet::Tsc(p) => {
+            out.push(0x19);
+            push_le(out, p.tsc(), 7);
+        }
+        Packet::Tma(p) => {
+            if p.fc() & !0x1ff != 0 {
+                return Err(PtErrorCode::BadPacket.into());
+            }
+            out.extend_from_slice(&[0x02, 0x73]);
+            push_le(out, u64::from(p.ctc()), 2);
+            out.push(0x00);
+            push_le(out, u64::from(p.fc()), 2);
+        }
+        Packet::Mtc(p) => out.extend_from_slice(&[0x59, p.ctc()]),
+        Packet::Cyc(p) => encode_cyc(p.value(), out)?,
+        Packet::Mnt(p) => {
+            out.extend_from_slice(&[0x02, 0xc3, 0x88]);
+            push_le(out, p.payload(), 8);
+        }
+        Packet::Exstop(p) => out.extend_from_slice(&[0x02, if p.ip() { 0xe2 } else { 0x62 }]),
+        Packet::Mwait(p) => {
+            out.extend_from_slice(&[0x02, 0xc2]);
+            push_le(out, u64::from(p.hints()), 4);
+            push_le(out, u64::from(p.ext()), 4);
+        }
+        Packet::Pwre(p) => {
+            let payload = (u64::from(p.state() & 0xf) << 12)
+                | (u64::from(p.substate() & 0xf) << 8)
+                | (u64::from(p.hw()) << 3);
+            out.extend_from_slice(&[0x02, 0x22]);
+            push_le(out, payload, 2);
+        }
+        Packet::Pwrx(p) => {
+            let payload = (u64::from(p.last() & 0xf) << 4)
+                | u64::from(p.deepest() & 0xf)
+                | (u64::from(p.interrupt()) << 8)
+                | (u64::from(p.store()) << 10)
+                | (u64::from(p.autonomous()) << 11);
+            out.extend_from_slice(&[0x02, 0xa2]);
+            push_le(out, payload, 5);
+        }
+        Packet::Ptw(p) => {
+            let size = match p.plc() {
+                0 => 4,
+                1 => 8,
+                _ => return Err(PtErrorCode::BadPacket.into()),
+            };
+            out.extend_from_slice(&[0x02, 0x12 | (p.plc() << 5) | (u8::from(p.ip()) << 7)]);
+            push_le(out, p.payload(), size);
+        }
+    }
+    Ok(())
+}
+
+/// Append the @size least significant bytes of @value to @out, in little endian order.
+fn push_le(out: &mut Vec<u8>, value: u64, size: usize) {
+    out.extend_from_slice(&value.to_le_bytes()[..size]);
+}
+
+fn encode_ip(opc: u8, ip: u64, compression: Compression, out: &mut Vec<u8>) {
+    let size = match compression {
+        Compression::Suppressed => 0,
+        Compression::Update16 => 2,
+        Compression::Update32 => 4,
+        Compression::Sext48 | Compression::Update48 => 6,
+        Compression::Full => 8,
+    };
+    out.push(opc | ((u32::from(compression) as u8) << 5));
+    push_le(out, ip, size);
+}
+
+fn encode_cyc(value: u64, out: &mut Vec<u8>) -> Result<(), PtError> {
+    let mut bytes = [0u8; CYC_MAX_SIZE];
+    bytes[0] = 0x03 | ((value as u8) << 3);
+    let mut ctc = value >> 5;
+    if ctc != 0 {
+        bytes[0] |= 0x04;
+    }
+
+    let mut end = 1;
+    while ctc != 0 {
+        if end >= CYC_MAX_SIZE {
+            return Err(PtErrorCode::BadPacket.into());
+        }
+        bytes[end] = ((ctc & 0x7f) as u8) << 1;
+        ctc >>= 7;
+        if ctc != 0 {
+            bytes[end] |= 0x01;
+        }
+        end += 1;
+    }
+    out.extend_from_slice(&bytes[..end]);
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use crate::error::{PtError, PtErrorCode, ensure_ptok, extract_pterr, write_sink};
+
+use libipt_sys::{
+    pt_alloc_encoder, pt_config, pt_enc_next, pt_enc_sync_set, pt_encoder, pt_free_encoder,
+    pt_packet,
+};
+use std::io::{Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::ptr::NonNull;
+
+/// Size of the scratch buffer every packet is encoded into, larger than any Intel PT packet.
+const SCRATCH_SIZE: usize = 32;
+
+/// An Intel PT packet encoder writing to a growable sink.
+///
+/// Unlike `Encoder`, which works on a fixed buffer and fails once it is full, the
+/// `StreamEncoder` writes every packet to an `io::Write` sink.
+/// Use a `Cursor<Vec<u8>>` to get a `Vec<u8>` growing automatically, or a file, a socket, ...
+/// to flush the trace as it is being encoded.
+///
+/// Built with `EncoderDecoderBuilder<Encoder>::build_stream()`.
+#[derive(Debug)]
+pub struct StreamEncoder<W> {
+    inner: NonNull<pt_encoder>,
+    // The buffer libipt encodes into, freed on drop, after `inner`.
+    scratch: NonNull<[u8; SCRATCH_SIZE]>,
+    sink: W,
+    offset: u64,
+    end: u64,
+}
+
+impl<W> StreamEncoder<W>
+where
+    W: Write,
+{
+    /// Allocate an Intel PT packet encoder writing to @sink.
+    ///
+    /// The buffer set in @config, if any, is ignored.
+    pub(crate) fn new(config: &pt_config, sink: W) -> Result<Self, PtError> {
+        let scratch = NonNull::from(Box::leak(Box::new([0u8; SCRATCH_SIZE])));
+
+        let mut config = *config;
+        config.begin = scratch.as_ptr().cast();
+        config.end = unsafe { config.begin.add(SCRATCH_SIZE) };
+        config.decode.callback = None;
+        config.decode.context = std::ptr::null_mut();
+
+        match NonNull::new(unsafe { pt_alloc_encoder(&raw const config) }) {
+            Some(inner) => Ok(Self {
+                inner,
+                scratch,
+                sink,
+                offset: 0,
+                end: 0,
+            }),
+            None => {
+                drop(unsafe { Box::from_raw(scratch.as_ptr()) });
+                Err(PtError::new(
+                    PtErrorCode::Internal,
+                    "Failed to allocate pt_encoder",
+                ))
+            }
+        }
+    }
+
+    /// Get the current packet encoder position.
+    ///
+    /// This is the offset relative to the sink position when the encoder was created.
+    #[must_use]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Encode an Intel PT packet.
+    ///
+    /// Writes @packet at the encoder's current position in the sink and advances the encoder
+    /// beyond the written packet.
+    /// In case of encoding errors, the encoder is not advanced and nothing is written.
+    /// Returns the number of bytes written on success, a PtError otherwise
+    /// Returns BadOpc if @packet.type is not known.
+    /// Returns BadPacket if @packet's payload is invalid.
+    /// Returns BadFile if writing to the sink fails.
+    pub fn next(&mut self, pck: impl Into<pt_packet>) -> Result<u32, PtError> {
+        ensure_ptok(unsafe { pt_enc_sync_set(self.inner.as_ptr(), 0) })?;
+        let size = extract_pterr(unsafe { pt_enc_next(self.inner.as_ptr(), &pck.into()) })?;
+
+        let scratch = unsafe { self.scratch.as_ref() };
+        write_sink(&mut self.sink, &scratch[..size as usize])?;
+
+        self.offset += u64::from(size);
+        self.end = self.end.max(self.offset);
+        Ok(size)
+    }
+
+    /// Flush the sink.
+    ///
+    /// Returns BadFile if flushing the sink fails.
+    pub fn flush(&mut self) -> Result<(), PtError> {
+        self.sink
+            .flush()
+            .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to flush the sink"))
+    }
+
+    /// Get a reference to the sink.
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    /// Consume the encoder, returning the sink.
+    pub fn into_inner(self) -> W {
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            this.free();
+            std::ptr::read(&this.sink)
+        }
+    }
+}
+
+impl<W> StreamEncoder<W>
+where
+    W: Write + Seek,
+{
+    /// Hard set synchronization point of an Intel PT packet encoder.
+    ///
+    /// Move the encoder to @offset within the trace written so far, subsequent packets
+    /// overwrite the existing trace.
+    /// Returns () on success, a PtError otherwise.
+    /// Returns Eos if the given offset is behind the end of the trace written so far.
+    /// Returns BadFile if seeking the sink fails.
+    pub fn set_offset(&mut self, offset: u64) -> Result<(), PtError> {
+        if offset > self.end {
+            return Err(PtErrorCode::Eos.into());
+        }
+
+        let delta = offset as i64 - self.offset as i64;
+        self.sink
+            .seek(SeekFrom::Current(delta))
+            .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to seek the sink"))?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+impl<W> StreamEncoder<W> {
+    /// Free the libipt encoder and then its scratch buffer.
+    ///
+    /// # Safety
+    /// Must be called exactly once.
+    unsafe fn free(&self) {
+        unsafe {
+            pt_free_encoder(self.inner.as_ptr());
+            drop(Box::from_raw(self.scratch.as_ptr()));
+        }
+    }
+}
+
+impl<W> Drop for StreamEncoder<W> {
+    fn drop(&mut self) {
+        unsafe { self.free() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::enc_dec_builder::PtEncoderDecoder;
+    use crate::packet::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_streamenc_grows() {
+        let mut enc = Encoder::<()>::builder()
+            .build_stream(Cursor::new(Vec::new()))
+            .unwrap();
+        assert_eq!(enc.offset(), 0);
+
+        for _ in 0..100 {
+            assert_eq!(enc.next(Psb::new()).unwrap(), 16);
+        }
+        assert_eq!(enc.offset(), 1600);
+        assert_eq!(enc.into_inner().into_inner().len(), 1600);
+    }
+
+    #[test]
+    fn test_streamenc_set_offset() {
+        let mut enc = Encoder::<()>::builder()
+            .build_stream(Cursor::new(Vec::new()))
+            .unwrap();
+
+        enc.next(Pad::new()).unwrap();
+        enc.next(Pad::new()).unwrap();
+        enc.next(Pad::new()).unwrap();
+        assert!(enc.set_offset(4).is_err());
+
+        enc.set_offset(1).unwrap();
+        assert_eq!(enc.offset(), 1);
+        enc.next(Mtc::new(0x42)).unwrap();
+        assert_eq!(enc.offset(), 3);
+        assert!(enc.next(Tnt8::new(0, 7)).is_err());
+        assert_eq!(enc.offset(), 3);
+
+        assert_eq!(enc.into_inner().into_inner(), [0x00, 0x59, 0x42]);
+    }
+}
@@ -60,13 +60,13 @@ impl Tnt64 {
     }
     /// TNT payload excluding stop bit
     #[inline]
-    pub fn payload(self) -> u8 {
-        self.0.payload as u8
+    pub fn payload(self) -> u64 {
+        self.0.payload
     }
     /// TNT payload excluding stop bit
     #[inline]
-    pub fn set_payload(&mut self, payload: u8) {
-        self.0.payload = payload as u64
+    pub fn set_payload(&mut self, payload: u64) {
+        self.0.payload = payload
     }
 }
 
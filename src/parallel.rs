@@ -0,0 +1,443 @@
+use crate::block::Block;
+use crate::enc_dec_builder::{EncoderDecoderBuilder, PtDecoder, PtEncoderDecoder};
+use crate::error::{PtError, PtErrorCode};
+use crate::image::Image;
+use crate::insn::Insn;
+use crate::merge::{Decoded, Lane, TraceDecoder};
+use crate::packet::PacketDecoder;
+use libipt_sys::pt_config;
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError, mpsc};
+use std::thread;
+
+/// The instruction addresses covered by a decoded item, used to stitch chunks together.
+pub trait IpRange {
+    /// The IP of the first instruction.
+    fn ip(&self) -> u64;
+
+    /// The IP of the last instruction.
+    fn end_ip(&self) -> u64;
+}
+
+impl IpRange for Insn {
+    fn ip(&self) -> u64 {
+        Insn::ip(*self)
+    }
+
+    fn end_ip(&self) -> u64 {
+        Insn::ip(*self)
+    }
+}
+
+impl IpRange for Block {
+    fn ip(&self) -> u64 {
+        Block::ip(self)
+    }
+
+    fn end_ip(&self) -> u64 {
+        Block::end_ip(self)
+    }
+}
+
+/// Find the offsets of all the PSB packets in @trace.
+///
+/// Returns `BadOpc` or `BadPacket` if @trace contains unknown packets.
+pub fn psb_offsets(trace: &[u8]) -> Result<Vec<u64>, PtError> {
+    if trace.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut decoder = PacketDecoder::<()>::builder().buffer(trace).build()?;
+    let mut offsets = Vec::new();
+    loop {
+        match decoder.sync_forward() {
+            Ok(()) => offsets.push(decoder.sync_offset()?),
+            Err(e) if e.code() == PtErrorCode::Eos => return Ok(offsets),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Split @trace in at most @chunks chunks of similar size, each starting with a PSB.
+///
+/// The trace before the first PSB can't be decoded and is not part of any chunk.
+/// Returns `BadOpc` or `BadPacket` if @trace contains unknown packets.
+pub fn split_at_psb(trace: &[u8], chunks: NonZeroUsize) -> Result<Vec<Range<usize>>, PtError> {
+    let offsets = psb_offsets(trace)?;
+    let Some(&first) = offsets.first() else {
+        return Ok(Vec::new());
+    };
+
+    let first = first as usize;
+    let target = (trace.len() - first).div_ceil(chunks.get()).max(1);
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut start = first;
+    for offset in offsets.into_iter().skip(1).map(|o| o as usize) {
+        if offset - start >= target {
+            ranges.push(start..offset);
+            start = offset;
+        }
+    }
+    ranges.push(start..trace.len());
+    Ok(ranges)
+}
+
+/// The configuration of the chunk decoders, shared with the worker threads.
+struct ChunkConfig(pt_config);
+
+// SAFETY: `ChunkConfig::new()` clears the only pointers of the configuration, the trace buffer and
+// the unknown packet callback, the rest is plain data.
+unsafe impl Sync for ChunkConfig {}
+
+impl ChunkConfig {
+    fn new<T>(builder: &EncoderDecoderBuilder<T>) -> Self
+    where
+        T: PtDecoder,
+    {
+        // Clones don't carry the unknown packet callback.
+        let mut config = builder.clone().config;
+        config.begin = ptr::null_mut();
+        config.end = ptr::null_mut();
+        Self(config)
+    }
+
+    /// A builder for a decoder of @chunk.
+    ///
+    /// # Safety
+    /// @chunk must outlive the decoders built with the returned builder.
+    unsafe fn builder<T>(&self, chunk: &[u8]) -> EncoderDecoderBuilder<T>
+    where
+        T: PtEncoderDecoder,
+    {
+        let mut builder = EncoderDecoderBuilder::new();
+        builder.config = self.0;
+        // SAFETY: the caller guarantees @chunk outlives the decoders, and decoders never write
+        // to their buffer.
+        unsafe { builder.buffer_from_raw(chunk.as_ptr().cast_mut(), chunk.len()) }
+    }
+}
+
+/// How far the results have been passed to the sink, workers wait for the sink to catch up.
+#[derive(Default)]
+struct Progress {
+    /// The number of chunks passed to the sink, and whether decoding has been aborted.
+    state: Mutex<(usize, bool)>,
+    changed: Condvar,
+}
+
+impl Progress {
+    /// Wait until the chunk @index is less than @window chunks ahead of the sink.
+    ///
+    /// Returns false if decoding has been aborted.
+    fn wait(&self, index: usize, window: usize) -> bool {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = self
+            .changed
+            .wait_while(state, |(done, aborted)| {
+                !*aborted && index >= done.saturating_add(window)
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        !state.1
+    }
+
+    fn update(&self, update: impl FnOnce(&mut (usize, bool))) {
+        update(&mut self.state.lock().unwrap_or_else(PoisonError::into_inner));
+        self.changed.notify_all();
+    }
+}
+
+/// Aborts decoding when dropped, so that the workers don't wait for a sink that is gone.
+struct AbortOnDrop<'p>(&'p Progress);
+
+impl Drop for AbortOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.update(|(_, aborted)| *aborted = true);
+    }
+}
+
+/// Decode a trace on multiple threads, splitting it at PSB boundaries.
+///
+/// Each chunk of the trace is decoded by its own decoder, on a pool of worker threads, and the
+/// results are stitched back together in trace order.
+///
+/// A chunk ends where the trace needed to decode further is in the next chunk, while the next chunk
+/// resumes at the IP of its PSB+, which is usually before that point: the instructions in between
+/// are decoded twice. They are kept from the next chunk only. When the overlap starts inside a
+/// block, the block of the first chunk is kept and the first block of the next chunk is dropped
+/// if it ends at the same instruction.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParallelDecoder {
+    chunks: Option<NonZeroUsize>,
+    threads: Option<NonZeroUsize>,
+}
+
+impl ParallelDecoder {
+    /// Create a parallel decoder using all the available cores.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            chunks: None,
+            threads: None,
+        }
+    }
+
+    /// The number of chunks the trace is split in, 4 per thread by default.
+    #[must_use]
+    pub const fn chunks(mut self, chunks: NonZeroUsize) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// The number of worker threads, `std::thread::available_parallelism()` by default.
+    #[must_use]
+    pub const fn threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Decode @trace with decoders built from @builder, passing the results to @sink in trace order.
+    ///
+    /// The buffer of @builder is ignored. @image is called on the worker threads to create the
+    /// image of each chunk decoder, given the chunk index. Use a `SharedSectionCache` to map the
+    /// traced binaries only once for all the chunks.
+    /// @sink is called on the calling thread with the results of each chunk, in order, once the
+    /// overlap with the next chunk has been removed. The workers don't get more than two chunks
+    /// per thread ahead of @sink: the results are not kept for the whole trace.
+    /// Decoding errors are part of the output, after an error the decoder resynchronizes at the
+    /// next PSB.
+    /// Returns an error if a decoder or an image can't be created, the chunks after it are not
+    /// passed to @sink.
+    pub fn decode<T, F, S>(
+        &self,
+        builder: &EncoderDecoderBuilder<T>,
+        trace: &[u8],
+        image: F,
+        mut sink: S,
+    ) -> Result<(), PtError>
+    where
+        T: PtDecoder + TraceDecoder,
+        T::Item: IpRange + Send,
+        F: Fn(usize) -> Result<Image, PtError> + Sync,
+        S: FnMut(Vec<Result<Decoded<T::Item>, PtError>>),
+    {
+        let threads = self
+            .threads
+            .or_else(|| thread::available_parallelism().ok())
+            .unwrap_or(NonZeroUsize::MIN);
+        let chunks = self
+            .chunks
+            .unwrap_or(threads.saturating_mul(NonZeroUsize::new(4).unwrap()));
+        let ranges = split_at_psb(trace, chunks)?;
+        let window = threads.get().saturating_mul(2);
+
+        let config = ChunkConfig::new(builder);
+        let next_chunk = AtomicUsize::new(0);
+        let progress = Progress::default();
+        let (sender, receiver) = mpsc::channel();
+        let worker = |sender: mpsc::Sender<_>| loop {
+            let index = next_chunk.fetch_add(1, Ordering::Relaxed);
+            let Some(range) = ranges.get(index) else {
+                return;
+            };
+            if !progress.wait(index, window) {
+                return;
+            }
+            let chunk = &trace[range.clone()];
+            // SAFETY: @chunk outlives the decoder, which is dropped by `decode_chunk()`.
+            let builder = unsafe { config.builder::<T>(chunk) };
+            let decoded = Self::decode_chunk(builder, || image(index));
+            if sender.send((index, decoded)).is_err() {
+                return;
+            }
+        };
+
+        thread::scope(|s| {
+            let _abort = AbortOnDrop(&progress);
+            for _ in 0..threads.get().min(ranges.len()) {
+                let sender = sender.clone();
+                s.spawn(|| worker(sender));
+            }
+            drop(sender);
+
+            // Reorder the chunks, and hold each one until the overlap with the next is removed.
+            let mut pending = BTreeMap::new();
+            let mut previous = None;
+            let mut next = 0;
+            for (index, decoded) in receiver {
+                pending.insert(index, decoded);
+                while let Some(decoded) = pending.remove(&next) {
+                    let mut decoded = decoded?;
+                    if let Some(mut output) = previous.take() {
+                        stitch(&mut output, &mut decoded);
+                        sink(output);
+                    }
+                    previous = Some(decoded);
+                    next += 1;
+                    progress.update(|(done, _)| *done = next);
+                }
+            }
+            if let Some(output) = previous {
+                sink(output);
+            }
+            Ok(())
+        })
+    }
+
+    fn decode_chunk<T, F>(
+        builder: EncoderDecoderBuilder<T>,
+        image: F,
+    ) -> Result<Vec<Result<Decoded<T::Item>, PtError>>, PtError>
+    where
+        T: PtDecoder + TraceDecoder,
+        F: FnOnce() -> Result<Image, PtError>,
+    {
+        let mut decoder = builder.build()?;
+        decoder.image().extend(&image()?)?;
+
+        let mut lane = Lane::new(decoder);
+        let mut decoded = Vec::new();
+        while let Some(result) = lane.fetch() {
+            decoded.push(result.map(|(_, item)| item));
+        }
+        Ok(decoded)
+    }
+}
+
+/// The instructions or blocks of @results, without events and errors.
+fn items<T>(
+    results: &[Result<Decoded<T>, PtError>],
+) -> impl DoubleEndedIterator<Item = (usize, &T)> {
+    results
+        .iter()
+        .enumerate()
+        .filter_map(|(i, result)| match result {
+            Ok(Decoded::Item(item)) => Some((i, item)),
+            _ => None,
+        })
+}
+
+/// Remove the overlap between @output and the results of the next chunk @next.
+///
+/// The overlap is the tail of @output decoding the same code as the beginning of @next, starting
+/// with the first item of @next.
+fn stitch<T: IpRange>(
+    output: &mut Vec<Result<Decoded<T>, PtError>>,
+    next: &mut Vec<Result<Decoded<T>, PtError>>,
+) {
+    let Some((first_index, first)) = items(next).next() else {
+        return;
+    };
+    let (resume, resume_end) = (first.ip(), first.end_ip());
+
+    // Only the tail of @output after the last error can overlap.
+    let tail = output.iter().rposition(Result::is_err).map_or(0, |i| i + 1);
+    let overlap = items(&output[tail..])
+        .rfind(|(_, item)| item.ip() <= resume && resume <= item.end_ip())
+        .map(|(i, item)| (tail + i, item.ip(), item.end_ip()));
+
+    match overlap {
+        Some((start, ip, _)) if ip == resume => {
+            // The same items, up to where the trace of @output ends.
+            let same = items(&output[start..])
+                .zip(items(next))
+                .all(|((_, a), (_, b))| a.ip() == b.ip());
+            if same {
+                output.truncate(start);
+            }
+        }
+        Some((start, _, end))
+            if items(&output[start + 1..]).next().is_none() && end == resume_end =>
+        {
+            // @next resumes inside the last block of @output.
+            next.remove(first_index);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::synth::{Step, Synthesizer};
+
+    #[derive(Debug, PartialEq)]
+    struct Span(u64, u64);
+
+    impl IpRange for Span {
+        fn ip(&self) -> u64 {
+            self.0
+        }
+
+        fn end_ip(&self) -> u64 {
+            self.1
+        }
+    }
+
+    fn ranges(results: &[Result<Decoded<Span>, PtError>]) -> Vec<(u64, u64)> {
+        items(results).map(|(_, r)| (r.0, r.1)).collect()
+    }
+
+    /// Stitch @next to @output and append it.
+    fn append(output: &mut Vec<Result<Decoded<Span>, PtError>>, ranges: &[(u64, u64)]) {
+        let mut next = decoded(ranges);
+        stitch(output, &mut next);
+        output.extend(next);
+    }
+
+    fn decoded(ranges: &[(u64, u64)]) -> Vec<Result<Decoded<Span>, PtError>> {
+        ranges
+            .iter()
+            .map(|&(ip, end)| Ok(Decoded::Item(Span(ip, end))))
+            .collect()
+    }
+
+    #[test]
+    fn test_stitch_insns() {
+        let mut output = decoded(&[(1, 1), (2, 2), (3, 3), (1, 1), (2, 2)]);
+        append(&mut output, &[(1, 1), (2, 2), (3, 3), (4, 4)]);
+        assert_eq!(
+            ranges(&output),
+            [(1, 1), (2, 2), (3, 3), (1, 1), (2, 2), (3, 3), (4, 4)]
+        );
+
+        // No overlap.
+        append(&mut output, &[(8, 8)]);
+        assert_eq!(ranges(&output).len(), 8);
+    }
+
+    #[test]
+    fn test_stitch_blocks() {
+        // The next chunk resumes inside the last block.
+        let mut output = decoded(&[(0x10, 0x20), (0x30, 0x40)]);
+        append(&mut output, &[(0x38, 0x40), (0x50, 0x60)]);
+        assert_eq!(ranges(&output), [(0x10, 0x20), (0x30, 0x40), (0x50, 0x60)]);
+
+        // The next chunk resumes at the last block.
+        let mut output = decoded(&[(0x10, 0x20), (0x30, 0x38)]);
+        append(&mut output, &[(0x30, 0x40)]);
+        assert_eq!(ranges(&output), [(0x10, 0x20), (0x30, 0x40)]);
+    }
+
+    #[test]
+    fn test_split_at_psb() {
+        let trace = Synthesizer::new()
+            .steps([Step::Time(1), Step::Psb(0), Step::Time(2), Step::Psb(0)])
+            .synthesize()
+            .unwrap();
+        let offsets = psb_offsets(&trace).unwrap();
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(offsets[0], 0);
+
+        let chunks = split_at_psb(&trace, NonZeroUsize::new(16).unwrap()).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].start, offsets[1] as usize);
+        assert_eq!(chunks[2].end, trace.len());
+
+        let chunks = split_at_psb(&trace, NonZeroUsize::MIN).unwrap();
+        assert_eq!(chunks, [0..trace.len()]);
+        assert!(split_at_psb(&[], NonZeroUsize::MIN).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::enc_dec_builder::{AddrFilter, AddrFilterType, AddrFilters, Frequency};
+use crate::error::PtError;
+
+/// Indexes of the `intel_pt` private data of a `PERF_RECORD_AUXTRACE_INFO` record.
+const PRIV_PMU_TYPE: usize = 0;
+pub(super) const PRIV_TIME_SHIFT: usize = 1;
+const PRIV_TIME_MULT: usize = 2;
+const PRIV_TIME_ZERO: usize = 3;
+const PRIV_CAP_USER_TIME_ZERO: usize = 4;
+const PRIV_TSC_BIT: usize = 5;
+const PRIV_NORETCOMP_BIT: usize = 6;
+const PRIV_PER_CPU_MMAPS: usize = 9;
+const PRIV_MTC_BIT: usize = 10;
+const PRIV_MTC_FREQ_BITS: usize = 11;
+const PRIV_TSC_CTC_N: usize = 12;
+const PRIV_TSC_CTC_D: usize = 13;
+const PRIV_CYC_BIT: usize = 14;
+const PRIV_MAX_NONTURBO_RATIO: usize = 15;
+pub(super) const PRIV_FILTER_STR_LEN: usize = 16;
+/// Older perf versions don't record the MTC, CYC and filter fields.
+pub(super) const PRIV_MIN: usize = PRIV_PER_CPU_MMAPS + 1;
+
+/// The `cyc_thresh` field of the `intel_pt` PMU config, `config:19-22`.
+const CYC_THRESH_MASK: u64 = 0xf << 19;
+
+/// Extract the field selected by @mask from @config.
+fn field(config: u64, mask: u64) -> u64 {
+    if mask == 0 {
+        0
+    } else {
+        (config & mask) >> mask.trailing_zeros()
+    }
+}
+
+/// The `intel_pt` PMU configuration of a perf recording.
+///
+/// It combines the `perf_event_attr` of the `intel_pt` event with the private data of the
+/// `PERF_RECORD_AUXTRACE_INFO` record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntelPtConfig {
+    /// The dynamic PMU type of `intel_pt`
+    pub pmu_type: u32,
+    /// The `perf_event_attr.config` of the `intel_pt` event
+    pub config: u64,
+    /// Timestamp Counter (TSC) packets are enabled
+    pub tsc: bool,
+    /// Return compression is disabled
+    pub noretcomp: bool,
+    /// Mini Time Counter (MTC) packets are enabled
+    pub mtc: bool,
+    /// The MTC frequency, as defined in IA32_RTIT_CTL.MTCFreq
+    pub mtc_period: u8,
+    /// Cycle Count (CYC) packets are enabled
+    pub cyc: bool,
+    /// The CYC threshold, as defined in IA32_RTIT_CTL.CycThresh
+    pub cyc_threshold: u8,
+    /// The numerator of the TSC to Core Crystal Clock (CTC) ratio, cpuid leaf 0x15 ebx
+    pub tsc_ctc_ratio_n: u32,
+    /// The denominator of the TSC to Core Crystal Clock (CTC) ratio, cpuid leaf 0x15 eax
+    pub tsc_ctc_ratio_d: u32,
+    /// The max non-turbo ratio
+    pub max_nonturbo_ratio: u8,
+    /// The trace has been recorded with one AUX area per cpu
+    pub per_cpu_mmaps: bool,
+    /// The address filter string given to perf, e.g. `filter 0xffffffff81000000/0x1000`
+    pub filter: String,
+    time_shift: u16,
+    time_mult: u32,
+    time_zero: u64,
+    cap_user_time_zero: bool,
+}
+
+impl IntelPtConfig {
+    /// Combine the `intel_pt` @private data of a `PERF_RECORD_AUXTRACE_INFO` record with the
+    /// @config of the `intel_pt` event.
+    ///
+    /// @private must contain at least `PRIV_MIN` values.
+    pub(super) fn new(private: &[u64], config: u64, filter: String) -> Self {
+        let get = |i: usize| private.get(i).copied().unwrap_or(0);
+        let flag = |i: usize| get(i) & config != 0;
+        Self {
+            pmu_type: get(PRIV_PMU_TYPE) as u32,
+            config,
+            tsc: flag(PRIV_TSC_BIT),
+            noretcomp: flag(PRIV_NORETCOMP_BIT),
+            mtc: flag(PRIV_MTC_BIT),
+            mtc_period: field(config, get(PRIV_MTC_FREQ_BITS)) as u8,
+            cyc: flag(PRIV_CYC_BIT),
+            cyc_threshold: field(config, CYC_THRESH_MASK) as u8,
+            tsc_ctc_ratio_n: get(PRIV_TSC_CTC_N) as u32,
+            tsc_ctc_ratio_d: get(PRIV_TSC_CTC_D) as u32,
+            max_nonturbo_ratio: get(PRIV_MAX_NONTURBO_RATIO) as u8,
+            per_cpu_mmaps: get(PRIV_PER_CPU_MMAPS) != 0,
+            filter,
+            time_shift: get(PRIV_TIME_SHIFT) as u16,
+            time_mult: get(PRIV_TIME_MULT) as u32,
+            time_zero: get(PRIV_TIME_ZERO),
+            cap_user_time_zero: get(PRIV_CAP_USER_TIME_ZERO) != 0,
+        }
+    }
+
+    /// The frequency values needed to decode timing packets.
+    #[must_use]
+    pub fn frequency(&self) -> Frequency {
+        Frequency::new(
+            self.mtc_period,
+            self.max_nonturbo_ratio,
+            self.tsc_ctc_ratio_n,
+            self.tsc_ctc_ratio_d,
+        )
+    }
+
+    /// The address filters of `filter`.
+    ///
+    /// Only filters with an absolute address range (`filter`, `stop` and `tracestop` without an
+    /// object file) can be resolved without symbols, other filters are ignored.
+    /// Returns `None` if there is no such filter.
+    /// Returns BadConfig if there are more than 4 filters.
+    pub fn addr_filters(&self) -> Result<Option<AddrFilters>, PtError> {
+        let filters: Vec<AddrFilter> = self
+            .filter
+            .split(',')
+            .filter_map(parse_addr_filter)
+            .collect();
+        if filters.is_empty() {
+            return Ok(None);
+        }
+        AddrFilters::new(&filters).map(Some)
+    }
+
+    /// Convert the perf time @ns to a TSC value.
+    ///
+    /// Returns `None` if the recording doesn't provide the conversion parameters.
+    #[must_use]
+    pub fn perf_time_to_tsc(&self, ns: u64) -> Option<u64> {
+        if !self.cap_user_time_zero || self.time_mult == 0 {
+            return None;
+        }
+        let (mult, shift) = (u64::from(self.time_mult), u32::from(self.time_shift));
+        let t = ns.wrapping_sub(self.time_zero);
+        let (quot, rem) = (t / mult, t % mult);
+        Some(
+            quot.checked_shl(shift)?
+                .wrapping_add(rem.checked_shl(shift)? / mult),
+        )
+    }
+
+    /// Convert the TSC value @tsc to perf time.
+    ///
+    /// Returns `None` if the recording doesn't provide the conversion parameters.
+    #[must_use]
+    pub fn tsc_to_perf_time(&self, tsc: u64) -> Option<u64> {
+        if !self.cap_user_time_zero || self.time_mult == 0 {
+            return None;
+        }
+        let (mult, shift) = (u64::from(self.time_mult), u32::from(self.time_shift));
+        let quot = tsc.checked_shr(shift)?;
+        let rem = tsc & (1u64.checked_shl(shift)? - 1);
+        Some(
+            self.time_zero
+                .wrapping_add(quot.wrapping_mul(mult))
+                .wrapping_add(rem.wrapping_mul(mult) >> shift),
+        )
+    }
+}
+
+/// Parse a single perf address filter, `<kind> <start>[/<size>] [@<file>]`.
+fn parse_addr_filter(filter: &str) -> Option<AddrFilter> {
+    let mut tokens = filter.split_whitespace();
+    let filter_type = match tokens.next()? {
+        "filter" => AddrFilterType::FILTER,
+        "stop" | "tracestop" => AddrFilterType::STOP,
+        _ => return None,
+    };
+    let range = tokens.next()?;
+    if tokens.next().is_some() || range.contains('@') {
+        // Relative to an object file, it needs the file's load address.
+        return None;
+    }
+
+    let number = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    };
+    let (start, size) = match range.split_once('/') {
+        Some((start, size)) => (number(start)?, number(size)?),
+        None => (number(range)?, 1),
+    };
+    let end = start.checked_add(size.checked_sub(1)?)?;
+    Some(AddrFilter::new(start, end, filter_type))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(filter: &str) -> IntelPtConfig {
+        // tsc: config:10, noretcomp: config:11, mtc: config:9, mtc_period: config:14-17,
+        // cyc: config:1, as on current hardware.
+        let mut private = [0u64; 16];
+        private[PRIV_PMU_TYPE] = 8;
+        private[PRIV_TIME_SHIFT] = 10;
+        private[PRIV_TIME_MULT] = 2048;
+        private[PRIV_TIME_ZERO] = 1000;
+        private[PRIV_CAP_USER_TIME_ZERO] = 1;
+        private[PRIV_TSC_BIT] = 1 << 10;
+        private[PRIV_NORETCOMP_BIT] = 1 << 11;
+        private[PRIV_MTC_BIT] = 1 << 9;
+        private[PRIV_MTC_FREQ_BITS] = 0xf << 14;
+        private[PRIV_TSC_CTC_N] = 168;
+        private[PRIV_TSC_CTC_D] = 2;
+        private[PRIV_CYC_BIT] = 1 << 1;
+        private[PRIV_MAX_NONTURBO_RATIO] = 24;
+        let config = 1 << 10 | 1 << 9 | 3 << 14 | 2 << 19;
+        IntelPtConfig::new(&private, config, filter.to_owned())
+    }
+
+    #[test]
+    fn test_intel_pt_config() {
+        let cfg = config("");
+        assert!(cfg.tsc && cfg.mtc && !cfg.cyc && !cfg.noretcomp);
+        assert_eq!(cfg.mtc_period, 3);
+        assert_eq!(cfg.cyc_threshold, 2);
+        assert_eq!(cfg.pmu_type, 8);
+
+        let freq = cfg.frequency();
+        assert_eq!(freq.mtc(), 3);
+        assert_eq!(freq.nom(), 24);
+        assert_eq!(freq.ctc(), 168);
+        assert_eq!(freq.tsc(), 2);
+    }
+
+    #[test]
+    fn test_intel_pt_time_conversion() {
+        let cfg = config("");
+        // time = time_zero + tsc * 2048 >> 10 = time_zero + tsc * 2
+        assert_eq!(cfg.tsc_to_perf_time(500), Some(2000));
+        assert_eq!(cfg.perf_time_to_tsc(2000), Some(500));
+    }
+
+    #[test]
+    fn test_intel_pt_addr_filters() {
+        assert!(config("").addr_filters().unwrap().is_none());
+        assert!(
+            config("filter main @ /bin/ls")
+                .addr_filters()
+                .unwrap()
+                .is_none()
+        );
+
+        let filters =
+            config("filter 0xffffffff81000000/0x1000,tracestop 0x4000 @ /bin/ls,stop 4096")
+                .addr_filters()
+                .unwrap()
+                .unwrap();
+        let addr0 = filters.addr0();
+        assert_eq!(addr0.from, 0xffff_ffff_8100_0000);
+        assert_eq!(addr0.to, 0xffff_ffff_8100_0fff);
+        assert_eq!(addr0.filter_type, AddrFilterType::FILTER);
+        let addr1 = filters.addr1();
+        assert_eq!((addr1.from, addr1.to), (4096, 4096));
+        assert_eq!(addr1.filter_type, AddrFilterType::STOP);
+        assert_eq!(filters.addr2().filter_type, AddrFilterType::DISABLED);
+    }
+}
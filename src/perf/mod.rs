@@ -0,0 +1,811 @@
+use crate::enc_dec_builder::{Cpu, CpuVendor, EncoderDecoderBuilder, PtEncoderDecoder};
+use crate::error::{PtError, PtErrorCode};
+use crate::image::linux::MmapRecord;
+use crate::sideband::{SidebandEvent, SidebandQueue};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+mod intel_pt;
+mod record;
+pub use intel_pt::*;
+use intel_pt::{PRIV_FILTER_STR_LEN, PRIV_MIN, PRIV_TIME_SHIFT};
+pub use record::*;
+
+const PERF_MAGIC: &[u8; 8] = b"PERFILE2";
+const PERF_HEADER_SIZE: u64 = 104;
+const HEADER_CPUID: u32 = 9;
+
+const PERF_RECORD_COMM: u32 = 3;
+const PERF_RECORD_MMAP2: u32 = 10;
+const PERF_RECORD_ITRACE_START: u32 = 12;
+const PERF_RECORD_SWITCH_CPU_WIDE: u32 = 15;
+const PERF_RECORD_AUXTRACE_INFO: u32 = 70;
+const PERF_RECORD_AUXTRACE: u32 = 71;
+
+const PERF_RECORD_MISC_COMM_EXEC: u16 = 1 << 13;
+const PERF_RECORD_MISC_SWITCH_OUT: u16 = 1 << 13;
+const PERF_RECORD_MISC_SWITCH_OUT_PREEMPT: u16 = 1 << 14;
+const PERF_AUXTRACE_INTEL_PT: u32 = 1;
+
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_ID: u64 = 1 << 6;
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+const PERF_SAMPLE_STREAM_ID: u64 = 1 << 9;
+const PERF_SAMPLE_IDENTIFIER: u64 = 1 << 16;
+const ATTR_FLAG_SAMPLE_ID_ALL: u64 = 1 << 18;
+const PROT_EXEC: u32 = 4;
+
+fn bad_perf(msg: &'static str) -> PtError {
+    PtError::new(PtErrorCode::Invalid, msg)
+}
+
+/// The file offset @len bytes after @off.
+fn offset(off: u64, len: u64) -> Result<u64, PtError> {
+    off.checked_add(len)
+        .ok_or_else(|| bad_perf("Truncated perf.data file"))
+}
+
+/// Bounds checked little endian reader.
+#[derive(Clone, Copy)]
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn slice(self, off: u64, len: u64) -> Result<&'a [u8], PtError> {
+        let start = usize::try_from(off).map_err(|_| bad_perf("Truncated perf.data file"))?;
+        let len = usize::try_from(len).map_err(|_| bad_perf("Truncated perf.data file"))?;
+        start
+            .checked_add(len)
+            .and_then(|end| self.0.get(start..end))
+            .ok_or_else(|| bad_perf("Truncated perf.data file"))
+    }
+
+    fn u16(self, off: u64) -> Result<u16, PtError> {
+        Ok(u16::from_le_bytes(self.slice(off, 2)?.try_into().unwrap()))
+    }
+
+    fn u32(self, off: u64) -> Result<u32, PtError> {
+        Ok(u32::from_le_bytes(self.slice(off, 4)?.try_into().unwrap()))
+    }
+
+    fn u64(self, off: u64) -> Result<u64, PtError> {
+        Ok(u64::from_le_bytes(self.slice(off, 8)?.try_into().unwrap()))
+    }
+
+    /// A NUL terminated (or padded) string of at most @len bytes.
+    fn string(self, off: u64, len: u64) -> Result<String, PtError> {
+        let bytes = self.slice(off, len)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+}
+
+/// The fields of a `perf_event_attr` needed to parse a recording.
+#[derive(Clone, Copy)]
+struct Attr {
+    type_: u32,
+    config: u64,
+    sample_type: u64,
+    flags: u64,
+}
+
+impl Attr {
+    /// Position of the sample id in the `sample_id` trailer, in u64s from the end of the record.
+    ///
+    /// Returns `None` if the trailer doesn't contain the sample id.
+    fn id_pos(self) -> Option<u64> {
+        if self.sample_type & PERF_SAMPLE_IDENTIFIER != 0 {
+            return Some(1);
+        }
+        if self.sample_type & PERF_SAMPLE_ID == 0 {
+            return None;
+        }
+        let after = [PERF_SAMPLE_STREAM_ID, PERF_SAMPLE_CPU]
+            .iter()
+            .filter(|&&f| self.sample_type & f != 0)
+            .count() as u64;
+        Some(1 + after)
+    }
+
+    /// Parse the `sample_id` trailer of the kernel record @rec.
+    fn sample_id(self, rec: Bytes) -> Result<SampleId, PtError> {
+        let mut sample = SampleId::default();
+        if self.flags & ATTR_FLAG_SAMPLE_ID_ALL == 0 {
+            return Ok(sample);
+        }
+
+        let fields = [
+            PERF_SAMPLE_TID,
+            PERF_SAMPLE_TIME,
+            PERF_SAMPLE_ID,
+            PERF_SAMPLE_STREAM_ID,
+            PERF_SAMPLE_CPU,
+            PERF_SAMPLE_IDENTIFIER,
+        ];
+        let size = 8 * fields
+            .iter()
+            .filter(|&&f| self.sample_type & f != 0)
+            .count() as u64;
+        let mut off = (rec.0.len() as u64)
+            .checked_sub(size)
+            .filter(|off| *off >= 8)
+            .ok_or_else(|| bad_perf("Invalid perf record sample_id"))?;
+
+        for f in fields.into_iter().filter(|f| self.sample_type & f != 0) {
+            match f {
+                PERF_SAMPLE_TID => {
+                    sample.pid = Some(rec.u32(off)?);
+                    sample.tid = Some(rec.u32(off + 4)?);
+                }
+                PERF_SAMPLE_TIME => sample.time = Some(rec.u64(off)?),
+                PERF_SAMPLE_CPU => sample.cpu = Some(rec.u32(off)?),
+                _ => {}
+            }
+            off += 8;
+        }
+        Ok(sample)
+    }
+}
+
+/// The events of a recording.
+struct Attrs {
+    attrs: Vec<Attr>,
+    /// Index in `attrs` of the event of each sample id.
+    ids: HashMap<u64, usize>,
+}
+
+impl Attrs {
+    /// The event that generated the kernel record @rec, whose `sample_id` layout must be used.
+    ///
+    /// As perf does, the sample id is found with the layout of the first event: a recording is
+    /// only valid if it is at the same position in the `sample_id` of all its events.
+    fn of_record(&self, rec: Bytes) -> Result<Attr, PtError> {
+        let first = self.attrs[0];
+        if self.attrs.len() == 1 || first.flags & ATTR_FLAG_SAMPLE_ID_ALL == 0 {
+            return Ok(first);
+        }
+        let Some(pos) = first.id_pos() else {
+            return Ok(first);
+        };
+
+        let off = (rec.0.len() as u64)
+            .checked_sub(8 * pos)
+            .filter(|off| *off >= 8)
+            .ok_or_else(|| bad_perf("Invalid perf record sample_id"))?;
+        self.ids
+            .get(&rec.u64(off)?)
+            .map(|&i| self.attrs[i])
+            .ok_or_else(|| bad_perf("Unknown perf event id"))
+    }
+}
+
+/// An Intel PT recording made with `perf record -e intel_pt//`.
+///
+/// The `perf.data` file is parsed once: the AUX area trace chunks of every cpu are located and the
+/// sideband records needed to build images and follow context switches are kept.
+/// The traces are not copied out of the file content, which is kept in memory.
+/// Only the (little endian) file format is supported, not the pipe format.
+#[derive(Debug, Clone)]
+pub struct PerfData {
+    data: Vec<u8>,
+    cpu: Option<Cpu>,
+    intel_pt: Option<IntelPtConfig>,
+    aux: BTreeMap<u32, Vec<Range<usize>>>,
+    sideband: Vec<SidebandRecord>,
+}
+
+impl PerfData {
+    /// Read and parse the `perf.data` file @filename.
+    ///
+    /// Returns BadFile if @filename can't be read.
+    /// Returns Invalid if @filename is not a valid `perf.data` file.
+    pub fn open(filename: &str) -> Result<Self, PtError> {
+        let data = std::fs::read(filename)
+            .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to read the perf.data file"))?;
+        Self::parse(data)
+    }
+
+    /// Parse the content of a `perf.data` file.
+    ///
+    /// Returns Invalid if @data is not a valid `perf.data` file.
+    pub fn parse(data: Vec<u8>) -> Result<Self, PtError> {
+        let file = Bytes(&data);
+        if file.slice(0, 8)? != PERF_MAGIC {
+            return Err(bad_perf("Not a perf.data file"));
+        }
+        if file.u64(8)? != PERF_HEADER_SIZE {
+            return Err(bad_perf("Unsupported perf.data header"));
+        }
+
+        let attr_size = file.u64(16)?;
+        let (attrs_off, attrs_size) = (file.u64(24)?, file.u64(32)?);
+        let (data_off, data_size) = (file.u64(40)?, file.u64(48)?);
+        if attr_size < 64 {
+            return Err(bad_perf("Invalid perf.data attribute size"));
+        }
+
+        // Each perf_event_attr is followed by the file section of the ids of the event.
+        let mut attrs = Attrs {
+            attrs: Vec::new(),
+            ids: HashMap::new(),
+        };
+        for i in 0..attrs_size / attr_size {
+            // `i * attr_size` is at most `attrs_size`.
+            let attr = Bytes(file.slice(offset(attrs_off, i * attr_size)?, attr_size)?);
+            let (ids_off, ids_size) = (attr.u64(attr_size - 16)?, attr.u64(attr_size - 8)?);
+            for id in 0..ids_size / 8 {
+                attrs
+                    .ids
+                    .insert(file.u64(offset(ids_off, id * 8)?)?, attrs.attrs.len());
+            }
+            attrs.attrs.push(Attr {
+                type_: attr.u32(0)?,
+                config: attr.u64(8)?,
+                sample_type: attr.u64(24)?,
+                flags: attr.u64(40)?,
+            });
+        }
+        if attrs.attrs.is_empty() {
+            return Err(bad_perf("perf.data file without events"));
+        }
+
+        let end = offset(data_off, data_size)?;
+        let cpu = Self::parse_cpuid(file, end)?;
+        let mut aux = BTreeMap::<u32, Vec<Range<usize>>>::new();
+        let mut sideband = Vec::new();
+        let mut auxtrace_info = None;
+
+        let mut off = data_off;
+        while end.saturating_sub(off) >= 8 {
+            let type_ = file.u32(off)?;
+            let misc = file.u16(off + 4)?;
+            let size = u64::from(file.u16(off + 6)?);
+            if size < 8 {
+                return Err(bad_perf("Invalid perf record size"));
+            }
+            let rec = Bytes(file.slice(off, size)?);
+            off += size;
+
+            let kind = match type_ {
+                PERF_RECORD_AUXTRACE => {
+                    // The trace data follows the record.
+                    let aux_size = rec.u64(8)?;
+                    let cpu = rec.u32(40)?;
+                    let start = off as usize;
+                    let trace = file.slice(off, aux_size)?;
+                    if !trace.is_empty() {
+                        aux.entry(cpu).or_default().push(start..start + trace.len());
+                    }
+                    off += aux_size;
+                    continue;
+                }
+                PERF_RECORD_AUXTRACE_INFO => {
+                    if rec.u32(8)? == PERF_AUXTRACE_INTEL_PT {
+                        auxtrace_info = Some(Self::parse_auxtrace_info(rec)?);
+                    }
+                    continue;
+                }
+                PERF_RECORD_ITRACE_START => SidebandKind::ItraceStart {
+                    pid: rec.u32(8)?,
+                    tid: rec.u32(12)?,
+                },
+                PERF_RECORD_COMM => SidebandKind::Comm {
+                    pid: rec.u32(8)?,
+                    tid: rec.u32(12)?,
+                    comm: rec.string(16, size.saturating_sub(16))?,
+                    exec: misc & PERF_RECORD_MISC_COMM_EXEC != 0,
+                },
+                PERF_RECORD_MMAP2 => {
+                    if rec.u32(64)? & PROT_EXEC == 0 {
+                        continue;
+                    }
+                    SidebandKind::Mmap {
+                        tid: rec.u32(12)?,
+                        record: MmapRecord {
+                            filename: rec.string(72, size.saturating_sub(72))?,
+                            pgoff: rec.u64(32)?,
+                            len: rec.u64(24)?,
+                            addr: rec.u64(16)?,
+                            pid: rec.u32(8)?,
+                        },
+                    }
+                }
+                PERF_RECORD_SWITCH_CPU_WIDE => SidebandKind::SwitchCpuWide {
+                    out: misc & PERF_RECORD_MISC_SWITCH_OUT != 0,
+                    preempt: misc & PERF_RECORD_MISC_SWITCH_OUT_PREEMPT != 0,
+                    next_prev_pid: rec.u32(8)?,
+                    next_prev_tid: rec.u32(12)?,
+                },
+                _ => continue,
+            };
+            sideband.push(SidebandRecord {
+                kind,
+                sample: attrs.of_record(rec)?.sample_id(rec)?,
+                tsc: None,
+            });
+        }
+
+        let mut intel_pt = None;
+        if let Some((private, filter)) = auxtrace_info {
+            let pmu_type = private[0] as u32;
+            let config = attrs
+                .attrs
+                .iter()
+                .find(|attr| attr.type_ == pmu_type)
+                .map_or(0, |attr| attr.config);
+            let config = IntelPtConfig::new(&private, config, filter);
+            for record in &mut sideband {
+                record.tsc = record
+                    .sample
+                    .time
+                    .and_then(|time| config.perf_time_to_tsc(time));
+            }
+            intel_pt = Some(config);
+        }
+
+        Ok(Self {
+            data,
+            cpu,
+            intel_pt,
+            aux,
+            sideband,
+        })
+    }
+
+    /// Parse the private data and the filter string of an `intel_pt` AUXTRACE_INFO record.
+    fn parse_auxtrace_info(rec: Bytes) -> Result<(Vec<u64>, String), PtError> {
+        let count = (rec.0.len() as u64).saturating_sub(16) / 8;
+        if count < PRIV_MIN as u64 {
+            return Err(bad_perf("Invalid intel_pt AUXTRACE_INFO record"));
+        }
+        let private = (0..count)
+            .map(|i| rec.u64(16 + i * 8))
+            .collect::<Result<Vec<_>, PtError>>()?;
+        if private[PRIV_TIME_SHIFT] >= u64::from(u64::BITS) {
+            return Err(bad_perf("Invalid intel_pt time shift"));
+        }
+
+        let filter = match private.get(PRIV_FILTER_STR_LEN) {
+            Some(&len) if len > 0 => rec.string(16 + (PRIV_FILTER_STR_LEN as u64 + 1) * 8, len)?,
+            _ => String::new(),
+        };
+        Ok((private, filter))
+    }
+
+    /// Parse the `HEADER_CPUID` feature, e.g. `GenuineIntel,6,142,10`.
+    ///
+    /// The feature sections follow the data section, one for each feature bit set in the header.
+    fn parse_cpuid(file: Bytes, features_off: u64) -> Result<Option<Cpu>, PtError> {
+        let features = file.u64(72)?;
+        if features & (1 << HEADER_CPUID) == 0 {
+            return Ok(None);
+        }
+        let index = u64::from((features & ((1 << HEADER_CPUID) - 1)).count_ones());
+        let section = offset(features_off, index * 16)?;
+        let (off, size) = (file.u64(section)?, file.u64(offset(section, 8)?)?);
+        let len = u64::from(file.u32(off)?).min(size.saturating_sub(4));
+        let cpuid = file.string(offset(off, 4)?, len)?;
+
+        let mut fields = cpuid.split(',');
+        let vendor = match fields.next() {
+            Some("GenuineIntel") => CpuVendor::INTEL,
+            _ => CpuVendor::UNKNOWN,
+        };
+        let mut number = || fields.next().and_then(|f| f.trim().parse::<u16>().ok());
+        Ok(match (number(), number(), number()) {
+            (Some(family), Some(model), Some(stepping)) => {
+                Some(Cpu::new(vendor, family, model as u8, stepping as u8))
+            }
+            _ => None,
+        })
+    }
+
+    /// The cpu the trace has been recorded on, if it has been recorded.
+    #[must_use]
+    pub fn cpu(&self) -> Option<Cpu> {
+        self.cpu
+    }
+
+    /// The `intel_pt` configuration, `None` if the recording doesn't contain an Intel PT trace.
+    #[must_use]
+    pub fn intel_pt(&self) -> Option<&IntelPtConfig> {
+        self.intel_pt.as_ref()
+    }
+
+    /// The cpus that have an AUX area trace, in increasing order.
+    ///
+    /// Traces recorded per thread (`--per-thread`) use the cpu `u32::MAX`.
+    pub fn cpus(&self) -> impl Iterator<Item = u32> + '_ {
+        self.aux.keys().copied()
+    }
+
+    /// The Intel PT trace recorded on @cpu.
+    ///
+    /// The AUXTRACE chunks of the cpu are concatenated: if some trace has been lost in between,
+    /// decoders will resynchronize at the next PSB.
+    /// The trace is borrowed from the file content if it is made of a single chunk, use
+    /// `aux_chunks()` to never copy it.
+    #[must_use]
+    pub fn aux(&self, cpu: u32) -> Option<Cow<'_, [u8]>> {
+        let chunks = self.aux.get(&cpu)?;
+        Some(match chunks.as_slice() {
+            [chunk] => Cow::Borrowed(&self.data[chunk.clone()]),
+            _ => Cow::Owned(self.aux_chunks(cpu).flatten().copied().collect()),
+        })
+    }
+
+    /// The AUXTRACE chunks of the Intel PT trace recorded on @cpu, in file order.
+    ///
+    /// Each chunk can be decoded on its own, decoders synchronize at its first PSB.
+    pub fn aux_chunks(&self, cpu: u32) -> impl Iterator<Item = &[u8]> + '_ {
+        self.aux
+            .get(&cpu)
+            .into_iter()
+            .flatten()
+            .map(|chunk| &self.data[chunk.clone()])
+    }
+
+    /// The sideband records, in file order.
+    #[must_use]
+    pub fn sideband(&self) -> &[SidebandRecord] {
+        &self.sideband
+    }
+
+    /// The executable mappings of the recording, to be used with `image::linux::MmapLoader`.
+    pub fn mmaps(&self) -> impl Iterator<Item = &MmapRecord> {
+        self.sideband.iter().filter_map(|r| match &r.kind {
+            SidebandKind::Mmap { record, .. } => Some(record),
+            _ => None,
+        })
+    }
+
+    /// A sideband queue following the processes running on @cpu, see `aux()`.
+    ///
+    /// Mappings of all processes are queued, process switches only if they happened on @cpu.
+    /// Records without a TSC time, e.g. the mappings synthesized by perf for the processes
+    /// already running when the recording started, are queued at time 0.
+    pub fn sideband_queue(&self, cpu: u32) -> Result<SidebandQueue, PtError> {
+        let mut queue = SidebandQueue::new()?;
+        for record in &self.sideband {
+            let on_cpu = cpu == u32::MAX || record.sample.cpu.is_none_or(|c| c == cpu);
+            let event = match &record.kind {
+                SidebandKind::Mmap { record, .. } => SidebandEvent::Mmap(record.clone()),
+                SidebandKind::ItraceStart { pid, .. } if on_cpu => {
+                    SidebandEvent::Switch { pid: *pid }
+                }
+                SidebandKind::SwitchCpuWide { out: false, .. } if on_cpu => {
+                    match record.sample.pid {
+                        Some(pid) => SidebandEvent::Switch { pid },
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            queue.push(record.tsc.unwrap_or(0), event);
+        }
+        Ok(queue)
+    }
+
+    /// A builder configured with the `Cpu`, `Frequency` and `AddrFilters` of the recording.
+    ///
+    /// Set the buffer to one of the `aux()` traces before building the decoder.
+    /// Returns BadConfig if the recording has more than 4 address filters.
+    pub fn builder<T>(&self) -> Result<EncoderDecoderBuilder<T>, PtError>
+    where
+        T: PtEncoderDecoder,
+    {
+        let mut builder = EncoderDecoderBuilder::<T>::new();
+        if let Some(cpu) = self.cpu {
+            builder = builder.cpu(cpu);
+        }
+        if let Some(intel_pt) = &self.intel_pt {
+            builder = builder.freq(intel_pt.frequency());
+            if let Some(filters) = intel_pt.addr_filters()? {
+                builder = builder.filter(filters);
+            }
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SAMPLE_TYPE: u64 = PERF_SAMPLE_TID | PERF_SAMPLE_TIME | PERF_SAMPLE_CPU;
+    const INTEL_PT_TYPE: u64 = 8;
+
+    fn record(type_: u32, misc: u16, body: &[u8], sample: Option<(u32, u64, u32)>) -> Vec<u8> {
+        let mut rec = body.to_vec();
+        if let Some((pid, time, cpu)) = sample {
+            for v in [pid, pid] {
+                rec.extend(v.to_le_bytes());
+            }
+            rec.extend(time.to_le_bytes());
+            rec.extend(u64::from(cpu).to_le_bytes());
+        }
+        let mut out = Vec::new();
+        out.extend(type_.to_le_bytes());
+        out.extend(misc.to_le_bytes());
+        out.extend((rec.len() as u16 + 8).to_le_bytes());
+        out.extend(rec);
+        out
+    }
+
+    fn words(values: &[u64]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn mmap2(pid: u32, addr: u64, prot: u32, filename: &str) -> Vec<u8> {
+        let mut body = words(&[u64::from(pid) | u64::from(pid) << 32, addr, 0x1000, 0x2000]);
+        body.extend([0u8; 24]);
+        body.extend(prot.to_le_bytes());
+        body.extend(2u32.to_le_bytes());
+        body.extend(filename.as_bytes());
+        body.resize(body.len().next_multiple_of(8) + 8, 0);
+        record(PERF_RECORD_MMAP2, 0, &body, Some((pid, 300, 0)))
+    }
+
+    fn auxtrace(cpu: u32, trace: &[u8]) -> Vec<u8> {
+        let mut body = words(&[trace.len() as u64, 0, 0]);
+        body.extend([0u32, 0, cpu, 0].iter().flat_map(|v| v.to_le_bytes()));
+        let mut rec = record(PERF_RECORD_AUXTRACE, 0, &body, None);
+        rec.extend(trace);
+        rec
+    }
+
+    fn perf_data() -> Vec<u8> {
+        let filter = b"filter 0x1000/0x100\0\0\0\0\0";
+        let mut private = vec![0u64; PRIV_FILTER_STR_LEN + 1];
+        private[0] = INTEL_PT_TYPE;
+        private[1] = 0; // time_shift
+        private[2] = 1; // time_mult
+        private[3] = 100; // time_zero
+        private[4] = 1; // cap_user_time_zero
+        private[5] = 1 << 10; // tsc
+        private[11] = 0xf << 14; // mtc_freq
+        private[12] = 84;
+        private[13] = 2;
+        private[15] = 24;
+        private[PRIV_FILTER_STR_LEN] = 19;
+        let mut info = words(&[u64::from(PERF_AUXTRACE_INTEL_PT)]);
+        info.extend(words(&private));
+        info.extend(filter);
+
+        let mut comm = words(&[7 | 7 << 32]);
+        comm.extend(b"ls\0\0\0\0\0\0");
+
+        let data = [
+            record(PERF_RECORD_AUXTRACE_INFO, 0, &info, None),
+            record(
+                PERF_RECORD_COMM,
+                PERF_RECORD_MISC_COMM_EXEC,
+                &comm,
+                Some((7, 200, 0)),
+            ),
+            mmap2(7, 0x40_0000, 5, "/bin/ls"),
+            mmap2(7, 0x60_0000, 3, "/bin/ls"),
+            record(
+                PERF_RECORD_ITRACE_START,
+                0,
+                &words(&[7 | 7 << 32]),
+                Some((7, 400, 1)),
+            ),
+            auxtrace(0, &[1, 2, 3]),
+            record(
+                PERF_RECORD_SWITCH_CPU_WIDE,
+                PERF_RECORD_MISC_SWITCH_OUT,
+                &words(&[9 | 9 << 32]),
+                Some((7, 500, 0)),
+            ),
+            auxtrace(1, &[4, 5]),
+            auxtrace(0, &[6]),
+        ]
+        .concat();
+
+        perf_file(
+            &[
+                (1, 9, SAMPLE_TYPE, &[]),
+                (INTEL_PT_TYPE, 1 << 10 | 2 << 14, SAMPLE_TYPE, &[]),
+            ],
+            &data,
+        )
+    }
+
+    /// A `perf.data` file with the events @attrs (type, config, sample_type and ids) and the
+    /// records @data.
+    fn perf_file(attrs: &[(u64, u64, u64, &[u64])], data: &[u8]) -> Vec<u8> {
+        let ids_off = PERF_HEADER_SIZE;
+        let mut ids = Vec::new();
+        let mut file_attrs = Vec::new();
+        for (type_, config, sample_type, event_ids) in attrs {
+            file_attrs.extend(words(&[
+                type_ | 64 << 32,
+                *config,
+                0,
+                *sample_type,
+                0,
+                ATTR_FLAG_SAMPLE_ID_ALL,
+                0,
+                0,
+                ids_off + ids.len() as u64,
+                8 * event_ids.len() as u64,
+            ]));
+            ids.extend(words(event_ids));
+        }
+
+        let cpuid = b"GenuineIntel,6,142,10\0\0\0";
+        let attrs_off = ids_off + ids.len() as u64;
+        let data_off = attrs_off + file_attrs.len() as u64;
+        let features_off = data_off + data.len() as u64;
+        // HEADER_CPUID is preceded by the HEADER_NRCPUS feature.
+        let cpuid_off = features_off + 2 * 16;
+
+        let mut file = PERF_MAGIC.to_vec();
+        file.extend(words(&[
+            PERF_HEADER_SIZE,
+            80,
+            attrs_off,
+            file_attrs.len() as u64,
+            data_off,
+            data.len() as u64,
+            0,
+            0,
+            1 << 7 | 1 << HEADER_CPUID,
+            0,
+            0,
+            0,
+        ]));
+        file.extend(ids);
+        file.extend(file_attrs);
+        file.extend(data);
+        file.extend(words(&[0, 0, cpuid_off, 4 + cpuid.len() as u64]));
+        file.extend((cpuid.len() as u32).to_le_bytes());
+        file.extend(cpuid);
+        file
+    }
+
+    #[test]
+    fn test_perf_data_aux() {
+        let perf = PerfData::parse(perf_data()).unwrap();
+        assert_eq!(perf.cpus().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(*perf.aux(0).unwrap(), [1, 2, 3, 6]);
+        assert!(matches!(perf.aux(1).unwrap(), Cow::Borrowed([4, 5])));
+        assert!(perf.aux(2).is_none());
+        assert_eq!(
+            perf.aux_chunks(0).collect::<Vec<_>>(),
+            [&[1, 2, 3][..], &[6][..]]
+        );
+        assert_eq!(perf.aux_chunks(2).count(), 0);
+        assert!(perf.cpu().is_some());
+    }
+
+    #[test]
+    fn test_perf_data_intel_pt() {
+        let perf = PerfData::parse(perf_data()).unwrap();
+        let intel_pt = perf.intel_pt().unwrap();
+        assert!(intel_pt.tsc && !intel_pt.mtc);
+        assert_eq!(intel_pt.mtc_period, 2);
+        assert_eq!(intel_pt.filter, "filter 0x1000/0x100");
+
+        let builder = perf.builder::<crate::block::BlockDecoder>().unwrap();
+        assert_eq!(builder.config.mtc_freq, 2);
+        assert_eq!(builder.config.nom_freq, 24);
+        assert_eq!(builder.config.cpuid_0x15_ebx, 84);
+        assert_eq!(builder.config.cpuid_0x15_eax, 2);
+        assert_eq!(builder.config.cpu.family, 6);
+        assert_eq!(builder.config.cpu.model, 142);
+        assert_eq!(builder.config.cpu.stepping, 10);
+        assert_eq!(builder.config.addr_filter.addr0_a, 0x1000);
+        assert_eq!(builder.config.addr_filter.addr0_b, 0x10ff);
+    }
+
+    #[test]
+    fn test_perf_data_sideband() {
+        let perf = PerfData::parse(perf_data()).unwrap();
+        let sideband = perf.sideband();
+        assert_eq!(sideband.len(), 4);
+        assert_eq!(
+            sideband[0].kind,
+            SidebandKind::Comm {
+                pid: 7,
+                tid: 7,
+                comm: "ls".to_owned(),
+                exec: true,
+            }
+        );
+        assert_eq!(sideband[0].sample.time, Some(200));
+        assert_eq!(sideband[0].tsc, Some(100));
+        assert_eq!(sideband[2].sample.cpu, Some(1));
+        assert_eq!(
+            sideband[3].kind,
+            SidebandKind::SwitchCpuWide {
+                out: true,
+                preempt: false,
+                next_prev_pid: 9,
+                next_prev_tid: 9,
+            }
+        );
+
+        let mmaps: Vec<_> = perf.mmaps().collect();
+        assert_eq!(mmaps.len(), 1);
+        assert_eq!(mmaps[0].filename, "/bin/ls");
+        assert_eq!(mmaps[0].addr, 0x40_0000);
+        assert_eq!(mmaps[0].pgoff, 0x2000);
+
+        // The mmap and the ITRACE_START on cpu 1, the switch out is ignored.
+        let queue = perf.sideband_queue(1).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.next_tsc(), Some(200));
+        assert_eq!(perf.sideband_queue(0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_perf_data_sample_id_layouts() {
+        // The sample_id of the dummy event has no time, the one of the intel_pt event has.
+        let dummy = PERF_SAMPLE_TID | PERF_SAMPLE_CPU | PERF_SAMPLE_IDENTIFIER;
+        let intel_pt = SAMPLE_TYPE | PERF_SAMPLE_IDENTIFIER;
+        let attrs: [(u64, u64, u64, &[u64]); 2] = [
+            (1, 9, dummy, &[10, 11]),
+            (INTEL_PT_TYPE, 0, intel_pt, &[20]),
+        ];
+        let comm = |pid: u32, sample_id: &[u64]| {
+            let mut body = words(&[u64::from(pid) | u64::from(pid) << 32]);
+            body.extend(b"ls\0\0\0\0\0\0");
+            body.extend(words(sample_id));
+            record(PERF_RECORD_COMM, 0, &body, None)
+        };
+
+        let data = [
+            comm(7, &[7 | 7 << 32, 3, 11]),
+            comm(8, &[8 | 8 << 32, 500, 2, 20]),
+        ]
+        .concat();
+        let perf = PerfData::parse(perf_file(&attrs, &data)).unwrap();
+        let sideband = perf.sideband();
+        assert_eq!(
+            sideband[0].sample,
+            SampleId {
+                pid: Some(7),
+                tid: Some(7),
+                time: None,
+                cpu: Some(3),
+            }
+        );
+        assert_eq!(
+            sideband[1].sample,
+            SampleId {
+                pid: Some(8),
+                tid: Some(8),
+                time: Some(500),
+                cpu: Some(2),
+            }
+        );
+
+        let data = comm(7, &[7 | 7 << 32, 3, 12]);
+        assert!(PerfData::parse(perf_file(&attrs, &data)).is_err());
+    }
+
+    #[test]
+    fn test_perf_data_invalid() {
+        assert!(PerfData::parse(b"PERFILE2".to_vec()).is_err());
+        let mut data = perf_data();
+        data.truncate(data.len() - 40);
+        assert!(PerfData::parse(data).is_err());
+
+        // Sections overflowing the file offsets.
+        for field in [24, 40, 48] {
+            let mut data = perf_data();
+            data[field..field + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+            assert!(PerfData::parse(data).is_err());
+        }
+
+        // A time shift overflowing the TSC conversions, in the first record.
+        let mut data = perf_data();
+        let time_shift = u64::from_le_bytes(data[40..48].try_into().unwrap()) as usize + 24;
+        data[time_shift..time_shift + 8].copy_from_slice(&64u64.to_le_bytes());
+        assert!(PerfData::parse(data).is_err());
+        assert!(PerfData::open("/this/file/does/not/exist").is_err());
+    }
+}
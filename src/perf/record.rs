@@ -0,0 +1,53 @@
+use crate::image::linux::MmapRecord;
+
+/// The `sample_id` fields appended to sideband records when `sample_id_all` is set.
+///
+/// Which fields are present depends on the `sample_type` of the recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SampleId {
+    /// Process id
+    pub pid: Option<u32>,
+    /// Thread id
+    pub tid: Option<u32>,
+    /// Perf time, in nanoseconds
+    pub time: Option<u64>,
+    /// The cpu the record has been generated on
+    pub cpu: Option<u32>,
+}
+
+/// The content of a sideband record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SidebandKind {
+    /// `PERF_RECORD_ITRACE_START`: tracing started for a thread.
+    ItraceStart { pid: u32, tid: u32 },
+    /// `PERF_RECORD_COMM`: a thread changed its name, on `exec` if `exec` is set.
+    Comm {
+        pid: u32,
+        tid: u32,
+        comm: String,
+        exec: bool,
+    },
+    /// `PERF_RECORD_MMAP2` of an executable mapping.
+    Mmap { tid: u32, record: MmapRecord },
+    /// `PERF_RECORD_SWITCH_CPU_WIDE`: a context switch.
+    ///
+    /// On switch out (`out` set), `next_prev_pid`/`next_prev_tid` identify the next thread,
+    /// otherwise the previous one.
+    SwitchCpuWide {
+        out: bool,
+        preempt: bool,
+        next_prev_pid: u32,
+        next_prev_tid: u32,
+    },
+}
+
+/// A sideband record of a perf recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SidebandRecord {
+    /// The record content
+    pub kind: SidebandKind,
+    /// The `sample_id` of the record
+    pub sample: SampleId,
+    /// The record time converted to TSC, if the recording allows it
+    pub tsc: Option<u64>,
+}
@@ -0,0 +1,298 @@
+use crate::block::Block;
+use crate::callstack::{CallStack, ExitKind, StackEvent};
+use crate::error::{PtError, write_sink};
+use crate::merge::TraceDecoder;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+
+/// The name of the frame at the bottom of every stack, for the code running before the first
+/// traced call.
+const ROOT: &str = "[unknown]";
+
+/// How the stacks of a `Profile` are weighted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    /// The number of executed instructions
+    Insns,
+    /// The time spent, from the decoder time (TSC, refined by MTC and CYC packets if enabled)
+    Time,
+}
+
+/// A function-level profile of a trace, exported as folded stacks or as a speedscope profile.
+///
+/// A stack is the list of the entry IPs of the functions on the call stack, outermost first,
+/// as rebuilt by a `CallStack`. Frames are named by a symbolizer closure mapping IPs to names,
+/// unnamed frames are shown as hexadecimal addresses.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    weight: Weight,
+    stacks: HashMap<Vec<u64>, u64>,
+}
+
+impl Profile {
+    /// Create an empty profile weighted by @weight.
+    #[must_use]
+    pub fn new(weight: Weight) -> Self {
+        Self {
+            weight,
+            stacks: HashMap::new(),
+        }
+    }
+
+    /// The weight of the profile.
+    #[must_use]
+    pub fn weight(&self) -> Weight {
+        self.weight
+    }
+
+    /// Add all the blocks of @callstack to the profile, until the end of its trace.
+    ///
+    /// Decoding errors are skipped, the time spent in trace gaps is not counted.
+    pub fn record<D>(&mut self, callstack: &mut CallStack<D>)
+    where
+        D: TraceDecoder<Item = Block>,
+    {
+        let mut stack = Vec::new();
+        // The time and the stack of the previous block.
+        let mut previous: Option<(u64, Vec<u64>)> = None;
+        while let Some(result) = callstack.next() {
+            let (tsc, event) = match result {
+                Ok(item) => item,
+                Err(_) => {
+                    previous = None;
+                    continue;
+                }
+            };
+            match event {
+                StackEvent::Block { block, .. } => {
+                    // The block is the last item of its step, the stack is up to date.
+                    stack.clear();
+                    stack.extend(callstack.stack().iter().map(|frame| frame.entry));
+                    match self.weight {
+                        Weight::Insns => self.add(&stack, u64::from(block.ninsn())),
+                        Weight::Time => {
+                            if let Some((start, previous)) = &previous {
+                                self.add(previous, tsc.saturating_sub(*start));
+                            }
+                            previous = Some((tsc, stack.clone()));
+                        }
+                    }
+                }
+                StackEvent::Exit {
+                    kind: ExitKind::Gap,
+                    ..
+                } => previous = None,
+                _ => {}
+            }
+        }
+    }
+
+    /// Add @weight to @stack.
+    pub fn add(&mut self, stack: &[u64], weight: u64) {
+        if weight == 0 {
+            return;
+        }
+        match self.stacks.get_mut(stack) {
+            Some(total) => *total += weight,
+            None => {
+                self.stacks.insert(stack.to_vec(), weight);
+            }
+        }
+    }
+
+    /// The stacks of the profile and their weights, in no particular order.
+    pub fn stacks(&self) -> impl Iterator<Item = (&[u64], u64)> {
+        self.stacks
+            .iter()
+            .map(|(stack, weight)| (stack.as_slice(), *weight))
+    }
+
+    /// The total weight of the profile.
+    #[must_use]
+    pub fn total(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// The named stacks, sorted by name.
+    fn named<F>(&self, mut symbolize: F) -> Vec<(Vec<String>, u64)>
+    where
+        F: FnMut(u64) -> Option<String>,
+    {
+        let mut names = HashMap::new();
+        let mut named: Vec<(Vec<String>, u64)> = self
+            .stacks()
+            .map(|(stack, weight)| {
+                let frames = std::iter::once(ROOT.to_owned())
+                    .chain(stack.iter().map(|&ip| {
+                        names
+                            .entry(ip)
+                            .or_insert_with(|| symbolize(ip).unwrap_or_else(|| format!("{ip:#x}")))
+                            .clone()
+                    }))
+                    .collect();
+                (frames, weight)
+            })
+            .collect();
+        named.sort();
+        named
+    }
+
+    /// Write the profile in the "folded" format of Brendan Gregg's flame graph tools.
+    ///
+    /// Each line is a stack, frames separated by `;`, followed by its weight.
+    /// @symbolize maps the entry IP of a function to its name.
+    /// Returns `BadFile` if @out can't be written.
+    pub fn write_folded<W, F>(&self, mut out: W, symbolize: F) -> Result<(), PtError>
+    where
+        W: Write,
+        F: FnMut(u64) -> Option<String>,
+    {
+        let mut text = String::new();
+        for (frames, weight) in self.named(symbolize) {
+            for (i, frame) in frames.iter().enumerate() {
+                if i > 0 {
+                    text.push(';');
+                }
+                text.push_str(&frame.replace(';', ":"));
+            }
+            writeln!(text, " {weight}").unwrap();
+        }
+        write_sink(&mut out, text.as_bytes())
+    }
+
+    /// Write the profile as a speedscope "sampled" profile named @name.
+    ///
+    /// See <https://www.speedscope.app/file-format-schema.json>.
+    /// @symbolize maps the entry IP of a function to its name.
+    /// Returns `BadFile` if @out can't be written.
+    pub fn write_speedscope<W, F>(
+        &self,
+        mut out: W,
+        name: &str,
+        symbolize: F,
+    ) -> Result<(), PtError>
+    where
+        W: Write,
+        F: FnMut(u64) -> Option<String>,
+    {
+        let named = self.named(symbolize);
+        let mut frames: Vec<&str> = Vec::new();
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        let mut samples = Vec::with_capacity(named.len());
+        for (stack, _) in &named {
+            let sample: Vec<usize> = stack
+                .iter()
+                .map(|frame| {
+                    let frame = frame.as_str();
+                    *indices.entry(frame).or_insert_with(|| {
+                        frames.push(frame);
+                        frames.len() - 1
+                    })
+                })
+                .collect();
+            samples.push(sample);
+        }
+
+        let mut json = String::from(
+            r#"{"$schema":"https://www.speedscope.app/file-format-schema.json","shared":{"frames":["#,
+        );
+        for (i, frame) in frames.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str(r#"{"name":"#);
+            push_json_str(&mut json, frame);
+            json.push('}');
+        }
+        json.push_str(r#"]},"profiles":[{"type":"sampled","name":"#);
+        push_json_str(&mut json, name);
+        write!(
+            json,
+            r#","unit":"none","startValue":0,"endValue":{},"samples":["#,
+            self.total()
+        )
+        .unwrap();
+        for (i, sample) in samples.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "{sample:?}").unwrap();
+        }
+        json.push_str(r#"],"weights":["#);
+        for (i, (_, weight)) in named.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "{weight}").unwrap();
+        }
+        json.push_str(r#"]}],"name":"#);
+        push_json_str(&mut json, name);
+        json.push_str(r#","exporter":"libipt-rs"}"#);
+        json.push('\n');
+        write_sink(&mut out, json.as_bytes())
+    }
+}
+
+/// Append @s to @json as a JSON string.
+fn push_json_str(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if u32::from(c) < 0x20 => write!(json, "\\u{:04x}", u32::from(c)).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profile() -> Profile {
+        let mut profile = Profile::new(Weight::Insns);
+        profile.add(&[], 3);
+        profile.add(&[0x1000], 2);
+        profile.add(&[0x1000, 0x2000], 4);
+        profile.add(&[0x1000], 1);
+        profile.add(&[0x3000], 0);
+        profile
+    }
+
+    fn symbolize(ip: u64) -> Option<String> {
+        (ip == 0x1000).then(|| "main".to_owned())
+    }
+
+    #[test]
+    fn test_profile_folded() {
+        let profile = profile();
+        assert_eq!(profile.total(), 10);
+        assert_eq!(profile.stacks().count(), 3);
+
+        let mut out = Vec::new();
+        profile.write_folded(&mut out, symbolize).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[unknown] 3\n[unknown];main 3\n[unknown];main;0x2000 4\n"
+        );
+    }
+
+    #[test]
+    fn test_profile_speedscope() {
+        let mut out = Vec::new();
+        profile()
+            .write_speedscope(&mut out, "a \"trace\"", symbolize)
+            .unwrap();
+        let json = String::from_utf8(out).unwrap();
+        assert!(
+            json.contains(r#""frames":[{"name":"[unknown]"},{"name":"main"},{"name":"0x2000"}]"#)
+        );
+        assert!(json.contains(r#""samples":[[0],[0, 1],[0, 1, 2]],"weights":[3,3,4]"#));
+        assert!(json.contains(r#""name":"a \"trace\"""#));
+        assert!(json.contains(r#""endValue":10"#));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::asid::Asid;
+use crate::block::BlockDecoder;
+use crate::enc_dec_builder::OwnedDecoder;
+use crate::error::{PtError, PtErrorCode};
+use crate::image::Image;
+use crate::image::linux::{MmapLoader, MmapRecord};
+use crate::insn::InsnDecoder;
+use std::collections::{HashMap, VecDeque};
+
+/// A sideband event, changing the memory image of the traced code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SidebandEvent {
+    /// The process @pid is scheduled in.
+    Switch { pid: u32 },
+    /// A file is mapped into a process.
+    Mmap(MmapRecord),
+    /// The range [@addr, @addr + @len[ is unmapped from the process @pid.
+    Munmap { pid: u32, addr: u64, len: u64 },
+}
+
+/// A decoder whose image can follow the sideband, see `SidebandQueue::apply()`.
+pub trait SidebandDecoder {
+    /// The current decoder time, see `InsnDecoder::time()`.
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError>;
+
+    /// The image the decoder uses for reading memory.
+    fn image(&mut self) -> &mut Image;
+}
+
+impl SidebandDecoder for InsnDecoder<'_> {
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        InsnDecoder::time(self)
+    }
+
+    fn image(&mut self) -> &mut Image {
+        InsnDecoder::image(self)
+    }
+}
+
+impl SidebandDecoder for BlockDecoder<'_> {
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        BlockDecoder::time(self)
+    }
+
+    fn image(&mut self) -> &mut Image {
+        BlockDecoder::image(self)
+    }
+}
+
+impl<D: SidebandDecoder> SidebandDecoder for OwnedDecoder<D> {
+    fn time(&mut self) -> Result<(u64, u32, u32), PtError> {
+        self.decoder_mut().time()
+    }
+
+    fn image(&mut self) -> &mut Image {
+        self.decoder_mut().image()
+    }
+}
+
+/// A queue of time-stamped sideband events, applied to a decoder image as the trace time passes.
+///
+/// The queue tracks the mappings of every process and the process currently running on the
+/// traced cpu. The image given to `advance()` or `apply()` always contains the sections of the
+/// current process, plus the sections of the `base` image (kernel, vdso, ...): it is rebuilt on
+/// every process switch.
+///
+/// This is similar to libipt's `pt_sb` sideband library.
+#[derive(Debug)]
+pub struct SidebandQueue {
+    events: VecDeque<(u64, SidebandEvent)>,
+    loader: MmapLoader,
+    base: Option<Image>,
+    maps: HashMap<u32, Vec<MmapRecord>>,
+    current: Option<u32>,
+}
+
+impl SidebandQueue {
+    /// Create an empty queue, with a new section cache.
+    pub fn new() -> Result<Self, PtError> {
+        Ok(Self::with_loader(MmapLoader::new()?))
+    }
+
+    /// Create an empty queue adding sections with @loader.
+    #[must_use]
+    pub fn with_loader(loader: MmapLoader) -> Self {
+        Self {
+            events: VecDeque::new(),
+            loader,
+            base: None,
+            maps: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// Set the sections shared by all processes, e.g. the kernel.
+    pub fn set_base(&mut self, base: Option<Image>) {
+        self.base = base;
+    }
+
+    /// Queue @event at the time @tsc.
+    ///
+    /// Events with the same time are applied in the order they are pushed.
+    pub fn push(&mut self, tsc: u64, event: SidebandEvent) {
+        let index = self.events.partition_point(|(t, _)| *t <= tsc);
+        self.events.insert(index, (tsc, event));
+    }
+
+    /// The number of pending events.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Whether there are no pending events.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The time of the next pending event.
+    #[must_use]
+    pub fn next_tsc(&self) -> Option<u64> {
+        self.events.front().map(|(tsc, _)| *tsc)
+    }
+
+    /// The process currently running, according to the events applied so far.
+    #[must_use]
+    pub fn current_pid(&self) -> Option<u32> {
+        self.current
+    }
+
+    /// Apply all the events up to the time @tsc (included) to @image.
+    ///
+    /// Returns the number of applied events.
+    pub fn advance(&mut self, tsc: u64, image: &mut Image) -> Result<u32, PtError> {
+        let mut applied = 0;
+        while self.events.front().is_some_and(|(t, _)| *t <= tsc) {
+            let (_, event) = self.events.pop_front().unwrap();
+            self.apply_event(event, image)?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Apply all the events up to the current time of @decoder to its image.
+    ///
+    /// Call it between `decode_next()`/`event()` calls, when the decoder is not running.
+    /// Nothing is applied if the decoder has no time yet.
+    /// Returns the number of applied events.
+    pub fn apply<D>(&mut self, decoder: &mut D) -> Result<u32, PtError>
+    where
+        D: SidebandDecoder,
+    {
+        match decoder.time() {
+            Ok((tsc, _, _)) => self.advance(tsc, decoder.image()),
+            Err(e) if e.code() == PtErrorCode::NoTime => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn apply_event(&mut self, event: SidebandEvent, image: &mut Image) -> Result<(), PtError> {
+        match event {
+            SidebandEvent::Switch { pid } => {
+                if self.current != Some(pid) {
+                    self.current = Some(pid);
+                    self.rebuild(image)?;
+                }
+            }
+            SidebandEvent::Mmap(record) => {
+                let maps = self.maps.entry(record.pid).or_default();
+                unmap(maps, record.addr, record.len);
+                if self.current == Some(record.pid) {
+                    self.loader.add(image, &record, None)?;
+                }
+                maps.push(record);
+            }
+            SidebandEvent::Munmap { pid, addr, len } => {
+                if let Some(maps) = self.maps.get_mut(&pid) {
+                    unmap(maps, addr, len);
+                    if self.current == Some(pid) {
+                        self.rebuild(image)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the content of @image with the base sections and the current process mappings.
+    fn rebuild(&self, image: &mut Image) -> Result<(), PtError> {
+        image.remove_by_asid(&Asid::default())?;
+        if let Some(base) = &self.base {
+            image.extend(base)?;
+        }
+        let records = self.current.and_then(|pid| self.maps.get(&pid));
+        for record in records.into_iter().flatten() {
+            self.loader.add(image, record, None)?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove the range [@addr, @addr + @len[ from @maps, shrinking or splitting overlapping records.
+fn unmap(maps: &mut Vec<MmapRecord>, addr: u64, len: u64) {
+    let end = addr.saturating_add(len);
+    let mut kept = Vec::with_capacity(maps.len());
+    for record in maps.drain(..) {
+        let record_end = record.addr + record.len;
+        if record_end <= addr || record.addr >= end {
+            kept.push(record);
+            continue;
+        }
+        if record.addr < addr {
+            kept.push(MmapRecord {
+                len: addr - record.addr,
+                ..record.clone()
+            });
+        }
+        if record_end > end {
+            kept.push(MmapRecord {
+                pgoff: record.pgoff + (end - record.addr),
+                len: record_end - end,
+                addr: end,
+                ..record
+            });
+        }
+    }
+    *maps = kept;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn record(pid: u32, addr: u64, len: u64) -> MmapRecord {
+        let file: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testfiles", "garbage.txt"]
+            .iter()
+            .collect();
+        MmapRecord {
+            filename: file.to_str().unwrap().to_owned(),
+            pgoff: 0,
+            len,
+            addr,
+            pid,
+        }
+    }
+
+    /// The number of sections of @image.
+    fn sections(image: &Image) -> u32 {
+        let mut copy = Image::new(None).unwrap();
+        copy.extend(image).unwrap();
+        copy.remove_by_asid(&Asid::default()).unwrap()
+    }
+
+    #[test]
+    fn test_sideband_unmap() {
+        let mut maps = vec![record(1, 0x1000, 0x3000)];
+        unmap(&mut maps, 0x2000, 0x1000);
+        assert_eq!(maps.len(), 2);
+        assert_eq!(
+            (maps[0].addr, maps[0].len, maps[0].pgoff),
+            (0x1000, 0x1000, 0)
+        );
+        assert_eq!(
+            (maps[1].addr, maps[1].len, maps[1].pgoff),
+            (0x3000, 0x1000, 0x2000)
+        );
+
+        unmap(&mut maps, 0, 0x10000);
+        assert!(maps.is_empty());
+    }
+
+    #[test]
+    fn test_sideband_queue() {
+        let mut queue = SidebandQueue::new().unwrap();
+        queue.push(30, SidebandEvent::Switch { pid: 2 });
+        queue.push(10, SidebandEvent::Mmap(record(1, 0x1000, 8)));
+        queue.push(10, SidebandEvent::Mmap(record(2, 0x2000, 8)));
+        queue.push(20, SidebandEvent::Switch { pid: 1 });
+        queue.push(
+            40,
+            SidebandEvent::Munmap {
+                pid: 2,
+                addr: 0x2000,
+                len: 8,
+            },
+        );
+        assert_eq!(queue.next_tsc(), Some(10));
+
+        let mut image = Image::new(None).unwrap();
+        assert_eq!(queue.advance(5, &mut image).unwrap(), 0);
+        assert_eq!(queue.advance(20, &mut image).unwrap(), 3);
+        assert_eq!(queue.current_pid(), Some(1));
+        assert_eq!(sections(&image), 1);
+
+        // Mappings of a process are added when it is scheduled in.
+        let mut base = Image::new(None).unwrap();
+        base.add_bytes(std::sync::Arc::from([0xc3]), 0xffff_8000_0000_0000, None)
+            .unwrap();
+        queue.set_base(Some(base));
+        assert_eq!(queue.advance(30, &mut image).unwrap(), 1);
+        assert_eq!(queue.current_pid(), Some(2));
+        assert_eq!(sections(&image), 2);
+
+        assert_eq!(queue.advance(u64::MAX, &mut image).unwrap(), 1);
+        assert_eq!(sections(&image), 1);
+        assert!(queue.is_empty());
+    }
+}
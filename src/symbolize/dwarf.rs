@@ -0,0 +1,544 @@
+use crate::image::elf::Endian;
+use std::rc::Rc;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK2: u64 = 0x03;
+const DW_FORM_BLOCK4: u64 = 0x04;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+/// The string sections referenced by DWARF 5 line tables.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct StrSections<'a> {
+    /// `.debug_str`
+    pub(crate) str: &'a [u8],
+    /// `.debug_line_str`
+    pub(crate) line_str: &'a [u8],
+}
+
+/// A row of a line table.
+#[derive(Debug, Clone)]
+struct Row {
+    address: u64,
+    end_sequence: bool,
+    file: Option<Rc<str>>,
+    line: u32,
+}
+
+/// The address to source line mapping of a `.debug_line` section.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LineTable {
+    rows: Vec<Row>,
+}
+
+impl LineTable {
+    /// Parse all the line programs of @debug_line.
+    ///
+    /// Parsing stops at the first malformed unit, the rows of the previous units are kept.
+    pub(crate) fn parse(debug_line: &[u8], strs: StrSections, endian: Endian) -> Self {
+        let mut rows = Vec::new();
+        let mut reader = Reader::new(debug_line, endian);
+        while !reader.data.is_empty() {
+            if parse_unit(&mut reader, strs, &mut rows).is_none() {
+                break;
+            }
+        }
+        // End of sequence rows first: a sequence can start where another one ends.
+        rows.sort_by_key(|row| (row.address, !row.end_sequence));
+        Self { rows }
+    }
+
+    /// The file and line of the instruction at @address.
+    pub(crate) fn find(&self, address: u64) -> Option<(Rc<str>, u32)> {
+        let index = self.rows.partition_point(|row| row.address <= address);
+        let row = &self.rows[index.checked_sub(1)?];
+        if row.end_sequence {
+            return None;
+        }
+        Some((row.file.clone()?, row.line))
+    }
+}
+
+/// A bounds checked DWARF reader.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    data: &'a [u8],
+    endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], endian: Endian) -> Self {
+        Self { data, endian }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| self.endian.u16(b, 0))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| self.endian.u32(b, 0))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8).map(|b| self.endian.u64(b, 0))
+    }
+
+    /// An offset or a length, 4 or 8 bytes wide.
+    fn offset(&mut self, dwarf64: bool) -> Option<u64> {
+        if dwarf64 {
+            self.u64()
+        } else {
+            self.u32().map(u64::from)
+        }
+    }
+
+    fn address(&mut self, size: usize) -> Option<u64> {
+        match size {
+            4 => self.u32().map(u64::from),
+            8 => self.u64(),
+            _ => None,
+        }
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    /// A NUL terminated string.
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.data.iter().position(|&b| b == 0)?;
+        let s = self.bytes(len)?;
+        self.bytes(1)?;
+        std::str::from_utf8(s).ok()
+    }
+}
+
+/// The NUL terminated string at @offset in @section.
+fn str_at(section: &[u8], offset: u64) -> Option<&str> {
+    let data = section.get(usize::try_from(offset).ok()?..)?;
+    let len = data.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&data[..len]).ok()
+}
+
+/// Join @dir and @name, unless @name is absolute.
+fn join(dir: Option<&str>, name: &str) -> Rc<str> {
+    match dir {
+        Some(dir) if !dir.is_empty() && !name.starts_with('/') => {
+            format!("{}/{name}", dir.trim_end_matches('/')).into()
+        }
+        _ => name.into(),
+    }
+}
+
+/// A DWARF 5 directory or file name entry.
+#[derive(Default)]
+struct Entry<'a> {
+    path: Option<&'a str>,
+    dir: u64,
+}
+
+/// Read the DWARF 5 entries of a directory or file name table.
+fn read_entries<'a>(
+    reader: &mut Reader<'a>,
+    strs: StrSections<'a>,
+    dwarf64: bool,
+) -> Option<Vec<Entry<'a>>> {
+    let format_count = reader.u8()?;
+    let mut formats = Vec::with_capacity(usize::from(format_count));
+    for _ in 0..format_count {
+        formats.push((reader.uleb()?, reader.uleb()?));
+    }
+
+    // Every entry takes at least one byte per format: there can't be more entries than bytes
+    // left, and none without formats.
+    let count = reader.uleb()?;
+    if (count > 0 && formats.is_empty()) || count > u64::try_from(reader.data.len()).ok()? {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut entry = Entry::default();
+        for &(content, form) in &formats {
+            let mut value = None;
+            let mut path = None;
+            match form {
+                DW_FORM_STRING => path = Some(reader.str()?),
+                DW_FORM_LINE_STRP => path = str_at(strs.line_str, reader.offset(dwarf64)?),
+                DW_FORM_STRP => path = str_at(strs.str, reader.offset(dwarf64)?),
+                DW_FORM_UDATA => value = Some(reader.uleb()?),
+                DW_FORM_DATA1 => value = Some(u64::from(reader.u8()?)),
+                DW_FORM_DATA2 => value = Some(u64::from(reader.u16()?)),
+                DW_FORM_DATA4 => value = Some(u64::from(reader.u32()?)),
+                DW_FORM_DATA8 => value = Some(reader.u64()?),
+                DW_FORM_DATA16 => _ = reader.bytes(16)?,
+                DW_FORM_BLOCK => {
+                    let len = reader.uleb()?;
+                    reader.bytes(usize::try_from(len).ok()?)?;
+                }
+                DW_FORM_BLOCK1 => _ = reader.bytes(usize::from(reader.u8()?))?,
+                DW_FORM_BLOCK2 => _ = reader.bytes(usize::from(reader.u16()?))?,
+                DW_FORM_BLOCK4 => _ = reader.bytes(usize::try_from(reader.u32()?).ok()?)?,
+                _ => return None,
+            }
+            match content {
+                DW_LNCT_PATH => entry.path = path,
+                DW_LNCT_DIRECTORY_INDEX => entry.dir = value.unwrap_or(0),
+                _ => {}
+            }
+        }
+        entries.push(entry);
+    }
+    Some(entries)
+}
+
+/// Parse a line program unit, appending its rows to @rows.
+fn parse_unit(reader: &mut Reader, strs: StrSections, rows: &mut Vec<Row>) -> Option<()> {
+    let mut dwarf64 = false;
+    let mut length = u64::from(reader.u32()?);
+    if length == 0xffff_ffff {
+        dwarf64 = true;
+        length = reader.u64()?;
+    }
+    let mut unit = Reader::new(reader.bytes(usize::try_from(length).ok()?)?, reader.endian);
+
+    let version = unit.u16()?;
+    if !(2..=5).contains(&version) {
+        return None;
+    }
+    let mut address_size = None;
+    if version >= 5 {
+        address_size = Some(usize::from(unit.u8()?));
+        // segment_selector_size
+        unit.u8()?;
+    }
+    let header_length = unit.offset(dwarf64)?;
+    let mut header = Reader::new(
+        unit.bytes(usize::try_from(header_length).ok()?)?,
+        unit.endian,
+    );
+    let mut program = unit;
+
+    let min_insn_length = u64::from(header.u8()?);
+    if version >= 4 {
+        // maximum_operations_per_instruction, VLIW only.
+        header.u8()?;
+    }
+    // default_is_stmt
+    header.u8()?;
+    let line_base = i64::from(header.u8()? as i8);
+    let line_range = header.u8()?;
+    let opcode_base = header.u8()?;
+    if line_range == 0 || opcode_base == 0 {
+        return None;
+    }
+    let opcode_lengths = header.bytes(usize::from(opcode_base - 1))?;
+
+    // The file names, indexed by the file register.
+    let mut files: Vec<Option<Rc<str>>> = Vec::new();
+    if version >= 5 {
+        let dirs = read_entries(&mut header, strs, dwarf64)?;
+        for file in read_entries(&mut header, strs, dwarf64)? {
+            let dir = usize::try_from(file.dir)
+                .ok()
+                .and_then(|dir| dirs.get(dir))
+                .and_then(|dir| dir.path);
+            files.push(file.path.map(|path| join(dir, path)));
+        }
+    } else {
+        let mut dirs = Vec::new();
+        loop {
+            let dir = header.str()?;
+            if dir.is_empty() {
+                break;
+            }
+            dirs.push(dir);
+        }
+        // File indices start at 1 before DWARF 5.
+        files.push(None);
+        loop {
+            let name = header.str()?;
+            if name.is_empty() {
+                break;
+            }
+            let dir = header.uleb()?;
+            // mtime and length
+            header.uleb()?;
+            header.uleb()?;
+            let dir = usize::try_from(dir)
+                .ok()
+                .and_then(|dir| dir.checked_sub(1))
+                .and_then(|dir| dirs.get(dir).copied());
+            files.push(Some(join(dir, name)));
+        }
+    }
+
+    let mut address = 0u64;
+    let mut file = 1u64;
+    let mut line = 1i64;
+    let file_name = |files: &[Option<Rc<str>>], file: u64| {
+        usize::try_from(file)
+            .ok()
+            .and_then(|file| files.get(file))
+            .cloned()
+            .flatten()
+    };
+    let row = |rows: &mut Vec<Row>, address, file, line: i64, end_sequence| {
+        rows.push(Row {
+            address,
+            end_sequence,
+            file,
+            line: u32::try_from(line).unwrap_or(0),
+        });
+    };
+
+    while !program.data.is_empty() {
+        let opcode = program.u8()?;
+        if opcode >= opcode_base {
+            let adjusted = opcode - opcode_base;
+            address = address.wrapping_add(u64::from(adjusted / line_range) * min_insn_length);
+            // A line overflow ends the unit, as any malformed unit.
+            line = line.checked_add(line_base + i64::from(adjusted % line_range))?;
+            row(rows, address, file_name(&files, file), line, false);
+            continue;
+        }
+        match opcode {
+            0 => {
+                let len = usize::try_from(program.uleb()?).ok()?;
+                let mut extended = Reader::new(program.bytes(len)?, program.endian);
+                match extended.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        row(rows, address, None, line, true);
+                        address = 0;
+                        file = 1;
+                        line = 1;
+                    }
+                    DW_LNE_SET_ADDRESS => {
+                        let size = address_size.unwrap_or(len - 1);
+                        address = extended.address(size)?;
+                    }
+                    DW_LNE_DEFINE_FILE => {
+                        let name = extended.str()?;
+                        files.push(Some(name.into()));
+                    }
+                    _ => {}
+                }
+            }
+            DW_LNS_COPY => row(rows, address, file_name(&files, file), line, false),
+            DW_LNS_ADVANCE_PC => {
+                address = address.wrapping_add(program.uleb()?.wrapping_mul(min_insn_length));
+            }
+            DW_LNS_ADVANCE_LINE => line = line.checked_add(program.sleb()?)?,
+            DW_LNS_SET_FILE => file = program.uleb()?,
+            DW_LNS_CONST_ADD_PC => {
+                let adjusted = (255 - opcode_base) / line_range;
+                address = address.wrapping_add(u64::from(adjusted) * min_insn_length);
+            }
+            DW_LNS_FIXED_ADVANCE_PC => {
+                address = address.wrapping_add(u64::from(program.u16()?));
+            }
+            _ => {
+                // Other standard opcodes only have ULEB128 operands.
+                for _ in 0..opcode_lengths[usize::from(opcode - 1)] {
+                    program.uleb()?;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A DWARF 4 line program for main.c at @address, with two rows and an end of sequence.
+    pub(crate) fn debug_line(address: u64) -> Vec<u8> {
+        let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+        program.extend_from_slice(&address.to_le_bytes());
+        program.extend_from_slice(&[
+            DW_LNS_ADVANCE_LINE,
+            9, // line 10
+            DW_LNS_COPY,
+            // address += 4, line += 2: (2 - line_base) + 14 * 4 + opcode_base
+            (2 + 5) + 14 * 4 + 13,
+            DW_LNS_ADVANCE_PC,
+            8,
+            0,
+            1,
+            DW_LNE_END_SEQUENCE,
+        ]);
+        line_unit(&program)
+    }
+
+    /// A DWARF 4 line program unit for main.c, running @program.
+    fn line_unit(program: &[u8]) -> Vec<u8> {
+        let mut header = vec![
+            1,            // minimum_instruction_length
+            1,            // maximum_operations_per_instruction
+            1,            // default_is_stmt
+            (-5i8) as u8, // line_base
+            14,           // line_range
+            13,           // opcode_base
+            0,
+            1,
+            1,
+            1,
+            1,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            1, // standard_opcode_lengths
+        ];
+        header.extend_from_slice(b"/src\0\0");
+        header.extend_from_slice(b"main.c\0\x01\0\0\0");
+
+        let mut unit = 4u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend_from_slice(program);
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend(unit);
+        data
+    }
+
+    #[test]
+    fn test_dwarf_lines() {
+        let table = LineTable::parse(&debug_line(0x1000), StrSections::default(), Endian(true));
+        assert_eq!(table.find(0xfff), None);
+        assert_eq!(table.find(0x1000), Some(("/src/main.c".into(), 10)));
+        assert_eq!(table.find(0x1003), Some(("/src/main.c".into(), 10)));
+        assert_eq!(table.find(0x1004), Some(("/src/main.c".into(), 12)));
+        assert_eq!(table.find(0x100b), Some(("/src/main.c".into(), 12)));
+        assert_eq!(table.find(0x100c), None);
+    }
+
+    #[test]
+    fn test_dwarf_line_overflow() {
+        // A line advance overflowing the line number, after a valid unit.
+        let mut program = vec![0, 9, DW_LNE_SET_ADDRESS];
+        program.extend_from_slice(&0x2000u64.to_le_bytes());
+        program.push(DW_LNS_ADVANCE_LINE);
+        program.extend_from_slice(&[0xff; 9]);
+        program.extend_from_slice(&[0, DW_LNS_COPY]);
+        let mut data = debug_line(0x1000);
+        data.extend(line_unit(&program));
+
+        let table = LineTable::parse(&data, StrSections::default(), Endian(true));
+        assert_eq!(table.find(0x1000), Some(("/src/main.c".into(), 10)));
+        assert_eq!(table.find(0x2000), None);
+    }
+
+    #[test]
+    fn test_dwarf_entries() {
+        let strs = StrSections::default();
+        // No formats and no entries.
+        let mut reader = Reader::new(&[0, 0], Endian(true));
+        assert_eq!(
+            read_entries(&mut reader, strs, false).map(|e| e.len()),
+            Some(0)
+        );
+        // No formats for 2^63 entries.
+        let mut huge = vec![0];
+        huge.extend_from_slice(&[0xff; 8]);
+        huge.push(0x7f);
+        let mut reader = Reader::new(&huge, Endian(true));
+        assert!(read_entries(&mut reader, strs, false).is_none());
+        // More path entries than bytes left.
+        let mut reader = Reader::new(
+            &[
+                1,
+                DW_LNCT_PATH as u8,
+                DW_FORM_STRING as u8,
+                0xe8,
+                0x07,
+                b'a',
+                0,
+            ],
+            Endian(true),
+        );
+        assert!(read_entries(&mut reader, strs, false).is_none());
+        let mut reader = Reader::new(
+            &[1, DW_LNCT_PATH as u8, DW_FORM_STRING as u8, 1, b'a', 0],
+            Endian(true),
+        );
+        let entries = read_entries(&mut reader, strs, false).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, Some("a"));
+    }
+
+    #[test]
+    fn test_dwarf_leb() {
+        let mut reader = Reader::new(&[0xe5, 0x8e, 0x26, 0x7f, 0x80, 0x7f], Endian(true));
+        assert_eq!(reader.uleb(), Some(624_485));
+        assert_eq!(reader.sleb(), Some(-1));
+        assert_eq!(reader.sleb(), Some(-128));
+        assert_eq!(reader.u8(), None);
+    }
+}
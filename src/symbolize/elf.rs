@@ -0,0 +1,170 @@
+use super::dwarf::{LineTable, StrSections};
+use crate::error::PtError;
+use crate::image::elf::{Ehdr, PT_LOAD, Phdr, Shdr, bad_elf};
+use std::io::Cursor;
+use std::rc::Rc;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHT_DYNSYM: u32 = 11;
+const SHF_COMPRESSED: u64 = 0x800;
+const STT_FUNC: u8 = 2;
+
+/// A function symbol, at its link address.
+#[derive(Debug, Clone)]
+pub(crate) struct FuncSym {
+    pub(crate) address: u64,
+    pub(crate) size: u64,
+    pub(crate) name: Rc<str>,
+}
+
+/// The symbols and the line table of an ELF file.
+#[derive(Debug, Clone)]
+pub(crate) struct DebugInfo {
+    /// The `PT_LOAD` segments, mapping file offsets to link addresses
+    loads: Vec<Phdr>,
+    symbols: Vec<FuncSym>,
+    lines: Option<LineTable>,
+}
+
+/// The @len bytes at @off of @data.
+fn slice(data: &[u8], off: u64, len: u64) -> Result<&[u8], PtError> {
+    usize::try_from(off)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(off, len)| data.get(off..off.checked_add(len)?))
+        .ok_or_else(|| bad_elf("Truncated ELF file"))
+}
+
+/// The NUL terminated string at @off of @strtab.
+fn str_at(strtab: &[u8], off: u32) -> Option<&str> {
+    let data = strtab.get(usize::try_from(off).ok()?..)?;
+    let len = data.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&data[..len]).ok()
+}
+
+impl DebugInfo {
+    /// Parse the program headers, the function symbols and, if @lines, the `.debug_line`
+    /// section of the ELF file @data.
+    ///
+    /// Returns `BadImage` if @data is not a valid ELF file.
+    pub(crate) fn parse(data: &[u8], lines: bool) -> Result<Self, PtError> {
+        let mut reader = Cursor::new(data);
+        let ehdr = Ehdr::read(&mut reader)?;
+        let (endian, is64) = (ehdr.endian, ehdr.is64);
+        let shdrs = ehdr.shdrs(&mut reader)?;
+        let loads = ehdr
+            .phdrs(&mut reader)?
+            .into_iter()
+            .filter(|phdr| phdr.kind == PT_LOAD)
+            .collect();
+
+        // The content of a section, None for sections without file data.
+        let content = |shdr: &Shdr| -> Result<Option<&[u8]>, PtError> {
+            if shdr.kind == SHT_NOBITS || shdr.flags & SHF_COMPRESSED != 0 {
+                return Ok(None);
+            }
+            slice(data, shdr.offset, shdr.size).map(Some)
+        };
+        let shstrtab = match shdrs.get(ehdr.shstrndx as usize) {
+            Some(shdr) => content(shdr)?.unwrap_or_default(),
+            None => &[],
+        };
+        let section = |name: &str| -> Result<&[u8], PtError> {
+            match shdrs
+                .iter()
+                .find(|shdr| str_at(shstrtab, shdr.name) == Some(name))
+            {
+                Some(shdr) => Ok(content(shdr)?.unwrap_or_default()),
+                None => Ok(&[]),
+            }
+        };
+
+        // Function symbols, from the static symbol table first.
+        let mut symbols = Vec::new();
+        for kind in [SHT_SYMTAB, SHT_DYNSYM] {
+            for shdr in shdrs.iter().filter(|shdr| shdr.kind == kind) {
+                let Some(symtab) = content(shdr)? else {
+                    continue;
+                };
+                let strtab = match shdrs.get(shdr.link as usize) {
+                    Some(strtab) => content(strtab)?.unwrap_or_default(),
+                    None => &[],
+                };
+                let entsize = if is64 { 24 } else { 16 };
+                for sym in symtab.chunks_exact(entsize) {
+                    let (name, info, shndx, address, size) = if is64 {
+                        (
+                            endian.u32(sym, 0),
+                            sym[4],
+                            endian.u16(sym, 6),
+                            endian.u64(sym, 8),
+                            endian.u64(sym, 16),
+                        )
+                    } else {
+                        (
+                            endian.u32(sym, 0),
+                            sym[12],
+                            endian.u16(sym, 14),
+                            u64::from(endian.u32(sym, 4)),
+                            u64::from(endian.u32(sym, 8)),
+                        )
+                    };
+                    // Skip undefined symbols.
+                    if info & 0xf != STT_FUNC || shndx == 0 {
+                        continue;
+                    }
+                    let Some(name) = str_at(strtab, name).filter(|name| !name.is_empty()) else {
+                        continue;
+                    };
+                    symbols.push(FuncSym {
+                        address,
+                        size,
+                        name: name.into(),
+                    });
+                }
+            }
+        }
+        // The sort is stable, the static symbols are kept over their dynamic copies.
+        symbols.sort_by_key(|sym| sym.address);
+        symbols.dedup_by_key(|sym| sym.address);
+
+        let lines = if lines {
+            let strs = StrSections {
+                str: section(".debug_str")?,
+                line_str: section(".debug_line_str")?,
+            };
+            Some(LineTable::parse(section(".debug_line")?, strs, endian))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            loads,
+            symbols,
+            lines,
+        })
+    }
+
+    /// The link address of the byte at file offset @offset.
+    pub(crate) fn link_address(&self, offset: u64) -> Option<u64> {
+        self.loads
+            .iter()
+            .find(|load| offset >= load.offset && offset - load.offset < load.size)
+            .map(|load| load.vaddr + (offset - load.offset))
+    }
+
+    /// The function containing the link address @address.
+    ///
+    /// Symbols without a size extend up to the next symbol.
+    pub(crate) fn function(&self, address: u64) -> Option<&FuncSym> {
+        let index = self.symbols.partition_point(|sym| sym.address <= address);
+        let sym = &self.symbols[index.checked_sub(1)?];
+        (sym.size == 0 || address - sym.address < sym.size).then_some(sym)
+    }
+
+    /// The source file and line of the link address @address.
+    pub(crate) fn line(&self, address: u64) -> Option<(Rc<str>, u32)> {
+        self.lines.as_ref()?.find(address)
+    }
+}
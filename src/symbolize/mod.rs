@@ -0,0 +1,427 @@
+mod dwarf;
+mod elf;
+
+use crate::block::Block;
+use crate::error::{PtError, PtErrorCode};
+use crate::image::elf::{ElfFile, bad_elf};
+use crate::image::{Image, SectionInfo};
+use elf::DebugInfo;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+/// The function, and optionally the source line, of an instruction pointer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    name: Rc<str>,
+    offset: u64,
+    location: Option<(Rc<str>, u32)>,
+}
+
+impl Symbol {
+    /// The (mangled) name of the function.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The offset of the instruction pointer from the start of the function.
+    #[must_use]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The source file of the instruction, if line info is available.
+    #[must_use]
+    pub fn file(&self) -> Option<&str> {
+        self.location.as_ref().map(|(file, _)| &**file)
+    }
+
+    /// The source line of the instruction, if line info is available.
+    #[must_use]
+    pub fn line(&self) -> Option<u32> {
+        self.location.as_ref().map(|&(_, line)| line)
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)?;
+        if let Some((file, line)) = &self.location {
+            write!(f, " at {file}:{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A file section mapped in memory.
+#[derive(Debug, Clone)]
+struct Mapping {
+    vaddr: u64,
+    size: u64,
+    /// The link address of the start of the section
+    link: u64,
+    info: Rc<DebugInfo>,
+}
+
+impl Mapping {
+    /// Whether @other maps the same file section at the same address.
+    fn same_section(&self, other: &Mapping) -> bool {
+        self.vaddr == other.vaddr
+            && self.size == other.size
+            && self.link == other.link
+            && Rc::ptr_eq(&self.info, &other.info)
+    }
+}
+
+/// Resolves instruction pointers to functions and source lines, from the ELF symbol tables and
+/// the DWARF line info of the traced binaries.
+///
+/// The symbolizer uses the same sections as the `Image` used for decoding: `add_image()` adds
+/// the sections the image reads through a `SectionCache`, with their isid. Other sections are
+/// added with `add_section()`.
+/// Isids are only unique within a `SectionCache`, and `Block::isid()` doesn't tell the cache
+/// apart: the sections of a symbolizer must come from a single cache.
+/// Each file is parsed once, results are cached per isid and IP.
+///
+/// Only uncompressed `.symtab`, `.dynsym` and `.debug_line` sections are supported,
+/// separate debug info files are not looked up.
+#[derive(Debug, Clone)]
+pub struct Symbolizer {
+    lines: bool,
+    files: HashMap<String, Rc<DebugInfo>>,
+    mappings: Vec<Mapping>,
+    isids: HashMap<u32, usize>,
+    cache: HashMap<(u32, u64), Option<Symbol>>,
+}
+
+impl Default for Symbolizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Symbolizer {
+    /// Create a symbolizer without any section, resolving source lines.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            lines: true,
+            files: HashMap::new(),
+            mappings: Vec::new(),
+            isids: HashMap::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Whether to parse the `.debug_line` sections to resolve source lines.
+    ///
+    /// This only applies to the files added afterwards.
+    #[must_use]
+    pub fn with_lines(mut self, lines: bool) -> Self {
+        self.lines = lines;
+        self
+    }
+
+    /// Use the ELF file @data as the content of @filename, instead of reading it from disk.
+    ///
+    /// Returns `BadImage` if @data is not a valid ELF file.
+    pub fn add_file_bytes(&mut self, filename: &str, data: &[u8]) -> Result<(), PtError> {
+        let info = DebugInfo::parse(data, self.lines)?;
+        self.files.insert(filename.to_owned(), Rc::new(info));
+        Ok(())
+    }
+
+    /// Add the file section @section, loaded as image section @isid.
+    ///
+    /// @isid is the identifier returned by `SectionCache::add_file()`, if the section was
+    /// added to the image through a cache. The file is read and parsed the first time it is
+    /// added.
+    /// Returns `BadFile` if the file can't be read.
+    /// Returns `BadImage` if the file is not a valid ELF file or @section is not part of a
+    /// `PT_LOAD` segment.
+    /// Returns `BadImage` if @isid identifies another section, added from another cache.
+    pub fn add_section(&mut self, section: &SectionInfo, isid: Option<u32>) -> Result<(), PtError> {
+        if !self.files.contains_key(&section.filename) {
+            let data = std::fs::read(&section.filename)
+                .map_err(|_| PtError::new(PtErrorCode::BadFile, "Failed to read the ELF file"))?;
+            self.add_file_bytes(&section.filename, &data)?;
+        }
+        let info = self.files[&section.filename].clone();
+        let link = info
+            .link_address(section.offset)
+            .ok_or_else(|| bad_elf("Section not contained in a PT_LOAD segment"))?;
+
+        let mapping = Mapping {
+            vaddr: section.virtual_address,
+            size: section.size,
+            link,
+            info,
+        };
+        if let Some(isid) = isid {
+            let known = self.isids.get(&isid).map(|&index| &self.mappings[index]);
+            if known.is_some_and(|known| !known.same_section(&mapping)) {
+                return Err(PtError::new(
+                    PtErrorCode::BadImage,
+                    "The isid identifies another section, from another SectionCache",
+                ));
+            }
+            self.isids.insert(isid, self.mappings.len());
+        }
+        self.mappings.push(mapping);
+        self.cache.clear();
+        Ok(())
+    }
+
+    /// Add the sections @image reads through a `SectionCache`, along with their isid.
+    ///
+    /// The sections added to @image with `Image::add_file()` or `Image::add_bytes()` are not
+    /// known, add them with `add_section()`.
+    /// Returns the number of added sections on success.
+    /// See `add_section()`.
+    pub fn add_image(&mut self, image: &Image) -> Result<u32, PtError> {
+        let mut added = 0;
+        for (isid, section, _) in image.cached_sections() {
+            self.add_section(&section, Some(isid))?;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Add the executable segments of @elf, relocated by @bias, as loaded by `ElfLoader`.
+    ///
+    /// Returns the number of added sections on success.
+    /// See `add_section()`.
+    pub fn add_elf(&mut self, elf: &ElfFile, bias: u64) -> Result<u32, PtError> {
+        let mut added = 0;
+        for section in elf.sections(bias) {
+            self.add_section(&section, None)?;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Resolve @ip, looking it up in all the added sections.
+    ///
+    /// If several sections overlap @ip, the last added one is used.
+    /// Returns None if @ip is not in any section or not in a function.
+    pub fn symbolize(&mut self, ip: u64) -> Option<Symbol> {
+        self.symbolize_isid(0, ip)
+    }
+
+    /// Resolve @ip, in the image section @isid.
+    ///
+    /// An @isid of zero, or of a section that was not added, falls back to `symbolize()`.
+    pub fn symbolize_isid(&mut self, isid: u32, ip: u64) -> Option<Symbol> {
+        if let Some(symbol) = self.cache.get(&(isid, ip)) {
+            return symbol.clone();
+        }
+
+        let contains = |m: &Mapping| ip >= m.vaddr && ip - m.vaddr < m.size;
+        let mapping = self
+            .isids
+            .get(&isid)
+            .map(|&index| &self.mappings[index])
+            .filter(|m| contains(m))
+            .or_else(|| self.mappings.iter().rev().find(|m| contains(m)));
+        let symbol = mapping.and_then(|m| {
+            let address = m.link.wrapping_add(ip - m.vaddr);
+            let function = m.info.function(address)?;
+            Some(Symbol {
+                name: function.name.clone(),
+                offset: address - function.address,
+                location: m.info.line(address),
+            })
+        });
+
+        self.cache.insert((isid, ip), symbol.clone());
+        symbol
+    }
+
+    /// Resolve the first instruction of @block, in its image section.
+    pub fn symbolize_block(&mut self, block: &Block) -> Option<Symbol> {
+        self.symbolize_isid(u32::try_from(block.isid()).unwrap_or(0), block.ip())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::SectionCache;
+    use crate::image::elf::{ELF_MAGIC, ELFCLASS64, ELFDATA2LSB};
+
+    const TEXT: u64 = 0x1000;
+    const LINK: u64 = 0x40_1000;
+    const BASE: u64 = 0x7f00_0000_1000;
+
+    fn put(elf: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        if elf.len() < offset + bytes.len() {
+            elf.resize(offset + bytes.len(), 0);
+        }
+        elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// A 64 bit ELF file with `main` and `helper`, and line info for `main`.
+    fn elf64() -> Vec<u8> {
+        let mut elf = vec![0u8; 64];
+        elf[..4].copy_from_slice(&ELF_MAGIC);
+        elf[4] = ELFCLASS64;
+        elf[5] = ELFDATA2LSB;
+        put(&mut elf, 16, &2u16.to_le_bytes());
+        put(&mut elf, 32, &64u64.to_le_bytes()); // e_phoff
+        put(&mut elf, 40, &0x1800u64.to_le_bytes()); // e_shoff
+        put(&mut elf, 54, &56u16.to_le_bytes()); // e_phentsize
+        put(&mut elf, 56, &1u16.to_le_bytes()); // e_phnum
+        put(&mut elf, 58, &64u16.to_le_bytes()); // e_shentsize
+        put(&mut elf, 60, &6u16.to_le_bytes()); // e_shnum
+        put(&mut elf, 62, &4u16.to_le_bytes()); // e_shstrndx
+
+        let mut phdr = [0u8; 56];
+        phdr[..4].copy_from_slice(&1u32.to_le_bytes());
+        phdr[4..8].copy_from_slice(&5u32.to_le_bytes());
+        phdr[8..16].copy_from_slice(&TEXT.to_le_bytes());
+        phdr[16..24].copy_from_slice(&LINK.to_le_bytes());
+        phdr[32..40].copy_from_slice(&0x100u64.to_le_bytes());
+        put(&mut elf, 64, &phdr);
+
+        put(&mut elf, 0x1100, b"\0main\0helper\0");
+        for (i, (name, address, size)) in [(1u32, LINK, 0x10u64), (6, LINK + 0x10, 0x20)]
+            .into_iter()
+            .enumerate()
+        {
+            let mut sym = [0u8; 24];
+            sym[..4].copy_from_slice(&name.to_le_bytes());
+            sym[4] = 0x12; // STB_GLOBAL, STT_FUNC
+            sym[6..8].copy_from_slice(&1u16.to_le_bytes());
+            sym[8..16].copy_from_slice(&address.to_le_bytes());
+            sym[16..24].copy_from_slice(&size.to_le_bytes());
+            put(&mut elf, 0x1200 + (i + 1) * 24, &sym);
+        }
+        put(
+            &mut elf,
+            0x1300,
+            b"\0.text\0.strtab\0.symtab\0.shstrtab\0.debug_line\0",
+        );
+        let debug_line = dwarf::test::debug_line(LINK);
+        put(&mut elf, 0x1400, &debug_line);
+
+        // name, type, offset, size, link
+        for (i, (name, kind, offset, size, link)) in [
+            (1u32, 1u32, TEXT, 0x100u64, 0u32),
+            (7, 3, 0x1100, 13, 0),
+            (15, 2, 0x1200, 3 * 24, 2),
+            (23, 3, 0x1300, 45, 0),
+            (33, 1, 0x1400, debug_line.len() as u64, 0),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut shdr = [0u8; 64];
+            shdr[..4].copy_from_slice(&name.to_le_bytes());
+            shdr[4..8].copy_from_slice(&kind.to_le_bytes());
+            shdr[24..32].copy_from_slice(&offset.to_le_bytes());
+            shdr[32..40].copy_from_slice(&size.to_le_bytes());
+            shdr[40..44].copy_from_slice(&link.to_le_bytes());
+            put(&mut elf, 0x1800 + (i + 1) * 64, &shdr);
+        }
+        elf
+    }
+
+    fn section() -> SectionInfo {
+        SectionInfo {
+            filename: "a.out".to_owned(),
+            offset: TEXT,
+            size: 0x100,
+            virtual_address: BASE,
+        }
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_file_bytes("a.out", &elf64()).unwrap();
+        symbolizer.add_section(&section(), Some(7)).unwrap();
+
+        let symbol = symbolizer.symbolize(BASE + 4).unwrap();
+        assert_eq!(symbol.name(), "main");
+        assert_eq!(symbol.offset(), 4);
+        assert_eq!(symbol.file(), Some("/src/main.c"));
+        assert_eq!(symbol.line(), Some(12));
+        assert_eq!(symbol.to_string(), "main+0x4 at /src/main.c:12");
+
+        let symbol = symbolizer.symbolize_isid(7, BASE + 0x18).unwrap();
+        assert_eq!(symbol.to_string(), "helper+0x8");
+        assert_eq!(symbolizer.symbolize_isid(7, BASE + 0x18), Some(symbol));
+
+        // Past the end of helper, and outside of the section.
+        assert_eq!(symbolizer.symbolize(BASE + 0x30), None);
+        assert_eq!(symbolizer.symbolize(BASE + 0x100), None);
+    }
+
+    #[test]
+    fn test_symbolize_isid_collision() {
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_file_bytes("a.out", &elf64()).unwrap();
+        symbolizer.add_section(&section(), Some(7)).unwrap();
+        // The same section, in another address space.
+        symbolizer.add_section(&section(), Some(7)).unwrap();
+
+        // Another section with the same isid, from another cache.
+        let mut other = section();
+        other.virtual_address = BASE + 0x1000;
+        assert_eq!(
+            symbolizer.add_section(&other, Some(7)).unwrap_err().code(),
+            PtErrorCode::BadImage
+        );
+        assert_eq!(
+            symbolizer
+                .symbolize_isid(7, BASE + 0x18)
+                .unwrap()
+                .to_string(),
+            "helper+0x8"
+        );
+    }
+
+    #[test]
+    fn test_symbolize_image() {
+        let path = std::env::temp_dir().join(format!("libipt-symbolize-{}", std::process::id()));
+        std::fs::write(&path, elf64()).unwrap();
+        let filename = path.to_str().unwrap();
+
+        let mut cache = SectionCache::new(None).unwrap();
+        let isid = cache.add_file(filename, TEXT, 0x100, BASE).unwrap();
+        let mut image = Image::new(None).unwrap();
+        image.add_cached(Rc::new(cache), isid, None).unwrap();
+
+        let mut symbolizer = Symbolizer::new();
+        let added = symbolizer.add_image(&image);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(added.unwrap(), 1);
+        let symbol = symbolizer.symbolize_isid(isid, BASE + 0x18).unwrap();
+        assert_eq!(symbol.to_string(), "helper+0x8");
+    }
+
+    #[test]
+    fn test_symbolize_no_lines() {
+        let mut symbolizer = Symbolizer::new().with_lines(false);
+        symbolizer.add_file_bytes("a.out", &elf64()).unwrap();
+        symbolizer.add_section(&section(), None).unwrap();
+        assert_eq!(symbolizer.symbolize(BASE).unwrap().to_string(), "main+0x0");
+    }
+
+    #[test]
+    fn test_symbolize_invalid() {
+        let mut symbolizer = Symbolizer::new();
+        assert!(symbolizer.add_file_bytes("a.out", &[0u8; 64]).is_err());
+        assert!(symbolizer.add_section(&section(), None).is_err());
+        assert!(symbolizer.add_file_bytes("b.out", &elf64()[..100]).is_err());
+
+        symbolizer.add_file_bytes("a.out", &elf64()).unwrap();
+        let mut section = section();
+        section.offset = 0x2000;
+        assert_eq!(
+            symbolizer.add_section(&section, None).unwrap_err().code(),
+            PtErrorCode::BadImage
+        );
+    }
+}
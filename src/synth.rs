@@ -0,0 +1,399 @@
+use crate::edges::RET_STACK_SIZE;
+use crate::enc_dec_builder::{Cpu, PtEncoderDecoder};
+use crate::error::PtError;
+use crate::packet::{
+    Compression, Encoder, Exec, LastIp, Mode, Ovf, Payload, Pip, Psb, Psbend, StreamEncoder,
+    TipPgd, Tnt8, Tsc, Tsx,
+};
+
+/// Maximum number of TNT bits in a single TNT-8 packet.
+const TNT8_MAX_BITS: u8 = 6;
+
+/// A step of the control flow described to the `Synthesizer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Tracing gets enabled, execution starts at the given IP.
+    Enable(u64),
+    /// Tracing gets disabled, at the given branch target or asynchronously if `None`.
+    Disable(Option<u64>),
+    /// A conditional branch, taken (`true`) or not taken (`false`).
+    Branch(bool),
+    /// An indirect jump to the given target.
+    Indirect(u64),
+    /// A call: `None` for direct calls, the target for indirect calls.
+    Call(Option<u64>),
+    /// A return to the given address.
+    ///
+    /// Compressed into a taken TNT bit if return compression is enabled and the matching call
+    /// has been traced.
+    Return(u64),
+    /// A CR3 switch, to the given CR3 value.
+    Cr3(u64),
+    /// A transaction begins at the given IP.
+    TsxBegin(u64),
+    /// A transaction commits at the given IP.
+    TsxCommit(u64),
+    /// A transaction aborts at `ip`, execution resumes at `handler`.
+    TsxAbort { ip: u64, handler: u64 },
+    /// An internal buffer overflow, tracing resumes at the given IP.
+    Overflow(u64),
+    /// A time stamp counter update.
+    Time(u64),
+    /// A PSB+ synchronization point, the given IP is the current IP.
+    Psb(u64),
+}
+
+/// Generate a valid Intel PT stream from a description of the control flow.
+///
+/// The trace starts with a PSB+ and tracing disabled, a `Step::Enable` is usually the first step.
+/// Packets are encoded with `packet::Encoder`, TNT bits are batched and IPs are compressed
+/// with a `LastIp` tracker.
+///
+/// The synthesizer knows nothing about the traced binary: it's up to the caller to describe a
+/// control flow matching the code in the image used for decoding.
+#[derive(Clone, Debug)]
+pub struct Synthesizer {
+    cpu: Option<Cpu>,
+    exec: Exec,
+    ret_compression: bool,
+    steps: Vec<Step>,
+}
+
+impl Default for Synthesizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Synthesizer {
+    /// Create a synthesizer for 64-bit code, without return compression.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            cpu: None,
+            exec: Exec::CSL,
+            ret_compression: false,
+            steps: Vec::new(),
+        }
+    }
+
+    /// The cpu the trace is encoded for.
+    #[must_use]
+    pub fn cpu(mut self, cpu: Cpu) -> Self {
+        self.cpu = Some(cpu);
+        self
+    }
+
+    /// The execution mode advertised in every PSB+, 64-bit (`Exec::CSL`) by default.
+    #[must_use]
+    pub fn exec_mode(mut self, exec: Exec) -> Self {
+        self.exec = exec;
+        self
+    }
+
+    /// Compress returns matching a traced call into taken TNT bits.
+    #[must_use]
+    pub fn ret_compression(mut self, value: bool) -> Self {
+        self.ret_compression = value;
+        self
+    }
+
+    /// Append a step to the control flow.
+    #[must_use]
+    pub fn step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Append some steps to the control flow.
+    #[must_use]
+    pub fn steps(mut self, steps: impl IntoIterator<Item = Step>) -> Self {
+        self.steps.extend(steps);
+        self
+    }
+
+    /// Generate the trace.
+    pub fn synthesize(&self) -> Result<Vec<u8>, PtError> {
+        let mut builder = Encoder::<()>::builder();
+        if let Some(cpu) = self.cpu {
+            builder = builder.cpu(cpu);
+        }
+
+        let mut state = State {
+            enc: builder.build_stream(Vec::new())?,
+            exec: self.exec,
+            ret_compression: self.ret_compression,
+            last_ip: LastIp::new(),
+            tnt: 0,
+            tnt_bits: 0,
+            depth: 0,
+            cr3: None,
+            enabled: false,
+        };
+
+        state.psb(None)?;
+        for step in &self.steps {
+            state.step(*step)?;
+        }
+        state.flush_tnt()?;
+
+        Ok(state.enc.into_inner())
+    }
+}
+
+/// The state of an ongoing synthesis.
+struct State {
+    enc: StreamEncoder<Vec<u8>>,
+    exec: Exec,
+    ret_compression: bool,
+    last_ip: LastIp,
+    tnt: u8,
+    tnt_bits: u8,
+    depth: usize,
+    cr3: Option<u64>,
+    enabled: bool,
+}
+
+impl State {
+    fn step(&mut self, step: Step) -> Result<(), PtError> {
+        match step {
+            Step::Branch(taken) => return self.push_tnt(taken),
+            Step::Call(None) => {
+                self.call();
+                return Ok(());
+            }
+            Step::Return(_) if self.ret_compression && self.depth > 0 => {
+                self.depth -= 1;
+                return self.push_tnt(true);
+            }
+            _ => {}
+        }
+
+        // Any other packet ends the current TNT batch.
+        self.flush_tnt()?;
+        match step {
+            Step::Branch(_) | Step::Call(None) => unreachable!(),
+            Step::Enable(ip) => {
+                self.enabled = true;
+                self.depth = 0;
+                self.enc.next(self.last_ip.tippge(ip))?;
+            }
+            Step::Disable(Some(ip)) => {
+                self.enabled = false;
+                self.enc.next(self.last_ip.tippgd(ip))?;
+            }
+            Step::Disable(None) => {
+                self.enabled = false;
+                self.enc.next(TipPgd::new(0, Compression::Suppressed))?;
+            }
+            Step::Indirect(target) | Step::Return(target) => {
+                self.enc.next(self.last_ip.tip(target))?;
+            }
+            Step::Call(Some(target)) => {
+                self.call();
+                self.enc.next(self.last_ip.tip(target))?;
+            }
+            Step::Cr3(cr3) => {
+                self.cr3 = Some(cr3);
+                self.enc.next(Pip::new(cr3, false))?;
+            }
+            Step::TsxBegin(ip) => {
+                self.enc.next(Mode::new(Payload::Tsx(Tsx::INTX)))?;
+                self.enc.next(self.last_ip.fup(ip))?;
+            }
+            Step::TsxCommit(ip) => {
+                self.enc.next(Mode::new(Payload::Tsx(Tsx::empty())))?;
+                self.enc.next(self.last_ip.fup(ip))?;
+            }
+            Step::TsxAbort { ip, handler } => {
+                self.enc.next(Mode::new(Payload::Tsx(Tsx::ABRT)))?;
+                self.enc.next(self.last_ip.fup(ip))?;
+                self.enc.next(self.last_ip.tip(handler))?;
+            }
+            Step::Overflow(ip) => {
+                self.enc.next(Ovf::new())?;
+                self.last_ip.reset();
+                self.depth = 0;
+                self.enabled = true;
+                self.enc.next(self.last_ip.fup(ip))?;
+            }
+            Step::Time(tsc) => {
+                self.enc.next(Tsc::new(tsc))?;
+            }
+            Step::Psb(ip) => self.psb(Some(ip))?,
+        }
+        Ok(())
+    }
+
+    /// Emit a PSB+, with the current IP if tracing is enabled.
+    fn psb(&mut self, ip: Option<u64>) -> Result<(), PtError> {
+        self.last_ip.reset();
+        self.depth = 0;
+
+        self.enc.next(Psb::new())?;
+        if let Some(cr3) = self.cr3 {
+            self.enc.next(Pip::new(cr3, false))?;
+        }
+        self.enc.next(Mode::new(Payload::Exec(self.exec)))?;
+        if let Some(ip) = ip.filter(|_| self.enabled) {
+            self.enc.next(self.last_ip.fup(ip))?;
+        }
+        self.enc.next(Psbend::new())?;
+        Ok(())
+    }
+
+    fn call(&mut self) {
+        self.depth = (self.depth + 1).min(RET_STACK_SIZE);
+    }
+
+    fn push_tnt(&mut self, taken: bool) -> Result<(), PtError> {
+        // The oldest bit is the most significant one.
+        self.tnt = (self.tnt << 1) | u8::from(taken);
+        self.tnt_bits += 1;
+        if self.tnt_bits == TNT8_MAX_BITS {
+            self.flush_tnt()?;
+        }
+        Ok(())
+    }
+
+    fn flush_tnt(&mut self) -> Result<(), PtError> {
+        if self.tnt_bits > 0 {
+            self.enc.next(Tnt8::new(self.tnt, self.tnt_bits))?;
+            self.tnt = 0;
+            self.tnt_bits = 0;
+        }
+        Ok(())
+    }
+}
+
+/// The program traced by the tests, and the steps running it.
+///
+/// Shared by the unit tests and the integration tests, not part of the API.
+#[doc(hidden)]
+pub mod fixture {
+    use super::Step;
+    use crate::image::Image;
+    use std::sync::Arc;
+
+    /// The load address of `CODE`.
+    pub const BASE: u64 = 0x1000;
+
+    /// The code of the program, a loop running twice.
+    #[rustfmt::skip]
+    pub const CODE: [u8; 17] = [
+        0x31, 0xc0,                   // 0x1000: xor eax, eax
+        0x85, 0xc0,                   // 0x1002: test eax, eax
+        0x74, 0x02,                   // 0x1004: je 0x1008
+        0xff, 0xc0,                   // 0x1006: inc eax
+        0xe8, 0x03, 0x00, 0x00, 0x00, // 0x1008: call 0x1010
+        0xff, 0xe0,                   // 0x100d: jmp rax
+        0x90,                         // 0x100f: nop
+        0xc3,                         // 0x1010: ret
+    ];
+
+    /// The instructions executed by `STEPS`.
+    pub const EXPECTED_IPS: [u64; 13] = [
+        0x1000, 0x1002, 0x1004, 0x1008, 0x1010, 0x100d, // je taken, jmp rax back to 0x1000
+        0x1000, 0x1002, 0x1004, 0x1006, 0x1008, 0x1010,
+        0x100d, // je not taken, jmp rax disables
+    ];
+
+    /// The first pass: tracing gets enabled, `je` is taken, `call` and `ret` back to `jmp rax`.
+    pub const FIRST_PASS: [Step; 4] = [
+        Step::Enable(BASE),
+        Step::Branch(true),
+        Step::Call(None),
+        Step::Return(0x100d),
+    ];
+
+    /// `jmp rax` back to the beginning of the code.
+    pub const LOOP: Step = Step::Indirect(BASE);
+
+    /// The second pass: `je` is not taken, `call` and `ret` back to `jmp rax`.
+    pub const SECOND_PASS: [Step; 3] =
+        [Step::Branch(false), Step::Call(None), Step::Return(0x100d)];
+
+    /// `jmp rax` out of the code, tracing gets disabled.
+    pub const DISABLE: Step = Step::Disable(Some(0x100f));
+
+    /// Both passes, executing `EXPECTED_IPS`.
+    pub const STEPS: [Step; 9] = [
+        FIRST_PASS[0],
+        FIRST_PASS[1],
+        FIRST_PASS[2],
+        FIRST_PASS[3],
+        LOOP,
+        SECOND_PASS[0],
+        SECOND_PASS[1],
+        SECOND_PASS[2],
+        DISABLE,
+    ];
+
+    /// An image with `CODE` loaded at `BASE`.
+    pub fn image() -> Image {
+        let mut image = Image::new(None).unwrap();
+        image.add_bytes(Arc::from(CODE), BASE, None).unwrap();
+        image
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::{Packet, PacketDecoder};
+
+    fn decode(trace: &[u8]) -> Vec<Packet<()>> {
+        let mut dec = PacketDecoder::<()>::builder()
+            .buffer(trace)
+            .build()
+            .unwrap();
+        dec.sync_forward().unwrap();
+        dec.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_synth_framing() {
+        let trace = Synthesizer::new()
+            .step(Step::Time(100))
+            .step(Step::Enable(0x1000))
+            .step(Step::Psb(0x1010))
+            .synthesize()
+            .unwrap();
+
+        let packets = decode(&trace);
+        assert!(matches!(packets[0], Packet::Psb(_)));
+        assert!(matches!(packets[1], Packet::Mode(_)));
+        assert!(matches!(packets[2], Packet::Psbend(_)));
+        assert!(matches!(packets[3], Packet::Tsc(_)));
+        assert!(matches!(packets[4], Packet::TipPge(_)));
+        assert!(matches!(packets[5], Packet::Psb(_)));
+        assert!(matches!(packets[6], Packet::Mode(_)));
+        assert!(matches!(packets[7], Packet::Fup(_)));
+        assert!(matches!(packets[8], Packet::Psbend(_)));
+        assert_eq!(packets.len(), 9);
+    }
+
+    #[test]
+    fn test_synth_tnt_batching() {
+        let trace = Synthesizer::new()
+            .ret_compression(true)
+            .step(Step::Enable(0x1000))
+            .steps([true, false, true, true, false, true, true].map(Step::Branch))
+            .step(Step::Call(None))
+            .step(Step::Return(0x1005))
+            .step(Step::Return(0x2000))
+            .synthesize()
+            .unwrap();
+
+        let packets = decode(&trace);
+        let tnts: Vec<(u8, u8)> = packets
+            .iter()
+            .filter_map(|p| match p {
+                Packet::Tnt8(t) => Some((t.payload(), t.bitsize())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tnts, [(0b101101, 6), (0b11, 2)]);
+        assert!(matches!(packets.last(), Some(Packet::Tip(_))));
+    }
+}
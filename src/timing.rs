@@ -0,0 +1,361 @@
+use crate::asid::Asid;
+use crate::block::Block;
+use crate::coverage::CoveredBlock;
+use crate::enc_dec_builder::Frequency;
+use crate::event::EventType;
+use crate::merge::{Decoded, Lane, TraceDecoder};
+use std::collections::HashMap;
+use std::mem;
+
+/// The frequency of the bus clock, the unit of the nominal frequency and of the core:bus ratio.
+const BUS_HZ: u64 = 100_000_000;
+
+/// A block with its estimated timing, see `TimingModel`.
+#[derive(Debug, Clone, Copy)]
+pub struct TimedBlock {
+    /// The block
+    pub block: Block,
+    /// The address space of the block
+    pub asid: Asid,
+    /// The estimated time stamp count at the start of the block
+    pub tsc: f64,
+    /// The estimated number of TSC ticks spent in the block
+    pub ticks: f64,
+    /// The estimated number of core cycles spent in the block, `None` if the core:bus ratio or
+    /// the nominal frequency is unknown
+    pub cycles: Option<f64>,
+    /// The estimated time spent in the block in nanoseconds, `None` if the TSC frequency is
+    /// unknown
+    pub ns: Option<f64>,
+}
+
+impl TimedBlock {
+    /// The key of the block in `TimingModel::blocks()`.
+    #[must_use]
+    pub fn key(&self) -> CoveredBlock {
+        CoveredBlock {
+            asid: self.asid,
+            isid: self.block.isid(),
+            ip: self.block.ip(),
+            end_ip: self.block.end_ip(),
+        }
+    }
+
+    /// The estimated number of core cycles per instruction of the block.
+    #[must_use]
+    pub fn cpi(&self) -> Option<f64> {
+        let ninsn = f64::from(self.block.ninsn());
+        self.cycles
+            .filter(|_| ninsn > 0.0)
+            .map(|cycles| cycles / ninsn)
+    }
+}
+
+/// The timing statistics of all the executions of a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BlockTiming {
+    /// The number of timed executions
+    pub count: u64,
+    /// The total number of instructions executed
+    pub insns: u64,
+    /// The total number of TSC ticks
+    pub ticks: f64,
+    /// The total number of core cycles, only counting the executions with a known core:bus
+    /// ratio
+    pub cycles: f64,
+    /// The fewest core cycles of an execution, `None` if the core:bus ratio was never known
+    pub min_cycles: Option<f64>,
+    /// The most core cycles of an execution, `None` if the core:bus ratio was never known
+    pub max_cycles: Option<f64>,
+}
+
+impl BlockTiming {
+    fn add(&mut self, block: &TimedBlock) {
+        self.count += 1;
+        self.insns += u64::from(block.block.ninsn());
+        self.ticks += block.ticks;
+        if let Some(cycles) = block.cycles {
+            self.cycles += cycles;
+            self.min_cycles = Some(self.min_cycles.map_or(cycles, |min| min.min(cycles)));
+            self.max_cycles = Some(self.max_cycles.map_or(cycles, |max| max.max(cycles)));
+        }
+    }
+}
+
+/// An instruction-level timing model, attributing the time between timing packets to the blocks
+/// and instructions executed in between.
+///
+/// The model doesn't read the timing packets itself, it uses the time of the events of the
+/// decoder: libipt estimates the time stamp count from the TSC, MTC and CYC packets, with the
+/// MTC, CTC and TSC ratios of the `Frequency` the decoder is built with.
+/// The trace is split at the time stamped events, and the elapsed ticks are spread over the
+/// blocks in between, in proportion to their number of instructions: the model has no
+/// per-instruction cost. The decoder must be built with `set_enable_tick_events(true)` for
+/// `Tick` events at the timing packets, otherwise only the few events carrying a time stamp
+/// (paging, mode changes, ...) split the trace and the estimates are very coarse. With cycle
+/// accurate tracing (CYC packets), ticks are then as fine as the branches.
+/// Ticks are converted back into core cycles with the nominal frequency of the `Frequency` only
+/// and the latest `Cbr` event, and into nanoseconds with the TSC frequency, the nominal
+/// frequency by default.
+///
+/// Blocks are keyed by address space and image section, as in `Coverage`, the address space
+/// being tracked from the paging and VMCS events.
+///
+/// The time spent in trace gaps (disabled tracing, overflows, decoding errors) is not
+/// attributed, neither are the blocks after the last time stamped event.
+#[derive(Debug, Clone)]
+pub struct TimingModel {
+    nom: u8,
+    tsc_hz: Option<u64>,
+    cbr: Option<u16>,
+    /// The time of the start of the pending blocks, `None` in trace gaps
+    start: Option<u64>,
+    pending: Vec<(Asid, Block)>,
+    blocks: HashMap<CoveredBlock, BlockTiming>,
+}
+
+impl TimingModel {
+    /// Create a timing model for a trace recorded with the timing configuration @freq.
+    ///
+    /// The TSC frequency is assumed to be the nominal frequency.
+    #[must_use]
+    pub fn new(freq: Frequency) -> Self {
+        let nom = freq.nom();
+        Self {
+            nom,
+            tsc_hz: (nom > 0).then_some(u64::from(nom) * BUS_HZ),
+            cbr: None,
+            start: None,
+            pending: Vec::new(),
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Set the frequency of the TSC to @hz, e.g. from `/sys/devices/system/cpu/cpu0/tsc_freq_khz`
+    /// or the `tsc_freq` of a `perf.data` file.
+    #[must_use]
+    pub fn with_tsc_frequency(mut self, hz: u64) -> Self {
+        self.tsc_hz = (hz > 0).then_some(hz);
+        self
+    }
+
+    /// The core:bus ratio in use, from the latest `Cbr` event.
+    #[must_use]
+    pub fn core_bus_ratio(&self) -> Option<u16> {
+        self.cbr
+    }
+
+    /// Set the core:bus ratio, e.g. from `BlockDecoder::core_bus_ratio()` after synchronizing.
+    pub fn set_core_bus_ratio(&mut self, ratio: u16) {
+        self.cbr = (ratio > 0).then_some(ratio);
+    }
+
+    /// The number of core cycles in @ticks TSC ticks.
+    ///
+    /// Returns `None` if the core:bus ratio or the nominal frequency is unknown.
+    #[must_use]
+    pub fn ticks_to_cycles(&self, ticks: f64) -> Option<f64> {
+        let cbr = self.cbr?;
+        (self.nom > 0).then(|| ticks * f64::from(cbr) / f64::from(self.nom))
+    }
+
+    /// The number of nanoseconds in @ticks TSC ticks.
+    ///
+    /// Returns `None` if the TSC frequency is unknown.
+    #[must_use]
+    pub fn ticks_to_ns(&self, ticks: f64) -> Option<f64> {
+        self.tsc_hz.map(|hz| ticks * 1e9 / hz as f64)
+    }
+
+    /// Time all the blocks of @decoder, until the end of its trace, calling @on_block with
+    /// each timed block in execution order.
+    ///
+    /// Pass a `&mut` decoder to keep using it afterwards.
+    /// Decoding errors are skipped, the decoder resynchronizes at the next PSB.
+    pub fn record<D, F>(&mut self, decoder: D, mut on_block: F)
+    where
+        D: TraceDecoder<Item = Block>,
+        F: FnMut(&TimedBlock),
+    {
+        let mut lane = Lane::new(decoder);
+        let mut asid = Asid::default();
+        self.gap();
+        while let Some(result) = lane.fetch() {
+            let event = match result {
+                Ok((_, Decoded::Item(block))) => {
+                    self.add_block(asid, &block);
+                    continue;
+                }
+                Ok((_, Decoded::Event(event))) => event,
+                Err(_) => {
+                    self.gap();
+                    continue;
+                }
+            };
+            if let Some(tsc) = event.tsc() {
+                self.tick(tsc, &mut on_block);
+            }
+            match event.event_type() {
+                EventType::Cbr(cbr) => self.set_core_bus_ratio(cbr.ratio()),
+                EventType::Paging(paging) => asid.set_cr3(paging.cr3()),
+                EventType::AsyncPaging(paging) => asid.set_cr3(paging.cr3()),
+                EventType::Vmcs(vmcs) => asid.set_vmcs(vmcs.base()),
+                EventType::AsyncVmcs(vmcs) => asid.set_vmcs(vmcs.base()),
+                EventType::Disabled(_) | EventType::AsnycDisabled(_) | EventType::Overflow(_) => {
+                    self.gap();
+                }
+                _ => {}
+            }
+        }
+        self.gap();
+    }
+
+    /// Add @block, executed in @asid after the previous blocks and before the next `tick()`.
+    ///
+    /// Blocks added in a trace gap, before any `tick()`, are ignored.
+    pub fn add_block(&mut self, asid: Asid, block: &Block) {
+        if self.start.is_some() && block.ninsn() > 0 {
+            self.pending.push((asid, *block));
+        }
+    }
+
+    /// The time is @tsc: spread the ticks elapsed since the previous `tick()` over the blocks
+    /// added in between and call @on_block with each of them.
+    pub fn tick<F>(&mut self, tsc: u64, mut on_block: F)
+    where
+        F: FnMut(&TimedBlock),
+    {
+        let Some(start) = self.start.filter(|&start| start <= tsc) else {
+            // The first time after a gap, or a time going backwards.
+            self.pending.clear();
+            self.start = Some(tsc);
+            return;
+        };
+        let insns: u64 = self.pending.iter().map(|(_, b)| u64::from(b.ninsn())).sum();
+        if insns == 0 {
+            self.start = Some(tsc);
+            return;
+        }
+
+        let ticks_per_insn = (tsc - start) as f64 / insns as f64;
+        let mut time = start as f64;
+        let mut pending = mem::take(&mut self.pending);
+        for (asid, block) in pending.drain(..) {
+            let ticks = ticks_per_insn * f64::from(block.ninsn());
+            let timed = TimedBlock {
+                block,
+                asid,
+                tsc: time,
+                ticks,
+                cycles: self.ticks_to_cycles(ticks),
+                ns: self.ticks_to_ns(ticks),
+            };
+            time += ticks;
+            on_block(&timed);
+            self.blocks.entry(timed.key()).or_default().add(&timed);
+        }
+        self.pending = pending;
+        self.start = Some(tsc);
+    }
+
+    /// The trace has a gap, the pending blocks are dropped and the time restarts at the next
+    /// `tick()`.
+    pub fn gap(&mut self) {
+        self.pending.clear();
+        self.start = None;
+    }
+
+    /// The timing statistics of each block, in no particular order.
+    pub fn blocks(&self) -> impl Iterator<Item = (&CoveredBlock, &BlockTiming)> {
+        self.blocks.iter()
+    }
+
+    /// The timing statistics of @block.
+    #[must_use]
+    pub fn block(&self, block: &CoveredBlock) -> Option<&BlockTiming> {
+        self.blocks.get(block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(ip: u64, ninsn: u16) -> Block {
+        Block::from_range(ip, ip + u64::from(ninsn) - 1, ninsn)
+    }
+
+    fn model() -> TimingModel {
+        // A 2.4GHz TSC, the core runs at 3.6GHz.
+        let mut model = TimingModel::new(Frequency::new(0, 24, 0, 0));
+        model.set_core_bus_ratio(36);
+        model
+    }
+
+    #[test]
+    fn test_timing_spread() {
+        let asid = Asid::default();
+        let mut model = model();
+        let mut timed = Vec::new();
+        // Blocks before the first time stamp are not timed.
+        model.add_block(asid, &block(0x1000, 1));
+        model.tick(1000, |b| timed.push(*b));
+        model.add_block(asid, &block(0x1000, 1));
+        model.add_block(asid, &block(0x2000, 3));
+        model.tick(1240, |b| timed.push(*b));
+        assert_eq!(timed.len(), 2);
+
+        assert_eq!(timed[0].block.ip(), 0x1000);
+        assert_eq!(timed[0].tsc, 1000.0);
+        assert_eq!(timed[0].ticks, 60.0);
+        assert_eq!(timed[0].cycles, Some(90.0));
+        assert_eq!(timed[0].ns, Some(25.0));
+        assert_eq!(timed[1].tsc, 1060.0);
+        assert_eq!(timed[1].ticks, 180.0);
+        assert_eq!(timed[1].cpi(), Some(90.0));
+
+        // The time spent in gaps is dropped.
+        model.add_block(asid, &block(0x1000, 1));
+        model.gap();
+        model.add_block(asid, &block(0x1000, 1));
+        model.tick(5000, |b| timed.push(*b));
+        model.add_block(asid, &block(0x1000, 1));
+        model.tick(5016, |b| timed.push(*b));
+        assert_eq!(timed.len(), 3);
+        assert_eq!(timed[2].tsc, 5000.0);
+        assert_eq!(timed[2].cycles, Some(24.0));
+
+        let key = timed[0].key();
+        assert_eq!((key.asid, key.ip, key.end_ip), (asid, 0x1000, 0x1000));
+        let timing = model.block(&key).unwrap();
+        assert_eq!(timing.count, 2);
+        assert_eq!(timing.insns, 2);
+        assert_eq!(timing.ticks, 76.0);
+        assert_eq!(timing.cycles, 114.0);
+        assert_eq!(timing.min_cycles, Some(24.0));
+        assert_eq!(timing.max_cycles, Some(90.0));
+        assert_eq!(model.blocks().count(), 2);
+
+        // The same block in another address space is timed separately.
+        let other = Asid::new(Some(0x1000), None);
+        model.add_block(other, &block(0x1000, 1));
+        model.tick(5032, |b| timed.push(*b));
+        assert_eq!(timed[3].asid, other);
+        assert_eq!(model.block(&key).unwrap().count, 2);
+        assert_eq!(model.block(&timed[3].key()).unwrap().count, 1);
+        assert_eq!(model.blocks().count(), 3);
+    }
+
+    #[test]
+    fn test_timing_units() {
+        let mut model = TimingModel::new(Frequency::default());
+        assert_eq!(model.ticks_to_cycles(10.0), None);
+        assert_eq!(model.ticks_to_ns(10.0), None);
+        model.set_core_bus_ratio(20);
+        assert_eq!(model.ticks_to_cycles(10.0), None);
+
+        let model = model.with_tsc_frequency(2_000_000_000);
+        assert_eq!(model.ticks_to_ns(10.0), Some(5.0));
+        assert_eq!(model.core_bus_ratio(), Some(20));
+    }
+}
@@ -0,0 +1,15 @@
+//! The program traced by the integration tests, and the steps running it.
+#![allow(dead_code, unused_imports)]
+
+pub use libipt::synth::fixture::*;
+use libipt::synth::{Step, Synthesizer};
+
+pub mod packets;
+
+/// Synthesize the trace of @steps, without return compression.
+pub fn synthesize(steps: &[Step]) -> Vec<u8> {
+    Synthesizer::new()
+        .steps(steps.iter().copied())
+        .synthesize()
+        .unwrap()
+}
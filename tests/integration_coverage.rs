@@ -0,0 +1,58 @@
+mod common;
+
+use common::{BASE, STEPS, image, synthesize};
+use libipt::block::BlockDecoder;
+use libipt::coverage::Coverage;
+use libipt::enc_dec_builder::PtEncoderDecoder;
+
+#[test]
+fn test_coverage_synth() {
+    let trace = synthesize(&STEPS);
+
+    let image = image();
+    let mut decoder = BlockDecoder::builder().buffer(&trace).build().unwrap();
+    decoder.image().extend(&image).unwrap();
+
+    let mut coverage = Coverage::new();
+    coverage.record(&mut decoder);
+
+    // The code is entered twice at its start, after the enable and after `jmp rax`.
+    let entries: u64 = coverage
+        .blocks()
+        .filter(|(block, _)| block.ip == BASE)
+        .map(|(_, hits)| hits)
+        .sum();
+    assert_eq!(entries, 2);
+    assert!(coverage.edges().count() > 0);
+    for (edge, _) in coverage.edges() {
+        assert!(coverage.hits(&edge.from) > 0 && coverage.hits(&edge.to) > 0);
+    }
+
+    // Every covered block can be expanded back into its instructions.
+    for (block, _) in coverage.blocks() {
+        let insns: Vec<_> = coverage
+            .instructions(block, &image)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(insns.last().unwrap().ip(), block.end_ip);
+    }
+
+    let mut merged = coverage.clone();
+    merged.merge(&coverage);
+    let total = |c: &Coverage| c.edges().map(|(_, hits)| hits).sum::<u64>();
+    assert_eq!(total(&merged), 2 * total(&coverage));
+
+    let mut map = vec![0u8; 1 << 16];
+    merged.write_afl_bitmap(&mut map).unwrap();
+    assert_eq!(
+        map.iter().map(|&n| u64::from(n)).sum::<u64>(),
+        total(&merged)
+    );
+
+    let mut dot = Vec::new();
+    merged.write_dot(&mut dot, |_| None).unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+    assert!(dot.starts_with("digraph coverage {"), "{dot}");
+    assert_eq!(dot.matches(" -> ").count(), merged.edges().count());
+}
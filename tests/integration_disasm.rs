@@ -0,0 +1,36 @@
+#![cfg(feature = "disasm")]
+
+mod common;
+
+use common::{DISABLE, FIRST_PASS, image, synthesize};
+use libipt::disasm::Disassembler;
+use libipt::enc_dec_builder::PtEncoderDecoder;
+use libipt::insn::InsnDecoder;
+
+#[test]
+fn test_disasm_listing() {
+    let trace = synthesize(&[&FIRST_PASS[..], &[DISABLE]].concat());
+
+    let image = image();
+    let builder = InsnDecoder::builder().buffer(&trace);
+    let mut decoder = builder.build().unwrap();
+    decoder.image().extend(&image).unwrap();
+
+    let mut listing = Vec::new();
+    let count = Disassembler::new()
+        .write_listing(decoder, &mut listing)
+        .unwrap();
+    let listing = String::from_utf8(listing).unwrap();
+    assert_eq!(count, 6, "{listing}");
+
+    let insns: Vec<&str> = listing.lines().filter(|l| !l.starts_with(';')).collect();
+    assert_eq!(insns.len(), 6);
+    assert!(
+        insns[0].trim_start().starts_with("1000:\t31 c0"),
+        "{listing}"
+    );
+    assert!(insns[0].ends_with("\txor eax,eax"), "{listing}");
+    assert!(insns[3].contains("e8 03 00 00 00"), "{listing}");
+    assert!(insns[4].ends_with("\tret"), "{listing}");
+    assert!(insns[5].ends_with("\tjmp rax"), "{listing}");
+}
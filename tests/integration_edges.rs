@@ -0,0 +1,90 @@
+mod common;
+
+use common::{DISABLE, FIRST_PASS, LOOP, SECOND_PASS, image};
+use libipt::block::BlockDecoder;
+use libipt::coverage::Coverage;
+use libipt::edges::EdgeDecoder;
+use libipt::enc_dec_builder::PtEncoderDecoder;
+use libipt::packet::PacketDecoder;
+use libipt::synth::{Step, Synthesizer};
+use std::collections::BTreeMap;
+
+fn trace(ret_compression: bool) -> Vec<u8> {
+    Synthesizer::new()
+        .ret_compression(ret_compression)
+        .steps(FIRST_PASS)
+        .steps([LOOP, Step::Psb(0x1000)])
+        .steps(SECOND_PASS)
+        // Tracing resumes at the beginning of the code for a third pass.
+        .step(Step::Overflow(0x1000))
+        .steps(FIRST_PASS[1..].iter().copied())
+        .step(DISABLE)
+        .synthesize()
+        .unwrap()
+}
+
+#[test]
+fn test_edges_match_block_decoder() {
+    let image = image();
+    let mut edges = EdgeDecoder::new(&image);
+
+    for ret_compression in [false, true] {
+        let trace = trace(ret_compression);
+
+        let mut decoder = BlockDecoder::builder().buffer(&trace).build().unwrap();
+        decoder.image().extend(&image).unwrap();
+        let mut coverage = Coverage::new();
+        coverage.record(&mut decoder);
+        let mut expected = BTreeMap::new();
+        for (edge, hits) in coverage.edges() {
+            *expected.entry((edge.from.ip, edge.to.ip)).or_insert(0) += hits;
+        }
+
+        let mut packets = PacketDecoder::<()>::builder()
+            .buffer(&trace)
+            .build()
+            .unwrap();
+        let mut fast = BTreeMap::new();
+        let stats = edges.decode(&mut packets, |from, to| {
+            *fast.entry((from, to)).or_insert(0) += 1;
+        });
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.edges, 7);
+        assert_eq!(fast, expected);
+
+        let mut map = vec![0u8; 1 << 16];
+        coverage.write_afl_bitmap(&mut map).unwrap();
+        let mut fast_map = vec![0u8; 1 << 16];
+        let mut packets = PacketDecoder::<()>::builder()
+            .buffer(&trace)
+            .build()
+            .unwrap();
+        edges.decode_afl(&mut packets, &mut fast_map).unwrap();
+        assert!(map == fast_map);
+    }
+    // The blocks are only decoded from the image once.
+    assert_eq!(edges.cached_blocks(), 4);
+}
+
+#[test]
+fn test_edges_long_trace() {
+    const LOOPS: u64 = 10_000;
+    let mut synth = Synthesizer::new().steps(FIRST_PASS);
+    for _ in 0..LOOPS {
+        synth = synth.step(LOOP).steps(SECOND_PASS);
+    }
+    let trace = synth.step(DISABLE).synthesize().unwrap();
+
+    let image = image();
+    let mut edges = EdgeDecoder::new(&image);
+    let mut packets = PacketDecoder::<()>::builder()
+        .buffer(&trace)
+        .build()
+        .unwrap();
+    let mut map = vec![0u8; 1 << 16];
+    let stats = edges.decode_afl(&mut packets, &mut map).unwrap();
+    assert_eq!(stats.errors, 0);
+    // The two edges of the first pass, then three per loop.
+    assert_eq!(stats.edges, 2 + 3 * LOOPS);
+    assert_eq!(edges.cached_blocks(), 4);
+}
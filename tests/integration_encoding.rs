@@ -1,3 +1,6 @@
+mod common;
+
+use common::packets::encode_all_packets;
 use libipt::enc_dec_builder::{Cpu, PtEncoderDecoder};
 use libipt::packet::*;
 
@@ -5,42 +8,35 @@ use libipt::packet::*;
 fn test_encoder_all_packets() {
     let mut inp = [0u8; 132];
 
-    let mut builder = Encoder::<()>::builder().cpu(Cpu::intel(1, 2, 3));
-    builder = unsafe { builder.buffer_from_raw(inp.as_mut_ptr(), inp.len()) };
+    let builder = Encoder::<()>::builder()
+        .cpu(Cpu::intel(1, 2, 3))
+        .buffer_mut(&mut inp);
 
     let mut enc: Encoder<()> = builder.build().unwrap();
 
-    let mut size: u32 = 0;
+    let size = encode_all_packets!(enc);
+    assert_eq!(size, 132);
+    assert!(enc.next(Pad::new()).is_err());
+}
 
-    size += enc.next(Pad::new()).unwrap();
-    size += enc.next(Psb::new()).unwrap();
-    size += enc.next(Psbend::new()).unwrap();
-    size += enc.next(Ovf::new()).unwrap();
+#[test]
+fn test_stream_encoder_grows() {
+    let mut enc = Encoder::<()>::builder()
+        .cpu(Cpu::intel(1, 2, 3))
+        .build_stream(std::io::Cursor::new(Vec::new()))
+        .unwrap();
 
+    let mut size: u32 = 0;
+    size += enc.next(Psb::new()).unwrap();
     size += enc.next(Fup::new(123, Compression::Sext48)).unwrap();
-    size += enc.next(Tip::new(321, Compression::Full)).unwrap();
-    size += enc.next(TipPge::new(666, Compression::Suppressed)).unwrap();
-    size += enc.next(TipPgd::new(888, Compression::Update16)).unwrap();
-    size += enc.next(Tnt8::new(3, 4)).unwrap();
-    size += enc.next(Tnt64::new(4, 13)).unwrap();
-    size += enc
-        .next(Mode::new(Payload::Exec(Exec::CSL | Exec::CSD)))
-        .unwrap();
-    size += enc.next(Pip::new(1337, false)).unwrap();
-    size += enc.next(Tsc::new(69)).unwrap();
-    size += enc.next(Cbr::new(5)).unwrap();
-    size += enc.next(Tma::new(420, 421)).unwrap();
-    size += enc.next(Mtc::new(0)).unwrap();
-    size += enc.next(Cyc::new(0xCA7)).unwrap();
-    size += enc.next(Stop::new()).unwrap();
-    size += enc.next(Vmcs::new(111)).unwrap();
-    size += enc.next(Mnt::new(222)).unwrap();
-    size += enc.next(Exstop::new(true)).unwrap();
-    size += enc.next(Mwait::new(333, 444)).unwrap();
-    size += enc.next(Pwre::new(101, 10, false)).unwrap();
-    size += enc.next(Pwrx::new(1, 2, false, true, false)).unwrap();
-    size += enc.next(Ptw::new(5, 0, false)).unwrap();
+    size += enc.next(Psbend::new()).unwrap();
+    for _ in 0..200 {
+        size += enc.next(Tnt8::new(3, 4)).unwrap();
+    }
 
-    assert_eq!(size, 132);
-    assert!(enc.next(Pad::new()).is_err());
+    assert_eq!(size, 225);
+    assert_eq!(enc.offset(), 225);
+    // Unlike `Encoder`, a `StreamEncoder` never runs out of space.
+    assert!(enc.next(Pad::new()).is_ok());
+    assert_eq!(enc.into_inner().into_inner().len(), 226);
 }
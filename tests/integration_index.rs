@@ -0,0 +1,72 @@
+mod common;
+
+use common::{DISABLE, EXPECTED_IPS, FIRST_PASS, LOOP, SECOND_PASS, image};
+use libipt::enc_dec_builder::PtEncoderDecoder;
+use libipt::error::PtErrorCode;
+use libipt::event::ExecModeType;
+use libipt::index::TraceIndex;
+use libipt::insn::InsnDecoder;
+use libipt::synth::{Step, Synthesizer};
+
+#[test]
+fn test_index_insn_decoding() {
+    let trace = Synthesizer::new()
+        .step(Step::Time(0x1234))
+        .steps(FIRST_PASS)
+        .steps([
+            LOOP,
+            Step::Cr3(0x5000),
+            Step::Time(0x2000),
+            Step::Psb(0x1000),
+        ])
+        .steps(SECOND_PASS)
+        .step(DISABLE)
+        .synthesize()
+        .unwrap();
+
+    let image = image();
+    let index = TraceIndex::build(&trace, &InsnDecoder::builder(), &image).unwrap();
+
+    let entries = index.entries();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(index.insns(), 13);
+    assert_eq!(
+        (entries[0].offset, entries[0].tsc, entries[0].ip),
+        (0, None, None)
+    );
+    assert_eq!(entries[1].tsc, Some(0x2000));
+    assert_eq!(entries[1].ip, Some(0x1000));
+    assert_eq!(entries[1].asid.cr3(), Some(0x5000));
+    assert_eq!(entries[1].mode, ExecModeType::Bit64);
+    assert!(entries[0].insns <= entries[1].insns && entries[1].insns <= index.insns());
+
+    let entry = index.find_tsc(0x3000).unwrap();
+    assert_eq!(entry, &entries[1]);
+    assert_eq!(index.find_offset(entry.offset - 1).unwrap(), &entries[0]);
+
+    // The decoder resumes at the IP of the PSB+.
+    let builder = InsnDecoder::builder().buffer(&trace);
+    let (mut decoder, mut status) = index.insn_decoder(entry, &builder).unwrap();
+    decoder.image().extend(&image).unwrap();
+    let mut ips = Vec::new();
+    loop {
+        while status.event_pending() {
+            status = decoder.event().unwrap().1;
+        }
+        if status.eos() {
+            break;
+        }
+        match decoder.decode_next() {
+            Ok((insn, s)) => {
+                ips.push(insn.ip());
+                status = s;
+            }
+            Err(e) if e.code() == PtErrorCode::Eos => break,
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+    assert_eq!(ips, EXPECTED_IPS[6..]);
+
+    let loaded = TraceIndex::from_bytes(&index.to_bytes()).unwrap();
+    assert_eq!(loaded, index);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::{DISABLE, EXPECTED_IPS, FIRST_PASS, LOOP, SECOND_PASS, image};
+use libipt::enc_dec_builder::PtEncoderDecoder;
+use libipt::insn::InsnDecoder;
+use libipt::merge::{Decoded, MergedDecoder};
+use libipt::synth::{Step, Synthesizer};
+
+fn trace(start: u64, middle: u64) -> Vec<u8> {
+    Synthesizer::new()
+        .step(Step::Time(start))
+        .steps(FIRST_PASS)
+        .steps([Step::Time(middle), LOOP])
+        .steps(SECOND_PASS)
+        .step(DISABLE)
+        .synthesize()
+        .unwrap()
+}
+
+#[test]
+fn test_merge_insn_decoders() {
+    let traces = [trace(100, 300), trace(200, 400)];
+    let mut images = [image(), image()];
+    let mut decoders = Vec::new();
+    for (trace, image) in traces.iter().zip(&mut images) {
+        let mut dec = InsnDecoder::builder().buffer(trace).build().unwrap();
+        dec.set_image(Some(image)).unwrap();
+        decoders.push(dec);
+    }
+
+    let mut ips = [Vec::new(), Vec::new()];
+    let mut last_tsc = 0;
+    for merged in MergedDecoder::new(decoders) {
+        assert!(merged.tsc >= last_tsc);
+        last_tsc = merged.tsc;
+        if let Decoded::Item(insn) = merged.result.unwrap() {
+            ips[merged.cpu].push(insn.ip());
+        }
+    }
+
+    assert_eq!(ips[0], EXPECTED_IPS);
+    assert_eq!(ips[1], EXPECTED_IPS);
+    assert_eq!(last_tsc, 400);
+}
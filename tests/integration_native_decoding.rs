@@ -0,0 +1,244 @@
+#![cfg(feature = "pure-rust-packets")]
+
+use libipt::enc_dec_builder::{Cpu, PtEncoderDecoder};
+use libipt::error::{PtError, PtErrorCode};
+use libipt::packet::*;
+
+/// A xorshift pseudo random number generator, the streams are the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn bool(&mut self) -> bool {
+        self.next() & 1 != 0
+    }
+}
+
+fn random_compression(rng: &mut Rng) -> Compression {
+    match rng.below(6) {
+        0 => Compression::Suppressed,
+        1 => Compression::Update16,
+        2 => Compression::Update32,
+        3 => Compression::Sext48,
+        4 => Compression::Update48,
+        _ => Compression::Full,
+    }
+}
+
+/// Encode @count random packets with libipt.
+///
+/// As in real traces, the stream starts with a PSB and every PSB is followed by a PSBEND.
+fn generate_stream(seed: u64, count: usize) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    let mut enc = Encoder::<()>::builder()
+        .cpu(Cpu::intel(1, 2, 3))
+        .build_stream(Vec::new())
+        .unwrap();
+
+    enc.next(Psb::new()).unwrap();
+    enc.next(Psbend::new()).unwrap();
+    for _ in 0..count {
+        let result = match rng.below(30) {
+            0 => enc.next(Pad::new()),
+            1 => {
+                // Back to back PSBs, only the last one is a synchronization point.
+                let run = 1 + rng.below(3);
+                (0..run)
+                    .try_for_each(|_| enc.next(Psb::new()).map(drop))
+                    .and_then(|()| enc.next(Psbend::new()))
+            }
+            2 => enc.next(Ovf::new()),
+            3 => enc.next(Stop::new()),
+            4 | 5 => enc.next(Tip::new(rng.next(), random_compression(&mut rng))),
+            6 => enc.next(TipPge::new(rng.next(), random_compression(&mut rng))),
+            7 => enc.next(TipPgd::new(rng.next(), random_compression(&mut rng))),
+            8 => enc.next(Fup::new(rng.next(), random_compression(&mut rng))),
+            9..=11 => {
+                let bitsize = 1 + rng.below(6) as u8;
+                enc.next(Tnt8::new(rng.below(1 << bitsize) as u8, bitsize))
+            }
+            12 => {
+                let bitsize = 1 + rng.below(46) as u8;
+                enc.next(Tnt64::new(rng.below(1 << bitsize), bitsize))
+            }
+            13 => {
+                let payload = if rng.bool() {
+                    Payload::Exec(Exec::from_bits_truncate(rng.below(4) as u32))
+                } else {
+                    Payload::Tsx(Tsx::from_bits_truncate(rng.below(4) as u32))
+                };
+                enc.next(Mode::new(payload))
+            }
+            14 => enc.next(Pip::new(rng.below(1 << 52) & !0x1f, rng.bool())),
+            15 => enc.next(Tsc::new(rng.below(1 << 56))),
+            16 => enc.next(Cbr::new(rng.next() as u8)),
+            17 => enc.next(Tma::new(rng.next() as u16, rng.below(0x200) as u16)),
+            18 => enc.next(Mtc::new(rng.next() as u8)),
+            19 | 20 => enc.next(Cyc::new(rng.below(1 << rng.below(40)))),
+            21 => enc.next(Vmcs::new(rng.below(1 << 52) & !0xfff)),
+            22 => enc.next(Mnt::new(rng.next())),
+            23 => enc.next(Exstop::new(rng.bool())),
+            24 => enc.next(Mwait::new(rng.next() as u32, rng.next() as u32)),
+            25 => enc.next(Pwre::new(
+                rng.below(16) as u8,
+                rng.below(16) as u8,
+                rng.bool(),
+            )),
+            26 => enc.next(Pwrx::new(
+                rng.below(16) as u8,
+                rng.below(16) as u8,
+                rng.bool(),
+                rng.bool(),
+                rng.bool(),
+            )),
+            _ => {
+                let plc = rng.below(2) as u8;
+                let payload = if plc == 0 {
+                    rng.next() & 0xffff_ffff
+                } else {
+                    rng.next()
+                };
+                enc.next(Ptw::new(payload, plc, rng.bool()))
+            }
+        };
+        result.unwrap();
+    }
+    enc.into_inner()
+}
+
+/// Decode up to @max packets with both decoders, checking they agree.
+///
+/// Returns the offsets of the decoded packets.
+fn assert_same_packets(
+    c: &mut PacketDecoder<()>,
+    rust: &mut NativePacketDecoder<()>,
+    max: usize,
+    seed: u64,
+) -> Vec<u64> {
+    let mut offsets = Vec::new();
+    for _ in 0..max {
+        let offset = c.offset().unwrap();
+        assert_eq!(rust.offset().unwrap(), offset, "seed {seed}");
+        match (c.next(), rust.next()) {
+            (None, None) => break,
+            (Some(c), Some(rust)) => assert_eq!(
+                c.map(|p| format!("{p:?}")).map_err(PtError::code),
+                rust.map(|p| format!("{p:?}")).map_err(PtError::code),
+                "seed {seed}, offset {offset:#x}"
+            ),
+            (c, rust) => panic!("seed {seed}, offset {offset:#x}: {c:?} {rust:?}"),
+        }
+        offsets.push(offset);
+    }
+    offsets
+}
+
+/// Synchronize both decoders with @sync, checking they agree.
+///
+/// Returns whether the decoders are synchronized.
+fn assert_same_sync<'a, C, R>(
+    c: &mut PacketDecoder<'a, ()>,
+    rust: &mut NativePacketDecoder<'a, ()>,
+    seed: u64,
+    c_sync: C,
+    rust_sync: R,
+) -> bool
+where
+    C: FnOnce(&mut PacketDecoder<'a, ()>) -> Result<(), PtError>,
+    R: FnOnce(&mut NativePacketDecoder<'a, ()>) -> Result<(), PtError>,
+{
+    let result = c_sync(c).map_err(PtError::code);
+    assert_eq!(
+        result,
+        rust_sync(rust).map_err(PtError::code),
+        "seed {seed}"
+    );
+    if result.is_ok() {
+        assert_eq!(c.offset().unwrap(), rust.offset().unwrap(), "seed {seed}");
+        assert_eq!(
+            c.sync_offset().unwrap(),
+            rust.sync_offset().unwrap(),
+            "seed {seed}"
+        );
+    }
+    result.is_ok()
+}
+
+#[test]
+fn test_native_pktdec_generated_streams() {
+    for seed in 0..64 {
+        let buf = generate_stream(seed, 512);
+        let mut c = PacketDecoder::<()>::builder().buffer(&buf).build().unwrap();
+        let mut rust = NativePacketDecoder::<()>::builder()
+            .buffer(&buf)
+            .build()
+            .unwrap();
+
+        // Every PSB, and a few packets after it.
+        let mut psbs = 0;
+        while assert_same_sync(
+            &mut c,
+            &mut rust,
+            seed,
+            PacketDecoder::sync_forward,
+            NativePacketDecoder::sync_forward,
+        ) {
+            assert_same_packets(&mut c, &mut rust, 4, seed);
+            psbs += 1;
+        }
+        assert!(psbs > 0);
+        assert_eq!(c.sync_forward().unwrap_err().code(), PtErrorCode::Eos);
+
+        // Every PSB, backward from the end.
+        let mut backward = 0;
+        while assert_same_sync(
+            &mut c,
+            &mut rust,
+            seed,
+            PacketDecoder::sync_backward,
+            NativePacketDecoder::sync_backward,
+        ) {
+            backward += 1;
+        }
+        assert_eq!(psbs, backward, "seed {seed}");
+
+        // The whole stream.
+        assert!(assert_same_sync(
+            &mut c,
+            &mut rust,
+            seed,
+            |c| c.sync_set(0),
+            |rust| rust.sync_set(0),
+        ));
+        let offsets = assert_same_packets(&mut c, &mut rust, usize::MAX, seed);
+        assert_eq!(c.offset().unwrap(), buf.len() as u64);
+
+        // Random packet boundaries.
+        let mut rng = Rng::new(seed);
+        for _ in 0..16 {
+            let offset = offsets[rng.below(offsets.len() as u64) as usize];
+            assert!(assert_same_sync(
+                &mut c,
+                &mut rust,
+                seed,
+                |c| c.sync_set(offset),
+                |rust| rust.sync_set(offset),
+            ));
+            assert_same_packets(&mut c, &mut rust, 8, seed);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "pure-rust-packets")]
+
+mod common;
+
+use common::packets::encode_all_packets;
+use libipt::enc_dec_builder::{Cpu, PtEncoderDecoder};
+use libipt::packet::*;
+
+/// Encode the packets of `encode_all_packets!`, then the variants and compression modes it
+/// doesn't cover.
+///
+/// Returns the size of the packets of `encode_all_packets!` and the total size.
+macro_rules! encode_more_packets {
+    ($enc:expr) => {{
+        let enc = &mut $enc;
+        let base = encode_all_packets!(*enc);
+        let mut size = base;
+
+        size += enc
+            .next(Tip::new(0xdead_beef, Compression::Update32))
+            .unwrap();
+        size += enc
+            .next(Fup::new(0x7fff_dead_beef, Compression::Update48))
+            .unwrap();
+        size += enc.next(Tnt64::new(0x1234_5678_9abc, 47 - 1)).unwrap();
+        size += enc.next(Mode::new(Payload::Tsx(Tsx::INTX))).unwrap();
+        size += enc.next(Pip::new(0xcafe_0000, true)).unwrap();
+        size += enc.next(Cyc::new(0x1f)).unwrap();
+        size += enc.next(Exstop::new(false)).unwrap();
+        size += enc.next(Ptw::new(0xfeed, 1, true)).unwrap();
+
+        (base, size)
+    }};
+}
+
+#[test]
+fn test_native_encoder_matches_c_encoder() {
+    let mut native = NativeEncoder::new(Vec::new());
+    let (base, size) = encode_more_packets!(native);
+    assert_eq!(base, 132);
+    assert_eq!(u64::from(size), native.offset());
+
+    let mut inp = vec![0u8; size as usize];
+    let mut enc: Encoder<()> = Encoder::builder()
+        .cpu(Cpu::intel(1, 2, 3))
+        .buffer_mut(&mut inp)
+        .build()
+        .unwrap();
+    assert_eq!(encode_more_packets!(enc), (base, size));
+    drop(enc);
+
+    assert_eq!(native.into_inner(), inp);
+}
+
+#[test]
+fn test_native_encoder_bad_packets() {
+    let mut enc = NativeEncoder::new(Vec::new());
+
+    assert!(enc.next(Tnt8::new(0, 7)).is_err());
+    assert!(enc.next(Tnt64::new(0, 47)).is_err());
+    assert!(enc.next(Tma::new(0, 0x200)).is_err());
+    assert!(enc.next(Ptw::new(0, 2, false)).is_err());
+    assert_eq!(enc.offset(), 0);
+    assert!(enc.into_inner().is_empty());
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use common::{DISABLE, EXPECTED_IPS, FIRST_PASS, LOOP, SECOND_PASS, image};
+use libipt::enc_dec_builder::PtEncoderDecoder;
+use libipt::image::Image;
+use libipt::insn::InsnDecoder;
+use libipt::merge::Decoded;
+use libipt::parallel::{ParallelDecoder, psb_offsets};
+use libipt::synth::{Step, Synthesizer};
+use std::num::NonZeroUsize;
+
+#[test]
+fn test_parallel_insn_decoding() {
+    // A PSB+ before the ret and another one at the beginning of the second iteration.
+    let trace = Synthesizer::new()
+        .step(Step::Time(0x1234))
+        .steps(FIRST_PASS[..3].iter().copied())
+        .steps([Step::Psb(0x1010), FIRST_PASS[3], LOOP, Step::Psb(0x1000)])
+        .steps(SECOND_PASS)
+        .step(DISABLE)
+        .synthesize()
+        .unwrap();
+    assert_eq!(psb_offsets(&trace).unwrap().len(), 3);
+
+    for threads in [1, 2] {
+        let mut chunks = 0;
+        let mut ips = Vec::new();
+        ParallelDecoder::new()
+            .chunks(NonZeroUsize::new(16).unwrap())
+            .threads(NonZeroUsize::new(threads).unwrap())
+            .decode(
+                &InsnDecoder::builder(),
+                &trace,
+                |_| Ok(image()),
+                |decoded| {
+                    chunks += 1;
+                    ips.extend(
+                        decoded
+                            .into_iter()
+                            .filter_map(|result| match result.unwrap() {
+                                Decoded::Item(insn) => Some(insn.ip()),
+                                Decoded::Event(_) => None,
+                            }),
+                    );
+                },
+            )
+            .unwrap();
+        assert_eq!(chunks, 3);
+        assert_eq!(ips, EXPECTED_IPS);
+    }
+
+    // Errors creating the chunk decoders are reported.
+    let result = ParallelDecoder::new()
+        .chunks(NonZeroUsize::new(16).unwrap())
+        .decode(
+            &InsnDecoder::builder(),
+            &trace,
+            |index| match index {
+                1 => Image::new(Some("bad\0name")),
+                _ => Ok(image()),
+            },
+            |_| {},
+        );
+    assert!(result.is_err());
+}
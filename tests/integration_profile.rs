@@ -0,0 +1,30 @@
+mod common;
+
+use common::{STEPS, image, synthesize};
+use libipt::block::BlockDecoder;
+use libipt::callstack::CallStack;
+use libipt::enc_dec_builder::PtEncoderDecoder;
+use libipt::profile::{Profile, Weight};
+use libipt::synth::Step;
+
+#[test]
+fn test_profile_callstack() {
+    let trace = synthesize(&[&[Step::Time(0x1000)][..], &STEPS].concat());
+
+    let image = image();
+    let builder = BlockDecoder::builder().buffer(&trace);
+    let mut callstack = CallStack::from_builder(&builder).unwrap();
+    callstack.decoder_mut().image().extend(&image).unwrap();
+
+    let mut profile = Profile::new(Weight::Insns);
+    profile.record(&mut callstack);
+    assert_eq!(callstack.depth(), 0);
+    assert_eq!(profile.total(), 13);
+
+    let mut folded = Vec::new();
+    profile
+        .write_folded(&mut folded, |ip| (ip == 0x1010).then(|| "ret".to_owned()))
+        .unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    assert!(folded.ends_with("[unknown];ret 2\n"), "{folded}");
+}
@@ -0,0 +1,120 @@
+mod common;
+
+use common::{BASE, CODE, EXPECTED_IPS, STEPS, image};
+use libipt::block::BlockDecoder;
+use libipt::enc_dec_builder::PtEncoderDecoder;
+use libipt::error::PtErrorCode;
+use libipt::insn::InsnDecoder;
+use libipt::status::Status;
+use libipt::synth::{Step, Synthesizer};
+
+fn trace(ret_compression: bool) -> Vec<u8> {
+    Synthesizer::new()
+        .ret_compression(ret_compression)
+        .step(Step::Time(0x1234))
+        .steps(STEPS)
+        .synthesize()
+        .unwrap()
+}
+
+#[test]
+fn test_synth_insn_decoding() {
+    for ret_compression in [false, true] {
+        let trace = trace(ret_compression);
+        let mut image = image();
+        let mut dec = InsnDecoder::builder().buffer(&trace).build().unwrap();
+        dec.set_image(Some(&mut image)).unwrap();
+
+        let mut ips = Vec::new();
+        let mut status = dec.sync_forward().unwrap();
+        loop {
+            while status.event_pending() {
+                status = dec.event().unwrap().1;
+            }
+            if status.eos() {
+                break;
+            }
+            match dec.decode_next() {
+                Ok((insn, s)) => {
+                    ips.push(insn.ip());
+                    status = s;
+                }
+                Err(e) if e.code() == PtErrorCode::Eos => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+
+        assert_eq!(ips, EXPECTED_IPS);
+    }
+}
+
+#[test]
+fn test_synth_block_decoding() {
+    for ret_compression in [false, true] {
+        let trace = trace(ret_compression);
+        let mut image = image();
+        let mut dec = BlockDecoder::builder().buffer(&trace).build().unwrap();
+        dec.set_image(Some(&mut image)).unwrap();
+
+        let mut blocks = Vec::new();
+        let mut status: Status = dec.sync_forward().unwrap();
+        loop {
+            while status.event_pending() {
+                status = dec.event().unwrap().1;
+            }
+            if status.eos() {
+                break;
+            }
+            match dec.decode_next() {
+                Ok((block, s)) => {
+                    if block.ninsn() > 0 {
+                        blocks.push(block);
+                    }
+                    status = s;
+                }
+                Err(e) if e.code() == PtErrorCode::Eos => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+
+        assert_eq!(blocks.first().unwrap().ip(), 0x1000);
+        assert_eq!(blocks.last().unwrap().end_ip(), 0x100d);
+        let ninsn: usize = blocks.iter().map(|b| usize::from(b.ninsn())).sum();
+        assert_eq!(ninsn, EXPECTED_IPS.len());
+    }
+}
+
+#[test]
+fn test_synth_block_instructions() {
+    for ret_compression in [false, true] {
+        let trace = trace(ret_compression);
+        let mut dec = BlockDecoder::builder().buffer(&trace).build().unwrap();
+        dec.image().extend(&image()).unwrap();
+
+        let mut ips = Vec::new();
+        let mut status: Status = dec.sync_forward().unwrap();
+        loop {
+            while status.event_pending() {
+                status = dec.event().unwrap().1;
+            }
+            if status.eos() {
+                break;
+            }
+            match dec.decode_next() {
+                Ok((block, s)) => {
+                    for insn in block.instructions(dec.image()) {
+                        let insn = insn.unwrap();
+                        let offset = (insn.ip() - BASE) as usize;
+                        assert_eq!(insn.raw(), &CODE[offset..offset + insn.raw().len()]);
+                        ips.push(insn.ip());
+                    }
+                    status = s;
+                }
+                Err(e) if e.code() == PtErrorCode::Eos => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+
+        assert_eq!(ips, EXPECTED_IPS);
+    }
+}
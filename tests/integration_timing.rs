@@ -0,0 +1,48 @@
+mod common;
+
+use common::{DISABLE, FIRST_PASS, LOOP, SECOND_PASS, image};
+use libipt::block::BlockDecoder;
+use libipt::enc_dec_builder::{Frequency, PtEncoderDecoder};
+use libipt::synth::{Step, Synthesizer};
+use libipt::timing::TimingModel;
+
+#[test]
+fn test_timing_synth() {
+    let trace = Synthesizer::new()
+        .step(Step::Time(0x1000))
+        .steps(FIRST_PASS)
+        .step(LOOP)
+        .steps(SECOND_PASS)
+        .steps([Step::Time(0x1100), DISABLE])
+        .synthesize()
+        .unwrap();
+
+    let freq = Frequency::new(0, 24, 0, 0);
+    let image = image();
+    let mut decoder = BlockDecoder::builder()
+        .freq(freq)
+        // Split the trace at every timing packet.
+        .set_enable_tick_events(true)
+        .buffer(&trace)
+        .build()
+        .unwrap();
+    decoder.image().extend(&image).unwrap();
+
+    let mut model = TimingModel::new(freq);
+    model.set_core_bus_ratio(36);
+    let mut timed = Vec::new();
+    model.record(&mut decoder, |block| timed.push(*block));
+
+    // The time between the enable and the disable is spread over all the blocks in between.
+    assert!(!timed.is_empty());
+    let ticks: f64 = timed.iter().map(|block| block.ticks).sum();
+    assert!((ticks - 256.0).abs() < 1e-6, "{ticks}");
+    for pair in timed.windows(2) {
+        assert!(pair[0].tsc <= pair[1].tsc);
+    }
+    for block in &timed {
+        assert!(model.block(&block.key()).is_some());
+    }
+    let cycles: f64 = model.blocks().map(|(_, timing)| timing.cycles).sum();
+    assert!((cycles - 384.0).abs() < 1e-6, "{cycles}");
+}